@@ -138,6 +138,8 @@ pub fn get_card_configuration() -> Option<Vec<gpu::Card>> {
                 min_power_limit_watt: (infobuf.max_power_limit / 1000) as i32,
                 max_ce_clock_mhz: infobuf.max_ce_clock as i32,
                 max_mem_clock_mhz: infobuf.max_mem_clock as i32,
+                compute_mode: "".to_string(),
+                compute_mode_raw: "".to_string(),
             })
         }
     }
@@ -159,11 +161,12 @@ pub fn get_card_utilization() -> Option<Vec<gpu::CardState>> {
                 index: dev as i32,
                 fan_speed_pct: infobuf.fan_speed_pct,
                 compute_mode: "".to_string(),
+                compute_mode_raw: "".to_string(),
                 perf_state: format!("{}", infobuf.perf_level),
                 mem_reserved_kib: 0,
                 mem_used_kib: (infobuf.mem_used / 1024) as i64,
                 gpu_utilization_pct: infobuf.gpu_util,
-                mem_utilization_pct: infobuf.mem_util,
+                mem_bandwidth_pct: infobuf.mem_util,
                 temp_c: infobuf.temp as i32,
                 power_watt: (infobuf.power / 1000) as i32,
                 power_limit_watt: (infobuf.power_limit / 1000) as i32,
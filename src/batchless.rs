@@ -64,10 +64,20 @@ fn parsed_full_test_output() -> HashMap<usize, procfs::Process> {
         (205414, 205408, 205408, "/usr/bin/dbus-broker-launch"),
         (205415, 205414, 205408, "dbus-broker"),
         (205417, 205060, 205417, "/usr/libexec/at-spi2-registryd"),
-        (205426, 205060, 205426, "/usr/libexec/gnome-shell-calendar-server"),
+        (
+            205426,
+            205060,
+            205426,
+            "/usr/libexec/gnome-shell-calendar-server",
+        ),
         (205435, 205060, 205435, "/usr/libexec/xdg-permission-store"),
         (205440, 205060, 205440, "/usr/libexec/dconf-service"),
-        (205451, 205060, 205451, "/usr/libexec/evolution-source-registry"),
+        (
+            205451,
+            205060,
+            205451,
+            "/usr/libexec/evolution-source-registry",
+        ),
         (205465, 205060, 205465, "/usr/bin/gjs"),
         (205470, 205060, 205470, "/usr/bin/ibus-daemon"),
         (205471, 205060, 205471, "/usr/libexec/gsd-a11y-settings"),
@@ -78,14 +88,29 @@ fn parsed_full_test_output() -> HashMap<usize, procfs::Process> {
         (205495, 205060, 205495, "/usr/libexec/gsd-media-keys"),
         (205512, 205231, 205231, "/usr/bin/gnome-software"),
         (205515, 205060, 205515, "/usr/libexec/gsd-power"),
-        (205520, 205060, 205520, "/usr/libexec/gsd-print-notifications"),
+        (
+            205520,
+            205060,
+            205520,
+            "/usr/libexec/gsd-print-notifications",
+        ),
         (205527, 205060, 205527, "/usr/libexec/gsd-rfkill"),
         (205530, 205060, 205530, "/usr/libexec/gsd-screensaver-proxy"),
         (205532, 205060, 205532, "/usr/libexec/gsd-sharing"),
-        (205533, 205231, 205231, "/usr/libexec/gsd-disk-utility-notify"),
+        (
+            205533,
+            205231,
+            205231,
+            "/usr/libexec/gsd-disk-utility-notify",
+        ),
         (205545, 205060, 205545, "/usr/libexec/gsd-smartcard"),
         (205560, 205060, 205560, "/usr/libexec/gsd-sound"),
-        (205567, 205231, 205231, "/usr/libexec/evolution-data-server/evolution-alarm-notify"),
+        (
+            205567,
+            205231,
+            205231,
+            "/usr/libexec/evolution-data-server/evolution-alarm-notify",
+        ),
         (205569, 205060, 205569, "/usr/libexec/gsd-usb-protection"),
         (205578, 205060, 205578, "/usr/libexec/gsd-wacom"),
         (205652, 205470, 205470, "/usr/libexec/ibus-dconf"),
@@ -94,21 +119,66 @@ fn parsed_full_test_output() -> HashMap<usize, procfs::Process> {
         (205678, 205060, 205678, "/usr/bin/abrt-applet"),
         (205679, 205060, 205679, "/usr/libexec/goa-daemon"),
         (205683, 205060, 205683, "/usr/bin/gjs"),
-        (205687, 205060, 205687, "/usr/libexec/evolution-calendar-factory"),
-        (205688, 205060, 205688, "/usr/libexec/gvfs-udisks2-volume-monitor"),
-        (205730, 205060, 205730, "/usr/libexec/gvfs-mtp-volume-monitor"),
+        (
+            205687,
+            205060,
+            205687,
+            "/usr/libexec/evolution-calendar-factory",
+        ),
+        (
+            205688,
+            205060,
+            205688,
+            "/usr/libexec/gvfs-udisks2-volume-monitor",
+        ),
+        (
+            205730,
+            205060,
+            205730,
+            "/usr/libexec/gvfs-mtp-volume-monitor",
+        ),
         (205747, 205060, 205747, "/usr/libexec/goa-identity-service"),
-        (205750, 205060, 205750, "/usr/libexec/evolution-addressbook-factory"),
-        (205761, 205060, 205761, "/usr/libexec/gvfs-gphoto2-volume-monitor"),
-        (205778, 205060, 205778, "/usr/libexec/gvfs-goa-volume-monitor"),
-        (205792, 205060, 205792, "/usr/libexec/gvfs-afc-volume-monitor"),
+        (
+            205750,
+            205060,
+            205750,
+            "/usr/libexec/evolution-addressbook-factory",
+        ),
+        (
+            205761,
+            205060,
+            205761,
+            "/usr/libexec/gvfs-gphoto2-volume-monitor",
+        ),
+        (
+            205778,
+            205060,
+            205778,
+            "/usr/libexec/gvfs-goa-volume-monitor",
+        ),
+        (
+            205792,
+            205060,
+            205792,
+            "/usr/libexec/gvfs-afc-volume-monitor",
+        ),
         (205834, 205060, 205520, "/usr/libexec/gsd-printer"),
         (205855, 205470, 205470, "/usr/libexec/ibus-engine-simple"),
         (205868, 205060, 205868, "/usr/bin/pipewire-pulse"),
         (205898, 205060, 205898, "/usr/libexec/xdg-desktop-portal"),
         (205916, 205060, 205916, "/usr/libexec/xdg-document-portal"),
-        (205947, 205060, 205947, "/usr/libexec/xdg-desktop-portal-gnome"),
-        (206013, 205060, 206013, "/usr/libexec/xdg-desktop-portal-gtk"),
+        (
+            205947,
+            205060,
+            205947,
+            "/usr/libexec/xdg-desktop-portal-gnome",
+        ),
+        (
+            206013,
+            205060,
+            206013,
+            "/usr/libexec/xdg-desktop-portal-gtk",
+        ),
         (206065, 205060, 206065, "/usr/libexec/gvfsd-metadata"),
         (206124, 205310, 205310, "/usr/lib64/firefox/firefox"),
         (206145, 205060, 206145, "/usr/libexec/cgroupify"),
@@ -162,9 +232,18 @@ fn parsed_full_test_output() -> HashMap<usize, procfs::Process> {
                 mem_pct: 0.0,
                 mem_size_kib: 0,
                 rssanon_kib: 0,
+                major_faults: 0,
+                minor_faults: 0,
+                num_threads: 0,
+                voluntary_ctxt_switches: 0,
+                nonvoluntary_ctxt_switches: 0,
+                cpu_times: Default::default(),
                 uid: 0,
                 user: "user".to_string(),
                 has_children: false,
+                is_zombie: false,
+                is_uninterruptible: false,
+                is_kernel_thread: false,
             },
         )
     })
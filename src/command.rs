@@ -1,12 +1,14 @@
 use std::io;
 use std::time::Duration;
-use subprocess::{Exec, ExitStatus, Redirection};
+use subprocess::{Exec, ExitStatus, PopenError, Redirection};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum CmdError {
     CouldNotStart(String),
+    NotPermitted(String),
     Failed(String),
     Hung(String),
+    TooLarge(String),
     InternalError(String),
 }
 
@@ -26,6 +28,19 @@ pub fn safe_command(
     command: &str,
     args: &[&str],
     timeout_seconds: u64,
+) -> Result<String, CmdError> {
+    safe_command_with_limit(command, args, timeout_seconds, usize::MAX)
+}
+
+// As safe_command, but if the program produces more than max_output_bytes of output on stdout then
+// it is killed and its output is discarded, so that a runaway program can't make us buffer an
+// unbounded amount of data.
+
+pub fn safe_command_with_limit(
+    command: &str,
+    args: &[&str],
+    timeout_seconds: u64,
+    max_output_bytes: usize,
 ) -> Result<String, CmdError> {
     let mut p = match Exec::cmd(command)
         .args(args)
@@ -34,6 +49,9 @@ pub fn safe_command(
         .popen()
     {
         Ok(p) => p,
+        Err(PopenError::IoError(e)) if e.kind() == io::ErrorKind::PermissionDenied => {
+            return Err(CmdError::NotPermitted(command.to_string()));
+        }
         Err(_) => {
             // TODO: Possibly too coarse-grained but the documentation is not
             // helpful in clarifying what might have happened.
@@ -48,9 +66,13 @@ pub fn safe_command(
     // we'll keep reading for as long as it does that, we won't abort the program after
     // timeout_seconds have passed.  I think this is probably OK even though it violates the letter
     // of the API.
+    //
+    // A read returns at most one byte more than the output limit, so output that is within the
+    // limit is read in one go and output beyond it is detected without reading all of it.
     let mut comm = p
         .communicate_start(None)
-        .limit_time(Duration::new(timeout_seconds, 0));
+        .limit_time(Duration::new(timeout_seconds, 0))
+        .limit_size(max_output_bytes.saturating_add(1));
     let mut stdout_result = "".to_string();
     let mut stderr_result = "".to_string();
     let code = loop {
@@ -67,6 +89,16 @@ pub fn safe_command(
                 } else if stdout.is_empty() {
                     // This is always EOF because timeouts are signaled as Err()
                     break None;
+                } else if stdout_result.len() + stdout.len() > max_output_bytes {
+                    let _ = p.terminate();
+                    let _ = p.wait();
+                    return Err(CmdError::TooLarge(format_failure(
+                        command,
+                        format!("Output exceeded {max_output_bytes} bytes and was discarded")
+                            .as_str(),
+                        "",
+                        &stderr_result,
+                    )));
                 } else {
                     stdout_result += &stdout;
                 }
@@ -118,7 +150,7 @@ pub fn safe_command(
                 Ok(stdout_result)
             }
         }
-        Ok(ExitStatus::Exited(126)) => Err(CmdError::CouldNotStart(format_failure(
+        Ok(ExitStatus::Exited(126)) => Err(CmdError::NotPermitted(format_failure(
             command,
             "Command cannot execute",
             &stdout_result,
@@ -185,7 +217,7 @@ fn test_safe_command() {
     }
     // Wrong permissions, not executable
     match safe_command("/etc/passwd", &[], 2) {
-        Err(CmdError::CouldNotStart(_)) => {}
+        Err(CmdError::NotPermitted(_)) => {}
         _ => {
             assert!(false)
         }
@@ -213,3 +245,25 @@ fn test_safe_command() {
         }
     }
 }
+
+#[test]
+fn test_safe_command_with_limit() {
+    // Within the limit
+    assert!(
+        safe_command_with_limit("grep", &["^name =", "Cargo.toml"], 2, 100)
+            == Ok("name = \"sonar\"\n".to_string())
+    );
+    // Beyond the limit, the output is not returned and the error says why
+    match safe_command_with_limit("cat", &["img/sonar.png"], 5, 10000) {
+        Err(CmdError::TooLarge(msg)) => {
+            assert!(msg.contains("exceeded 10000 bytes and was discarded"));
+            assert!(msg.len() < 1000);
+        }
+        other => panic!("Test: expected TooLarge, got {other:?}"),
+    }
+    // A program that never stops producing output is killed rather than timing out
+    match safe_command_with_limit("yes", &[], 60, 1000000) {
+        Err(CmdError::TooLarge(_)) => {}
+        other => panic!("Test: expected TooLarge, got {other:?}"),
+    }
+}
@@ -0,0 +1,301 @@
+// Settings for a command can be read from a file with `--config FILE`, so that scripts and cron
+// entries need not repeat long argument lists.  The settings are turned into command line arguments
+// and placed before the arguments on the actual command line.
+//
+// The file is ini-like.  Blank lines and lines starting with '#' or ';' are ignored.  A line
+// `[command]` starts a section whose settings apply only to that command; settings before the first
+// section apply to every command that accepts them and are ignored by the others.  A setting is
+// `name = value`, where the name is an option name without the leading dashes, or just `name` for
+// an option without a value.  For an option without a value, the values `true` and `false` turn it
+// on and off; for other options they are just values.  Options that can be repeated, such as `tag`,
+// can be given several times.
+//
+// An option on the command line overrides all the file's settings for that option, also for
+// options that can be repeated.  The output format options are alternatives and count as one
+// option for this purpose, so `--csv` on the command line overrides `json` in the file.  On the
+// command line, `--name=false` turns off an option without a value that the file turns on, and
+// `--name=true` is the same as `--name`, whether or not there is a config file.
+//
+// For example:
+//
+//   [ps]
+//   exclude-system-jobs
+//   exclude-users = root,zabbix
+//   min-cpu-time = 60
+//   tag = cluster=fox
+
+use std::collections::HashSet;
+use std::fs;
+
+const FORMAT_OPTIONS: &[&str] = &["csv", "flat", "json", "msgpack", "syslog-summary"];
+
+// Expand `--config FILE` or `--config=FILE` among the arguments following the command in `args`,
+// which is the full argument vector including the program name.  Only one config file is allowed.
+// `options` are the names of the options the command accepts and `flags` those among them that
+// take no value.
+
+pub fn expand_config(
+    args: &[String],
+    options: &[&str],
+    flags: &[&str],
+) -> Result<Vec<String>, String> {
+    if args.len() < 2 {
+        return Ok(args.to_vec());
+    }
+    let command = args[1].as_str();
+    let mut filename = None;
+    let mut rest = vec![];
+    let mut i = 2;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        let value = if arg == "--config" {
+            i += 1;
+            match args.get(i) {
+                Some(v) => Some(v.clone()),
+                None => return Err("--config requires a file name".to_string()),
+            }
+        } else {
+            arg.strip_prefix("--config=").map(|v| v.to_string())
+        };
+        match value {
+            Some(v) => {
+                if filename.is_some() {
+                    return Err("--config can be given only once".to_string());
+                }
+                filename = Some(v);
+            }
+            None => rest.push(args[i].clone()),
+        }
+        i += 1;
+    }
+    let text = match filename {
+        Some(filename) => match fs::read_to_string(&filename) {
+            Ok(text) => text,
+            Err(e) => return Err(format!("Could not read config file {filename}: {e}")),
+        },
+        None => String::new(),
+    };
+    let given = rest
+        .iter()
+        .filter_map(|arg| option_name(arg))
+        .collect::<HashSet<&str>>();
+    let format_given = FORMAT_OPTIONS.iter().any(|f| given.contains(f));
+    let overridden =
+        |name: &str| given.contains(name) || (format_given && FORMAT_OPTIONS.contains(&name));
+    let mut expanded = args[..2].to_vec();
+    expanded.extend(
+        config_args(&text, command, options, flags)?
+            .into_iter()
+            .filter(|arg| !option_name(arg).is_some_and(overridden)),
+    );
+    for arg in &rest {
+        match arg.split_once('=') {
+            Some((opt, "false")) if is_flag(opt, flags) => {}
+            Some((opt, "true")) if is_flag(opt, flags) => expanded.push(opt.to_string()),
+            _ => expanded.push(arg.clone()),
+        }
+    }
+    Ok(expanded)
+}
+
+// The name of the option in `--name` or `--name=value`, None if `arg` is not an option.
+
+fn option_name(arg: &str) -> Option<&str> {
+    let name = arg.strip_prefix("--")?;
+    Some(name.split_once('=').map_or(name, |(name, _)| name))
+}
+
+// Whether `opt`, eg `--load`, names one of the options without a value in `flags`.
+
+fn is_flag(opt: &str, flags: &[&str]) -> bool {
+    opt.strip_prefix("--")
+        .is_some_and(|name| flags.contains(&name))
+}
+
+// Turn the settings for `command` in the text of a config file into command line arguments.  The
+// settings before the first section are only used if they are among the command's `options`.
+// `true` and `false` turn the options in `flags` on and off.
+
+pub fn config_args(
+    text: &str,
+    command: &str,
+    options: &[&str],
+    flags: &[&str],
+) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut applies = true;
+    let mut common = true;
+    for (lineno, l) in text.lines().enumerate() {
+        let l = l.trim();
+        if l.is_empty() || l.starts_with('#') || l.starts_with(';') {
+            continue;
+        }
+        if let Some(section) = l.strip_prefix('[') {
+            match section.strip_suffix(']') {
+                Some(name) => (applies, common) = (name.trim() == command, false),
+                None => return Err(format!("Bad section on config line {}: {l}", lineno + 1)),
+            }
+            continue;
+        }
+        if !applies {
+            continue;
+        }
+        let (name, value) = match l.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (l, None),
+        };
+        if name.is_empty() || name.starts_with('-') || name.contains(char::is_whitespace) {
+            return Err(format!("Bad setting on config line {}: {l}", lineno + 1));
+        }
+        if common && !options.contains(&name) {
+            continue;
+        }
+        match value {
+            None => args.push(format!("--{name}")),
+            Some("true") if flags.contains(&name) => args.push(format!("--{name}")),
+            Some("false") if flags.contains(&name) => {}
+            Some(value) => args.push(format!("--{name}={value}")),
+        }
+    }
+    Ok(args)
+}
+
+#[test]
+pub fn config_args_test() {
+    let text = "
+# Common settings
+tag = site=uio
+token-file = /etc/sonar/token
+
+[ps]
+exclude-system-jobs
+load = true
+rollup = false
+exclude-users = root, zabbix
+min-cpu-time=60
+tag = cluster=fox
+
+expect-cluster = false
+
+[sysinfo]
+csv
+";
+    let ps_options = ["exclude-system-jobs", "tag", "token-file"];
+    let ps_flags = ["exclude-system-jobs", "load", "rollup"];
+    let slurm_options = ["token-file", "window"];
+    assert!(
+        config_args(text, "ps", &ps_options, &ps_flags).unwrap()
+            == [
+                "--tag=site=uio",
+                "--token-file=/etc/sonar/token",
+                "--exclude-system-jobs",
+                "--load",
+                "--exclude-users=root, zabbix",
+                "--min-cpu-time=60",
+                "--tag=cluster=fox",
+                "--expect-cluster=false",
+            ]
+    );
+    // Settings in a section are used whether the command accepts them or not, common settings only
+    // if it does.
+    assert!(config_args(text, "sysinfo", &["tag"], &[]).unwrap() == ["--tag=site=uio", "--csv"]);
+    assert!(
+        config_args(text, "slurm", &slurm_options, &[]).unwrap()
+            == ["--token-file=/etc/sonar/token"]
+    );
+    assert!(config_args("[ps\nload", "ps", &ps_options, &ps_flags).is_err());
+    assert!(config_args("--load", "ps", &ps_options, &ps_flags).is_err());
+    assert!(config_args("min cpu time = 5", "ps", &ps_options, &ps_flags).is_err());
+}
+
+// The file's settings come before the remaining arguments, and the command line overrides them.
+
+#[test]
+pub fn expand_config_test() {
+    let dir = std::env::temp_dir().join(format!("sonar-config-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let filename = dir.join("sonar.ini");
+    fs::write(
+        &filename,
+        "tag = site=uio\n[ps]\nexclude-users = root\nmin-cpu-time = 60\nload\njson\n",
+    )
+    .unwrap();
+    let filename = filename.to_str().unwrap();
+    let args = |xs: &[&str]| xs.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+    let options = [
+        "exclude-users",
+        "json",
+        "load",
+        "min-cpu-time",
+        "sort",
+        "tag",
+    ];
+    let flags = ["json", "load", "sort"];
+    let expand = |xs: &[&str]| expand_config(&args(xs), &options, &flags);
+
+    let expanded = expand(&["sonar", "ps", &format!("--config={filename}")]).unwrap();
+    assert!(
+        expanded
+            == args(&[
+                "sonar",
+                "ps",
+                "--tag=site=uio",
+                "--exclude-users=root",
+                "--min-cpu-time=60",
+                "--load",
+                "--json"
+            ])
+    );
+
+    // Options on the command line replace the file's settings for the same option, a format option
+    // replaces the file's format, and `=false` turns an option off.
+    let expanded = expand(&[
+        "sonar",
+        "ps",
+        "--config",
+        filename,
+        "--min-cpu-time",
+        "5",
+        "--tag=cluster=fox",
+        "--csv",
+        "--load=false",
+        "--sort=true",
+    ])
+    .unwrap();
+    assert!(
+        expanded
+            == args(&[
+                "sonar",
+                "ps",
+                "--exclude-users=root",
+                "--min-cpu-time",
+                "5",
+                "--tag=cluster=fox",
+                "--csv",
+                "--sort"
+            ])
+    );
+
+    // A command that doesn't accept the common settings doesn't get them.
+    let expanded = expand_config(
+        &args(&["sonar", "slurm", "--config", filename]),
+        &["window"],
+        &[],
+    );
+    assert!(expanded.unwrap() == args(&["sonar", "slurm"]));
+
+    // No config file, but `=true` and `=false` still apply to options without a value, and only to
+    // those.
+    let plain = args(&["sonar", "ps", "--load"]);
+    assert!(expand(&["sonar", "ps", "--load"]).unwrap() == plain);
+    assert!(expand(&["sonar", "ps", "--load=true", "--sort=false"]).unwrap() == plain);
+    let expanded = expand(&["sonar", "ps", "--exclude-users=false", "--tag=true"]).unwrap();
+    assert!(expanded == args(&["sonar", "ps", "--exclude-users=false", "--tag=true"]));
+
+    // Errors
+    assert!(expand(&["sonar", "ps", "--config"]).is_err());
+    assert!(expand(&["sonar", "ps", "--config", "/nonexistent/sonar.ini"]).is_err());
+    assert!(expand(&["sonar", "ps", "--config", filename, "--config", filename]).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,175 @@
+// Best-effort resolution of the container runtime and image for a process.
+//
+// The container ID is found in /proc/{pid}/cgroup, where container runtimes place processes in
+// cgroups whose names embed the 64-hex-digit container ID, eg
+//
+//    0::/system.slice/docker-<id>.scope                  (docker, cgroup v2 with systemd)
+//    12:pids:/docker/<id>                                 (docker, cgroup v1)
+//    0::/machine.slice/libpod-<id>.scope                 (podman)
+//    0::/kubepods.slice/.../cri-containerd-<id>.scope    (containerd)
+//    0::/kubepods.slice/.../crio-<id>.scope              (cri-o)
+//
+// The image name is then looked up in the runtime's metadata.  Currently only docker's metadata is
+// consulted, and it is usually readable only by root, so the image is frequently unknown even when
+// the runtime is known.  Everything here must degrade to None when nothing can be resolved.
+
+use std::collections::HashMap;
+use std::fs;
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Container {
+    pub runtime: String,
+    pub id: String,
+}
+
+const ID_LEN: usize = 64;
+
+// Cgroup name prefixes and the runtimes they belong to.  For the path-element form (cgroup v1
+// docker) the prefix is the directory name followed by '/'.
+const PREFIXES: [(&str, &str); 5] = [
+    ("docker-", "docker"),
+    ("docker/", "docker"),
+    ("libpod-", "podman"),
+    ("cri-containerd-", "containerd"),
+    ("crio-", "cri-o"),
+];
+
+// Extract the container from the contents of /proc/{pid}/cgroup, if there is one.
+
+pub fn container_from_cgroup(cgroup: &str) -> Option<Container> {
+    for l in cgroup.lines() {
+        for (prefix, runtime) in PREFIXES {
+            if let Some(x) = l.find(prefix) {
+                let rest = &l[x + prefix.len()..];
+                if rest.len() >= ID_LEN && rest[..ID_LEN].chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Some(Container {
+                        runtime: runtime.to_string(),
+                        id: rest[..ID_LEN].to_string(),
+                    });
+                }
+            }
+        }
+    }
+    None
+}
+
+// Abstraction of the runtimes' metadata stores, so that they can be virtualized for testing.
+
+pub trait ContainerAPI {
+    // Return the image name for the container, if it can be determined.
+    fn image(&self, container: &Container) -> Option<String>;
+}
+
+pub struct RealContainerAPI {}
+
+impl RealContainerAPI {
+    pub fn new() -> RealContainerAPI {
+        RealContainerAPI {}
+    }
+}
+
+impl ContainerAPI for RealContainerAPI {
+    fn image(&self, container: &Container) -> Option<String> {
+        if container.runtime == "docker" {
+            let filename = format!("/var/lib/docker/containers/{}/config.v2.json", container.id);
+            match fs::read_to_string(filename) {
+                Ok(s) => image_from_docker_config(&s),
+                Err(_) => None,
+            }
+        } else {
+            None
+        }
+    }
+}
+
+// Docker's config.v2.json has a toplevel "Image" field with the image hash, and the image name in
+// the "Image" field of the "Config" object.  We want the name.  A full JSON parser would be
+// overkill; the field values are JSON strings and we stop at the first unescaped quote.
+
+pub fn image_from_docker_config(config: &str) -> Option<String> {
+    let config_start = config.find("\"Config\":{")?;
+    let after = &config[config_start..];
+    let image_start = after.find("\"Image\":\"")? + "\"Image\":\"".len();
+    let mut image = "".to_string();
+    let mut escaped = false;
+    for c in after[image_start..].chars() {
+        if escaped {
+            image.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            return if image.is_empty() { None } else { Some(image) };
+        } else {
+            image.push(c);
+        }
+    }
+    None
+}
+
+// Cache image lookups by container ID, there will typically be many processes per container.
+
+pub struct ContainerTable<'a> {
+    api: &'a dyn ContainerAPI,
+    images: HashMap<String, Option<String>>,
+}
+
+impl<'a> ContainerTable<'a> {
+    pub fn new(api: &'a dyn ContainerAPI) -> ContainerTable<'a> {
+        ContainerTable {
+            api,
+            images: HashMap::new(),
+        }
+    }
+
+    pub fn image(&mut self, container: &Container) -> Option<String> {
+        if let Some(image) = self.images.get(&container.id) {
+            return image.clone();
+        }
+        let image = self.api.image(container);
+        self.images.insert(container.id.clone(), image.clone());
+        image
+    }
+}
+
+#[cfg(test)]
+pub struct MockContainerAPI {
+    images: HashMap<String, String>,
+}
+
+#[cfg(test)]
+impl MockContainerAPI {
+    pub fn new(images: HashMap<String, String>) -> MockContainerAPI {
+        MockContainerAPI { images }
+    }
+}
+
+#[cfg(test)]
+impl ContainerAPI for MockContainerAPI {
+    fn image(&self, container: &Container) -> Option<String> {
+        self.images.get(&container.id).cloned()
+    }
+}
+
+#[test]
+pub fn container_from_cgroup_test() {
+    let id = "4b8d0a7cfa1c2bb4c8f4ab12e0f2d57d29fc0fd6a4b0a1e3c5d7e9f1a3b5c7d9";
+    let c = container_from_cgroup(&format!("0::/system.slice/docker-{id}.scope\n"))
+        .expect("Test: docker v2");
+    assert!(c.runtime == "docker" && c.id == id);
+    let c = container_from_cgroup(&format!("13:misc:/\n12:pids:/docker/{id}\n"))
+        .expect("Test: docker v1");
+    assert!(c.runtime == "docker" && c.id == id);
+    let c = container_from_cgroup(&format!("0::/machine.slice/libpod-{id}.scope/container\n"))
+        .expect("Test: podman");
+    assert!(c.runtime == "podman" && c.id == id);
+    assert!(container_from_cgroup("0::/user.slice/user-1000.slice/session-3.scope\n").is_none());
+    assert!(container_from_cgroup("0::/system.slice/docker-1234.scope\n").is_none());
+}
+
+#[test]
+pub fn image_from_docker_config_test() {
+    let config = r#"{"StreamConfig":{},"State":{"Running":true},"Config":{"Hostname":"4b8d0a7cfa1c","Image":"pytorch/pytorch:2.1.0","Cmd":["python"]},"Image":"sha256:7d2b"}"#;
+    assert!(image_from_docker_config(config) == Some("pytorch/pytorch:2.1.0".to_string()));
+    assert!(image_from_docker_config(r#"{"Image":"sha256:7d2b"}"#).is_none());
+}
@@ -0,0 +1,189 @@
+// Structural comparison of two output::Value trees, for `sonar sysinfo --diff`.  The result is an
+// array of changes, one object per field that was added, removed, or changed, with the field's
+// dotted `path`, the kind of `change` ("added", "removed", or "changed"), and its `old` and/or
+// `new` value.  An object or array that is added or removed is one change, not one per field.
+//
+// Arrays whose elements are all objects with a "uuid" field, such as gpu_info, are compared element
+// by element by uuid, and the path component is the uuid, so that each card is compared with itself
+// even if the cards are listed in a different order.  Other arrays are compared by index.
+//
+// Numbers are compared by value and not by representation, as a number read back from JSON may be
+// unsigned where sonar produced a signed one.
+
+use crate::output;
+
+// Fields whose names are in `ignore` are not compared at any level, they are for values that change
+// all the time, such as timestamps.
+
+pub fn diff(old: &output::Value, new: &output::Value, ignore: &[&str]) -> output::Array {
+    let mut changes = output::Array::new();
+    diff_at("", old, new, ignore, &mut changes);
+    changes
+}
+
+fn diff_at(
+    path: &str,
+    old: &output::Value,
+    new: &output::Value,
+    ignore: &[&str],
+    changes: &mut output::Array,
+) {
+    match (old, new) {
+        (output::Value::O(old), output::Value::O(new)) => {
+            for (name, old_value) in old.iter() {
+                if ignore.contains(&name) {
+                    continue;
+                }
+                let p = join(path, name);
+                match new.get(name) {
+                    Some(new_value) => diff_at(&p, old_value, new_value, ignore, changes),
+                    None => push_change(changes, &p, "removed", Some(old_value), None),
+                }
+            }
+            for (name, new_value) in new.iter() {
+                if !ignore.contains(&name) && old.get(name).is_none() {
+                    push_change(changes, &join(path, name), "added", None, Some(new_value));
+                }
+            }
+        }
+        (output::Value::A(old), output::Value::A(new)) => {
+            let (old_keys, new_keys) = match (uuid_keys(old), uuid_keys(new)) {
+                (Some(o), Some(n)) => (o, n),
+                _ => (index_keys(old), index_keys(new)),
+            };
+            for (i, key) in old_keys.iter().enumerate() {
+                let p = join(path, key);
+                match new_keys.iter().position(|k| k == key) {
+                    Some(j) => diff_at(&p, old.at(i), new.at(j), ignore, changes),
+                    None => push_change(changes, &p, "removed", Some(old.at(i)), None),
+                }
+            }
+            for (j, key) in new_keys.iter().enumerate() {
+                if !old_keys.contains(key) {
+                    push_change(changes, &join(path, key), "added", None, Some(new.at(j)));
+                }
+            }
+        }
+        _ => {
+            if !same_scalar(old, new) {
+                push_change(changes, path, "changed", Some(old), Some(new));
+            }
+        }
+    }
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{path}.{name}")
+    }
+}
+
+fn uuid_keys(a: &output::Array) -> Option<Vec<String>> {
+    (0..a.len())
+        .map(|i| match a.at(i) {
+            output::Value::O(o) => match o.get("uuid") {
+                Some(output::Value::S(s)) => Some(s.clone()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+fn index_keys(a: &output::Array) -> Vec<String> {
+    (0..a.len()).map(|i| i.to_string()).collect()
+}
+
+fn same_scalar(old: &output::Value, new: &output::Value) -> bool {
+    let integer = |v: &output::Value| match v {
+        output::Value::U(u) => Some(*u as i128),
+        output::Value::I(i) => Some(*i as i128),
+        _ => None,
+    };
+    let float = |v: &output::Value| match v {
+        output::Value::U(u) => Some(*u as f64),
+        output::Value::I(i) => Some(*i as f64),
+        output::Value::F(f) => Some(*f),
+        _ => None,
+    };
+    match (old, new) {
+        (output::Value::S(a), output::Value::S(b)) => a == b,
+        (output::Value::E(), output::Value::E()) => true,
+        _ => match (integer(old), integer(new)) {
+            (Some(a), Some(b)) => a == b,
+            _ => matches!((float(old), float(new)), (Some(a), Some(b)) if a == b),
+        },
+    }
+}
+
+fn push_change(
+    changes: &mut output::Array,
+    path: &str,
+    change: &str,
+    old: Option<&output::Value>,
+    new: Option<&output::Value>,
+) {
+    let mut o = output::Object::new();
+    o.push_s("path", path.to_string());
+    o.push_s("change", change.to_string());
+    if let Some(old) = old {
+        o.push("old", old.clone());
+    }
+    if let Some(new) = new {
+        o.push("new", new.clone());
+    }
+    changes.push_o(o);
+}
+
+#[test]
+pub fn diff_test() {
+    use crate::json;
+
+    let old = json::parse(
+        r#"{"time": "t1", "cores": 8, "model": "Xeon", "gone": "x", "list": [1, 2, 3],
+            "cards": [{"uuid": "A", "mem": 40}, {"uuid": "B", "mem": 40}]}"#,
+    )
+    .unwrap();
+    let new = json::parse(
+        r#"{"time": "t2", "cores": 8.0, "model": "Epyc", "list": [1, 5], "extra": {"k": -1},
+            "cards": [{"uuid": "B", "mem": 40}, {"uuid": "C", "mem": 80}]}"#,
+    )
+    .unwrap();
+    let changes = diff(&old, &new, &["time"]);
+    let summary = (0..changes.len())
+        .map(|i| match changes.at(i) {
+            output::Value::O(o) => match (o.get("path"), o.get("change")) {
+                (Some(output::Value::S(p)), Some(output::Value::S(c))) => format!("{c} {p}"),
+                _ => panic!("Test: bad change"),
+            },
+            _ => panic!("Test: Expected object"),
+        })
+        .collect::<Vec<String>>();
+    assert!(
+        summary
+            == [
+                "changed model",
+                "removed gone",
+                "changed list.1",
+                "removed list.2",
+                "removed cards.A",
+                "added cards.C",
+                "added extra",
+            ]
+    );
+    match changes.at(0) {
+        output::Value::O(o) => {
+            assert!(matches!(o.get("old"), Some(output::Value::S(s)) if s == "Xeon"));
+            assert!(matches!(o.get("new"), Some(output::Value::S(s)) if s == "Epyc"));
+        }
+        _ => panic!("Test: Expected object"),
+    }
+    assert!(diff(&old, &old, &[]).len() == 0);
+    assert!(same_scalar(&output::Value::I(3), &output::Value::U(3)));
+    assert!(!same_scalar(
+        &output::Value::S("3".to_string()),
+        &output::Value::U(3)
+    ));
+}
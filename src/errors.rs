@@ -0,0 +1,103 @@
+// Errors that are reported in the output carry a code alongside the free-text message, so that
+// consumers can distinguish classes of failure without parsing the message.  The codes are stable
+// strings; the messages are not.
+
+use crate::command;
+use crate::output;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    // A command line argument was malformed.
+    BadArgument,
+    // A subprocess (eg sacct) could not be started or was not found.
+    SubcommandNotFound,
+    // Sonar lacks the permission to run a subprocess or read a file.
+    PermissionDenied,
+    // A subprocess ran but failed.
+    SubcommandFailed,
+    // A subprocess did not complete in time and was killed.
+    SubcommandTimeout,
+    // The output of a subprocess could not be parsed.
+    Parse,
+    // System data (eg in /proc) were missing or unparseable, or something else went wrong.
+    System,
+    // A GPU was found but its data could not be obtained from the driver library.
+    GpuProbeFailure,
+    // The data were obtained but indicate a problem with the node.  This is not a failure of sonar.
+    Health,
+    // The data were obtained but disagree with sonar's configuration, eg the cluster name.
+    Config,
+}
+
+impl ErrorCode {
+    pub fn from_cmd_error(e: &command::CmdError) -> ErrorCode {
+        match e {
+            command::CmdError::CouldNotStart(_) => ErrorCode::SubcommandNotFound,
+            command::CmdError::NotPermitted(_) => ErrorCode::PermissionDenied,
+            command::CmdError::Failed(_) => ErrorCode::SubcommandFailed,
+            command::CmdError::Hung(_) => ErrorCode::SubcommandTimeout,
+            command::CmdError::TooLarge(_) => ErrorCode::SubcommandFailed,
+            command::CmdError::InternalError(_) => ErrorCode::System,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::BadArgument => "bad-argument",
+            ErrorCode::SubcommandNotFound => "subcommand-not-found",
+            ErrorCode::PermissionDenied => "permission-denied",
+            ErrorCode::SubcommandFailed => "subcommand-failed",
+            ErrorCode::SubcommandTimeout => "subcommand-timeout",
+            ErrorCode::Parse => "parse",
+            ErrorCode::System => "system",
+            ErrorCode::GpuProbeFailure => "gpu-probe",
+            ErrorCode::Health => "health",
+            ErrorCode::Config => "config",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, message: String) -> Error {
+        Error { code, message }
+    }
+
+    // Add the "error" and "error_code" fields to the object.
+    pub fn add_to(self, obj: &mut output::Object) {
+        obj.push_s("error", self.message);
+        obj.push_s("error_code", self.code.as_str().to_string());
+    }
+}
+
+// Most low-level code reports errors as plain strings; these are failures to obtain system data.
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::new(ErrorCode::System, message)
+    }
+}
+
+#[test]
+pub fn error_code_test() {
+    let e = command::CmdError::CouldNotStart("sacct: Command not found".to_string());
+    assert!(ErrorCode::from_cmd_error(&e) == ErrorCode::SubcommandNotFound);
+    let e = command::CmdError::NotPermitted("sacct: Command cannot execute".to_string());
+    assert!(ErrorCode::from_cmd_error(&e) == ErrorCode::PermissionDenied);
+    let e = command::CmdError::Hung("sacct: Timed out".to_string());
+    assert!(ErrorCode::from_cmd_error(&e) == ErrorCode::SubcommandTimeout);
+    let e = command::CmdError::TooLarge("sacct: Output exceeded 1024 bytes".to_string());
+    assert!(ErrorCode::from_cmd_error(&e) == ErrorCode::SubcommandFailed);
+    let e: Error = "Could not find btime".to_string().into();
+    assert!(e.code == ErrorCode::System);
+
+    let mut obj = output::Object::new();
+    Error::new(ErrorCode::BadArgument, "Bad --span: x".to_string()).add_to(&mut obj);
+    assert!(matches!(obj.get("error"), Some(output::Value::S(s)) if s == "Bad --span: x"));
+    assert!(matches!(obj.get("error_code"), Some(output::Value::S(s)) if s == "bad-argument"));
+}
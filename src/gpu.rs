@@ -3,9 +3,11 @@ use crate::amd;
 use crate::gpuset;
 #[cfg(feature = "nvidia")]
 use crate::nvidia;
+use crate::ps;
 #[cfg(feature = "xpu")]
 use crate::xpu;
-use crate::ps;
+
+use std::collections::HashSet;
 
 // Per-sample process information, across cards.  The GPU layer can report a single datum for a
 // process across multiple cards, or multiple data breaking down the process per card even if the
@@ -24,6 +26,13 @@ pub struct Process {
                                  //   when the GPU layer simply can't know.
 }
 
+// Compute modes normalized across vendors.  The vendor modules map their own modes onto these and
+// keep the vendor's name for the mode alongside.
+
+pub const COMPUTE_MODE_DEFAULT: &str = "default"; // Any number of processes may share the card
+pub const COMPUTE_MODE_EXCLUSIVE_PROCESS: &str = "exclusive-process"; // At most one process
+pub const COMPUTE_MODE_PROHIBITED: &str = "prohibited"; // No compute processes at all
+
 // Sample-invariant card information
 
 #[derive(PartialEq, Default, Clone, Debug)]
@@ -41,6 +50,8 @@ pub struct Card {
     pub min_power_limit_watt: i32,
     pub max_ce_clock_mhz: i32,
     pub max_mem_clock_mhz: i32,
+    pub compute_mode: String,     // See COMPUTE_MODE_*; "" if unknown
+    pub compute_mode_raw: String, // NVIDIA: "Default", "Prohibited", "ExclusiveProcess"; "" if unknown
 }
 
 // Per-sample card information, across processes
@@ -49,12 +60,13 @@ pub struct Card {
 pub struct CardState {
     pub index: i32, // Stable card identifier
     pub fan_speed_pct: f32,
-    pub compute_mode: String,
+    pub compute_mode: String,     // See COMPUTE_MODE_*; "" if unknown
+    pub compute_mode_raw: String, // The vendor's name for the mode
     pub perf_state: String,
     pub mem_reserved_kib: i64,
-    pub mem_used_kib: i64,
+    pub mem_used_kib: i64, // Memory capacity in use
     pub gpu_utilization_pct: f32,
-    pub mem_utilization_pct: f32,
+    pub mem_bandwidth_pct: f32, // Memory controller (bandwidth) utilization, not capacity in use
     pub temp_c: i32,
     pub power_watt: i32,
     pub power_limit_watt: i32,
@@ -68,7 +80,8 @@ pub struct CardState {
 // assuming that nodes don't have cards from multiple manufacturers.
 //
 // get_card_configuration() and get_card_utilization() return vectors that are sorted by their index
-// fields, and indices shall be tightly packed.
+// fields, and indices shall be tightly packed, except when cards have been excluded by
+// ExcludingGpuAPI.
 
 pub trait GPU {
     fn get_manufacturer(&mut self) -> String;
@@ -100,29 +113,197 @@ impl GpuAPI for RealGpuAPI {
         }
         #[cfg(feature = "amd")]
         if let Some(amd) = amd::probe() {
-            return Some(amd)
+            return Some(amd);
         }
         #[cfg(feature = "xpu")]
         if let Some(xpu) = xpu::probe() {
-            return Some(xpu)
+            return Some(xpu);
         }
-        return None
+        return None;
+    }
+}
+
+// ExcludingGpuAPI hides some of the cards of another GpuAPI, eg a display GPU on a compute node,
+// along with their share of the process data.  Cards are excluded by index or by UUID.  The
+// remaining cards keep their indices, so that they agree with the vendor's tools and with other
+// sonar runs.
+
+pub struct ExcludingGpuAPI<'a> {
+    gpus: &'a dyn GpuAPI,
+    exclude: &'a [String],
+}
+
+impl<'a> ExcludingGpuAPI<'a> {
+    pub fn new(gpus: &'a dyn GpuAPI, exclude: &'a [String]) -> ExcludingGpuAPI<'a> {
+        ExcludingGpuAPI { gpus, exclude }
+    }
+}
+
+impl GpuAPI for ExcludingGpuAPI<'_> {
+    fn probe(&self) -> Option<Box<dyn GPU>> {
+        let mut gpu = self.gpus.probe()?;
+        if self.exclude.is_empty() {
+            return Some(gpu);
+        }
+        let mut excluded = HashSet::new();
+        let mut by_uuid = false;
+        for x in self.exclude {
+            match x.parse::<i32>() {
+                Ok(index) => {
+                    excluded.insert(index);
+                }
+                Err(_) => by_uuid = true,
+            }
+        }
+        if by_uuid {
+            for c in gpu.get_card_configuration().unwrap_or_default() {
+                if self.exclude.contains(&c.uuid) {
+                    excluded.insert(c.index);
+                }
+            }
+        }
+        Some(Box::new(ExcludingGPU { gpu, excluded }))
+    }
+}
+
+struct ExcludingGPU {
+    gpu: Box<dyn GPU>,
+    excluded: HashSet<i32>,
+}
+
+impl GPU for ExcludingGPU {
+    fn get_manufacturer(&mut self) -> String {
+        self.gpu.get_manufacturer()
+    }
+
+    fn get_card_configuration(&mut self) -> Result<Vec<Card>, String> {
+        let mut cards = self.gpu.get_card_configuration()?;
+        cards.retain(|c| !self.excluded.contains(&c.index));
+        Ok(cards)
+    }
+
+    // A process that ran only on excluded cards is dropped.  A process whose cards are not known
+    // is kept, as the unknown cards may not be excluded.
+    fn get_process_utilization(
+        &mut self,
+        user_by_pid: &ps::UserTable,
+    ) -> Result<Vec<Process>, String> {
+        let mut processes = self.gpu.get_process_utilization(user_by_pid)?;
+        processes.retain_mut(|p| match &mut p.devices {
+            Some(devices) if !devices.is_empty() => {
+                devices.retain(|d| !self.excluded.contains(&(*d as i32)));
+                !devices.is_empty()
+            }
+            _ => true,
+        });
+        Ok(processes)
+    }
+
+    fn get_card_utilization(&mut self) -> Result<Vec<CardState>, String> {
+        let mut cards = self.gpu.get_card_utilization()?;
+        cards.retain(|c| !self.excluded.contains(&c.index));
+        Ok(cards)
     }
 }
 
 #[cfg(test)]
-pub struct MockGpuAPI {}
+pub struct MockGpuAPI {
+    cards: Option<Vec<Card>>,
+    card_states: Vec<CardState>,
+    processes: Vec<Process>,
+    failing: bool,
+}
 
 #[cfg(test)]
 impl MockGpuAPI {
     pub fn new() -> MockGpuAPI {
-        MockGpuAPI {}
+        MockGpuAPI {
+            cards: None,
+            card_states: vec![],
+            processes: vec![],
+            failing: false,
+        }
+    }
+
+    // A mock GPU with the given card configuration and no process or utilization data.
+    pub fn with_cards(cards: Vec<Card>) -> MockGpuAPI {
+        MockGpuAPI {
+            cards: Some(cards),
+            card_states: vec![],
+            processes: vec![],
+            failing: false,
+        }
+    }
+
+    // A mock GPU that is present but whose utilization data can't be obtained.
+    pub fn failing() -> MockGpuAPI {
+        MockGpuAPI {
+            failing: true,
+            ..MockGpuAPI::with_cards(vec![])
+        }
+    }
+
+    // Add per-sample card utilization data, there is a GPU even if there is no configuration.
+    pub fn with_card_states(mut self, card_states: Vec<CardState>) -> MockGpuAPI {
+        self.cards.get_or_insert_with(Vec::new);
+        self.card_states = card_states;
+        self
+    }
+
+    // Add per-sample process data, there is a GPU even if there is no configuration.
+    pub fn with_processes(mut self, processes: Vec<Process>) -> MockGpuAPI {
+        self.cards.get_or_insert_with(Vec::new);
+        self.processes = processes;
+        self
     }
 }
 
 #[cfg(test)]
 impl GpuAPI for MockGpuAPI {
     fn probe(&self) -> Option<Box<dyn GPU>> {
-        None
+        self.cards.as_ref().map(|cards| {
+            Box::new(MockGPU {
+                cards: cards.clone(),
+                card_states: self.card_states.clone(),
+                processes: self.processes.clone(),
+                failing: self.failing,
+            }) as Box<dyn GPU>
+        })
+    }
+}
+
+#[cfg(test)]
+struct MockGPU {
+    cards: Vec<Card>,
+    card_states: Vec<CardState>,
+    processes: Vec<Process>,
+    failing: bool,
+}
+
+#[cfg(test)]
+impl GPU for MockGPU {
+    fn get_manufacturer(&mut self) -> String {
+        "NVIDIA".to_string()
+    }
+
+    fn get_card_configuration(&mut self) -> Result<Vec<Card>, String> {
+        Ok(self.cards.clone())
+    }
+
+    fn get_process_utilization(
+        &mut self,
+        _user_by_pid: &ps::UserTable,
+    ) -> Result<Vec<Process>, String> {
+        if self.failing {
+            return Err("Mock process failure".to_string());
+        }
+        Ok(self.processes.clone())
+    }
+
+    fn get_card_utilization(&mut self) -> Result<Vec<CardState>, String> {
+        if self.failing {
+            return Err("Mock card failure".to_string());
+        }
+        Ok(self.card_states.clone())
     }
 }
@@ -27,18 +27,163 @@ SOFTWARE.
 
  */
 
+use crate::log;
+
 use std::ffi::OsString;
 use std::io;
 use std::os::unix::ffi::OsStringExt;
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+// The host name is used in output records and lockfile names.  By default it is what gethostname()
+// returns, but an empty or unqualified name on a misconfigured node makes the records ambiguous.
+// With --qualify-hostname several sources are consulted, in order: gethostname(), /etc/hostname,
+// and the reverse DNS name of the primary network interface.  The most qualified (most dots)
+// non-empty name wins, with ties going to the earlier source.  DNS is only consulted if the local
+// sources do not provide a qualified name, and each lookup is bounded by a timeout as it may be
+// slow.  The name is resolved once per run.
+
+#[derive(Clone, Copy, Debug)]
+pub struct Qualify {
+    pub timeout: Duration, // For each DNS lookup
+    pub retries: u32,      // Additional DNS lookups after one that failed or timed out
+}
+
+pub const DEFAULT_DNS_TIMEOUT_SECS: u64 = 2;
+
+static QUALIFY: OnceLock<Qualify> = OnceLock::new();
+static HOSTNAME: OnceLock<String> = OnceLock::new();
+
+// Enable the qualified host name.  This must be called before the first get() to have any effect.
+
+pub fn qualify(q: Qualify) {
+    let _ = QUALIFY.set(q);
+}
 
 pub fn get() -> String {
+    HOSTNAME
+        .get_or_init(|| match QUALIFY.get() {
+            Some(q) => get_qualified(q),
+            None => match from_gethostname() {
+                Some(s) => s,
+                None => "unknown-host".to_string(),
+            },
+        })
+        .clone()
+}
+
+fn get_qualified(q: &Qualify) -> String {
+    let mut candidates = vec![from_gethostname(), from_etc_hostname()];
+    if !candidates
+        .iter()
+        .any(|c| matches!(c, Some(s) if s.contains('.')))
+    {
+        candidates.push(with_timeout(from_reverse_dns, q.timeout, q.retries));
+    }
+    match choose(&candidates) {
+        Some(s) => s,
+        None => {
+            log::error("Could not determine the host name");
+            "unknown-host".to_string()
+        }
+    }
+}
+
+// Run the lookup on a helper thread and wait at most `timeout` for it, up to 1+`retries` times
+// until it produces a name.  A helper thread that times out is abandoned; it exits when the lookup
+// eventually returns, or with the process.
+
+fn with_timeout(lookup: fn() -> Option<String>, timeout: Duration, retries: u32) -> Option<String> {
+    for _ in 0..=retries {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // The receiver is gone if we timed out.
+            let _ = sender.send(lookup());
+        });
+        match receiver.recv_timeout(timeout) {
+            Ok(Some(name)) => return Some(name),
+            Ok(None) => {}
+            Err(_) => log::warning(&format!(
+                "Reverse DNS lookup of the host name timed out after {}ms",
+                timeout.as_millis()
+            )),
+        }
+    }
+    None
+}
+
+fn choose(candidates: &[Option<String>]) -> Option<String> {
+    let mut best: Option<&String> = None;
+    for c in candidates.iter().flatten() {
+        if c.is_empty() {
+            continue;
+        }
+        match best {
+            Some(b) if b.matches('.').count() >= c.matches('.').count() => {}
+            _ => best = Some(c),
+        }
+    }
+    best.cloned()
+}
+
+fn from_gethostname() -> Option<String> {
     match primitive_get() {
-        Ok(hn) => match hn.into_string() {
-            Ok(s) => s,
-            Err(_) => "unknown-host".to_string(),
-        },
-        Err(_) => "unknown-host".to_string(),
+        Ok(hn) => hn.into_string().ok(),
+        Err(_) => None,
+    }
+}
+
+fn from_etc_hostname() -> Option<String> {
+    match std::fs::read_to_string("/etc/hostname") {
+        Ok(s) => s
+            .lines()
+            .map(|l| l.trim())
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| l.to_string()),
+        Err(_) => None,
+    }
+}
+
+// The primary interface is taken to be the first interface that is up, is not a loopback device,
+// and has an IPv4 address.
+
+fn from_reverse_dns() -> Option<String> {
+    const MAX_HOST: usize = 1025; // NI_MAXHOST
+    let mut result = None;
+    unsafe {
+        let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+        if libc::getifaddrs(&mut ifap) != 0 {
+            return None;
+        }
+        let mut p = ifap;
+        while !p.is_null() {
+            let ifa = &*p;
+            if !ifa.ifa_addr.is_null()
+                && (*ifa.ifa_addr).sa_family as libc::c_int == libc::AF_INET
+                && (ifa.ifa_flags & libc::IFF_UP as libc::c_uint) != 0
+                && (ifa.ifa_flags & libc::IFF_LOOPBACK as libc::c_uint) == 0
+            {
+                let mut buffer = vec![0u8; MAX_HOST];
+                let r = libc::getnameinfo(
+                    ifa.ifa_addr,
+                    std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                    buffer.as_mut_ptr() as *mut libc::c_char,
+                    MAX_HOST as libc::socklen_t,
+                    std::ptr::null_mut(),
+                    0,
+                    libc::NI_NAMEREQD,
+                );
+                if r == 0 {
+                    result = wrap_buffer(buffer).into_string().ok();
+                }
+                break;
+            }
+            p = ifa.ifa_next;
+        }
+        libc::freeifaddrs(ifap);
     }
+    result
 }
 
 pub fn primitive_get() -> io::Result<OsString> {
@@ -72,3 +217,43 @@ fn wrap_buffer(mut bytes: Vec<u8>) -> OsString {
 
     OsString::from_vec(bytes)
 }
+
+#[test]
+pub fn choose_hostname_test() {
+    let s = |x: &str| Some(x.to_string());
+    // Most qualified wins
+    assert!(
+        choose(&[s("c1-10"), s("c1-10.fox"), s("c1-10.fox.educloud.no")])
+            == s("c1-10.fox.educloud.no")
+    );
+    // Ties go to the earlier source
+    assert!(choose(&[s("c1-10.fox"), s("c1-11.fox")]) == s("c1-10.fox"));
+    // Empty and missing names are skipped
+    assert!(choose(&[s(""), None, s("c1-10")]) == s("c1-10"));
+    // Total failure
+    assert!(choose(&[s(""), None, None]).is_none());
+}
+
+#[test]
+pub fn hostname_timeout_test() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    // The first lookup is too slow, the second fails, the third succeeds
+    fn lookup() -> Option<String> {
+        match CALLS.fetch_add(1, Ordering::SeqCst) {
+            0 => {
+                thread::sleep(Duration::from_millis(500));
+                Some("slow.example.com".to_string())
+            }
+            1 => None,
+            _ => Some("c1-10.fox".to_string()),
+        }
+    }
+    let timeout = Duration::from_millis(50);
+    assert!(with_timeout(lookup, timeout, 1).is_none());
+    assert!(CALLS.load(Ordering::SeqCst) == 2);
+    CALLS.store(0, Ordering::SeqCst);
+    assert!(with_timeout(lookup, timeout, 2) == Some("c1-10.fox".to_string()));
+    assert!(CALLS.load(Ordering::SeqCst) == 3);
+}
@@ -4,6 +4,25 @@
 use crate::procfs;
 use std::collections::HashMap;
 
+// Information about a job known to the job queue.
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct JobInfo {
+    pub account: String,
+    pub partition: String,
+    pub reservation: String,
+    pub reservation_start: String,
+    pub reservation_end: String,
+}
+
+// The time window of a reservation known to the job queue.
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Reservation {
+    pub start_time: String,
+    pub end_time: String,
+}
+
 pub trait JobManager {
     // Compute a job ID from a process ID.
     //
@@ -11,4 +30,22 @@ pub trait JobManager {
     // performed on a particular instance of JobManager.
     fn job_id_from_pid(&mut self, pid: usize, processes: &HashMap<usize, procfs::Process>)
         -> usize;
+
+    // Look up the job in the job queue, if there is one.  This may be expensive and the caller
+    // should cache the result.  The default is that there is no information.
+    fn job_info(&mut self, _job_id: usize) -> Option<JobInfo> {
+        None
+    }
+
+    // Look up a reservation by name in the job queue, if there is one.  This may be expensive and
+    // the caller should cache the result.  The default is that there is no information.
+    fn reservation(&mut self, _name: &str) -> Option<Reservation> {
+        None
+    }
+
+    // The name of the cluster according to the job queue's configuration, if there is one.  The
+    // default is that there is no information.
+    fn cluster_name(&mut self) -> Option<String> {
+        None
+    }
 }
@@ -0,0 +1,256 @@
+// A minimal JSON reader for small operator-supplied files, producing an output::Value so that the
+// data can be merged into sonar's own output.  output::Value has no booleans or null, so true and
+// false become the strings "true" and "false", a null field of an object is omitted, and a null
+// array element is an empty element.  A null at the top level is an error.
+
+use crate::output;
+
+pub fn parse(s: &str) -> Result<output::Value, String> {
+    let mut p = Parser {
+        chars: s.chars().collect::<Vec<char>>(),
+        pos: 0,
+    };
+    let v = p.value()?;
+    if let output::Value::E() = v {
+        return Err("Unsupported toplevel null".to_string());
+    }
+    p.skip_whitespace();
+    if p.pos < p.chars.len() {
+        return Err(p.error("Trailing garbage"));
+    }
+    Ok(v)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> String {
+        format!("{msg} at offset {}", self.pos)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("Expected '{c}'")))
+        }
+    }
+
+    fn value(&mut self) -> Result<output::Value, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(output::Value::S(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(_) if self.keyword("true") => Ok(output::Value::S("true".to_string())),
+            Some(_) if self.keyword("false") => Ok(output::Value::S("false".to_string())),
+            Some(_) if self.keyword("null") => Ok(output::Value::E()),
+            Some(_) => Err(self.error("Unsupported value")),
+            None => Err(self.error("Unexpected end of input")),
+        }
+    }
+
+    // Consume `word` if it is next in the input.
+    fn keyword(&mut self, word: &str) -> bool {
+        let word = word.chars().collect::<Vec<char>>();
+        if self.chars[self.pos..].starts_with(&word) {
+            self.pos += word.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn object(&mut self) -> Result<output::Value, String> {
+        self.expect('{')?;
+        let mut o = output::Object::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(output::Value::O(o));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            let v = self.value()?;
+            if !matches!(v, output::Value::E()) {
+                o.push(&key, v);
+            }
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(output::Value::O(o));
+                }
+                _ => return Err(self.error("Expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<output::Value, String> {
+        self.expect('[')?;
+        let mut a = output::Array::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(output::Value::A(a));
+        }
+        loop {
+            a.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(output::Value::A(a));
+                }
+                _ => return Err(self.error("Expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.peek() != Some('"') {
+            return Err(self.error("Expected string"));
+        }
+        self.pos += 1;
+        let mut s = "".to_string();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(self.error("Unterminated string")),
+            };
+            self.pos += 1;
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let e = match self.peek() {
+                        Some(e) => e,
+                        None => return Err(self.error("Unterminated string")),
+                    };
+                    self.pos += 1;
+                    match e {
+                        '"' | '\\' | '/' => s.push(e),
+                        'n' => s.push('\n'),
+                        't' => s.push('\t'),
+                        'r' => s.push('\r'),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'u' => {
+                            // Chars outside the BMP are written as a UTF-16 surrogate pair,
+                            // \ud83d\ude00, and the two halves are combined.
+                            let mut u = self.hex4()?;
+                            if (0xD800..0xDC00).contains(&u) {
+                                let low = if self.keyword("\\u") { self.hex4()? } else { 0 };
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(self.error("Bad \\u surrogate pair"));
+                                }
+                                u = 0x10000 + ((u - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            match char::from_u32(u) {
+                                Some(u) => s.push(u),
+                                None => return Err(self.error("Bad \\u escape")),
+                            }
+                        }
+                        _ => return Err(self.error("Bad escape")),
+                    }
+                }
+                _ => s.push(c),
+            }
+        }
+    }
+
+    // The four hex digits of a \u escape.
+    fn hex4(&mut self) -> Result<u32, String> {
+        if self.pos + 4 > self.chars.len() {
+            return Err(self.error("Bad \\u escape"));
+        }
+        let hex = self.chars[self.pos..self.pos + 4]
+            .iter()
+            .collect::<String>();
+        self.pos += 4;
+        u32::from_str_radix(&hex, 16).map_err(|_| self.error("Bad \\u escape"))
+    }
+
+    fn number(&mut self) -> Result<output::Value, String> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if !(c.is_ascii_digit() || "+-.eE".contains(c)) {
+                break;
+            }
+            self.pos += 1;
+        }
+        let text = self.chars[start..self.pos].iter().collect::<String>();
+        if let Ok(u) = text.parse::<u64>() {
+            Ok(output::Value::U(u))
+        } else if let Ok(i) = text.parse::<i64>() {
+            Ok(output::Value::I(i))
+        } else if let Ok(f) = text.parse::<f64>() {
+            Ok(output::Value::F(f))
+        } else {
+            Err(self.error("Bad number"))
+        }
+    }
+}
+
+#[test]
+pub fn json_parse_test() {
+    let v = parse(
+        r#" { "rack": "r12", "slot": 7, "offset": -3, "weight": 1.5,
+              "fabric": ["ib0", "eth\"0\u00e6", null, "\ud83d\ude00"], "empty": {},
+              "active": true, "retired": false, "asset": null } "#,
+    )
+    .expect("Test: good json");
+    let o = match v {
+        output::Value::O(o) => o,
+        _ => panic!("Test: not an object"),
+    };
+    assert!(matches!(o.get("rack"), Some(output::Value::S(s)) if s == "r12"));
+    assert!(matches!(o.get("slot"), Some(output::Value::U(7))));
+    assert!(matches!(o.get("offset"), Some(output::Value::I(-3))));
+    assert!(matches!(o.get("weight"), Some(output::Value::F(x)) if *x == 1.5));
+    match o.get("fabric") {
+        Some(output::Value::A(a)) => {
+            assert!(a.len() == 4);
+            assert!(matches!(a.at(1), output::Value::S(s) if s == "eth\"0\u{e6}"));
+            assert!(matches!(a.at(2), output::Value::E()));
+            assert!(matches!(a.at(3), output::Value::S(s) if s == "\u{1f600}"));
+        }
+        _ => panic!("Test: no fabric"),
+    }
+    assert!(matches!(o.get("empty"), Some(output::Value::O(e)) if e.is_empty()));
+    assert!(matches!(o.get("active"), Some(output::Value::S(s)) if s == "true"));
+    assert!(matches!(o.get("retired"), Some(output::Value::S(s)) if s == "false"));
+    assert!(o.get("asset").is_none());
+
+    assert!(parse(r#"{"rack": "r12""#).is_err());
+    assert!(parse(r#"{"rack": "r12",}"#).is_err());
+    assert!(parse(r#"{"rack": tru}"#).is_err());
+    assert!(parse("null").is_err());
+    assert!(parse(r#""\ud83d""#).is_err());
+    assert!(parse(r#""\ud83dx""#).is_err());
+    assert!(parse(r#""\ud83d\u0041""#).is_err());
+    assert!(parse(r#"{"rack": "r12"} x"#).is_err());
+    assert!(parse("").is_err());
+}
@@ -1,13 +1,130 @@
 // A trivial logging package, that can be replaced by something more interesting if necessary.
+//
+// Messages go to stderr.  Two environment variables, read by init(), control them, for when sonar's
+// own logs are collected centrally:
+//
+// - SONAR_LOG_LEVEL is the lowest level that is logged, "debug", "info" (the default), "warning" or
+//   "error"
+// - SONAR_LOG_FORMAT=json writes each message as a JSON object with fields "time", "level" and
+//   "message" on a line of its own, rather than as "Level: message"
+
+use crate::output;
+use crate::time;
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Level {
+    Debug = 0,
+    Info = 1,
+    Warning = 2,
+    Error = 3,
+}
+
+static MIN_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
 
 pub fn init() {
-    // Currently nothing
+    // The format is set first so that a warning about the level is in the right format.
+    if let Ok(s) = std::env::var("SONAR_LOG_FORMAT") {
+        JSON_FORMAT.store(s == "json", Ordering::Relaxed);
+    }
+    if let Ok(s) = std::env::var("SONAR_LOG_LEVEL") {
+        match parse_level(&s) {
+            Some(level) => MIN_LEVEL.store(level as u8, Ordering::Relaxed),
+            None => warning(&format!("Unknown SONAR_LOG_LEVEL {s}, ignored")),
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub fn debug(s: &str) {
+    log(Level::Debug, s);
 }
 
 pub fn info(s: &str) {
-    eprintln!("Info: {s}");
+    log(Level::Info, s);
+}
+
+pub fn warning(s: &str) {
+    log(Level::Warning, s);
 }
 
 pub fn error(s: &str) {
-    eprintln!("Error: {s}");
+    log(Level::Error, s);
+}
+
+fn log(level: Level, s: &str) {
+    if level as u8 >= MIN_LEVEL.load(Ordering::Relaxed) {
+        eprint!(
+            "{}",
+            format_message(
+                level,
+                s,
+                JSON_FORMAT.load(Ordering::Relaxed),
+                &time::now_iso8601()
+            )
+        );
+    }
+}
+
+fn parse_level(s: &str) -> Option<Level> {
+    match s {
+        "debug" => Some(Level::Debug),
+        "info" => Some(Level::Info),
+        "warning" | "warn" => Some(Level::Warning),
+        "error" => Some(Level::Error),
+        _ => None,
+    }
+}
+
+// Format a message as a complete line.
+
+fn format_message(level: Level, s: &str, json: bool, timestamp: &str) -> String {
+    let (name, label) = match level {
+        Level::Debug => ("debug", "Debug"),
+        Level::Info => ("info", "Info"),
+        Level::Warning => ("warning", "Warning"),
+        Level::Error => ("error", "Error"),
+    };
+    if json {
+        let mut o = output::Object::new();
+        o.push_s("time", timestamp.to_string());
+        o.push_s("level", name.to_string());
+        o.push_s("message", s.to_string());
+        let mut buf = vec![];
+        output::write_json(&mut buf, &output::Value::O(o));
+        String::from_utf8_lossy(&buf).to_string()
+    } else {
+        format!("{label}: {s}\n")
+    }
+}
+
+#[test]
+pub fn log_format_test() {
+    use crate::json;
+
+    let timestamp = "2025-01-24T10:39:00+01:00";
+    assert!(
+        format_message(Level::Info, "Lockfile present, exiting", false, timestamp)
+            == "Info: Lockfile present, exiting\n"
+    );
+    assert!(format_message(Level::Warning, "Clamped", false, timestamp) == "Warning: Clamped\n");
+
+    let line = format_message(Level::Error, "Bad \"file\"\n", true, timestamp);
+    assert!(line.ends_with('\n') && line.matches('\n').count() == 1);
+    let o = match json::parse(&line) {
+        Ok(output::Value::O(o)) => o,
+        _ => panic!("Test: not a JSON object"),
+    };
+    assert!(matches!(o.get("time"), Some(output::Value::S(s)) if s == timestamp));
+    assert!(matches!(o.get("level"), Some(output::Value::S(s)) if s == "error"));
+    assert!(matches!(o.get("message"), Some(output::Value::S(s)) if s == "Bad \"file\"\n"));
+
+    assert!(parse_level("warn") == Some(Level::Warning));
+    assert!(parse_level("debug") == Some(Level::Debug));
+    assert!(parse_level("trace").is_none());
+    assert!((Level::Error as u8) > (Level::Warning as u8));
+    assert!((Level::Info as u8) > (Level::Debug as u8));
+    assert!(format_message(Level::Debug, "Scanned", false, timestamp) == "Debug: Scanned\n");
 }
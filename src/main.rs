@@ -4,11 +4,16 @@ mod amd;
 mod amd_smi;
 mod batchless;
 mod command;
+mod config;
+mod containers;
+mod diff;
+mod errors;
 mod gpu;
 mod gpuset;
 mod hostname;
 mod interrupt;
 mod jobs;
+mod json;
 mod log;
 #[cfg(feature = "nvidia")]
 mod nvidia;
@@ -22,6 +27,7 @@ mod slurm;
 mod slurmjobs;
 mod sysinfo;
 mod time;
+mod token;
 mod users;
 mod util;
 #[cfg(feature = "xpu")]
@@ -33,49 +39,47 @@ const USAGE_ERROR: i32 = 2; // clap, Python, Go
 
 enum Commands {
     /// Take a snapshot of the currently running processes
-    PS {
-        /// Synthesize a job ID from the process tree in which a process finds itself
-        batchless: bool,
+    PS(Box<PsArgs>),
+    /// Extract system information
+    Sysinfo {
+        /// Output CSV, not JSON
+        csv: bool,
 
-        /// Merge process records that have the same job ID and command name
-        rollup: bool,
+        /// Output flattened `key value` lines, not JSON
+        flat: bool,
 
-        /// Include records for jobs that have on average used at least this percentage of CPU,
-        /// note this is nonmonotonic [default: none]
-        min_cpu_percent: Option<f64>,
+        /// Output MessagePack, not JSON
+        msgpack: bool,
 
-        /// Include records for jobs that presently use at least this percentage of real memory,
-        /// note this is nonmonotonic [default: none]
-        min_mem_percent: Option<f64>,
+        /// Include InfiniBand port state and data counters
+        infiniband: bool,
 
-        /// Include records for jobs that have used at least this much CPU time (in seconds)
-        /// [default: none]
-        min_cpu_time: Option<usize>,
+        /// Include the CPU's vector instruction set extensions
+        cpu_flags: bool,
 
-        /// Exclude records for system jobs (uid < 1000)
-        exclude_system_jobs: bool,
+        /// Static key=value labels to attach to the record
+        tags: Vec<(String, String)>,
 
-        /// Exclude records whose users match these comma-separated names [default: none]
-        exclude_users: Option<String>,
+        /// Authentication token to attach to the record, empty for none
+        token: String,
 
-        /// Exclude records whose commands start with these comma-separated names [default: none]
-        exclude_commands: Option<String>,
+        /// Resolve a qualified host name from several sources, None for gethostname() alone
+        qualify_hostname: Option<hostname::Qualify>,
 
-        /// Create a per-host lockfile in this directory and exit early if the file exists on
-        /// startup [default: none]
-        lockdir: Option<String>,
+        /// JSON file with operator-maintained node metadata to include
+        metadata: Option<String>,
 
-        /// One output record per Sonar invocation will contain a load= field with an encoding of
-        /// the per-cpu usage since boot.
-        load: bool,
+        /// Collect only these sections, all if None
+        sections: Option<Vec<String>>,
 
-        /// Output JSON, not CSV
-        json: bool,
-    },
-    /// Extract system information
-    Sysinfo {
-        /// Output CSV, not JSON
-        csv: bool,
+        /// Leave out the GPU cards with these indices or UUIDs
+        exclude_gpus: Vec<String>,
+
+        /// Report the position of each GPU card in PCI bus address order
+        physical_index: bool,
+
+        /// Emit only the differences from this baseline sysinfo JSON file
+        diff: Option<String>,
     },
     /// Extract slurm job information
     Slurmjobs {
@@ -88,12 +92,189 @@ enum Commands {
         /// to is exclusive.  Precludes -window.
         span: Option<String>,
 
+        /// Fail if any line of sacct output is malformed, rather than skipping the line
+        strict: bool,
+
+        /// Group the step records of each job under the job record
+        nest_steps: bool,
+
+        /// Emit the jobs in envelopes of at most this many jobs each
+        batch_size: Option<usize>,
+
+        /// Fail rather than buffer more than this many MiB of sacct output
+        max_output_mib: Option<usize>,
+
+        /// Add the working directory and submit line of each job
+        job_submit_info: bool,
+
+        /// Authentication token to attach to every envelope, empty for none
+        token: String,
+
         /// Output json, not CSV
         json: bool,
+
+        /// Output MessagePack with the structure of the JSON output, not CSV
+        msgpack: bool,
     },
     Version {},
 }
 
+// The options of `sonar ps`, boxed in Commands::PS since there are so many of them.
+
+struct PsArgs {
+    /// Synthesize a job ID from the process tree in which a process finds itself
+    batchless: bool,
+
+    /// Merge process records that have the same job ID and command name
+    rollup: bool,
+
+    /// Merge process records that have the same command name, regardless of job
+    aggregate_by_command: bool,
+
+    /// Include records for jobs that have on average used at least this percentage of CPU,
+    /// note this is nonmonotonic [default: none]
+    min_cpu_percent: Option<f64>,
+
+    /// Include records for jobs that presently use at least this percentage of real memory,
+    /// note this is nonmonotonic [default: none]
+    min_mem_percent: Option<f64>,
+
+    /// Include records for jobs that have used at least this much CPU time (in seconds)
+    /// [default: none]
+    min_cpu_time: Option<usize>,
+
+    /// Include records for processes that presently have at least this many threads, note this
+    /// is nonmonotonic [default: none]
+    min_threads: Option<usize>,
+
+    /// Include records for processes that use a GPU and have used at least this percentage of
+    /// it, processes that don't use a GPU are not affected, note this is nonmonotonic
+    /// [default: none]
+    min_gpu_percent: Option<f64>,
+
+    /// Exclude records for system jobs (uid < 1000)
+    exclude_system_jobs: bool,
+
+    /// Exclude records for kernel threads [default: include them]
+    exclude_kernel_threads: bool,
+
+    /// Exclude records for processes that use no GPU compute or memory
+    gpu_only: bool,
+
+    /// Leave out the GPU cards with these indices or UUIDs [default: none]
+    exclude_gpus: Vec<String>,
+
+    /// Exclude records whose users match these comma-separated names [default: none]
+    exclude_users: Option<String>,
+
+    /// Exclude records whose commands start with these comma-separated names [default: none]
+    exclude_commands: Option<String>,
+
+    /// Exclude the processes with these pids [default: none]
+    exclude_pids: Vec<usize>,
+
+    /// Exclude the processes in these process groups [default: none]
+    exclude_pgrps: Vec<usize>,
+
+    /// Compare user and command names to the exclusion filters without regard to case
+    case_insensitive_filters: bool,
+
+    /// Report how many records each filter left out
+    explain_filters: bool,
+
+    /// Create a per-host lockfile in this directory and exit early if the file exists on
+    /// startup [default: none]
+    lockdir: Option<String>,
+
+    /// Read process data from this mount of procfs rather than /proc [default: /proc]
+    proc_root: Option<String>,
+
+    /// Read the process files in /proc with this many threads [default: 1]
+    scan_threads: Option<usize>,
+
+    /// Give up on looking up a user name after this many seconds [default: no limit]
+    user_lookup_timeout: Option<u64>,
+
+    /// Which process data to collect, "full" or "cpu" [default: full]
+    profile: procfs::Profile,
+
+    /// One output record per Sonar invocation will contain a load= field with an encoding of
+    /// the per-cpu usage since boot.
+    load: bool,
+
+    /// Resolve the container runtime and image of processes running in containers
+    containers: bool,
+
+    /// Do not append " <defunct>" to the command name of zombie processes
+    no_defunct_suffix: bool,
+
+    /// Truncate command names to this many characters, marking them with "..."
+    max_command_length: Option<usize>,
+
+    /// Report the user and system CPU time of each process and of its children separately
+    cpu_time_breakdown: bool,
+
+    /// Look up the account and partition of each Slurm job with scontrol
+    job_info: bool,
+
+    /// Compute memory percentages relative to the cgroup memory limit, if any
+    mem_pct_of_limit: bool,
+
+    /// Sort the records by pid so that the output is reproducible
+    sort: bool,
+
+    /// Report these environment variables of each process
+    env_allowlist: Option<String>,
+
+    /// Include sonar itself and its child processes
+    include_self: bool,
+
+    /// Read the CPU time of each Slurm job from the job's cgroup
+    job_cgroup_cpu: bool,
+
+    /// Report an error if more than this many processes are in uninterruptible sleep
+    d_state_warn: Option<usize>,
+
+    /// Report an error if the job queue's cluster name is not this
+    expect_cluster: Option<String>,
+
+    /// Report the OOM-killer scores of each process
+    oom_score: bool,
+
+    /// Report the number of established TCP connections of each process
+    tcp_connections: bool,
+
+    /// Report the GPUs each process may use, from its environment
+    gpu_affinity: bool,
+
+    /// Report the logical and storage IO of each process
+    io: bool,
+
+    /// Report the anonymous transparent hugepage memory of each process
+    hugepages: bool,
+
+    /// Replace user and command names by indices into a per-sample string table
+    intern_strings: bool,
+
+    /// Output a single-line summary of the node suitable for syslog, not CSV
+    syslog_summary: bool,
+
+    /// Static key=value labels to attach to every record
+    tags: Vec<(String, String)>,
+
+    /// Authentication token to attach to every record, empty for none
+    token: String,
+
+    /// Resolve a qualified host name from several sources, None for gethostname() alone
+    qualify_hostname: Option<hostname::Qualify>,
+
+    /// Output JSON, not CSV
+    json: bool,
+
+    /// Output MessagePack with the structure of the JSON output, not CSV
+    msgpack: bool,
+}
+
 fn main() {
     // Obtain the time stamp early so that it more properly reflects the time the sample was
     // obtained, not the time when reporting was allowed to run.  The latter is subject to greater
@@ -107,39 +288,121 @@ fn main() {
     let writer: &mut dyn io::Write = &mut stdout;
 
     match &command_line() {
-        Commands::PS {
-            rollup,
-            batchless,
-            min_cpu_percent,
-            min_mem_percent,
-            min_cpu_time,
-            exclude_system_jobs,
-            exclude_users,
-            exclude_commands,
-            lockdir,
-            load,
-            json,
-        } => {
+        Commands::PS(ps_args) => {
+            let PsArgs {
+                rollup,
+                aggregate_by_command,
+                batchless,
+                min_cpu_percent,
+                min_mem_percent,
+                min_cpu_time,
+                min_threads,
+                min_gpu_percent,
+                exclude_system_jobs,
+                exclude_kernel_threads,
+                gpu_only,
+                exclude_gpus,
+                exclude_users,
+                exclude_commands,
+                exclude_pids,
+                exclude_pgrps,
+                case_insensitive_filters,
+                explain_filters,
+                lockdir,
+                proc_root,
+                scan_threads,
+                user_lookup_timeout,
+                profile,
+                load,
+                containers,
+                no_defunct_suffix,
+                max_command_length,
+                cpu_time_breakdown,
+                job_info,
+                mem_pct_of_limit,
+                sort,
+                env_allowlist,
+                include_self,
+                job_cgroup_cpu,
+                d_state_warn,
+                expect_cluster,
+                oom_score,
+                tcp_connections,
+                gpu_affinity,
+                io,
+                hugepages,
+                intern_strings,
+                syslog_summary,
+                tags,
+                token,
+                qualify_hostname,
+                json,
+                msgpack,
+            } = ps_args.as_ref();
+            if let Some(q) = qualify_hostname {
+                hostname::qualify(*q);
+            }
             let opts = ps::PsOptions {
                 rollup: *rollup,
+                aggregate_by_command: *aggregate_by_command,
                 always_print_something: true,
                 min_cpu_percent: *min_cpu_percent,
                 min_mem_percent: *min_mem_percent,
                 min_cpu_time: *min_cpu_time,
+                min_threads: *min_threads,
+                min_gpu_percent: *min_gpu_percent,
                 exclude_system_jobs: *exclude_system_jobs,
-                load: *load,
+                exclude_kernel_threads: *exclude_kernel_threads,
+                gpu_only: *gpu_only,
+                // The syslog summary reports the node's GPU load, which comes with --load.
+                load: *load || *syslog_summary,
+                containers: *containers,
+                no_defunct_suffix: *no_defunct_suffix,
+                max_command_length: *max_command_length,
+                cpu_time_breakdown: *cpu_time_breakdown,
+                job_info: *job_info,
+                mem_pct_of_limit: *mem_pct_of_limit,
+                sort: *sort,
+                include_self: *include_self,
+                job_cgroup_cpu: *job_cgroup_cpu,
+                d_state_warn: *d_state_warn,
+                expect_cluster: expect_cluster.clone(),
+                oom_score: *oom_score,
+                tcp_connections: *tcp_connections,
+                gpu_affinity: *gpu_affinity,
+                io: *io,
+                hugepages: *hugepages,
+                intern_strings: *intern_strings,
+                syslog_summary: *syslog_summary,
+                tags: tags.clone(),
+                token: token.clone(),
+                env_allowlist: if let Some(s) = env_allowlist {
+                    s.split(',').map(|s| s.trim()).collect::<Vec<&str>>()
+                } else {
+                    vec![]
+                },
+                exclude_gpus: exclude_gpus.clone(),
                 exclude_users: if let Some(s) = exclude_users {
-                    s.split(',').collect::<Vec<&str>>()
+                    s.split(',').map(|s| s.trim()).collect::<Vec<&str>>()
                 } else {
                     vec![]
                 },
                 exclude_commands: if let Some(s) = exclude_commands {
-                    s.split(',').collect::<Vec<&str>>()
+                    s.split(',').map(|s| s.trim()).collect::<Vec<&str>>()
                 } else {
                     vec![]
                 },
+                exclude_pids: exclude_pids.clone(),
+                exclude_pgrps: exclude_pgrps.clone(),
+                case_insensitive_filters: *case_insensitive_filters,
+                explain_filters: *explain_filters,
                 lockdir: lockdir.clone(),
+                proc_root: proc_root.clone(),
+                scan_threads: *scan_threads,
+                user_lookup_timeout: *user_lookup_timeout,
+                profile: *profile,
                 json: *json,
+                msgpack: *msgpack,
             };
             if *batchless {
                 let mut jm = batchless::BatchlessJobManager::new();
@@ -149,11 +412,60 @@ fn main() {
                 ps::create_snapshot(writer, &mut jm, &opts, &timestamp);
             }
         }
-        Commands::Sysinfo { csv } => {
-            sysinfo::show_system(writer, &timestamp, *csv);
+        Commands::Sysinfo {
+            csv,
+            flat,
+            msgpack,
+            infiniband,
+            cpu_flags,
+            tags,
+            token,
+            qualify_hostname,
+            metadata,
+            sections,
+            exclude_gpus,
+            physical_index,
+            diff,
+        } => {
+            if let Some(q) = qualify_hostname {
+                hostname::qualify(*q);
+            }
+            let opts = sysinfo::SysinfoOptions {
+                infiniband: *infiniband,
+                cpu_flags: *cpu_flags,
+                tags: tags.clone(),
+                token: token.clone(),
+                metadata: metadata.clone(),
+                sections: sections.clone(),
+                exclude_gpus: exclude_gpus.clone(),
+                physical_index: *physical_index,
+                diff: diff.clone(),
+            };
+            sysinfo::show_system(writer, &timestamp, *csv, *flat, *msgpack, &opts);
         }
-        Commands::Slurmjobs { window, span, json } => {
-            slurmjobs::show_slurm_jobs(writer, window, span, &timestamp, *json);
+        Commands::Slurmjobs {
+            window,
+            span,
+            strict,
+            nest_steps,
+            batch_size,
+            max_output_mib,
+            job_submit_info,
+            token,
+            json,
+            msgpack,
+        } => {
+            let opts = slurmjobs::SlurmjobsOptions {
+                window: *window,
+                span: span.clone(),
+                strict: *strict,
+                nest_steps: *nest_steps,
+                batch_size: *batch_size,
+                max_output_mib: *max_output_mib,
+                job_submit_info: *job_submit_info,
+                token: token.clone(),
+            };
+            slurmjobs::show_slurm_jobs(writer, &timestamp, *json, *msgpack, &opts);
         }
         Commands::Version {} => {
             show_version(writer);
@@ -165,10 +477,19 @@ fn main() {
 // For the sake of simplicity:
 //  - allow repeated options to overwrite earlier values
 //  - all error reporting is via a generic "usage" message, without specificity as to what was wrong
-//  - both --json and --csv are accepted to all commands
+//  - --json, --csv, and --msgpack are accepted to all commands
 
 fn command_line() -> Commands {
     let args = std::env::args().collect::<Vec<String>>();
+    let command = args.get(1).map_or("", |s| s.as_str());
+    let (options, flags) = (command_options(command), command_flags(command));
+    let args = match config::expand_config(&args, options, flags) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(USAGE_ERROR);
+        }
+    };
     let mut next = 1;
     if next < args.len() {
         let command = args[next].as_ref();
@@ -177,16 +498,56 @@ fn command_line() -> Commands {
             "ps" => {
                 let mut batchless = false;
                 let mut rollup = false;
+                let mut aggregate_by = None;
                 let mut min_cpu_percent = None;
                 let mut min_mem_percent = None;
                 let mut min_cpu_time = None;
+                let mut min_threads = None;
+                let mut min_gpu_percent = None;
+                let mut d_state_warn = None;
+                let mut expect_cluster = None;
+                let mut tags = vec![];
+                let mut token = None;
+                let mut token_file = None;
+                let mut qualify_hostname = false;
+                let mut hostname_timeout = None;
+                let mut hostname_retries = None;
                 let mut exclude_system_jobs = false;
+                let mut exclude_kernel_threads = false;
+                let mut gpu_only = false;
+                let mut exclude_gpus = vec![];
                 let mut exclude_users = None;
                 let mut exclude_commands = None;
+                let mut exclude_pids = vec![];
+                let mut exclude_pgrps = vec![];
+                let mut case_insensitive_filters = false;
+                let mut explain_filters = false;
                 let mut lockdir = None;
+                let mut proc_root = None;
+                let mut scan_threads = None;
+                let mut user_lookup_timeout = None;
+                let mut profile = procfs::Profile::Full;
                 let mut load = false;
+                let mut containers = false;
+                let mut no_defunct_suffix = false;
+                let mut max_command_length = None;
+                let mut cpu_time_breakdown = false;
+                let mut job_info = false;
+                let mut mem_pct_of_limit = false;
+                let mut sort = false;
+                let mut env_allowlist = None;
+                let mut include_self = false;
+                let mut job_cgroup_cpu = false;
+                let mut oom_score = false;
+                let mut tcp_connections = false;
+                let mut gpu_affinity = false;
+                let mut io = false;
+                let mut hugepages = false;
+                let mut intern_strings = false;
+                let mut syslog_summary = false;
                 let mut json = false;
                 let mut csv = false;
+                let mut msgpack = false;
                 while next < args.len() {
                     let arg = args[next].as_ref();
                     next += 1;
@@ -196,14 +557,65 @@ fn command_line() -> Commands {
                         (next, rollup) = (new_next, true);
                     } else if let Some(new_next) = bool_arg(arg, &args, next, "--load") {
                         (next, load) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--containers") {
+                        (next, containers) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--no-defunct-suffix")
+                    {
+                        (next, no_defunct_suffix) = (new_next, true);
+                    } else if let Some(new_next) =
+                        bool_arg(arg, &args, next, "--cpu-time-breakdown")
+                    {
+                        (next, cpu_time_breakdown) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--job-info") {
+                        (next, job_info) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--mem-pct-of-limit")
+                    {
+                        (next, mem_pct_of_limit) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--sort") {
+                        (next, sort) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--include-self") {
+                        (next, include_self) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--oom-score") {
+                        (next, oom_score) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--tcp-connections") {
+                        (next, tcp_connections) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--gpu-affinity") {
+                        (next, gpu_affinity) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--io") {
+                        (next, io) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--hugepages") {
+                        (next, hugepages) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--intern-strings") {
+                        (next, intern_strings) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--syslog-summary") {
+                        (next, syslog_summary) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--job-cgroup-cpu") {
+                        (next, job_cgroup_cpu) = (new_next, true);
                     } else if let Some(new_next) = bool_arg(arg, &args, next, "--json") {
                         (next, json) = (new_next, true);
                     } else if let Some(new_next) = bool_arg(arg, &args, next, "--csv") {
                         (next, csv) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--msgpack") {
+                        (next, msgpack) = (new_next, true);
                     } else if let Some(new_next) =
                         bool_arg(arg, &args, next, "--exclude-system-jobs")
                     {
                         (next, exclude_system_jobs) = (new_next, true);
+                    } else if let Some(new_next) =
+                        bool_arg(arg, &args, next, "--exclude-kernel-threads")
+                    {
+                        (next, exclude_kernel_threads) = (new_next, true);
+                    } else if let Some(new_next) =
+                        bool_arg(arg, &args, next, "--include-kernel-threads")
+                    {
+                        (next, exclude_kernel_threads) = (new_next, false);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--gpu-only") {
+                        (next, gpu_only) = (new_next, true);
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--exclude-gpus")
+                    {
+                        next = new_next;
+                        exclude_gpus = gpu_list_value(&value);
                     } else if let Some((new_next, value)) =
                         string_arg(arg, &args, next, "--exclude-users")
                     {
@@ -212,10 +624,56 @@ fn command_line() -> Commands {
                         string_arg(arg, &args, next, "--exclude-commands")
                     {
                         (next, exclude_commands) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        numeric_list_arg::<usize>(arg, &args, next, "--exclude-pids")
+                    {
+                        (next, exclude_pids) = (new_next, value);
+                    } else if let Some((new_next, value)) =
+                        numeric_list_arg::<usize>(arg, &args, next, "--exclude-pgrps")
+                    {
+                        (next, exclude_pgrps) = (new_next, value);
+                    } else if let Some(new_next) =
+                        bool_arg(arg, &args, next, "--case-insensitive-filters")
+                    {
+                        (next, case_insensitive_filters) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--explain-filters") {
+                        (next, explain_filters) = (new_next, true);
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--env-allowlist")
+                    {
+                        (next, env_allowlist) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--aggregate-by")
+                    {
+                        (next, aggregate_by) = (new_next, Some(value));
                     } else if let Some((new_next, value)) =
                         string_arg(arg, &args, next, "--lockdir")
                     {
                         (next, lockdir) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--proc-root")
+                    {
+                        (next, proc_root) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<usize>(arg, &args, next, "--scan-threads")
+                    {
+                        (next, scan_threads) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<u64>(arg, &args, next, "--user-lookup-timeout")
+                    {
+                        (next, user_lookup_timeout) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--profile")
+                    {
+                        next = new_next;
+                        profile = match value.as_str() {
+                            "full" => procfs::Profile::Full,
+                            "cpu" => procfs::Profile::Cpu,
+                            _ => {
+                                eprintln!("--profile must be full or cpu");
+                                std::process::exit(USAGE_ERROR);
+                            }
+                        };
                     } else if let Some((new_next, value)) =
                         numeric_arg::<f64>(arg, &args, next, "--min-cpu-percent")
                     {
@@ -228,6 +686,47 @@ fn command_line() -> Commands {
                         numeric_arg::<usize>(arg, &args, next, "--min-cpu-time")
                     {
                         (next, min_cpu_time) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<usize>(arg, &args, next, "--min-threads")
+                    {
+                        (next, min_threads) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<f64>(arg, &args, next, "--min-gpu-percent")
+                    {
+                        (next, min_gpu_percent) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<usize>(arg, &args, next, "--max-command-length")
+                    {
+                        (next, max_command_length) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<usize>(arg, &args, next, "--d-state-warn")
+                    {
+                        (next, d_state_warn) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--expect-cluster")
+                    {
+                        (next, expect_cluster) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) = string_arg(arg, &args, next, "--tag") {
+                        next = new_next;
+                        tags.push(tag_value(&value));
+                    } else if let Some((new_next, value)) = string_arg(arg, &args, next, "--token")
+                    {
+                        (next, token) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--token-file")
+                    {
+                        (next, token_file) = (new_next, Some(value));
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--qualify-hostname")
+                    {
+                        (next, qualify_hostname) = (new_next, true);
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<u64>(arg, &args, next, "--hostname-timeout")
+                    {
+                        (next, hostname_timeout) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<u32>(arg, &args, next, "--hostname-retries")
+                    {
+                        (next, hostname_retries) = (new_next, Some(value));
                     } else {
                         usage(true);
                     }
@@ -243,28 +742,120 @@ fn command_line() -> Commands {
                     eprintln!("--rollup and --batchless are incompatible");
                     std::process::exit(USAGE_ERROR);
                 }
-                if json && csv {
-                    eprintln!("--csv and --json are incompatible");
+                let aggregate_by_command = match aggregate_by.as_deref() {
+                    None => false,
+                    Some("command") => true,
+                    Some(_) => usage(true),
+                };
+                if rollup && aggregate_by_command {
+                    eprintln!("--rollup and --aggregate-by are incompatible");
+                    std::process::exit(USAGE_ERROR);
+                }
+                if [json, csv, msgpack, syslog_summary]
+                    .iter()
+                    .filter(|x| **x)
+                    .count()
+                    > 1
+                {
+                    eprintln!("--csv, --json, --msgpack, and --syslog-summary are incompatible");
                     std::process::exit(USAGE_ERROR);
                 }
+                if intern_strings && !json && !msgpack {
+                    eprintln!("--intern-strings requires --json or --msgpack");
+                    std::process::exit(USAGE_ERROR);
+                }
+                if profile == procfs::Profile::Cpu {
+                    // These need the per-process files that the cpu profile does not read.
+                    let memory_options = [
+                        ("--io", io),
+                        ("--hugepages", hugepages),
+                        ("--min-mem-percent", min_mem_percent.is_some()),
+                        ("--mem-pct-of-limit", mem_pct_of_limit),
+                    ];
+                    if let Some((name, _)) = memory_options.iter().find(|(_, given)| *given) {
+                        eprintln!("{name} is incompatible with --profile=cpu");
+                        std::process::exit(USAGE_ERROR);
+                    }
+                }
+                // The filter terms are lowercased once here, the names they are compared to are
+                // lowercased by the filter.
+                if case_insensitive_filters {
+                    exclude_users = exclude_users.map(|s| s.to_lowercase());
+                    exclude_commands = exclude_commands.map(|s| s.to_lowercase());
+                }
+                let token = token_value(token, token_file);
+                let qualify_hostname =
+                    qualify_value(qualify_hostname, hostname_timeout, hostname_retries);
 
-                Commands::PS {
+                Commands::PS(Box::new(PsArgs {
                     batchless,
                     rollup,
+                    aggregate_by_command,
                     min_cpu_percent,
                     min_mem_percent,
                     min_cpu_time,
+                    min_threads,
+                    min_gpu_percent,
                     exclude_system_jobs,
+                    exclude_kernel_threads,
+                    gpu_only,
+                    exclude_gpus,
                     exclude_users,
                     exclude_commands,
+                    exclude_pids,
+                    exclude_pgrps,
+                    case_insensitive_filters,
+                    explain_filters,
                     lockdir,
+                    proc_root,
+                    scan_threads,
+                    user_lookup_timeout,
+                    profile,
                     load,
+                    containers,
+                    no_defunct_suffix,
+                    max_command_length,
+                    cpu_time_breakdown,
+                    job_info,
+                    mem_pct_of_limit,
+                    sort,
+                    env_allowlist,
+                    include_self,
+                    job_cgroup_cpu,
+                    d_state_warn,
+                    expect_cluster,
+                    oom_score,
+                    tcp_connections,
+                    gpu_affinity,
+                    io,
+                    hugepages,
+                    intern_strings,
+                    syslog_summary,
+                    tags,
+                    token,
+                    qualify_hostname,
                     json,
-                }
+                    msgpack,
+                }))
             }
             "sysinfo" => {
                 let mut json = false;
                 let mut csv = false;
+                let mut flat = false;
+                let mut msgpack = false;
+                let mut infiniband = false;
+                let mut cpu_flags = false;
+                let mut tags = vec![];
+                let mut token = None;
+                let mut token_file = None;
+                let mut qualify_hostname = false;
+                let mut hostname_timeout = None;
+                let mut hostname_retries = None;
+                let mut metadata = None;
+                let mut sections = None;
+                let mut exclude_gpus = vec![];
+                let mut physical_index = false;
+                let mut diff = None;
                 while next < args.len() {
                     let arg = args[next].as_ref();
                     next += 1;
@@ -272,21 +863,107 @@ fn command_line() -> Commands {
                         (next, json) = (new_next, true);
                     } else if let Some(new_next) = bool_arg(arg, &args, next, "--csv") {
                         (next, csv) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--infiniband") {
+                        (next, infiniband) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--cpu-flags") {
+                        (next, cpu_flags) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--flat") {
+                        (next, flat) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--msgpack") {
+                        (next, msgpack) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--physical-index") {
+                        (next, physical_index) = (new_next, true);
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--metadata")
+                    {
+                        (next, metadata) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) = string_arg(arg, &args, next, "--diff") {
+                        (next, diff) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--sections")
+                    {
+                        next = new_next;
+                        let names = value
+                            .split(',')
+                            .map(|s| s.trim().to_string())
+                            .collect::<Vec<_>>();
+                        for name in &names {
+                            if !sysinfo::SECTIONS.contains(&name.as_str()) {
+                                eprintln!("Unknown sysinfo section {name}");
+                                std::process::exit(USAGE_ERROR);
+                            }
+                        }
+                        sections = Some(names);
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--exclude-gpus")
+                    {
+                        next = new_next;
+                        exclude_gpus = gpu_list_value(&value);
+                    } else if let Some((new_next, value)) = string_arg(arg, &args, next, "--tag") {
+                        next = new_next;
+                        tags.push(tag_value(&value));
+                    } else if let Some((new_next, value)) = string_arg(arg, &args, next, "--token")
+                    {
+                        (next, token) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--token-file")
+                    {
+                        (next, token_file) = (new_next, Some(value));
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--qualify-hostname")
+                    {
+                        (next, qualify_hostname) = (new_next, true);
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<u64>(arg, &args, next, "--hostname-timeout")
+                    {
+                        (next, hostname_timeout) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<u32>(arg, &args, next, "--hostname-retries")
+                    {
+                        (next, hostname_retries) = (new_next, Some(value));
                     } else {
                         usage(true);
                     }
                 }
-                if json && csv {
-                    eprintln!("--csv and --json are incompatible");
+                if [json, csv, flat, msgpack].iter().filter(|x| **x).count() > 1 {
+                    eprintln!("--csv, --json, --flat, and --msgpack are incompatible");
                     std::process::exit(USAGE_ERROR);
                 }
-                Commands::Sysinfo { csv }
+                if diff.is_some() && csv {
+                    eprintln!("--diff is incompatible with --csv");
+                    std::process::exit(USAGE_ERROR);
+                }
+                let token = token_value(token, token_file);
+                let qualify_hostname =
+                    qualify_value(qualify_hostname, hostname_timeout, hostname_retries);
+                Commands::Sysinfo {
+                    csv,
+                    flat,
+                    msgpack,
+                    infiniband,
+                    cpu_flags,
+                    tags,
+                    token,
+                    qualify_hostname,
+                    metadata,
+                    sections,
+                    exclude_gpus,
+                    physical_index,
+                    diff,
+                }
             }
             "slurm" => {
                 let mut window = None;
                 let mut span = None;
+                let mut strict = false;
+                let mut nest_steps = false;
+                let mut batch_size = None;
+                let mut max_output_mib = None;
+                let mut job_submit_info = false;
+                let mut token = None;
+                let mut token_file = None;
                 let mut json = false;
                 let mut csv = false;
+                let mut msgpack = false;
                 while next < args.len() {
                     let arg = args[next].as_ref();
                     next += 1;
@@ -296,10 +973,33 @@ fn command_line() -> Commands {
                         (next, window) = (new_next, Some(value));
                     } else if let Some((new_next, value)) = string_arg(arg, &args, next, "--span") {
                         (next, span) = (new_next, Some(value));
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--strict") {
+                        (next, strict) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--nest-steps") {
+                        (next, nest_steps) = (new_next, true);
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<usize>(arg, &args, next, "--batch-size")
+                    {
+                        (next, batch_size) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        numeric_arg::<usize>(arg, &args, next, "--max-output-mib")
+                    {
+                        (next, max_output_mib) = (new_next, Some(value));
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--job-submit-info") {
+                        (next, job_submit_info) = (new_next, true);
+                    } else if let Some((new_next, value)) = string_arg(arg, &args, next, "--token")
+                    {
+                        (next, token) = (new_next, Some(value));
+                    } else if let Some((new_next, value)) =
+                        string_arg(arg, &args, next, "--token-file")
+                    {
+                        (next, token_file) = (new_next, Some(value));
                     } else if let Some(new_next) = bool_arg(arg, &args, next, "--json") {
                         (next, json) = (new_next, true);
                     } else if let Some(new_next) = bool_arg(arg, &args, next, "--csv") {
                         (next, csv) = (new_next, true);
+                    } else if let Some(new_next) = bool_arg(arg, &args, next, "--msgpack") {
+                        (next, msgpack) = (new_next, true);
                     } else {
                         usage(true);
                     }
@@ -307,11 +1007,39 @@ fn command_line() -> Commands {
                 if window.is_some() && span.is_some() {
                     usage(true);
                 }
-                if json && csv {
-                    eprintln!("--csv and --json are incompatible");
+                if [json, csv, msgpack].iter().filter(|x| **x).count() > 1 {
+                    eprintln!("--csv, --json, and --msgpack are incompatible");
+                    std::process::exit(USAGE_ERROR);
+                }
+                if nest_steps && !json && !msgpack {
+                    eprintln!("--nest-steps requires --json or --msgpack");
+                    std::process::exit(USAGE_ERROR);
+                }
+                if batch_size.is_some() && !json && !msgpack {
+                    eprintln!("--batch-size requires --json or --msgpack");
+                    std::process::exit(USAGE_ERROR);
+                }
+                if batch_size == Some(0) {
+                    eprintln!("--batch-size must be positive");
                     std::process::exit(USAGE_ERROR);
                 }
-                Commands::Slurmjobs { window, span, json }
+                if max_output_mib == Some(0) {
+                    eprintln!("--max-output-mib must be positive");
+                    std::process::exit(USAGE_ERROR);
+                }
+                let token = token_value(token, token_file);
+                Commands::Slurmjobs {
+                    window,
+                    span,
+                    strict,
+                    nest_steps,
+                    batch_size,
+                    max_output_mib,
+                    job_submit_info,
+                    token,
+                    json,
+                    msgpack,
+                }
             }
             "version" => Commands::Version {},
             "help" => {
@@ -326,6 +1054,211 @@ fn command_line() -> Commands {
     }
 }
 
+// The names of the options each command accepts, for the config file (see config.rs).  These must
+// be kept in sync with command_line().
+
+fn command_options(command: &str) -> &'static [&'static str] {
+    match command {
+        "ps" => &[
+            "aggregate-by",
+            "batchless",
+            "case-insensitive-filters",
+            "containers",
+            "cpu-time-breakdown",
+            "csv",
+            "d-state-warn",
+            "env-allowlist",
+            "exclude-commands",
+            "exclude-gpus",
+            "exclude-kernel-threads",
+            "exclude-pgrps",
+            "exclude-pids",
+            "exclude-system-jobs",
+            "exclude-users",
+            "expect-cluster",
+            "explain-filters",
+            "gpu-affinity",
+            "gpu-only",
+            "hostname-retries",
+            "hostname-timeout",
+            "hugepages",
+            "include-kernel-threads",
+            "include-self",
+            "intern-strings",
+            "io",
+            "job-cgroup-cpu",
+            "job-info",
+            "json",
+            "load",
+            "lockdir",
+            "max-command-length",
+            "mem-pct-of-limit",
+            "min-cpu-percent",
+            "min-cpu-time",
+            "min-gpu-percent",
+            "min-mem-percent",
+            "min-threads",
+            "msgpack",
+            "no-defunct-suffix",
+            "oom-score",
+            "proc-root",
+            "profile",
+            "qualify-hostname",
+            "rollup",
+            "scan-threads",
+            "sort",
+            "syslog-summary",
+            "tag",
+            "tcp-connections",
+            "token",
+            "token-file",
+            "user-lookup-timeout",
+        ],
+        "sysinfo" => &[
+            "cpu-flags",
+            "csv",
+            "diff",
+            "exclude-gpus",
+            "flat",
+            "hostname-retries",
+            "hostname-timeout",
+            "infiniband",
+            "json",
+            "metadata",
+            "msgpack",
+            "physical-index",
+            "qualify-hostname",
+            "sections",
+            "tag",
+            "token",
+            "token-file",
+        ],
+        "slurm" => &[
+            "batch-size",
+            "csv",
+            "job-submit-info",
+            "json",
+            "max-output-mib",
+            "msgpack",
+            "nest-steps",
+            "span",
+            "strict",
+            "token",
+            "token-file",
+            "window",
+        ],
+        _ => &[],
+    }
+}
+
+// The options among command_options() that take no value, which `=true` and `=false` turn on and
+// off (see config.rs).  These must be kept in sync with command_line().
+
+fn command_flags(command: &str) -> &'static [&'static str] {
+    match command {
+        "ps" => &[
+            "batchless",
+            "case-insensitive-filters",
+            "containers",
+            "cpu-time-breakdown",
+            "csv",
+            "exclude-kernel-threads",
+            "exclude-system-jobs",
+            "explain-filters",
+            "gpu-affinity",
+            "gpu-only",
+            "hugepages",
+            "include-kernel-threads",
+            "include-self",
+            "intern-strings",
+            "io",
+            "job-cgroup-cpu",
+            "job-info",
+            "json",
+            "load",
+            "mem-pct-of-limit",
+            "msgpack",
+            "no-defunct-suffix",
+            "oom-score",
+            "qualify-hostname",
+            "rollup",
+            "sort",
+            "syslog-summary",
+            "tcp-connections",
+        ],
+        "sysinfo" => &[
+            "cpu-flags",
+            "csv",
+            "flat",
+            "infiniband",
+            "json",
+            "msgpack",
+            "physical-index",
+            "qualify-hostname",
+        ],
+        "slurm" => &[
+            "csv",
+            "job-submit-info",
+            "json",
+            "msgpack",
+            "nest-steps",
+            "strict",
+        ],
+        _ => &[],
+    }
+}
+
+// The cards named by --exclude-gpus, by index or UUID.
+
+fn gpu_list_value(value: &str) -> Vec<String> {
+    value.split(',').map(|s| s.trim().to_string()).collect()
+}
+
+fn tag_value(value: &str) -> (String, String) {
+    match output::parse_tag(value) {
+        Ok(tag) => tag,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(USAGE_ERROR);
+        }
+    }
+}
+
+// The token is resolved once the command line has been parsed, see token.rs.  It is never printed.
+
+fn token_value(token: Option<String>, token_file: Option<String>) -> String {
+    let env = std::env::var(token::TOKEN_ENV_VAR).ok();
+    match token::resolve_token(token.as_deref(), env.as_deref(), token_file.as_deref()) {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(USAGE_ERROR);
+        }
+    }
+}
+
+// The host name is qualified only on request, see hostname.rs.
+
+fn qualify_value(
+    qualify: bool,
+    timeout: Option<u64>,
+    retries: Option<u32>,
+) -> Option<hostname::Qualify> {
+    if !qualify {
+        if timeout.is_some() || retries.is_some() {
+            eprintln!("--hostname-timeout and --hostname-retries require --qualify-hostname");
+            std::process::exit(USAGE_ERROR);
+        }
+        return None;
+    }
+    Some(hostname::Qualify {
+        timeout: std::time::Duration::from_secs(
+            timeout.unwrap_or(hostname::DEFAULT_DNS_TIMEOUT_SECS),
+        ),
+        retries: retries.unwrap_or(0),
+    })
+}
+
 fn bool_arg(arg: &str, _args: &[String], next: usize, opt_name: &str) -> Option<usize> {
     if arg == opt_name {
         Some(next)
@@ -370,6 +1303,30 @@ fn numeric_arg<T: std::str::FromStr>(
     }
 }
 
+// A comma-separated list of numbers, eg pids.
+
+fn numeric_list_arg<T: std::str::FromStr>(
+    arg: &str,
+    args: &[String],
+    next: usize,
+    opt_name: &str,
+) -> Option<(usize, Vec<T>)> {
+    if let Some((next, strval)) = string_arg(arg, args, next, opt_name) {
+        match strval
+            .split(',')
+            .map(|s| s.trim().parse::<T>())
+            .collect::<Result<Vec<T>, _>>()
+        {
+            Ok(values) => Some((next, values)),
+            _ => {
+                usage(true);
+            }
+        }
+    } else {
+        None
+    }
+}
+
 fn usage(is_error: bool) -> ! {
     let mut stdout = std::io::stdout();
     let mut stderr = std::io::stderr();
@@ -388,12 +1345,45 @@ Commands:
   slurm    Print slurm job information for a [start,end) time interval
   help     Print this message
 
+Options for all commands:
+  --config file
+      Read settings from an ini-like file, one `option = value` or `option`
+      per line, optionally in a `[command]` section; settings before the
+      first section apply to the commands that accept them.  An option on the
+      command line replaces the file's settings for that option (the output
+      formats count as one option), and --option=false turns off an option
+      that the file turns on
+  --token token
+      Attach the field token with this value to the output, for the receiver
+      to authenticate or identify it; overrides SONAR_TOKEN and --token-file
+      [default: none]
+  --token-file filename
+      Take the token from the first line of this file if neither --token nor
+      the SONAR_TOKEN environment variable is set [default: none]
+
+Options for `ps` and `sysinfo`:
+  --qualify-hostname
+      Take the most qualified of the names from gethostname(), /etc/hostname
+      and the reverse DNS name of the primary network interface as the host
+      name, rather than the name from gethostname() alone.  DNS is consulted
+      only if neither of the others is qualified
+  --hostname-timeout seconds
+      With --qualify-hostname, give up on a reverse DNS lookup after this many
+      seconds [default: 2]
+  --hostname-retries n
+      With --qualify-hostname, repeat a reverse DNS lookup that failed or
+      timed out up to n times [default: 0]
+
 Options for `ps`:
   --batchless
       Synthesize a job ID from the process tree in which a process finds itself
   --rollup
       Merge process records that have the same job ID and command name (not
       compatible with --batchless)
+  --aggregate-by command
+      Merge process records that have the same command name, regardless of job,
+      user, or parent process, for node-level profiling (not compatible with
+      --rollup)
   --min-cpu-percent percentage
       Include records for jobs that have on average used at least this
       percentage of CPU, note this is nonmonotonic [default: none]
@@ -403,19 +1393,157 @@ Options for `ps`:
   --min-cpu-time seconds
       Include records for jobs that have used at least this much CPU time
       [default: none]
+  --min-threads n
+      Include records for processes that presently have at least this many
+      threads, note this is nonmonotonic [default: none]
+  --min-gpu-percent percentage
+      Include records for processes that use a GPU and have used at least this
+      percentage of it; processes that don't use a GPU are judged by the other
+      filters alone, note this is nonmonotonic [default: none]
   --exclude-system-jobs
       Exclude records for system jobs (uid < 1000)
+  --exclude-kernel-threads
+      Exclude records for kernel threads (descendants of kthreadd)
+  --include-kernel-threads
+      Include records for kernel threads, overriding an earlier
+      --exclude-kernel-threads, eg from a config file [default]
+  --gpu-only
+      Exclude records for processes that use no GPU compute or memory
+  --exclude-gpus gpu,gpu,...
+      Leave out the GPU cards with these indices or UUIDs, eg a display GPU,
+      and the processes' use of them [default: none]
   --exclude-users user,user,...
       Exclude records whose users match these names [default: none]
   --exclude-commands command,command,...
       Exclude records whose commands start with these names [default: none]
+  --exclude-pids pid,pid,...
+      Exclude the processes with these pids, eg a noisy monitoring agent
+      [default: none]
+  --exclude-pgrps pgrp,pgrp,...
+      Exclude the processes in these process groups [default: none]
+  --case-insensitive-filters
+      Match user and command names to --exclude-users and --exclude-commands
+      without regard to case
+  --explain-filters
+      Report how many records each filter left out, in the excluded field or on
+      stderr with CSV output
   --lockdir directory
       Create a per-host lockfile in this directory and exit early if the file
       exists on startup [default: none]
+  --proc-root directory
+      Read process data from this mount of procfs, eg the /proc of a container
+      or another PID namespace, rather than /proc [default: /proc]
+  --scan-threads n
+      Read the process files in /proc with n threads, for nodes with very many
+      processes [default: 1]
+  --user-lookup-timeout seconds
+      Give up on looking up a user name after this many seconds, as when the
+      user database is slow, and name that user _user_<uid>
+      [default: no limit]
+  --profile full|cpu
+      Collect all process data, or only the cpu data, which skips reading the
+      memory files of each process and leaves the memory fields and context
+      switch counts out; excludes the options that need them [default: full]
   --load
-      Print per-cpu and per-gpu load data
+      Print per-cpu and per-gpu load data, the number of running and blocked
+      threads, and energy counters
+  --containers
+      Resolve the container runtime and image of processes running in
+      containers (costly)
+  --no-defunct-suffix
+      Do not append \" <defunct>\" to the command names of zombie processes, they
+      are identified by the zombie field regardless
+  --max-command-length n
+      Truncate command names longer than n characters to n characters, ending
+      in \"...\"; rollup and filters use the full name [default: none]
+  --cpu-time-breakdown
+      Report the user and system CPU time of each process and of its exited
+      children separately (utime_sec, stime_sec, cutime_sec, cstime_sec)
+  --job-info
+      Look up the account and partition of each Slurm job with scontrol (costly)
+  --job-cgroup-cpu
+      Read the total CPU time of each Slurm job from the job's cgroup
+  --d-state-warn n
+      Report a health error if more than n processes are in uninterruptible
+      sleep (state D) [default: none]
+  --expect-cluster name
+      Read the cluster name from slurm.conf and report a config error if it
+      is not this name [default: none]
+  --oom-score
+      Report the oom_score and oom_score_adj of each process
+  --tcp-connections
+      Report the number of established TCP connections of each process whose
+      file descriptors can be read (tcp_connections)
+  --gpu-affinity
+      On GPU nodes, report the GPUs each process may use according to its
+      environment, where readable (gpus_allowed)
+  --io
+      Report the logical (rchar, wchar) and storage (read_bytes, write_bytes)
+      IO of each process, where readable
+  --hugepages
+      Report the anonymous transparent hugepage memory of each process
+      (anonhugekib), where readable
+  --intern-strings
+      With --json or --msgpack, emit each distinct user and command name once
+      per sample in a string table and refer to them by index (user_ix,
+      cmd_ix)
+  --syslog-summary
+      Format output as a single human-readable line per sample that summarizes
+      the node (process count, top users by CPU, CPU, memory and GPU load),
+      for forwarding to syslog, not as CSV
+  --mem-pct-of-limit
+      Compute memory percentages relative to the cgroup memory limit of the
+      node or container, if it is below the installed memory, and report the
+      limit (mem_limit_kib)
+  --sort
+      Sort the records by pid (rolled-up records by their smallest pid), so that
+      the output for the same process table is reproducible
+  --env-allowlist name,name,...
+      Report these environment variables of each process, where readable
+      [default: none]
+  --include-self
+      Include sonar itself and its child processes, which are excluded by
+      default
+  --tag key=value
+      Add the field key with the value to the tags object of every record;
+      may be repeated.  The key must match [a-z][a-z0-9_]*
   --json
       Format output as JSON, not CSV
+  --msgpack
+      Format output as MessagePack with the structure of the JSON output, not
+      CSV
+
+Options for `sysinfo`:
+  --infiniband
+      Include InfiniBand port state and data counters
+  --cpu-flags
+      Include the CPU's vector instruction set extensions, eg avx2, avx512f,
+      sve (cpu_flags)
+  --csv
+      Format output as CSV, not JSON
+  --flat
+      Format output as one `key value` line per field, with dotted keys for
+      nested fields (eg gpu_info.0.model), not JSON
+  --msgpack
+      Format output as MessagePack, not JSON
+  --tag key=value
+      Add the field key with the value to the tags object of the record; may
+      be repeated
+  --metadata filename
+      Include the JSON object in the file as the metadata field; a missing or
+      malformed file is reported and ignored [default: none]
+  --sections section,...
+      Collect only the named sections, from cpu, memory, and gpu [default: all]
+  --exclude-gpus gpu,gpu,...
+      Leave out the GPU cards with these indices or UUIDs, eg a display GPU
+      [default: none]
+  --physical-index
+      Report the position of each GPU card in PCI bus address order, which
+      unlike the index is stable across reboots (physical_index)
+  --diff filename
+      Compare the information to the baseline in this file, the JSON output of
+      an earlier sonar sysinfo, and emit only the fields that were added,
+      removed, or changed [default: none]
 
 Options for `slurm`:
   --window minutes
@@ -424,8 +1552,26 @@ Options for `slurm`:
   --span start,end
       Both `start` and `end` are on the form yyyy-mm-dd.  Mostly useful for seeding a
       database with older data.  Precludes --window
+  --strict
+      Fail if any line of sacct output is malformed; by default malformed lines
+      are skipped and reported on stderr
+  --nest-steps
+      With --json or --msgpack, place the records for the steps of each job
+      (batch, extern, 0, ...) in a steps array in the job's record
+  --batch-size n
+      With --json or --msgpack, emit the jobs as a sequence of envelopes of at
+      most n jobs each, rather than as a single envelope [default: none]
+  --max-output-mib n
+      Fail with an error record, rather than buffer it, if the sacct output
+      exceeds n MiB [default: 512]
+  --job-submit-info
+      Add the working directory and submit line of each job (WorkDir,
+      SubmitLine); these may reveal private paths and arguments
   --json
       Format output as JSON, not CSV
+  --msgpack
+      Format output as MessagePack with the structure of the JSON output, not
+      CSV
 ",
     );
     let _ = out.flush();
@@ -117,8 +117,15 @@ pub fn get_card_configuration() -> Option<Vec<gpu::Card>> {
 
     let mut result = vec![];
     let mut infobuf: NvmlCardInfo = Default::default();
+    let mut statebuf: NvmlCardState = Default::default();
     for dev in 0..num_devices {
         if unsafe { nvml_device_get_card_info(dev, &mut infobuf) } == 0 {
+            // The compute mode is part of the card state, not the card info.
+            let mode = if unsafe { nvml_device_get_card_state(dev, &mut statebuf) } == 0 {
+                statebuf.compute_mode
+            } else {
+                COMP_MODE_UNKNOWN
+            };
             result.push(gpu::Card {
                 bus_addr: cstrdup(&infobuf.bus_addr),
                 index: dev as i32,
@@ -133,6 +140,11 @@ pub fn get_card_configuration() -> Option<Vec<gpu::Card>> {
                 min_power_limit_watt: (infobuf.max_power_limit / 1000) as i32,
                 max_ce_clock_mhz: infobuf.max_ce_clock as i32,
                 max_mem_clock_mhz: infobuf.max_mem_clock as i32,
+                compute_mode: normalized_compute_mode(mode).to_string(),
+                compute_mode_raw: match mode {
+                    COMP_MODE_UNKNOWN => "".to_string(),
+                    x => compute_mode_name(x).to_string(),
+                },
             })
         }
     }
@@ -140,6 +152,24 @@ pub fn get_card_configuration() -> Option<Vec<gpu::Card>> {
     Some(result)
 }
 
+fn compute_mode_name(mode: cty::c_int) -> &'static str {
+    match mode {
+        COMP_MODE_DEFAULT => "Default",
+        COMP_MODE_PROHIBITED => "Prohibited",
+        COMP_MODE_EXCLUSIVE_PROCESS => "ExclusiveProcess",
+        _ => "Unknown",
+    }
+}
+
+fn normalized_compute_mode(mode: cty::c_int) -> &'static str {
+    match mode {
+        COMP_MODE_DEFAULT => gpu::COMPUTE_MODE_DEFAULT,
+        COMP_MODE_PROHIBITED => gpu::COMPUTE_MODE_PROHIBITED,
+        COMP_MODE_EXCLUSIVE_PROCESS => gpu::COMPUTE_MODE_EXCLUSIVE_PROCESS,
+        _ => "",
+    }
+}
+
 pub fn get_card_utilization() -> Option<Vec<gpu::CardState>> {
     let mut num_devices: cty::uint32_t = 0;
     if unsafe { nvml_device_get_count(&mut num_devices) } != 0 {
@@ -150,12 +180,7 @@ pub fn get_card_utilization() -> Option<Vec<gpu::CardState>> {
     let mut infobuf: NvmlCardState = Default::default();
     for dev in 0..num_devices {
         if unsafe { nvml_device_get_card_state(dev, &mut infobuf) } == 0 {
-            let mode = match infobuf.compute_mode {
-                COMP_MODE_DEFAULT => "Default",
-                COMP_MODE_PROHIBITED => "Prohibited",
-                COMP_MODE_EXCLUSIVE_PROCESS => "ExclusiveProcess",
-                COMP_MODE_UNKNOWN | _ => "Unknown",
-            };
+            let mode = compute_mode_name(infobuf.compute_mode);
             let perf = match infobuf.perf_state {
                 PERF_STATE_UNKNOWN => "Unknown".to_string(),
                 x => format!("P{x}"),
@@ -163,12 +188,13 @@ pub fn get_card_utilization() -> Option<Vec<gpu::CardState>> {
             result.push(gpu::CardState {
                 index: dev as i32,
                 fan_speed_pct: infobuf.fan_speed as f32,
-                compute_mode: mode.to_string(),
+                compute_mode: normalized_compute_mode(infobuf.compute_mode).to_string(),
+                compute_mode_raw: mode.to_string(),
                 perf_state: perf,
                 mem_reserved_kib: (infobuf.mem_reserved / 1024) as i64,
                 mem_used_kib: (infobuf.mem_used / 1024) as i64,
                 gpu_utilization_pct: infobuf.gpu_util,
-                mem_utilization_pct: infobuf.mem_util,
+                mem_bandwidth_pct: infobuf.mem_util,
                 temp_c: infobuf.temp as i32,
                 power_watt: (infobuf.power / 1000) as i32,
                 power_limit_watt: (infobuf.power_limit / 1000) as i32,
@@ -222,3 +248,17 @@ pub fn get_process_utilization(user_by_pid: &ps::UserTable) -> Option<Vec<gpu::P
 
     Some(result)
 }
+
+#[test]
+pub fn compute_mode_test() {
+    assert!(normalized_compute_mode(COMP_MODE_DEFAULT) == gpu::COMPUTE_MODE_DEFAULT);
+    assert!(compute_mode_name(COMP_MODE_DEFAULT) == "Default");
+    assert!(normalized_compute_mode(COMP_MODE_PROHIBITED) == gpu::COMPUTE_MODE_PROHIBITED);
+    assert!(compute_mode_name(COMP_MODE_PROHIBITED) == "Prohibited");
+    assert!(
+        normalized_compute_mode(COMP_MODE_EXCLUSIVE_PROCESS) == gpu::COMPUTE_MODE_EXCLUSIVE_PROCESS
+    );
+    assert!(compute_mode_name(COMP_MODE_EXCLUSIVE_PROCESS) == "ExclusiveProcess");
+    assert!(normalized_compute_mode(COMP_MODE_UNKNOWN).is_empty());
+    assert!(normalized_compute_mode(17).is_empty());
+}
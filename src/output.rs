@@ -1,14 +1,13 @@
 // Define a nested data structure of arrays, objects, and scalar values that can subsequently be
-// serialized, currently as CSV and JSON, following conventions that are backward compatible with
+// serialized as CSV, JSON, or MessagePack, following conventions that are backward compatible with
 // the older ad-hoc Sonar formatting code.
-//
-// Adding eg a compact binary serialization form would be very simple.
 
 use crate::util;
 
+use std::collections::HashMap;
 use std::io;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Value {
     A(Array),
     O(Object),
@@ -19,13 +18,13 @@ pub enum Value {
     E(), // Empty array element only, never a field or toplevel value
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Field {
     tag: String,
     value: Value,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Object {
     fields: Vec<Field>,
 }
@@ -40,7 +39,6 @@ impl Object {
         self.fields.is_empty()
     }
 
-    #[cfg(test)]
     pub fn get(&self, key: &str) -> Option<&Value> {
         for f in &self.fields {
             if key == &f.tag {
@@ -50,6 +48,11 @@ impl Object {
         None
     }
 
+    // The fields in order, as (tag, value) pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.fields.iter().map(|f| (f.tag.as_str(), &f.value))
+    }
+
     pub fn push(&mut self, tag: &str, value: Value) {
         self.fields.push(Field {
             tag: tag.to_string(),
@@ -94,9 +97,60 @@ impl Object {
     pub fn push_f(&mut self, tag: &str, f: f64) {
         self.push(tag, Value::F(f));
     }
+
+    // If there is a string-valued field `tag`, intern its value in `table` and replace the field by
+    // a field `ix_tag` holding the string's index in the table.
+    pub fn intern_field(&mut self, tag: &str, ix_tag: &str, table: &mut StringTable) {
+        for f in &mut self.fields {
+            if f.tag == tag {
+                if let Value::S(s) = &f.value {
+                    f.value = Value::U(table.intern(s));
+                    f.tag = ix_tag.to_string();
+                }
+                return;
+            }
+        }
+    }
+}
+
+// A table of distinct strings, so that records can refer to a repeated string by its index in the
+// table rather than repeating it.  The table is emitted as an array of strings in order of first
+// appearance.
+
+pub struct StringTable {
+    strings: Vec<String>,
+    index: HashMap<String, u64>,
+}
+
+impl StringTable {
+    pub fn new() -> StringTable {
+        StringTable {
+            strings: vec![],
+            index: HashMap::new(),
+        }
+    }
+
+    pub fn intern(&mut self, s: &str) -> u64 {
+        if let Some(ix) = self.index.get(s) {
+            return *ix;
+        }
+        let ix = self.strings.len() as u64;
+        self.strings.push(s.to_string());
+        self.index.insert(s.to_string(), ix);
+        ix
+    }
+
+    pub fn into_array(self) -> Array {
+        Array::from_vec(
+            self.strings
+                .into_iter()
+                .map(Value::S)
+                .collect::<Vec<Value>>(),
+        )
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Array {
     elements: Vec<Value>,
     nonempty_base45: bool,
@@ -121,6 +175,10 @@ impl Array {
         }
     }
 
+    pub fn into_vec(self) -> Vec<Value> {
+        self.elements
+    }
+
     pub fn push(&mut self, value: Value) {
         self.elements.push(value)
     }
@@ -180,6 +238,121 @@ fn write_chars(writer: &mut dyn io::Write, s: &str) {
     let _ = writer.write(s.as_bytes());
 }
 
+// Each record type carries its own schema version in a "schema" field of its envelope, so that
+// consumers can gate on the evolution of one type independently of the others and of the program
+// version.  Bump a type's version when the fields of that type change.
+
+pub const PS_SCHEMA_VERSION: u64 = 5; // 2: tags, 3: nvcsw and nivcsw, 4: token, 5: vmstat counters
+pub const SYSINFO_SCHEMA_VERSION: u64 = 4; // 2: tags, 3: token, 4: cpu_flags
+pub const SLURM_SCHEMA_VERSION: u64 = 3; // 2: AllocResources, 3: token
+
+pub fn push_schema(o: &mut Object, version: u64) {
+    o.push_u("schema", version);
+}
+
+// Tags are static `key=value` labels supplied by the user and attached to the envelope of every
+// record as the string fields of a `tags` object, so that they can't collide with the fields sonar
+// itself emits.  The key must be a lower-case identifier; the value is arbitrary.
+
+pub fn parse_tag(s: &str) -> Result<(String, String), String> {
+    let (key, value) = match s.split_once('=') {
+        Some(x) => x,
+        None => return Err(format!("Tag must be key=value: {s}")),
+    };
+    let mut cs = key.chars();
+    if !cs.next().map(|c| c.is_ascii_lowercase()).unwrap_or(false)
+        || !cs.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(format!("Bad tag key: {key}"));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+pub fn push_tags(o: &mut Object, tags: &[(String, String)]) {
+    if tags.is_empty() {
+        return;
+    }
+    let mut t = Object::new();
+    for (key, value) in tags {
+        t.push_s(key, value.clone());
+    }
+    o.push_o("tags", t);
+}
+
+// The token (see token.rs) is attached to the same envelopes as the tags, if there is one.
+
+pub fn push_token(o: &mut Object, token: &str) {
+    if !token.is_empty() {
+        o.push_s("token", token.to_string());
+    }
+}
+
+#[test]
+pub fn test_tags() {
+    let mut o = Object::new();
+    o.push_s("host", "c1-10".to_string());
+    let tags = ["datacenter=osl", "rack=r12,row 3", "env=", "host=elsewhere"]
+        .iter()
+        .map(|t| parse_tag(t).expect("Test: good tag"))
+        .collect::<Vec<(String, String)>>();
+    push_tags(&mut o, &tags);
+    assert!(matches!(o.get("host"), Some(Value::S(s)) if s == "c1-10"));
+    let t = match o.get("tags") {
+        Some(Value::O(t)) => t,
+        _ => panic!("Test: no tags"),
+    };
+    assert!(matches!(t.get("datacenter"), Some(Value::S(s)) if s == "osl"));
+    assert!(matches!(t.get("rack"), Some(Value::S(s)) if s == "r12,row 3"));
+    assert!(matches!(t.get("env"), Some(Value::S(s)) if s.is_empty()));
+    assert!(matches!(t.get("host"), Some(Value::S(s)) if s == "elsewhere"));
+
+    // No tags, no object
+    let mut o = Object::new();
+    push_tags(&mut o, &[]);
+    assert!(o.get("tags").is_none());
+
+    assert!(parse_tag("Rack=r12").is_err());
+    assert!(parse_tag("2rack=r12").is_err());
+    assert!(parse_tag("rack").is_err());
+    assert!(parse_tag("=r12").is_err());
+}
+
+#[test]
+pub fn test_string_table() {
+    let mut table = StringTable::new();
+    let mut records = vec![];
+    for i in 0..1000 {
+        let mut o = Object::new();
+        o.push_s(
+            "user",
+            if i % 2 == 0 { "zappa" } else { "root" }.to_string(),
+        );
+        o.push_s(
+            "cmd",
+            if i % 10 == 0 { "mpirun" } else { "vasp_std" }.to_string(),
+        );
+        o.push_u("pid", i);
+        o.intern_field("user", "user_ix", &mut table);
+        o.intern_field("cmd", "cmd_ix", &mut table);
+        o.intern_field("nonesuch", "nonesuch_ix", &mut table);
+        records.push(o);
+    }
+    let strings = table.into_array();
+    assert!(strings.len() == 4);
+    let decode = |v: Option<&Value>| match v {
+        Some(Value::U(ix)) => match strings.at(*ix as usize) {
+            Value::S(s) => s.clone(),
+            _ => panic!("Test: not a string"),
+        },
+        _ => panic!("Test: not an index"),
+    };
+    for (i, o) in records.iter().enumerate() {
+        assert!(o.get("user").is_none() && o.get("cmd").is_none());
+        assert!(decode(o.get("user_ix")) == if i % 2 == 0 { "zappa" } else { "root" });
+        assert!(decode(o.get("cmd_ix")) == if i % 10 == 0 { "mpirun" } else { "vasp_std" });
+    }
+}
+
 // JSON output follows the standard.
 
 pub fn write_json(writer: &mut dyn io::Write, v: &Value) {
@@ -383,6 +556,319 @@ pub fn test_csv() {
     assert!(expect == got);
 }
 
+// Flat output is one `KEY VALUE` line per scalar, like a sysctl dump.  The KEY is the dotted path
+// of object tags and array indices leading to the scalar, eg `gpu_info.0.model`, and the VALUE is
+// the bare representation of the scalar, in which backslash, newline and carriage return are
+// escaped as `\\`, `\n` and `\r` so that every line is one field.  Empty values, including empty
+// strings, and empty objects and arrays produce no lines.
+
+pub fn write_flat(writer: &mut dyn io::Write, v: &Value) {
+    write_flat_int(writer, "", v);
+}
+
+fn write_flat_int(writer: &mut dyn io::Write, path: &str, v: &Value) {
+    let key = |k: &str| {
+        if path.is_empty() {
+            k.to_string()
+        } else {
+            format!("{path}.{k}")
+        }
+    };
+    match v {
+        Value::A(a) => {
+            for (i, elt) in a.elements.iter().enumerate() {
+                write_flat_int(writer, &key(&i.to_string()), elt);
+            }
+        }
+        Value::O(o) => {
+            for fld in &o.fields {
+                write_flat_int(writer, &key(&fld.tag), &fld.value);
+            }
+        }
+        Value::E() => {}
+        Value::S(s) if s.is_empty() => {}
+        scalar => {
+            let value = format_csv_value(scalar)
+                .replace('\\', "\\\\")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r");
+            write_chars(writer, &format!("{path} {value}\n"));
+        }
+    }
+}
+
+#[test]
+pub fn test_flat() {
+    let mut o = Object::new();
+    o.push_s("hostname", "c1-10".to_string());
+    o.push_i("cpu_cores", 64);
+    let mut gpus = Array::new();
+    let mut g0 = Object::new();
+    g0.push_s("model", "NVIDIA A100".to_string());
+    g0.push_u("index", 0);
+    gpus.push_o(g0);
+    let mut g1 = Object::new();
+    g1.push_s("model", "NVIDIA A100".to_string());
+    g1.push_f("util", 12.5);
+    g1.push_s("firmware", "".to_string());
+    gpus.push_o(g1);
+    gpus.push_e();
+    o.push_a("gpu_info", gpus);
+    o.push_o("empty", Object::new());
+    o.push_s("motd", "Welcome\r\nto C:\\node\n".to_string());
+    let expect = concat!(
+        "hostname c1-10\n",
+        "cpu_cores 64\n",
+        "gpu_info.0.model NVIDIA A100\n",
+        "gpu_info.0.index 0\n",
+        "gpu_info.1.model NVIDIA A100\n",
+        "gpu_info.1.util 12.5\n",
+        "motd Welcome\\r\\nto C:\\\\node\\n\n",
+    );
+    let mut output = Vec::new();
+    write_flat(&mut output, &Value::O(o));
+    let got = String::from_utf8_lossy(&output);
+    assert!(expect == got);
+}
+
+// MessagePack output is a structural encoding of the value tree, see
+// https://github.com/msgpack/msgpack/blob/master/spec.md.  Objects become maps keyed by the field
+// tags, arrays become arrays, and scalars use the smallest encoding that holds them; non-negative
+// signed integers are encoded as unsigned, as the format allows.  An empty array element becomes
+// nil, so a generic decoder reconstructs exactly the shape of the JSON output.  A base45-encoded
+// array becomes its encoded string.  There is no record separator: records written one after
+// another form a valid MessagePack stream.
+
+pub fn write_msgpack(writer: &mut dyn io::Write, v: &Value) {
+    let mut buf = vec![];
+    encode_msgpack(&mut buf, v);
+    let _ = writer.write(&buf);
+}
+
+fn encode_msgpack(buf: &mut Vec<u8>, v: &Value) {
+    match v {
+        Value::A(a) => encode_msgpack_array(buf, a),
+        Value::O(o) => {
+            encode_msgpack_header(buf, o.fields.len(), 0x80, 0xde);
+            for fld in &o.fields {
+                encode_msgpack_string(buf, &fld.tag);
+                encode_msgpack(buf, &fld.value);
+            }
+        }
+        Value::S(s) => encode_msgpack_string(buf, s),
+        Value::U(u) => encode_msgpack_uint(buf, *u),
+        Value::I(i) => {
+            if *i >= 0 {
+                encode_msgpack_uint(buf, *i as u64);
+            } else if *i >= -32 {
+                buf.push(*i as u8);
+            } else if *i >= i8::MIN as i64 {
+                buf.push(0xd0);
+                buf.push(*i as u8);
+            } else if *i >= i16::MIN as i64 {
+                buf.push(0xd1);
+                buf.extend_from_slice(&(*i as i16).to_be_bytes());
+            } else if *i >= i32::MIN as i64 {
+                buf.push(0xd2);
+                buf.extend_from_slice(&(*i as i32).to_be_bytes());
+            } else {
+                buf.push(0xd3);
+                buf.extend_from_slice(&i.to_be_bytes());
+            }
+        }
+        Value::F(f) => {
+            buf.push(0xcb);
+            buf.extend_from_slice(&f.to_be_bytes());
+        }
+        Value::E() => buf.push(0xc0),
+    }
+}
+
+fn encode_msgpack_array(buf: &mut Vec<u8>, a: &Array) {
+    if a.nonempty_base45 {
+        let us = a
+            .elements
+            .iter()
+            .map(|x| {
+                if let Value::U(u) = x {
+                    *u
+                } else {
+                    panic!("Not a Value::U")
+                }
+            })
+            .collect::<Vec<u64>>();
+        encode_msgpack_string(buf, &encode_cpu_secs_base45el(&us));
+        return;
+    }
+    encode_msgpack_header(buf, a.elements.len(), 0x90, 0xdc);
+    for elt in &a.elements {
+        encode_msgpack(buf, elt);
+    }
+}
+
+// Maps and arrays have a 4-bit "fix" length form and then 16-bit and 32-bit forms whose markers are
+// adjacent.
+
+fn encode_msgpack_header(buf: &mut Vec<u8>, len: usize, fix: u8, marker16: u8) {
+    if len < 16 {
+        buf.push(fix | len as u8);
+    } else if len <= u16::MAX as usize {
+        buf.push(marker16);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(marker16 + 1);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn encode_msgpack_string(buf: &mut Vec<u8>, s: &str) {
+    let len = s.len();
+    if len < 32 {
+        buf.push(0xa0 | len as u8);
+    } else if len <= u8::MAX as usize {
+        buf.push(0xd9);
+        buf.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        buf.push(0xda);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(0xdb);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn encode_msgpack_uint(buf: &mut Vec<u8>, u: u64) {
+    if u < 128 {
+        buf.push(u as u8);
+    } else if u <= u8::MAX as u64 {
+        buf.push(0xcc);
+        buf.push(u as u8);
+    } else if u <= u16::MAX as u64 {
+        buf.push(0xcd);
+        buf.extend_from_slice(&(u as u16).to_be_bytes());
+    } else if u <= u32::MAX as u64 {
+        buf.push(0xce);
+        buf.extend_from_slice(&(u as u32).to_be_bytes());
+    } else {
+        buf.push(0xcf);
+        buf.extend_from_slice(&u.to_be_bytes());
+    }
+}
+
+// A generic decoder for the subset of MessagePack that write_msgpack produces, for testing.  It
+// knows nothing about sonar's data, so it checks that the encoding is self-describing.  Nil becomes
+// an empty value, and non-negative integers come back as unsigned.
+
+#[cfg(test)]
+pub fn read_msgpack(bytes: &[u8]) -> Value {
+    let mut pos = 0;
+    let v = decode_msgpack(bytes, &mut pos);
+    assert!(pos == bytes.len(), "Test: trailing bytes");
+    v
+}
+
+#[cfg(test)]
+fn decode_msgpack(bytes: &[u8], pos: &mut usize) -> Value {
+    let mut take = |n: usize| {
+        let b = &bytes[*pos..*pos + n];
+        *pos += n;
+        b
+    };
+    let be = |b: &[u8]| b.iter().fold(0u64, |acc, x| (acc << 8) | *x as u64);
+    let marker = take(1)[0];
+    let (kind, len) = match marker {
+        0x00..=0x7f => return Value::U(marker as u64),
+        0x80..=0x8f => ('o', (marker & 0x0f) as usize),
+        0x90..=0x9f => ('a', (marker & 0x0f) as usize),
+        0xa0..=0xbf => ('s', (marker & 0x1f) as usize),
+        0xc0 => return Value::E(),
+        0xcb => return Value::F(f64::from_bits(be(take(8)))),
+        0xcc => return Value::U(be(take(1))),
+        0xcd => return Value::U(be(take(2))),
+        0xce => return Value::U(be(take(4))),
+        0xcf => return Value::U(be(take(8))),
+        0xd0 => return Value::I(take(1)[0] as i8 as i64),
+        0xd1 => return Value::I(be(take(2)) as i16 as i64),
+        0xd2 => return Value::I(be(take(4)) as i32 as i64),
+        0xd3 => return Value::I(be(take(8)) as i64),
+        0xd9 => ('s', be(take(1)) as usize),
+        0xda => ('s', be(take(2)) as usize),
+        0xdb => ('s', be(take(4)) as usize),
+        0xdc => ('a', be(take(2)) as usize),
+        0xdd => ('a', be(take(4)) as usize),
+        0xde => ('o', be(take(2)) as usize),
+        0xdf => ('o', be(take(4)) as usize),
+        0xe0..=0xff => return Value::I(marker as i8 as i64),
+        _ => panic!("Test: unexpected marker {marker:#x}"),
+    };
+    match kind {
+        's' => Value::S(String::from_utf8(take(len).to_vec()).expect("Test: utf8")),
+        'a' => {
+            let mut a = Array::new();
+            for _ in 0..len {
+                a.push(decode_msgpack(bytes, pos));
+            }
+            Value::A(a)
+        }
+        _ => {
+            let mut o = Object::new();
+            for _ in 0..len {
+                let tag = match decode_msgpack(bytes, pos) {
+                    Value::S(s) => s,
+                    _ => panic!("Test: map key is not a string"),
+                };
+                o.push(&tag, decode_msgpack(bytes, pos));
+            }
+            Value::O(o)
+        }
+    }
+}
+
+// The JSON text of the decoded value must be that of the original.
+
+#[cfg(test)]
+pub fn assert_msgpack_roundtrip(v: &Value) {
+    let mut expect = Vec::new();
+    write_json(&mut expect, v);
+    let mut bytes = Vec::new();
+    write_msgpack(&mut bytes, v);
+    let mut got = Vec::new();
+    write_json(&mut got, &read_msgpack(&bytes));
+    assert!(String::from_utf8_lossy(&expect) == String::from_utf8_lossy(&got));
+}
+
+#[test]
+pub fn test_msgpack() {
+    let mut a = Array::new();
+    let mut o = Object::new();
+    o.push_o("o", Object::new());
+    o.push_a("a", Array::new());
+    o.push_s("s", "x".repeat(40));
+    o.push_u("u", 123);
+    o.push_u("big", 1 << 40);
+    o.push_i("i", -12);
+    o.push_i("j", -40000);
+    o.push_i("k", 300);
+    o.push_f("f", 12.5);
+    a.push_o(o);
+    a.push_e();
+    for i in 0..20 {
+        a.push_u(i);
+    }
+    assert_msgpack_roundtrip(&Value::A(a));
+
+    let mut load = Array::new();
+    for x in [1, 30, 89, 12] {
+        load.push_u(x);
+    }
+    load.set_encode_nonempty_base45();
+    let a = Array::from_vec(vec![Value::I(-1), Value::A(load)]);
+    let mut bytes = Vec::new();
+    write_msgpack(&mut bytes, &Value::A(a));
+    assert!(bytes == [0x92, 0xff, 0xa6, b')', b'(', b't', b'*', b'1', b'b']);
+}
+
 // Encode a nonempty u64 array compactly.
 //
 // The output must be ASCII text (32 <= c < 128), ideally without ',' or '"' or '\' or ' ' to not
@@ -15,12 +15,42 @@ pub struct Process {
     pub cputime_sec: usize,
     pub mem_size_kib: usize,
     pub rssanon_kib: usize,
+    pub major_faults: usize,
+    pub minor_faults: usize,
+    pub num_threads: usize,
+    pub voluntary_ctxt_switches: usize, // Cumulative, from /proc/{pid}/status, 0 if absent
+    pub nonvoluntary_ctxt_switches: usize, // Ditto
+    pub cpu_times: CpuTimes,
     pub command: String,
     pub has_children: bool,
+    pub is_zombie: bool,
+    pub is_uninterruptible: bool, // In state D, usually waiting for IO
+    pub is_kernel_thread: bool,
 }
 
-/// Read the /proc/meminfo file from the fs and return the value for total installed memory.
+/// The components of a process's CPU time in /proc/{pid}/stat, in seconds: user and system time of
+/// the process itself and of its waited-for children.  `cputime_sec` is their rounded sum.
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct CpuTimes {
+    pub user_sec: f64,
+    pub system_sec: f64,
+    pub child_user_sec: f64,
+    pub child_system_sec: f64,
+}
+
+/// Node-level data extracted from /proc/stat.  The cpu times are in seconds since boot; the process
+/// counts are instantaneous.
+#[derive(PartialEq, Debug, Default)]
+pub struct SystemStat {
+    pub cpu_total_secs: u64,
+    pub per_cpu_secs: Vec<u64>,
+    pub offline_cpus: Vec<usize>, // Indices of present cpus that are not online, from sysfs
+    pub procs_running: u64,
+    pub procs_blocked: u64,
+    pub boot_time: u64, // Seconds since epoch
+}
 
+/// Read the /proc/meminfo file from the fs and return the value for total installed memory.
 pub fn get_memtotal_kib(fs: &dyn procfsapi::ProcfsAPI) -> Result<usize, String> {
     let mut memtotal_kib = 0;
     let meminfo_s = fs.read_to_string("meminfo")?;
@@ -43,10 +73,58 @@ pub fn get_memtotal_kib(fs: &dyn procfsapi::ProcfsAPI) -> Result<usize, String>
     Ok(memtotal_kib)
 }
 
+/// Read the /proc/meminfo file from the fs and return the estimate of memory available for new
+/// work without swapping, or None if it is not there.
+pub fn get_memavailable_kib(fs: &dyn procfsapi::ProcfsAPI) -> Option<usize> {
+    let meminfo_s = fs.read_to_string("meminfo").ok()?;
+    let l = meminfo_s.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    l.split_ascii_whitespace().nth(1)?.parse::<usize>().ok()
+}
+
+/// Find the memory limit of the cgroup sonar runs in, ie, of the container or cgroup-constrained
+/// node, from /proc/self/cgroup and the cgroup filesystem.  The effective limit is the smallest one
+/// on the path to the root of the hierarchy: memory.max for cgroup v2, memory.limit_in_bytes for
+/// the v1 memory controller.  Returns None if there is no limit ("max"), the files can't be read,
+/// or the limit is not below `memtotal_kib` and is therefore no constraint.
+pub fn get_memory_limit_kib(fs: &dyn procfsapi::ProcfsAPI, memtotal_kib: usize) -> Option<usize> {
+    let cgroup = fs.read_to_string("self/cgroup").ok()?;
+    let mut limit_bytes: Option<u64> = None;
+    for l in cgroup.lines() {
+        // hierarchy-ID:controller-list:cgroup-path
+        let mut fields = l.splitn(3, ':');
+        let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        let (dir, file) = if controllers.is_empty() {
+            ("fs/cgroup".to_string(), "memory.max")
+        } else if controllers.split(',').any(|c| c == "memory") {
+            (format!("fs/cgroup/{controllers}"), "memory.limit_in_bytes")
+        } else {
+            continue;
+        };
+        let mut path = path.trim_end_matches('/');
+        loop {
+            // "max" does not parse and is no limit
+            if let Ok(s) = fs.read_sys_to_string(&format!("{dir}{path}/{file}")) {
+                if let Ok(bytes) = s.trim().parse::<u64>() {
+                    limit_bytes = Some(limit_bytes.map_or(bytes, |l| l.min(bytes)));
+                }
+            }
+            match path.rfind('/') {
+                Some(x) => path = &path[..x],
+                None => break,
+            }
+        }
+    }
+    let limit_kib = (limit_bytes? / 1024) as usize;
+    if limit_kib < memtotal_kib {
+        Some(limit_kib)
+    } else {
+        None
+    }
+}
+
 /// Read the /proc/cpuinfo file from the fs and return information about installed CPUs.
 ///
 /// Fun fact: this file is very different on x86_64 and aarch64.
-
 pub fn get_cpu_info(fs: &dyn procfsapi::ProcfsAPI) -> Result<(String, i32, i32, i32), String> {
     let mut physids = HashMap::<i32, bool>::new();
     let mut processors = HashSet::<i32>::new();
@@ -105,6 +183,36 @@ pub fn get_cpu_info(fs: &dyn procfsapi::ProcfsAPI) -> Result<(String, i32, i32,
     }
 }
 
+/// The prefixes of the CPU feature flags that matter for which vectorized code can run: the AVX
+/// family (avx, avx2, avx512f, avx_vnni, ...) and AMX on x86_64, and SVE (sve, sve2, ...) on
+/// aarch64.
+const VECTOR_FLAG_PREFIXES: &[&str] = &["avx", "amx", "sve"];
+
+/// Read the feature flags of the first core from /proc/cpuinfo, the `flags` line on x86_64 and the
+/// `Features` line on aarch64, and return those matching VECTOR_FLAG_PREFIXES, sorted and without
+/// duplicates.  Returns an empty vector if the file can't be read or has no flags.
+pub fn get_cpu_flags(fs: &dyn procfsapi::ProcfsAPI) -> Vec<String> {
+    let Ok(cpuinfo) = fs.read_to_string("cpuinfo") else {
+        return vec![];
+    };
+    let Some(flags) = cpuinfo
+        .split('\n')
+        .find(|l| l.starts_with("flags") || l.starts_with("Features"))
+        .and_then(|l| l.split_once(':'))
+        .map(|(_, flags)| flags)
+    else {
+        return vec![];
+    };
+    let mut flags = flags
+        .split_ascii_whitespace()
+        .filter(|f| VECTOR_FLAG_PREFIXES.iter().any(|p| f.starts_with(p)))
+        .map(|f| f.to_string())
+        .collect::<Vec<String>>();
+    flags.sort();
+    flags.dedup();
+    flags
+}
+
 fn text_field(l: &str) -> Result<String, String> {
     if let Some((_, after)) = l.split_once(':') {
         Ok(after.trim().to_string())
@@ -131,6 +239,475 @@ fn i32_field(l: &str) -> Result<i32, String> {
     }
 }
 
+/// Read the RAPL energy counters from /sys/class/powercap and return (domain, microjoules) pairs
+/// sorted by domain, or an empty vector if there are no counters or they can't be read.
+///
+/// The top-level domains "intel-rapl:N" are typically packages and are named by their `name` file
+/// (eg "package-0"); subdomains "intel-rapl:N:M" (eg "core", "dram") are named by their parent's
+/// name and their own, eg "package-0/dram".  The counters are cumulative but wrap around at
+/// `max_energy_range_uj`, which consumers must handle.  On most recent kernels `energy_uj` is
+/// readable only by root.
+pub fn get_energy_counters(fs: &dyn procfsapi::ProcfsAPI) -> Vec<(String, u64)> {
+    const POWERCAP: &str = "class/powercap";
+    let mut domains = match fs.read_sys_dir(POWERCAP) {
+        Ok(names) => names
+            .into_iter()
+            .filter(|n| n.starts_with("intel-rapl:"))
+            .collect::<Vec<String>>(),
+        Err(_) => return vec![],
+    };
+    domains.sort();
+    let name_of = |domain: &str| -> Option<String> {
+        fs.read_sys_to_string(&format!("{POWERCAP}/{domain}/name"))
+            .ok()
+            .map(|s| s.trim().to_string())
+    };
+    let mut result = vec![];
+    for domain in domains {
+        let name = match domain.rfind(':') {
+            Some(x) if domain[..x].contains(':') => {
+                match (name_of(&domain[..x]), name_of(&domain)) {
+                    (Some(parent), Some(name)) => parent + "/" + &name,
+                    _ => continue,
+                }
+            }
+            _ => match name_of(&domain) {
+                Some(name) => name,
+                None => continue,
+            },
+        };
+        if let Ok(s) = fs.read_sys_to_string(&format!("{POWERCAP}/{domain}/energy_uj")) {
+            if let Ok(uj) = s.trim().parse::<u64>() {
+                result.push((name, uj));
+            }
+        }
+    }
+    result
+}
+
+/// Information about an InfiniBand port, from /sys/class/infiniband/<device>/ports/<port>.
+#[derive(PartialEq, Debug)]
+pub struct IbPort {
+    pub device: String,
+    pub port: String,
+    pub state: String,  // eg "ACTIVE", from `state`, which is eg "4: ACTIVE"
+    pub xmit_data: u64, // counters/port_xmit_data, in units of 4 octets
+    pub rcv_data: u64,  // counters/port_rcv_data, in units of 4 octets
+}
+
+/// Enumerate the InfiniBand devices and their ports and read the ports' state and data counters.
+/// Nodes without InfiniBand hardware produce an empty vector, as do ports whose data can't be
+/// read.  The result is sorted by device and port.
+pub fn get_infiniband_ports(fs: &dyn procfsapi::ProcfsAPI) -> Vec<IbPort> {
+    const INFINIBAND: &str = "class/infiniband";
+    let mut result = vec![];
+    let mut devices = fs.read_sys_dir(INFINIBAND).unwrap_or_default();
+    devices.sort();
+    for device in devices {
+        let mut ports = fs
+            .read_sys_dir(&format!("{INFINIBAND}/{device}/ports"))
+            .unwrap_or_default();
+        ports.sort_by_key(|p| p.parse::<usize>().unwrap_or_default());
+        for port in ports {
+            let dir = format!("{INFINIBAND}/{device}/ports/{port}");
+            let read_u64 = |name: &str| -> Option<u64> {
+                fs.read_sys_to_string(&format!("{dir}/counters/{name}"))
+                    .ok()
+                    .and_then(|s| s.trim().parse::<u64>().ok())
+            };
+            let state = match fs.read_sys_to_string(&format!("{dir}/state")) {
+                Ok(s) => match s.find(':') {
+                    Some(x) => s[x + 1..].trim().to_string(),
+                    None => s.trim().to_string(),
+                },
+                Err(_) => continue,
+            };
+            if let (Some(xmit_data), Some(rcv_data)) =
+                (read_u64("port_xmit_data"), read_u64("port_rcv_data"))
+            {
+                result.push(IbPort {
+                    device: device.clone(),
+                    port,
+                    state,
+                    xmit_data,
+                    rcv_data,
+                });
+            }
+        }
+    }
+    result
+}
+
+/// Read the boot time, in seconds since epoch, from the `btime` line of /proc/stat.
+pub fn get_boot_time(fs: &dyn procfsapi::ProcfsAPI) -> Result<u64, String> {
+    parse_boot_time(&fs.read_to_string("stat")?)
+}
+
+fn parse_boot_time(stat_s: &str) -> Result<u64, String> {
+    for l in stat_s.split('\n') {
+        if l.starts_with("btime ") {
+            let fields = l.split_ascii_whitespace().collect::<Vec<&str>>();
+            let boot_time = parse_usize_field(&fields, 1, l, "stat", 0, "btime")? as u64;
+            if boot_time != 0 {
+                return Ok(boot_time);
+            }
+        }
+    }
+    Err(format!("Could not find btime in /proc/stat: {stat_s}"))
+}
+
+/// The node's load averages over 1, 5 and 15 minutes and its counts of runnable and existing
+/// scheduling entities (threads), from /proc/loadavg.
+#[derive(PartialEq, Debug, Default)]
+pub struct LoadAvg {
+    pub load1: f64,
+    pub load5: f64,
+    pub load15: f64,
+    pub runnable: u64,
+    pub existing: u64,
+}
+
+/// Read /proc/loadavg, eg "0.52 0.58 0.59 3/1187 23456".  Returns None if the file can't be read
+/// or is malformed.
+pub fn get_loadavg(fs: &dyn procfsapi::ProcfsAPI) -> Option<LoadAvg> {
+    let s = fs.read_to_string("loadavg").ok()?;
+    let fields = s.split_ascii_whitespace().collect::<Vec<&str>>();
+    if fields.len() < 4 {
+        return None;
+    }
+    let (runnable, existing) = fields[3].split_once('/')?;
+    Some(LoadAvg {
+        load1: fields[0].parse::<f64>().ok()?,
+        load5: fields[1].parse::<f64>().ok()?,
+        load15: fields[2].parse::<f64>().ok()?,
+        runnable: runnable.parse::<u64>().ok()?,
+        existing: existing.parse::<u64>().ok()?,
+    })
+}
+
+/// The node's cumulative counts of major page faults and of pages swapped in and out since boot,
+/// from /proc/vmstat.  A high rate of major faults along with swapping indicates that the node is
+/// thrashing.
+#[derive(PartialEq, Debug, Default)]
+pub struct VmStat {
+    pub pgmajfault: u64,
+    pub pswpin: u64,
+    pub pswpout: u64,
+}
+
+/// Read /proc/vmstat, which has one "name value" pair per line.  Returns None if the file can't be
+/// read or any of the fields is missing or malformed.
+pub fn get_vmstat(fs: &dyn procfsapi::ProcfsAPI) -> Option<VmStat> {
+    let s = fs.read_to_string("vmstat").ok()?;
+    let (mut pgmajfault, mut pswpin, mut pswpout) = (None, None, None);
+    for l in s.split('\n') {
+        let Some((name, value)) = l.split_once(' ') else {
+            continue;
+        };
+        match name {
+            "pgmajfault" => pgmajfault = Some(value.trim().parse::<u64>().ok()?),
+            "pswpin" => pswpin = Some(value.trim().parse::<u64>().ok()?),
+            "pswpout" => pswpout = Some(value.trim().parse::<u64>().ok()?),
+            _ => {}
+        }
+    }
+    Some(VmStat {
+        pgmajfault: pgmajfault?,
+        pswpin: pswpin?,
+        pswpout: pswpout?,
+    })
+}
+
+/// Runtime CPU state from /sys/devices/system/cpu.  /proc/cpuinfo lists only the online CPUs, so
+/// when SMT or individual CPUs are switched off without a reboot the topology computed from it
+/// shrinks; these fields make that visible.  Each field is None if the file can't be read.
+#[derive(PartialEq, Debug, Default)]
+pub struct CpuState {
+    pub present_cpus: Option<u64>, // Number of CPUs in `present`, ie configured
+    pub online_cpus: Option<u64>,  // Number of CPUs in `online`
+    pub smt_control: Option<String>, // `smt/control`, eg "on", "off", "notsupported"
+    pub smt_active: Option<bool>,  // `smt/active`
+}
+
+pub fn get_cpu_state(fs: &dyn procfsapi::ProcfsAPI) -> CpuState {
+    const CPU: &str = "devices/system/cpu";
+    let read = |name: &str| -> Option<String> {
+        fs.read_sys_to_string(&format!("{CPU}/{name}"))
+            .ok()
+            .map(|s| s.trim().to_string())
+    };
+    CpuState {
+        present_cpus: read("present").and_then(|s| count_cpu_list(&s)),
+        online_cpus: read("online").and_then(|s| count_cpu_list(&s)),
+        smt_control: read("smt/control").filter(|s| !s.is_empty()),
+        smt_active: match read("smt/active").as_deref() {
+            Some("1") => Some(true),
+            Some("0") => Some(false),
+            _ => None,
+        },
+    }
+}
+
+// Count the CPUs in a sysfs cpu list, eg "0-3,8-11,16".  Returns None if the list is malformed.
+
+fn count_cpu_list(s: &str) -> Option<u64> {
+    parse_cpu_list(s).map(|cpus| cpus.len() as u64)
+}
+
+// The CPUs in a sysfs cpu list, in the order listed.  Returns None if the list is malformed.
+
+fn parse_cpu_list(s: &str) -> Option<Vec<usize>> {
+    let mut cpus = vec![];
+    for range in s.trim().split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((lo, hi)) => {
+                let (lo, hi) = (lo.parse::<usize>().ok()?, hi.parse::<usize>().ok()?);
+                if hi < lo {
+                    return None;
+                }
+                cpus.extend(lo..=hi);
+            }
+            None => cpus.push(range.parse::<usize>().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+// The CPUs that are present but not online, according to sysfs, in ascending order.  Empty if
+// sysfs can't tell.
+
+fn get_offline_cpus(fs: &dyn procfsapi::ProcfsAPI) -> Vec<usize> {
+    let read = |name: &str| {
+        fs.read_sys_to_string(&format!("devices/system/cpu/{name}"))
+            .ok()
+            .and_then(|s| parse_cpu_list(&s))
+    };
+    match (read("present"), read("online")) {
+        (Some(present), Some(online)) => {
+            let mut offline = present
+                .into_iter()
+                .filter(|cpu| !online.contains(cpu))
+                .collect::<Vec<usize>>();
+            offline.sort();
+            offline
+        }
+        _ => vec![],
+    }
+}
+
+/// The CPU clock frequencies of the node, in MHz, for normalizing performance across nodes.  `max`
+/// and `base` come from cpufreq in sysfs (`cpuinfo_max_freq` and `base_frequency`, the latter only
+/// with some drivers, eg intel_pstate) for cpu0, which is never taken offline.  Without cpufreq, as
+/// in many VMs, both are None: the "cpu MHz" in /proc/cpuinfo is the current frequency, which
+/// varies with load and power saving, not the maximum.
+#[derive(PartialEq, Debug, Default)]
+pub struct CpuFreq {
+    pub max_mhz: Option<u64>,
+    pub base_mhz: Option<u64>,
+}
+
+pub fn get_cpu_freq(fs: &dyn procfsapi::ProcfsAPI) -> CpuFreq {
+    const CPUFREQ: &str = "devices/system/cpu/cpu0/cpufreq";
+    let read_khz = |name: &str| -> Option<u64> {
+        fs.read_sys_to_string(&format!("{CPUFREQ}/{name}"))
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .filter(|khz| *khz > 0)
+            .map(|khz| (khz + 500) / 1000)
+    };
+    CpuFreq {
+        max_mhz: read_khz("cpuinfo_max_freq"),
+        base_mhz: read_khz("base_frequency"),
+    }
+}
+
+/// Read the CPU time consumed by Slurm job `job_id`, in microseconds, from the job's cgroup.  `pid`
+/// is any process in the job.  The cgroup's counter includes the time of every process that ever
+/// ran in the job, so unlike sums over the live processes it does not depend on how child time was
+/// propagated to the parents.  Returns None if the process is not in a cgroup for the job or the
+/// counter can't be read.
+///
+/// With cgroup v2 the job's cgroup is eg /system.slice/slurmstepd.scope/job_123 and the counter is
+/// `usage_usec` in its cpu.stat.  With cgroup v1 the job's cgroup in the cpuacct hierarchy is eg
+/// /slurm/uid_1000/job_123 and the counter is cpuacct.usage, in nanoseconds.
+pub fn get_job_cgroup_cpu_usec(
+    fs: &dyn procfsapi::ProcfsAPI,
+    pid: usize,
+    job_id: usize,
+) -> Option<u64> {
+    let cgroup = fs.read_to_string(&format!("{pid}/cgroup")).ok()?;
+    let job_dir = format!("/job_{job_id}");
+    for l in cgroup.lines() {
+        // hierarchy-ID:controller-list:cgroup-path
+        let mut fields = l.splitn(3, ':');
+        let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        let end = match path.find(&job_dir) {
+            Some(x) => x + job_dir.len(),
+            None => continue,
+        };
+        // Don't mistake job_1234 for job_123
+        if !(path[end..].is_empty() || path[end..].starts_with('/')) {
+            continue;
+        }
+        let job_path = &path[..end];
+        if controllers.is_empty() {
+            let stat = fs
+                .read_sys_to_string(&format!("fs/cgroup{job_path}/cpu.stat"))
+                .ok()?;
+            return stat.lines().find_map(|l| {
+                l.strip_prefix("usage_usec ")
+                    .and_then(|v| v.trim().parse::<u64>().ok())
+            });
+        }
+        if controllers.split(',').any(|c| c == "cpuacct") {
+            let usage = fs
+                .read_sys_to_string(&format!("fs/cgroup/{controllers}{job_path}/cpuacct.usage"))
+                .ok()?;
+            return usage.trim().parse::<u64>().ok().map(|ns| ns / 1000);
+        }
+    }
+    None
+}
+
+/// Read a single integer from /proc/{pid}/{name}, eg oom_score or oom_score_adj.  Returns None if
+/// the file can't be read or doesn't hold an integer, as when the process has gone away.
+pub fn get_proc_int(fs: &dyn procfsapi::ProcfsAPI, pid: usize, name: &str) -> Option<i64> {
+    fs.read_to_string(&format!("{pid}/{name}"))
+        .ok()?
+        .trim()
+        .parse::<i64>()
+        .ok()
+}
+
+/// Return the network namespace of the process, eg "net:[4026531840]", from the links in
+/// /proc/{pid}/ns.  Returns None if they can't be read, which is the case when its fds can't be.
+pub fn get_net_namespace(fs: &dyn procfsapi::ProcfsAPI, pid: usize) -> Option<String> {
+    fs.read_proc_links(&format!("{pid}/ns"))
+        .ok()?
+        .into_iter()
+        .find(|t| t.starts_with("net:["))
+}
+
+/// Return the inodes of the established TCP connections, IPv4 and IPv6, in the network namespace
+/// of the process, from /proc/{pid}/net/tcp{,6}.  Returns None if neither file can be read.
+pub fn get_established_tcp_inodes(
+    fs: &dyn procfsapi::ProcfsAPI,
+    pid: usize,
+) -> Option<HashSet<u64>> {
+    const TCP_ESTABLISHED: &str = "01";
+    let mut inodes = HashSet::new();
+    let mut found = false;
+    for file in ["net/tcp", "net/tcp6"] {
+        if let Ok(s) = fs.read_to_string(&format!("{pid}/{file}")) {
+            found = true;
+            // The first line is a header.  Fields are "sl local_address rem_address st
+            // tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode ..."
+            for l in s.lines().skip(1) {
+                let fields = l.split_ascii_whitespace().collect::<Vec<&str>>();
+                if fields.len() > 9 && fields[3] == TCP_ESTABLISHED {
+                    if let Ok(inode) = fields[9].parse::<u64>() {
+                        inodes.insert(inode);
+                    }
+                }
+            }
+        }
+    }
+    found.then_some(inodes)
+}
+
+/// Count the open file descriptors of the process that are sockets with inodes in `inodes`.
+/// Returns None if the process's fds can't be read, as is normal for other users' processes when
+/// sonar is not privileged.
+pub fn count_process_sockets(
+    fs: &dyn procfsapi::ProcfsAPI,
+    pid: usize,
+    inodes: &HashSet<u64>,
+) -> Option<u64> {
+    let targets = fs.read_proc_links(&format!("{pid}/fd")).ok()?;
+    Some(
+        targets
+            .iter()
+            .filter_map(|t| {
+                t.strip_prefix("socket:[")?
+                    .strip_suffix(']')?
+                    .parse::<u64>()
+                    .ok()
+            })
+            .filter(|inode| inodes.contains(inode))
+            .count() as u64,
+    )
+}
+
+/// The IO counters of a process from /proc/{pid}/io, in bytes.  `rchar` and `wchar` count logical
+/// IO, including IO that is satisfied by the page cache, while `read_bytes` and `write_bytes` count
+/// the IO that reached the storage layer.
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct ProcessIo {
+    pub rchar: u64,
+    pub wchar: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+/// Read /proc/{pid}/io.  Returns None if the file can't be read, as is normal for other users'
+/// processes when sonar is not privileged, or has none of the fields.
+pub fn get_process_io(fs: &dyn procfsapi::ProcfsAPI, pid: usize) -> Option<ProcessIo> {
+    let s = fs.read_to_string(&format!("{pid}/io")).ok()?;
+    let mut io = ProcessIo::default();
+    let mut found = false;
+    for l in s.lines() {
+        if let Some((name, value)) = l.split_once(':') {
+            let field = match name {
+                "rchar" => &mut io.rchar,
+                "wchar" => &mut io.wchar,
+                "read_bytes" => &mut io.read_bytes,
+                "write_bytes" => &mut io.write_bytes,
+                _ => continue,
+            };
+            if let Ok(n) = value.trim().parse::<u64>() {
+                *field = n;
+                found = true;
+            }
+        }
+    }
+    if found {
+        Some(io)
+    } else {
+        None
+    }
+}
+
+/// Read the anonymous transparent hugepage memory of a process in KiB from the AnonHugePages field
+/// of /proc/{pid}/smaps_rollup.  Returns None if the file can't be read, as is normal for other
+/// users' processes when sonar is not privileged, or has no such field.
+pub fn get_anon_hugepages_kib(fs: &dyn procfsapi::ProcfsAPI, pid: usize) -> Option<usize> {
+    let s = fs.read_to_string(&format!("{pid}/smaps_rollup")).ok()?;
+    for l in s.lines() {
+        if let Some(rest) = l.strip_prefix("AnonHugePages:") {
+            // We expect "AnonHugePages:\s+(\d+)\s+kB"
+            let fields = rest.split_ascii_whitespace().collect::<Vec<&str>>();
+            if fields.len() == 2 && fields[1] == "kB" {
+                return fields[0].parse::<usize>().ok();
+            }
+            return None;
+        }
+    }
+    None
+}
+
+/// Which per-process files get_process_information reads.  `Cpu` reads only /proc/{pid}/stat and
+/// leaves the memory fields (`mem_pct`, `mem_size_kib` and `rssanon_kib`) zero, which saves two
+/// file reads per process on nodes with very many processes.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Profile {
+    #[default]
+    Full,
+    Cpu,
+}
+
+/// The processes found by get_process_information, keyed by pid.
+pub type ProcessTable = HashMap<usize, Process>;
+
 /// Obtain process information via /proc and return a hashmap of structures with all the information
 /// we need, keyed by pid.  Pids uniquely tag the records.
 ///
@@ -141,11 +718,21 @@ fn i32_field(l: &str) -> Result<i32, String> {
 ///
 /// The underlying computing system -- /proc, system tables, and clock -- is virtualized through the
 /// ProcfsAPI instance.
-
+///
+/// If `scan_threads` is greater than 1 then the per-process files are read by that many threads,
+/// which speeds up the scan on nodes with very many processes.  The result is the same as for a
+/// serial scan.
+///
+/// The `profile` selects the per-process files that are read, see Profile.
+///
+/// The third element of the result is a message about user lookups that timed out, if any; the
+/// processes of those users are still returned but are named `_user_{uid}`, see UserTable.
 pub fn get_process_information(
     fs: &dyn procfsapi::ProcfsAPI,
     memtotal_kib: usize,
-) -> Result<(HashMap<usize, Process>, u64, Vec<u64>), String> {
+    scan_threads: usize,
+    profile: Profile,
+) -> Result<(ProcessTable, SystemStat, Option<String>), String> {
     // We need this for a lot of things.  On x86 and x64 this is always 100 but in principle it
     // might be something else, so read the true value.
 
@@ -163,10 +750,11 @@ pub fn get_process_information(
     // The boot time is first field of the `btime` line of /proc/stat.  It is measured in seconds
     // since epoch.  We need this to compute the process's real time, which we need to compute
     // ps-compatible cpu utilization.
+    //
+    // The `procs_running` and `procs_blocked` lines hold the number of runnable threads and the
+    // number of threads blocked on IO right now.  These complement the cpu times.
 
-    let mut boot_time = 0;
-    let mut cpu_total_secs = 0;
-    let mut per_cpu_secs = vec![];
+    let mut system_stat = SystemStat::default();
     let stat_s = fs.read_to_string("stat")?;
     for l in stat_s.split('\n') {
         if l.starts_with("cpu") {
@@ -182,25 +770,34 @@ pub fn get_process_information(
                 sum += parse_usize_field(&fields, i, l, "stat", 0, "cpu")? as u64;
             }
             if l.starts_with("cpu ") {
-                cpu_total_secs = sum / ticks_per_sec;
+                system_stat.cpu_total_secs = sum / ticks_per_sec;
             } else {
                 let cpu_no = match fields[0][3..].parse::<usize>() {
                     Ok(x) => x,
                     Err(_) => continue, // Too harsh to error out
                 };
-                if per_cpu_secs.len() < cpu_no + 1 {
-                    per_cpu_secs.resize(cpu_no + 1, 0u64);
+                if system_stat.per_cpu_secs.len() < cpu_no + 1 {
+                    system_stat.per_cpu_secs.resize(cpu_no + 1, 0u64);
                 }
-                per_cpu_secs[cpu_no] = sum / ticks_per_sec;
+                system_stat.per_cpu_secs[cpu_no] = sum / ticks_per_sec;
             }
-        } else if l.starts_with("btime ") {
+        } else if l.starts_with("procs_running ") {
+            let fields = l.split_ascii_whitespace().collect::<Vec<&str>>();
+            system_stat.procs_running =
+                parse_usize_field(&fields, 1, l, "stat", 0, "procs_running")? as u64;
+        } else if l.starts_with("procs_blocked ") {
             let fields = l.split_ascii_whitespace().collect::<Vec<&str>>();
-            boot_time = parse_usize_field(&fields, 1, l, "stat", 0, "btime")? as u64;
+            system_stat.procs_blocked =
+                parse_usize_field(&fields, 1, l, "stat", 0, "procs_blocked")? as u64;
         }
     }
-    if boot_time == 0 {
-        return Err(format!("Could not find btime in /proc/stat: {stat_s}"));
-    }
+    let boot_time = parse_boot_time(&stat_s)?;
+
+    // Offline cpus have no line in /proc/stat, but their slots in per_cpu_secs are zero, and would
+    // be indistinguishable from idle cpus if they were not listed.  A gap in the cpu numbers in
+    // /proc/stat is not evidence of an offline cpu, as the numbering can be sparse, so ask sysfs.
+    system_stat.offline_cpus = get_offline_cpus(fs);
+    system_stat.boot_time = boot_time;
 
     // Enumerate all pids, and collect the uids while we're here.
     //
@@ -215,13 +812,93 @@ pub fn get_process_information(
 
     // Collect remaining system data from /proc/{pid}/stat for the enumerated pids.
 
-    let kib_per_page = fs.page_size_in_kib();
+    let scan = ProcessScan {
+        boot_time,
+        memtotal_kib,
+        clock_ticks_per_sec: ticks_per_sec as f64,
+        kib_per_page: fs.page_size_in_kib(),
+        profile,
+        max_cpu_pct: if system_stat.per_cpu_secs.is_empty() {
+            f64::MAX
+        } else {
+            system_stat.per_cpu_secs.len() as f64 * 100.0
+        },
+    };
+
+    // The files are read in parallel if requested, but the results are merged serially and in the
+    // order of the pids, so that the first error is the same as for a serial scan.  The user names
+    // are looked up during the merge, as the system's user lookup functions are not thread-safe.
+
+    let mut processes = vec![];
+    if scan_threads > 1 && pids.len() > 1 {
+        let chunk_size = pids.len().div_ceil(scan_threads);
+        std::thread::scope(|scope| {
+            let workers = pids
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    let scan = &scan;
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|&(pid, uid)| scan.read_process(fs, pid, uid))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            for worker in workers {
+                processes.extend(worker.join().expect("Process scan thread panicked"));
+            }
+        });
+    } else {
+        processes = pids
+            .iter()
+            .map(|&(pid, uid)| scan.read_process(fs, pid, uid))
+            .collect::<Vec<_>>();
+    }
+
     let mut result = HashMap::<usize, Process>::new();
     let mut ppids = HashSet::<usize>::new();
     let mut user_table = UserTable::new();
-    let clock_ticks_per_sec = ticks_per_sec as f64;
+    for p in processes {
+        if let Some(mut p) = p? {
+            p.user = user_table.lookup(fs, p.uid as u32);
+            ppids.insert(p.ppid);
+            result.insert(p.pid, p);
+        }
+    }
+
+    // Mark the processes that have children.
+    for (_, p) in result.iter_mut() {
+        p.has_children = ppids.contains(&p.pid);
+    }
+
+    Ok((result, system_stat, user_table.lookup_error()))
+}
+
+// The per-scan constants needed to read the information about a single process.
 
-    for (pid, uid) in pids {
+struct ProcessScan {
+    boot_time: u64,
+    memtotal_kib: usize,
+    clock_ticks_per_sec: f64,
+    kib_per_page: usize,
+    profile: Profile,
+    // The cpu percentage of a process can't be more than 100 times the number of cpus, or if that
+    // is not known, the largest f64.
+    max_cpu_pct: f64,
+}
+
+impl ProcessScan {
+    // Read the information about one process from /proc/{pid}/{stat,statm,status}.  Returns
+    // Ok(None) if the process should be skipped, usually because it has gone away since the pids
+    // were enumerated.  The user name is left empty.
+
+    fn read_process(
+        &self,
+        fs: &dyn procfsapi::ProcfsAPI,
+        pid: usize,
+        uid: u32,
+    ) -> Result<Option<Process>, String> {
         // Basic system variables.  Intermediate time values are represented in ticks to prevent
         // various roundoff artifacts resulting in NaN or Infinity.
 
@@ -229,9 +906,17 @@ pub fn get_process_information(
         let mut realtime_ticks;
         let ppid;
         let pgrp;
-        let mut comm;
+        let comm;
+        let zombie;
+        let uninterruptible;
+        let minor_faults;
+        let major_faults;
+        let num_threads;
+        let is_kernel_thread;
         let utime_ticks;
         let stime_ticks;
+        let cutime_ticks;
+        let cstime_ticks;
         if let Ok(line) = fs.read_to_string(&format!("{pid}/stat")) {
             // The comm field is a little tricky, it must be extracted first as the contents between
             // the first '(' and the last ')' in the line.
@@ -279,21 +964,31 @@ pub fn get_process_information(
             // information about their presence is used in consumers.
 
             let dead = fields[0] == "X";
-            let zombie = fields[0] == "Z";
+            zombie = fields[0] == "Z";
+            uninterruptible = fields[0] == "D";
 
             if dead {
                 // Just drop dead jobs
-                continue;
-            }
-
-            if zombie {
-                // This tag is used by consumers but it's an artifact of `ps`, not the kernel
-                comm += " <defunct>";
+                return Ok(None);
             }
 
             ppid = parse_usize_field(&fields, 1, &line, "stat", pid, "ppid")?;
             pgrp = parse_usize_field(&fields, 2, &line, "stat", pid, "pgrp")?;
 
+            // Kernel threads have the PF_KTHREAD flag set.  They are also all descendants of
+            // kthreadd, which is pid 2; check that too in case the flag value changes.
+            const PF_KTHREAD: usize = 0x00200000;
+            let flags = parse_usize_field(&fields, 6, &line, "stat", pid, "flags")?;
+            is_kernel_thread = (flags & PF_KTHREAD) != 0 || pid == 2 || ppid == 2;
+
+            // Page faults are cumulative for the process itself.  The counts for waited-for
+            // children (cminflt, cmajflt) are not included, unlike for the cpu time below: faults
+            // are indicative of memory pressure on the process and not a resource that is consumed.
+            minor_faults = parse_usize_field(&fields, 7, &line, "stat", pid, "minflt")?;
+            major_faults = parse_usize_field(&fields, 9, &line, "stat", pid, "majflt")?;
+
+            num_threads = parse_usize_field(&fields, 17, &line, "stat", pid, "num_threads")?;
+
             // Generally we want to record cumulative self+child time.  The child time we read will
             // be for children that have terminated and have been wait()ed for.  The logic is that
             // in a tree of processes in a job, Sonar will observe the parent and the children
@@ -315,32 +1010,43 @@ pub fn get_process_information(
             // tree and correct the data, if necessary.
             utime_ticks = parse_usize_field(&fields, 11, &line, "stat", pid, "utime")? as f64;
             stime_ticks = parse_usize_field(&fields, 12, &line, "stat", pid, "stime")? as f64;
-            let cutime_ticks = parse_usize_field(&fields, 13, &line, "stat", pid, "cutime")? as f64;
-            let cstime_ticks = parse_usize_field(&fields, 14, &line, "stat", pid, "cstime")? as f64;
+            cutime_ticks = parse_usize_field(&fields, 13, &line, "stat", pid, "cutime")? as f64;
+            cstime_ticks = parse_usize_field(&fields, 14, &line, "stat", pid, "cstime")? as f64;
             bsdtime_ticks = utime_ticks + stime_ticks + cutime_ticks + cstime_ticks;
             let start_time_ticks =
                 parse_usize_field(&fields, 19, &line, "stat", pid, "starttime")? as f64;
 
-            // boot_time and the current time are both time_t, ie, a 31-bit quantity in 2023 and a
-            // 32-bit quantity before 2038.  clock_ticks_per_sec is on the order of 100.  Ergo
-            // boot_ticks and now_ticks can be represented in about 32+7=39 bits, fine for an f64.
-            let now_ticks = fs.now_in_secs_since_epoch() as f64 * clock_ticks_per_sec;
-            let boot_ticks = boot_time as f64 * clock_ticks_per_sec;
+            // self.boot_time and the current time are both time_t, ie, a 31-bit quantity in 2023
+            // and a 32-bit quantity before 2038.  self.clock_ticks_per_sec is on the order of 100.
+            // Ergo boot_ticks and now_ticks can be represented in about 32+7=39 bits, fine for an
+            // f64.
+            let now_ticks = fs.now_in_secs_since_epoch() as f64 * self.clock_ticks_per_sec;
+            let boot_ticks = self.boot_time as f64 * self.clock_ticks_per_sec;
 
             // start_time_ticks should be on the order of a few years, there is no risk of overflow
             // here, and in any case boot_ticks + start_time_ticks <= now_ticks, and by the above
             // reasoning now_ticks fits in an f64, ergo the sum does too.
             //
-            // Take the max with 1 here to ensure realtime_ticks is not zero.
+            // More precisely, all the tick values are integers converted from at most 64 bits, and
+            // an f64 represents integers exactly up to 2^53.  At 100 ticks per second that is about
+            // 2.8 million years of uptime or cpu time, and at 10000 ticks per second 28 thousand
+            // years, so the values are exact for any real process.  Beyond that they are rounded,
+            // but every value is finite (the largest u64 is about 1.8e19) and so are sums and
+            // differences of them.
+            //
+            // The clocks can disagree, eg if the system time was set back after the process was
+            // started, and then the difference can be zero or negative.  Take the max with 1 here
+            // to ensure realtime_ticks is at least 1; a NaN, which can't arise from the above,
+            // would be replaced too.
             realtime_ticks = now_ticks - (boot_ticks + start_time_ticks);
-            if realtime_ticks < 1.0 {
+            if realtime_ticks.is_nan() || realtime_ticks < 1.0 {
                 realtime_ticks = 1.0;
             }
         } else {
             // This is *usually* benign - the process may have gone away since we enumerated the
             // /proc directory.  It is *possibly* indicative of a permission problem, but that
             // problem would be so pervasive that diagnosing it here is not right.
-            continue;
+            return Ok(None);
         }
 
         // We want the value corresponding to the "size" field printed by ps.  This is a saga.  When
@@ -352,16 +1058,22 @@ pub fn get_process_information(
         // "data" field which is documented as "data + stack", this is the sixth space-separated
         // field.
 
-        let size_kib;
-        let rss_kib;
-        if let Ok(s) = fs.read_to_string(&format!("{pid}/statm")) {
+        //
+        // With the cpu profile the memory files are not read and the sizes are zero.
+
+        let mut size_kib = 0;
+        let mut rss_kib = 0;
+        if self.profile == Profile::Cpu {
+            // Nothing
+        } else if let Ok(s) = fs.read_to_string(&format!("{pid}/statm")) {
             let fields = s.split_ascii_whitespace().collect::<Vec<&str>>();
             rss_kib = parse_usize_field(&fields, 1, &s, "statm", pid, "resident set size")?
-                * kib_per_page;
-            size_kib = parse_usize_field(&fields, 5, &s, "statm", pid, "data size")? * kib_per_page;
+                * self.kib_per_page;
+            size_kib =
+                parse_usize_field(&fields, 5, &s, "statm", pid, "data size")? * self.kib_per_page;
         } else {
             // This is *usually* benign - see above.
-            continue;
+            return Ok(None);
         }
 
         // The best value for resident memory is probably the Pss (proportional set size) field of
@@ -380,9 +1092,19 @@ pub fn get_process_information(
         // In order to not confuse the matter we're going to name the fields in our internal data
         // structures and in the output by the fields that they are taken from, so "rssanon", not
         // "resident" or "rss" or similar.
+        //
+        // The status file also has the cumulative context switch counts.  Nonvoluntary switches
+        // indicate contention for the cpus.  Like RssAnon they may be absent, and are then zero.
         let mut rssanon_kib = 0;
-        let mut was_found = false;
-        if let Ok(status_info) = fs.read_to_string(&format!("{pid}/status")) {
+        let mut voluntary_ctxt_switches = 0;
+        let mut nonvoluntary_ctxt_switches = 0;
+        //
+        // With the cpu profile the status file is not read either, and a process that has gone away
+        // since its stat file was read is reported with the data from that file.
+        let mut was_found = self.profile == Profile::Cpu;
+        if self.profile == Profile::Cpu {
+            // Nothing
+        } else if let Ok(status_info) = fs.read_to_string(&format!("{pid}/status")) {
             was_found = true;
             for l in status_info.split('\n') {
                 if l.starts_with("RssAnon:") {
@@ -399,7 +1121,26 @@ pub fn get_process_information(
                         pid,
                         "private resident set size",
                     )?;
-                    break;
+                } else if l.starts_with("voluntary_ctxt_switches:") {
+                    let fields = l.split_ascii_whitespace().collect::<Vec<&str>>();
+                    voluntary_ctxt_switches = parse_usize_field(
+                        &fields,
+                        1,
+                        l,
+                        "status",
+                        pid,
+                        "voluntary context switches",
+                    )?;
+                } else if l.starts_with("nonvoluntary_ctxt_switches:") {
+                    let fields = l.split_ascii_whitespace().collect::<Vec<&str>>();
+                    nonvoluntary_ctxt_switches = parse_usize_field(
+                        &fields,
+                        1,
+                        l,
+                        "status",
+                        pid,
+                        "nonvoluntary context switches",
+                    )?;
                 }
             }
         }
@@ -409,7 +1150,7 @@ pub fn get_process_information(
             // filtering too much too early, we'll just keep going here with a zero value if the
             // file was found but was missing that field.
             if !was_found {
-                continue;
+                return Ok(None);
             }
         }
 
@@ -420,71 +1161,106 @@ pub fn get_process_information(
         // are one-tenth of a percent off from those of ps.  One can argue about whether round(),
         // floor() or ceil() is the most correct, but it's unlikely to matter much.
 
-        // realtime_ticks is nonzero, so this division will not produce NaN or Infinity
+        // realtime_ticks is at least 1 and the cpu ticks are finite, so this division will not
+        // produce NaN or Infinity.  The value can exceed 100% for a multithreaded process, which is
+        // intended, but if the clocks disagree it can also be absurdly large; clamp it to what the
+        // cpus can deliver.
         let pcpu_value = (utime_ticks + stime_ticks) / realtime_ticks;
-        let pcpu_formatted = (pcpu_value * 1000.0).round() / 10.0;
-
-        // clock_ticks_per_sec is nonzero, so this division will not produce NaN or Infinity.  See
-        // block comment earlier about why bsdtime_ticks is the best base value here.
-        let cputime_sec = (bsdtime_ticks / clock_ticks_per_sec).round() as usize;
+        let pcpu_formatted = f64::min((pcpu_value * 1000.0).round() / 10.0, self.max_cpu_pct);
+
+        // self.clock_ticks_per_sec is nonzero, so this division will not produce NaN or Infinity.
+        // See block comment earlier about why bsdtime_ticks is the best base value here.
+        let cputime_sec = (bsdtime_ticks / self.clock_ticks_per_sec).round() as usize;
+        let cpu_times = CpuTimes {
+            user_sec: utime_ticks / self.clock_ticks_per_sec,
+            system_sec: stime_ticks / self.clock_ticks_per_sec,
+            child_user_sec: cutime_ticks / self.clock_ticks_per_sec,
+            child_system_sec: cstime_ticks / self.clock_ticks_per_sec,
+        };
 
         // Note ps uses rss not size here.  Also, ps doesn't trust rss to be <= 100% of memory, so
-        // let's not trust it either.  memtotal_kib is nonzero, so this division will not produce
-        // NaN or Infinity.
+        // let's not trust it either.  self.memtotal_kib is nonzero, so this division will not
+        // produce NaN or Infinity.
         let pmem = f64::min(
-            ((rss_kib as f64) * 1000.0 / (memtotal_kib as f64)).round() / 10.0,
+            ((rss_kib as f64) * 1000.0 / (self.memtotal_kib as f64)).round() / 10.0,
             99.9,
         );
 
-        result.insert(
+        Ok(Some(Process {
             pid,
-            Process {
-                pid,
-                ppid,
-                pgrp,
-                uid: uid as usize,
-                user: user_table.lookup(fs, uid),
-                cpu_pct: pcpu_formatted,
-                mem_pct: pmem,
-                cputime_sec,
-                mem_size_kib: size_kib,
-                rssanon_kib,
-                command: comm,
-                has_children: false,
-            },
-        );
-        ppids.insert(ppid);
+            ppid,
+            pgrp,
+            uid: uid as usize,
+            user: "".to_string(), // Filled in by the caller
+            cpu_pct: pcpu_formatted,
+            mem_pct: pmem,
+            cputime_sec,
+            mem_size_kib: size_kib,
+            rssanon_kib,
+            major_faults,
+            minor_faults,
+            num_threads,
+            voluntary_ctxt_switches,
+            nonvoluntary_ctxt_switches,
+            cpu_times,
+            command: comm,
+            has_children: false,
+            is_zombie: zombie,
+            is_uninterruptible: uninterruptible,
+            is_kernel_thread,
+        }))
     }
-
-    // Mark the processes that have children.
-    for (_, p) in result.iter_mut() {
-        p.has_children = ppids.contains(&p.pid);
-    }
-
-    Ok((result, cpu_total_secs, per_cpu_secs))
 }
 
 // The UserTable optimizes uid -> name lookup.
+//
+// If a lookup times out then that user is named _user_{uid} and is not looked up again during the
+// scan, but other users are still looked up.  The first timeout and the number of timeouts are
+// remembered so that they can be reported.
 
 struct UserTable {
     ht: HashMap<u32, String>,
+    first_timeout: Option<String>,
+    timeouts: usize,
 }
 
 impl UserTable {
     fn new() -> UserTable {
-        UserTable { ht: HashMap::new() }
+        UserTable {
+            ht: HashMap::new(),
+            first_timeout: None,
+            timeouts: 0,
+        }
     }
 
     fn lookup(&mut self, fs: &dyn procfsapi::ProcfsAPI, uid: u32) -> String {
         if let Some(name) = self.ht.get(&uid) {
-            name.clone()
-        } else if let Some(name) = fs.user_by_uid(uid) {
-            self.ht.insert(uid, name.clone());
-            name
-        } else {
-            format!("_noinfo_{uid}")
+            return name.clone();
+        }
+        match fs.user_by_uid(uid) {
+            Ok(Some(name)) => {
+                self.ht.insert(uid, name.clone());
+                name
+            }
+            Ok(None) => format!("_noinfo_{uid}"),
+            Err(msg) => {
+                let name = format!("_user_{uid}");
+                self.ht.insert(uid, name.clone());
+                self.first_timeout.get_or_insert(msg);
+                self.timeouts += 1;
+                name
+            }
         }
     }
+
+    fn lookup_error(&self) -> Option<String> {
+        self.first_timeout.as_ref().map(|msg| {
+            format!(
+                "{msg}, the user database may be slow; {} user(s) were not looked up",
+                self.timeouts
+            )
+        })
+    }
 }
 
 // For the parse test we use the full text of stat and meminfo, but for stat we only want the
@@ -583,7 +1359,10 @@ DirectMap1G:    11534336 kB
         "4018/statm".to_string(),
         "1255967 185959 54972 200 0 316078 0".to_string(),
     );
-    files.insert("4018/status".to_string(), "RssAnon: 12345 kB".to_string());
+    files.insert(
+        "4018/status".to_string(),
+        std::include_str!("testdata/proc-status.txt").to_string(),
+    );
 
     let ticks_per_sec = 100.0; // We define this
     let utime_ticks = 51361.0; // field(/proc/4018/stat, 14)
@@ -593,7 +1372,10 @@ DirectMap1G:    11534336 kB
     let rss: f64 = 185959.0 * 4.0; // pages_to_kib(field(/proc/4018/statm, 1))
     let memtotal = 16093776.0; // field(/proc/meminfo, "MemTotal:")
     let size = 316078 * 4; // pages_to_kib(field(/proc/4018/statm, 5))
-    let rssanon = 12345; // field(/proc/4018/status, "RssAnon:")
+    let rssanon = 512340; // field(/proc/4018/status, "RssAnon:")
+    let minflt = 19293188; // field(/proc/4018/stat, 10)
+    let majflt = 1823; // field(/proc/4018/stat, 12)
+    let num_threads = 187; // field(/proc/4018/stat, 20)
 
     // now = boot_time + start_time + utime_ticks + stime_ticks + arbitrary idle time
     let now = (boot_time
@@ -604,8 +1386,8 @@ DirectMap1G:    11534336 kB
 
     let fs = procfsapi::MockFS::new(files, pids, users, now);
     let memtotal_kib = get_memtotal_kib(&fs).expect("Test: Must have data");
-    let (mut info, total_secs, per_cpu_secs) =
-        get_process_information(&fs, memtotal_kib).expect("Test: Must have data");
+    let (mut info, system_stat, _) =
+        get_process_information(&fs, memtotal_kib, 1, Profile::Full).expect("Test: Must have data");
     assert!(info.len() == 1);
     let mut xs = info.drain();
     let p = xs.next().expect("Test: Should have data").1;
@@ -615,6 +1397,7 @@ DirectMap1G:    11534336 kB
     assert!(p.command == "firefox"); // field(/proc/4018/stat, 2)
     assert!(p.ppid == 2190); // field(/proc/4018/stat, 4)
     assert!(p.pgrp == 2189); // field(/proc/4018/stat, 5)
+    assert!(!p.is_kernel_thread); // field(/proc/4018/stat, 9)
 
     let now_time = now as f64;
     let now_ticks = now_time * ticks_per_sec;
@@ -629,11 +1412,25 @@ DirectMap1G:    11534336 kB
 
     assert!(p.mem_size_kib == size);
     assert!(p.rssanon_kib == rssanon);
-
+    assert!(p.minor_faults == minflt);
+    assert!(p.major_faults == majflt);
+    assert!(p.num_threads == num_threads);
+    assert!(p.voluntary_ctxt_switches == 120775); // field(/proc/4018/status, "voluntary_ctxt_switches:")
+    assert!(p.nonvoluntary_ctxt_switches == 8934); // field(/proc/4018/status, "nonvoluntary_ctxt_switches:")
+    assert!(p.cpu_times.user_sec == utime_ticks / ticks_per_sec);
+    assert!(p.cpu_times.system_sec == stime_ticks / ticks_per_sec);
+    assert!(p.cpu_times.child_user_sec == 5390.0 / ticks_per_sec); // field(/proc/4018/stat, 16)
+    assert!(p.cpu_times.child_system_sec == 2925.0 / ticks_per_sec); // field(/proc/4018/stat, 17)
+
+    let total_secs = system_stat.cpu_total_secs;
+    let per_cpu_secs = system_stat.per_cpu_secs;
     assert!(total_secs == (241155 + 582 + 127006 + 0 + 3816) / 100); // "cpu " line of "stat" data
     assert!(per_cpu_secs.len() == 8);
     assert!(per_cpu_secs[0] == (32528 + 189 + 19573 + 0 + 1149) / 100); // "cpu0 " line of "stat" data
     assert!(per_cpu_secs[7] == (27582 + 61 + 12558 + 0 + 426) / 100); // "cpu7 " line of "stat" data
+    assert!(system_stat.offline_cpus.is_empty());
+    assert!(system_stat.procs_running == 1); // "procs_running" line of "stat" data
+    assert!(system_stat.procs_blocked == 0); // "procs_blocked" line of "stat" data
 }
 
 #[test]
@@ -678,7 +1475,7 @@ pub fn procfs_dead_and_undead_test() {
     let fs = procfsapi::MockFS::new(files, pids, users, procfsapi::unix_now());
     let memtotal_kib = get_memtotal_kib(&fs).expect("Test: Must have data");
     let (mut info, _, _) =
-        get_process_information(&fs, memtotal_kib).expect("Test: Must have data");
+        get_process_information(&fs, memtotal_kib, 1, Profile::Full).expect("Test: Must have data");
 
     // 4020 should be dropped - it's dead
     assert!(info.len() == 2);
@@ -691,8 +1488,168 @@ pub fn procfs_dead_and_undead_test() {
     }
     assert!(p.pid == 4018);
     assert!(p.command == "firefox");
+    assert!(!p.is_zombie);
+    // No context switch counts in the status file
+    assert!(p.voluntary_ctxt_switches == 0);
+    assert!(p.nonvoluntary_ctxt_switches == 0);
     assert!(q.pid == 4019);
-    assert!(q.command == "firefox <defunct>");
+    assert!(q.command == "firefox");
+    assert!(q.is_zombie);
+}
+
+// Offline cpus have no line in /proc/stat and must be reported as such, not as idle.  Which cpus
+// are offline is known from sysfs, not from the gaps in /proc/stat: here cpu 4 is not present at
+// all, and cpu 7 is offline but above the highest online cpu.
+
+#[test]
+pub fn procfs_offline_cpus_test() {
+    let mut files = HashMap::new();
+    files.insert(
+        "stat".to_string(),
+        "cpu  3000 0 1000 90000 0 0 0 0 0 0
+cpu0 1000 0 500 30000 0 0 0 0 0 0
+cpu2 1000 0 300 30000 0 0 0 0 0 0
+cpu5 1000 0 200 30000 0 0 0 0 0 0
+btime 1698303295
+"
+        .to_string(),
+    );
+    let mut sys_files = HashMap::new();
+    sys_files.insert(
+        "devices/system/cpu/present".to_string(),
+        "0-3,5-7\n".to_string(),
+    );
+    sys_files.insert(
+        "devices/system/cpu/online".to_string(),
+        "0,2,5\n".to_string(),
+    );
+    let fs = procfsapi::MockFS::new(files.clone(), vec![], HashMap::new(), 1698303295 + 1000)
+        .with_sys_files(sys_files);
+    let (_, system_stat, _) =
+        get_process_information(&fs, 16093776, 1, Profile::Full).expect("Test: Must have data");
+    assert!(system_stat.per_cpu_secs == vec![15, 0, 13, 0, 0, 12]);
+    assert!(system_stat.offline_cpus == vec![1, 3, 6, 7]);
+
+    // Without sysfs nothing is known to be offline
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 1698303295 + 1000);
+    let (_, system_stat, _) =
+        get_process_information(&fs, 16093776, 1, Profile::Full).expect("Test: Must have data");
+    assert!(system_stat.offline_cpus.is_empty());
+}
+
+// A parallel scan must give the same result as a serial scan, also when processes vanish and when
+// the data are bad.
+
+#[test]
+pub fn procfs_parallel_scan_test() {
+    let mut pids = vec![];
+    let mut users = HashMap::new();
+    let mut files = HashMap::new();
+    files.insert("stat".to_string(), "btime 1698303295".to_string());
+    for pid in 1000..3000 {
+        let uid = 1000 + (pid % 7) as u32;
+        users.insert(uid, format!("user{uid}"));
+        pids.push((pid, uid));
+        // Every 13th process has vanished, every 17th is a zombie, every 19th is dead
+        if pid % 13 == 0 {
+            continue;
+        }
+        let state = if pid % 17 == 0 {
+            "Z"
+        } else if pid % 19 == 0 {
+            "X"
+        } else {
+            "S"
+        };
+        let ppid = if pid % 5 == 0 { 1 } else { pid - 1 };
+        files.insert(
+            format!("{pid}/stat"),
+            format!("{pid} (cmd {pid}) {state} {ppid} {pid} {pid} 0 -1 4194560 {pid} 0 3 0 {pid} 15 7 2 20 0 1 0 16400 5144358912 184775"),
+        );
+        files.insert(
+            format!("{pid}/statm"),
+            format!("1255967 {pid} 54972 200 0 316078 0"),
+        );
+        files.insert(format!("{pid}/status"), format!("RssAnon: {pid} kB"));
+    }
+    let memtotal_kib = 16093776;
+    let now = 1698303295 + 1000;
+
+    let fs = procfsapi::MockFS::new(files.clone(), pids.clone(), users.clone(), now);
+    let (serial, _, _) =
+        get_process_information(&fs, memtotal_kib, 1, Profile::Full).expect("Test: serial");
+    let live = (1000..3000).filter(|p| p % 13 != 0 && (p % 19 != 0 || p % 17 == 0));
+    assert!(serial.len() == live.count());
+    assert!(serial[&1002].user == "user1001");
+    assert!(serial[&1002].has_children);
+    assert!(!serial[&2999].has_children);
+    for threads in [2, 3, 8, 5000] {
+        let (parallel, _, _) = get_process_information(&fs, memtotal_kib, threads, Profile::Full)
+            .expect("Test: parallel");
+        assert!(parallel == serial);
+    }
+
+    // The first error in pid order is reported whether the scan is serial or parallel.
+    files.insert("1500/stat".to_string(), "1500 (bad) S".to_string());
+    files.insert("2500/stat".to_string(), "2500 (bad) S".to_string());
+    let fs = procfsapi::MockFS::new(files, pids, users, now);
+    let serial_err = get_process_information(&fs, memtotal_kib, 1, Profile::Full).unwrap_err();
+    assert!(serial_err.contains("/proc/1500/stat"));
+    let parallel_err = get_process_information(&fs, memtotal_kib, 4, Profile::Full).unwrap_err();
+    assert!(parallel_err == serial_err);
+}
+
+// A user lookup that takes too long falls back to a placeholder name and is not retried, the
+// database is still consulted for the other users, and the timeout is reported once.
+
+#[test]
+pub fn procfs_user_timeout_test() {
+    use std::time::Duration;
+
+    let mut files = HashMap::new();
+    files.insert("stat".to_string(), "btime 1698303295".to_string());
+    let mut pids = vec![];
+    for (pid, uid) in [(100, 1000), (101, 1001), (102, 1000)] {
+        pids.push((pid, uid));
+        files.insert(
+            format!("{pid}/stat"),
+            format!("{pid} (cmd) S 1 {pid} {pid} 0 -1 4194560 0 0 0 0 15 7 2 2 20 0 1 0 16400 5144358912 184775"),
+        );
+        files.insert(
+            format!("{pid}/statm"),
+            "1255967 100 54972 200 0 316078 0".to_string(),
+        );
+        files.insert(format!("{pid}/status"), "RssAnon: 100 kB".to_string());
+    }
+    let users = HashMap::from([(1000, "zappa".to_string()), (1001, "beefheart".to_string())]);
+    let now = 1698303295 + 1000;
+
+    let fs = procfsapi::MockFS::new(files.clone(), pids.clone(), users.clone(), now)
+        .with_user_delay(
+            &[1000],
+            Duration::from_millis(500),
+            Duration::from_millis(20),
+        );
+    let (info, _, lookup_error) =
+        get_process_information(&fs, 16093776, 1, Profile::Full).expect("Test: data");
+    assert!(info[&100].user == "_user_1000");
+    assert!(info[&101].user == "beefheart");
+    assert!(info[&102].user == "_user_1000");
+    let msg = lookup_error.expect("Test: error");
+    assert!(msg.starts_with("Lookup of user 1000 timed out after 20ms"));
+    assert!(msg.ends_with("1 user(s) were not looked up"));
+
+    // Fast enough
+    let fs = procfsapi::MockFS::new(files, pids, users, now).with_user_delay(
+        &[1000, 1001],
+        Duration::from_millis(1),
+        Duration::from_secs(10),
+    );
+    let (info, _, lookup_error) =
+        get_process_information(&fs, 16093776, 1, Profile::Full).expect("Test: data");
+    assert!(info[&100].user == "zappa");
+    assert!(info[&101].user == "beefheart");
+    assert!(lookup_error.is_none());
 }
 
 #[test]
@@ -1156,4 +2113,445 @@ power management:
     assert!(sockets == 2);
     assert!(cores == 4);
     assert!(threads == 2);
+    // Broadwell has AVX2 but not AVX-512.  The `vmx flags` line is not the flags.
+    assert!(get_cpu_flags(&fs) == ["avx", "avx2"]);
+}
+
+#[test]
+pub fn procfs_cpu_flags_test() {
+    let cpuinfo = "processor\t: 0\nflags\t\t: fpu sse2 avx512f avx2 avx512bw avx avx512f amx_tile avx_vnni fma\n\nprocessor\t: 1\nflags\t\t: fpu avx\n";
+    let mut files = HashMap::new();
+    files.insert("cpuinfo".to_string(), cpuinfo.to_string());
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0);
+    assert!(get_cpu_flags(&fs) == ["amx_tile", "avx", "avx2", "avx512bw", "avx512f", "avx_vnni"]);
+
+    let cpuinfo = "processor\t: 0\nBogoMIPS\t: 2000.00\nFeatures\t: fp asimd sve svebf16 sve2 sha512\nCPU architecture: 8\n";
+    let mut files = HashMap::new();
+    files.insert("cpuinfo".to_string(), cpuinfo.to_string());
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0);
+    assert!(get_cpu_flags(&fs) == ["sve", "sve2", "svebf16"]);
+
+    let fs = procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), 0);
+    assert!(get_cpu_flags(&fs).is_empty());
+}
+
+#[test]
+pub fn procfs_energy_test() {
+    let mut sys_files = HashMap::new();
+    for (domain, name, uj) in [
+        ("intel-rapl:0", "package-0", "64425543402"),
+        ("intel-rapl:0:0", "dram", "4213560815"),
+    ] {
+        sys_files.insert(format!("class/powercap/{domain}/name"), format!("{name}\n"));
+        sys_files.insert(
+            format!("class/powercap/{domain}/energy_uj"),
+            format!("{uj}\n"),
+        );
+        sys_files.insert(
+            format!("class/powercap/{domain}/max_energy_range_uj"),
+            "262143328850\n".to_string(),
+        );
+    }
+    // Not RAPL, should be ignored
+    sys_files.insert("class/powercap/dtpm/name".to_string(), "dtpm\n".to_string());
+    let fs =
+        procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), 0).with_sys_files(sys_files);
+    let energy = get_energy_counters(&fs);
+    assert!(energy.len() == 2);
+    assert!(energy[0] == ("package-0".to_string(), 64425543402));
+    assert!(energy[1] == ("package-0/dram".to_string(), 4213560815));
+
+    // No powercap is not an error
+    let fs = procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), 0);
+    assert!(get_energy_counters(&fs).is_empty());
+}
+
+#[test]
+pub fn procfs_infiniband_test() {
+    let mut sys_files = HashMap::new();
+    for (port, state, xmit, rcv) in [
+        ("1", "4: ACTIVE\n", "1208925819614629", "1152921504606846"),
+        ("2", "1: DOWN\n", "0", "0"),
+    ] {
+        let dir = format!("class/infiniband/mlx5_0/ports/{port}");
+        sys_files.insert(format!("{dir}/state"), state.to_string());
+        sys_files.insert(
+            format!("{dir}/counters/port_xmit_data"),
+            format!("{xmit}\n"),
+        );
+        sys_files.insert(format!("{dir}/counters/port_rcv_data"), format!("{rcv}\n"));
+    }
+    let fs =
+        procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), 0).with_sys_files(sys_files);
+    let ports = get_infiniband_ports(&fs);
+    assert!(ports.len() == 2);
+    assert!(ports[0].device == "mlx5_0" && ports[0].port == "1");
+    assert!(ports[0].state == "ACTIVE");
+    assert!(ports[0].xmit_data == 1208925819614629);
+    assert!(ports[0].rcv_data == 1152921504606846);
+    assert!(ports[1].port == "2" && ports[1].state == "DOWN" && ports[1].xmit_data == 0);
+
+    // No hardware
+    let fs = procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), 0);
+    assert!(get_infiniband_ports(&fs).is_empty());
+}
+
+#[test]
+pub fn procfs_cpu_state_test() {
+    let cpu_files = |online: &str, control: &str, active: &str| {
+        let mut sys_files = HashMap::new();
+        for (name, contents) in [
+            ("present", "0-15\n"),
+            ("online", online),
+            ("smt/control", control),
+            ("smt/active", active),
+        ] {
+            sys_files.insert(format!("devices/system/cpu/{name}"), contents.to_string());
+        }
+        procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), 0).with_sys_files(sys_files)
+    };
+
+    // SMT on, all siblings online
+    let state = get_cpu_state(&cpu_files("0-15\n", "on\n", "1\n"));
+    assert!(state.present_cpus == Some(16));
+    assert!(state.online_cpus == Some(16));
+    assert!(state.smt_control.as_deref() == Some("on"));
+    assert!(state.smt_active == Some(true));
+
+    // SMT switched off at runtime, the second thread of each core is offline
+    let state = get_cpu_state(&cpu_files("0-7\n", "off\n", "0\n"));
+    assert!(state.present_cpus == Some(16));
+    assert!(state.online_cpus == Some(8));
+    assert!(state.smt_control.as_deref() == Some("off"));
+    assert!(state.smt_active == Some(false));
+
+    assert!(count_cpu_list("0-3,8-11,16") == Some(9));
+    assert!(count_cpu_list("3-1").is_none());
+    assert!(count_cpu_list("x").is_none());
+    assert!(count_cpu_list("0,2,5-6\n") == Some(4));
+
+    // No sysfs
+    let fs = procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), 0);
+    assert!(get_cpu_state(&fs) == CpuState::default());
+}
+
+#[test]
+pub fn procfs_cpu_freq_test() {
+    let cpuinfo = "processor\t: 0\ncpu MHz\t\t: 1200.000\nprocessor\t: 1\ncpu MHz\t\t: 3499.712\n";
+    let mut files = HashMap::new();
+    files.insert("cpuinfo".to_string(), cpuinfo.to_string());
+    let mut sys_files = HashMap::new();
+    for (name, contents) in [
+        ("cpuinfo_max_freq", "3700000\n"),
+        ("base_frequency", "2100000\n"),
+    ] {
+        sys_files.insert(
+            format!("devices/system/cpu/cpu0/cpufreq/{name}"),
+            contents.to_string(),
+        );
+    }
+
+    // cpufreq
+    let fs =
+        procfsapi::MockFS::new(files.clone(), vec![], HashMap::new(), 0).with_sys_files(sys_files);
+    let freq = get_cpu_freq(&fs);
+    assert!(freq.max_mhz == Some(3700));
+    assert!(freq.base_mhz == Some(2100));
+
+    // No cpufreq, the current cpu MHz in cpuinfo is not the maximum
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0);
+    assert!(get_cpu_freq(&fs) == CpuFreq::default());
+
+    // Neither
+    let fs = procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), 0);
+    assert!(get_cpu_freq(&fs) == CpuFreq::default());
+}
+
+#[test]
+pub fn procfs_process_io_test() {
+    let mut files = HashMap::new();
+    files.insert(
+        "4018/io".to_string(),
+        "rchar: 323934931\nwchar: 323929600\nsyscr: 632687\nsyscw: 632675\nread_bytes: 4096\nwrite_bytes: 323932160\ncancelled_write_bytes: 0\n".to_string(),
+    );
+    files.insert("4019/io".to_string(), "garbage\n".to_string());
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0);
+    let io = get_process_io(&fs, 4018).expect("Test: io");
+    assert!(io.rchar == 323934931);
+    assert!(io.wchar == 323929600);
+    assert!(io.read_bytes == 4096);
+    assert!(io.write_bytes == 323932160);
+    assert!(get_process_io(&fs, 4019).is_none());
+    assert!(get_process_io(&fs, 4020).is_none());
+}
+
+#[test]
+pub fn procfs_anon_hugepages_test() {
+    let mut files = HashMap::new();
+    files.insert(
+        "4018/smaps_rollup".to_string(),
+        std::include_str!("testdata/smaps-rollup.txt").to_string(),
+    );
+    files.insert("4019/smaps_rollup".to_string(), "Rss: 100 kB\n".to_string());
+    files.insert(
+        "4020/smaps_rollup".to_string(),
+        "AnonHugePages: lots\n".to_string(),
+    );
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0);
+    assert!(get_anon_hugepages_kib(&fs, 4018) == Some(1050624));
+    assert!(get_anon_hugepages_kib(&fs, 4019).is_none());
+    assert!(get_anon_hugepages_kib(&fs, 4020).is_none());
+    assert!(get_anon_hugepages_kib(&fs, 4021).is_none());
+}
+
+#[test]
+pub fn procfs_loadavg_test() {
+    let mut files = HashMap::new();
+    files.insert(
+        "loadavg".to_string(),
+        "0.52 0.58 1.59 3/1187 23456\n".to_string(),
+    );
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0);
+    assert!(
+        get_loadavg(&fs)
+            == Some(LoadAvg {
+                load1: 0.52,
+                load5: 0.58,
+                load15: 1.59,
+                runnable: 3,
+                existing: 1187,
+            })
+    );
+
+    let mut files = HashMap::new();
+    files.insert("loadavg".to_string(), "0.52 0.58 1.59 3\n".to_string());
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0);
+    assert!(get_loadavg(&fs).is_none());
+    let fs = procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), 0);
+    assert!(get_loadavg(&fs).is_none());
+}
+
+#[test]
+pub fn procfs_vmstat_test() {
+    let mut files = HashMap::new();
+    files.insert(
+        "vmstat".to_string(),
+        std::include_str!("testdata/vmstat.txt").to_string(),
+    );
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0);
+    assert!(
+        get_vmstat(&fs)
+            == Some(VmStat {
+                pgmajfault: 2914485,
+                pswpin: 182734,
+                pswpout: 406620,
+            })
+    );
+
+    let mut files = HashMap::new();
+    files.insert(
+        "vmstat".to_string(),
+        "pgmajfault 17\npswpin 0\n".to_string(),
+    );
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0);
+    assert!(get_vmstat(&fs).is_none());
+    let mut files = HashMap::new();
+    files.insert(
+        "vmstat".to_string(),
+        "pgmajfault lots\npswpin 0\npswpout 0\n".to_string(),
+    );
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0);
+    assert!(get_vmstat(&fs).is_none());
+    let fs = procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), 0);
+    assert!(get_vmstat(&fs).is_none());
+}
+
+#[test]
+pub fn procfs_memory_limit_test() {
+    let memtotal_kib = 16093776;
+
+    // cgroup v2, the parent's limit is the smaller one
+    let mut files = HashMap::new();
+    files.insert(
+        "self/cgroup".to_string(),
+        "0::/kubepods.slice/pod1234/cri-containerd-abcd.scope\n".to_string(),
+    );
+    let mut sys_files = HashMap::new();
+    sys_files.insert(
+        "fs/cgroup/kubepods.slice/pod1234/cri-containerd-abcd.scope/memory.max".to_string(),
+        "max\n".to_string(),
+    );
+    sys_files.insert(
+        "fs/cgroup/kubepods.slice/pod1234/memory.max".to_string(),
+        "4294967296\n".to_string(),
+    );
+    let fs =
+        procfsapi::MockFS::new(files.clone(), vec![], HashMap::new(), 0).with_sys_files(sys_files);
+    assert!(get_memory_limit_kib(&fs, memtotal_kib) == Some(4194304));
+    // A limit that is not below MemTotal is no limit
+    assert!(get_memory_limit_kib(&fs, 4194304).is_none());
+
+    // Unlimited all the way up
+    let mut sys_files = HashMap::new();
+    sys_files.insert(
+        "fs/cgroup/kubepods.slice/pod1234/cri-containerd-abcd.scope/memory.max".to_string(),
+        "max\n".to_string(),
+    );
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0).with_sys_files(sys_files);
+    assert!(get_memory_limit_kib(&fs, memtotal_kib).is_none());
+
+    // cgroup v1, where no limit is a very large number
+    let mut files = HashMap::new();
+    files.insert(
+        "self/cgroup".to_string(),
+        "12:cpu,cpuacct:/docker/abcd\n5:memory:/docker/abcd\n".to_string(),
+    );
+    let mut sys_files = HashMap::new();
+    sys_files.insert(
+        "fs/cgroup/memory/docker/abcd/memory.limit_in_bytes".to_string(),
+        "2147483648\n".to_string(),
+    );
+    sys_files.insert(
+        "fs/cgroup/memory/memory.limit_in_bytes".to_string(),
+        "9223372036854771712\n".to_string(),
+    );
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0).with_sys_files(sys_files);
+    assert!(get_memory_limit_kib(&fs, memtotal_kib) == Some(2097152));
+
+    // No cgroup information
+    let fs = procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), 0);
+    assert!(get_memory_limit_kib(&fs, memtotal_kib).is_none());
+}
+
+#[test]
+pub fn procfs_job_cgroup_cpu_test() {
+    // cgroup v2
+    let mut files = HashMap::new();
+    files.insert(
+        "4018/cgroup".to_string(),
+        "0::/system.slice/slurmstepd.scope/job_4139711/step_0/user/task_0\n".to_string(),
+    );
+    let mut sys_files = HashMap::new();
+    sys_files.insert(
+        "fs/cgroup/system.slice/slurmstepd.scope/job_4139711/cpu.stat".to_string(),
+        "usage_usec 92837461234\nuser_usec 90000000000\nsystem_usec 2837461234\n".to_string(),
+    );
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0).with_sys_files(sys_files);
+    assert!(get_job_cgroup_cpu_usec(&fs, 4018, 4139711) == Some(92837461234));
+    assert!(get_job_cgroup_cpu_usec(&fs, 4018, 413971).is_none());
+
+    // cgroup v1, the cpuacct counter is in nanoseconds
+    let mut files = HashMap::new();
+    files.insert(
+        "4018/cgroup".to_string(),
+        "12:memory:/slurm/uid_2101171/job_280678/step_0/task_0\n\
+         4:cpu,cpuacct:/slurm/uid_2101171/job_280678/step_0/task_0\n"
+            .to_string(),
+    );
+    let mut sys_files = HashMap::new();
+    sys_files.insert(
+        "fs/cgroup/cpu,cpuacct/slurm/uid_2101171/job_280678/cpuacct.usage".to_string(),
+        "1234567891234\n".to_string(),
+    );
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0).with_sys_files(sys_files);
+    assert!(get_job_cgroup_cpu_usec(&fs, 4018, 280678) == Some(1234567891));
+
+    // Not in a job cgroup, and no cgroup information at all
+    let mut files = HashMap::new();
+    files.insert(
+        "4018/cgroup".to_string(),
+        "0::/user.slice/user-1000.slice/session-3.scope\n".to_string(),
+    );
+    let fs = procfsapi::MockFS::new(files, vec![], HashMap::new(), 0);
+    assert!(get_job_cgroup_cpu_usec(&fs, 4018, 3).is_none());
+    assert!(get_job_cgroup_cpu_usec(&fs, 4019, 3).is_none());
+}
+
+// The cpu profile reads only /proc/{pid}/stat, and the cpu fields are the same as for the full
+// profile while the memory fields are zero.
+
+#[test]
+pub fn procfs_cpu_profile_test() {
+    let mut files = HashMap::new();
+    files.insert("stat".to_string(), "btime 1698303295".to_string());
+    let mut pids = vec![];
+    for pid in [100, 101] {
+        pids.push((pid, 1000));
+        files.insert(
+            format!("{pid}/stat"),
+            format!("{pid} (cmd) S 1 {pid} {pid} 0 -1 4194560 0 0 0 0 15 7 2 2 20 0 1 0 16400 5144358912 184775"),
+        );
+        files.insert(
+            format!("{pid}/statm"),
+            "1255967 100 54972 200 0 316078 0".to_string(),
+        );
+        files.insert(format!("{pid}/status"), "RssAnon: 100 kB".to_string());
+    }
+    let users = HashMap::from([(1000, "zappa".to_string())]);
+    let now = 1698303295 + 1000;
+
+    let fs = procfsapi::MockFS::new(files.clone(), pids.clone(), users.clone(), now);
+    let (full, _, _) =
+        get_process_information(&fs, 16093776, 1, Profile::Full).expect("Test: data");
+    assert!(fs.files_read().iter().any(|f| f == "100/statm"));
+    assert!(full[&100].rssanon_kib == 100);
+
+    let fs = procfsapi::MockFS::new(files, pids, users, now);
+    let (info, _, _) = get_process_information(&fs, 16093776, 1, Profile::Cpu).expect("Test: data");
+    let read = fs.files_read();
+    assert!(read.iter().any(|f| f == "100/stat") && read.iter().any(|f| f == "101/stat"));
+    assert!(!read
+        .iter()
+        .any(|f| f.ends_with("/statm") || f.ends_with("/status")));
+    assert!(info.len() == 2);
+    let p = &info[&100];
+    assert!(p.cputime_sec == full[&100].cputime_sec);
+    assert!(p.cpu_times.user_sec == 0.15 && p.cpu_times.system_sec == 0.07);
+    assert!(p.cpu_pct == full[&100].cpu_pct);
+    assert!(p.user == "zappa" && p.command == "cmd");
+    assert!(p.mem_pct == 0.0 && p.mem_size_kib == 0 && p.rssanon_kib == 0);
+}
+
+// Very large tick values, and a process that appears to have started after the current time,
+// produce finite cpu percentages that are no larger than the cpus can deliver.
+
+#[test]
+pub fn procfs_large_ticks_test() {
+    let stat = "cpu  1 0 0 0 0 0 0\ncpu0 1 0 0 0 0 0 0\ncpu1 0 0 0 0 0 0 0\nbtime 1698303295";
+    let mut files = HashMap::new();
+    files.insert("stat".to_string(), stat.to_string());
+    let huge = u64::MAX / 4;
+    let pids = vec![(100, 1000), (101, 1000), (102, 1000)];
+    for (pid, utime, starttime) in [
+        // Running for tens of thousands of years, with most of the time on the cpu
+        (100, huge / 2, 0),
+        // Started in the future
+        (101, 5000, huge),
+        // Both
+        (102, huge, huge),
+    ] {
+        files.insert(
+            format!("{pid}/stat"),
+            format!("{pid} (cmd) S 1 {pid} {pid} 0 -1 4194560 0 0 0 0 {utime} {utime} 0 0 20 0 1 0 {starttime} 5144358912 184775"),
+        );
+        files.insert(
+            format!("{pid}/statm"),
+            "1255967 100 54972 200 0 316078 0".to_string(),
+        );
+        files.insert(format!("{pid}/status"), "RssAnon: 100 kB".to_string());
+    }
+    let users = HashMap::from([(1000, "zappa".to_string())]);
+    let now = huge / 100;
+
+    let fs = procfsapi::MockFS::new(files, pids, users, now);
+    let (info, _, _) =
+        get_process_information(&fs, 16093776, 1, Profile::Full).expect("Test: data");
+    for pid in [100, 101, 102] {
+        let p = &info[&pid];
+        assert!(p.cpu_pct.is_finite() && p.cpu_pct >= 0.0 && p.cpu_pct <= 200.0);
+        assert!(p.mem_pct.is_finite());
+    }
+    assert!(info[&100].cpu_pct == 100.0);
+    assert!(info[&101].cpu_pct == 200.0);
+    assert!(info[&102].cpu_pct == 200.0);
 }
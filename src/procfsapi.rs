@@ -1,28 +1,38 @@
 // This creates a API by which procfs can access the underlying computing system, allowing the
 // system to be virtualized.  In turn, that allows sensible test cases to be written.
+//
+// The API must be Sync because /proc may be scanned by several threads at once.
 
-use crate::users::get_user_by_uid;
+use crate::users::{get_user_by_uid, lookup_with_timeout};
 
 use std::fs;
 use std::os::linux::fs::MetadataExt;
 use std::path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[cfg(test)]
 use std::collections::HashMap;
+#[cfg(test)]
+use std::sync::Mutex;
 
-pub trait ProcfsAPI {
+pub trait ProcfsAPI: Sync {
     // Open /proc/<path> (which can have multiple path elements, eg, {PID}/filename), read it, and
     // return its entire contents as a string.  Return a sensible error message if the file can't
     // be opened or read.
     fn read_to_string(&self, path: &str) -> Result<String, String>;
 
+    // Return the targets of the symbolic links in the directory /proc/<path>, eg {PID}/fd.  Return
+    // a sensible error message if the directory can't be read, as when permission is denied.
+    fn read_proc_links(&self, path: &str) -> Result<Vec<String>, String>;
+
     // Return (pid,uid) for every file /proc/{PID}.  Return a sensible error message in case
     // something goes really, really wrong, but otherwise try to make the best of it.
     fn read_proc_pids(&self) -> Result<Vec<(usize, u32)>, String>;
 
     // Try to figure out the user's name from system tables, this may be an expensive operation.
-    fn user_by_uid(&self, uid: u32) -> Option<String>;
+    // Return Ok(None) if there is no such user and Err with a sensible message if the lookup did
+    // not complete within the allotted time.
+    fn user_by_uid(&self, uid: u32) -> Result<Option<String>, String>;
 
     // Return the value of CLK_TCK, or 0 on error.
     fn clock_ticks_per_sec(&self) -> usize;
@@ -32,30 +42,76 @@ pub trait ProcfsAPI {
 
     // Return the current time in seconds since Unix epoch.
     fn now_in_secs_since_epoch(&self) -> u64;
+
+    // Return the pid of the running sonar process, or 0 if there is none.
+    fn own_pid(&self) -> usize;
+
+    // Return the pid that the process with pid `host_pid` in the host's PID namespace has in the
+    // namespace of the /proc being read, or None if it is not in that namespace.  GPU drivers
+    // report host pids.
+    fn pid_from_host(&self, host_pid: usize) -> Option<usize>;
+
+    // Open /sys/<path>, read it, and return its entire contents as a string.  Return a sensible
+    // error message if the file can't be opened or read.
+    fn read_sys_to_string(&self, path: &str) -> Result<String, String>;
+
+    // Return the names of the entries in the directory /sys/<path>.  Return a sensible error
+    // message if the directory can't be read.
+    fn read_sys_dir(&self, path: &str) -> Result<Vec<String>, String>;
 }
 
-// RealFS is used to actually access /proc, system tables, and system clock.
+// RealFS is used to actually access /proc, system tables, and system clock.  The proc root is
+// normally /proc but can be some other mount of procfs, eg the /proc of a container or another PID
+// namespace that has been bind-mounted into the sonar sidecar.
 
-pub struct RealFS {}
+pub struct RealFS {
+    proc_root: path::PathBuf,
+    user_timeout: Option<Duration>,
+}
 
 impl RealFS {
     pub fn new() -> RealFS {
-        RealFS {}
+        RealFS::with_proc_root("/proc")
+    }
+
+    pub fn with_proc_root(proc_root: &str) -> RealFS {
+        RealFS {
+            proc_root: path::PathBuf::from(proc_root),
+            user_timeout: None,
+        }
+    }
+
+    // Give up on user lookups that take longer than the timeout, by default they are not limited.
+    pub fn with_user_timeout(mut self, user_timeout: Option<Duration>) -> RealFS {
+        self.user_timeout = user_timeout;
+        self
     }
 }
 
 impl ProcfsAPI for RealFS {
     fn read_to_string(&self, path: &str) -> Result<String, String> {
-        let filename = format!("/proc/{path}");
-        match fs::read_to_string(path::Path::new(&filename)) {
+        let filename = self.proc_root.join(path);
+        match fs::read_to_string(&filename) {
             Ok(s) => Ok(s),
-            Err(_) => Err(format!("Unable to read {filename}")),
+            Err(_) => Err(format!("Unable to read {}", filename.display())),
+        }
+    }
+
+    fn read_proc_links(&self, path: &str) -> Result<Vec<String>, String> {
+        let dirname = self.proc_root.join(path);
+        match fs::read_dir(&dirname) {
+            Ok(dir) => Ok(dir
+                .flatten()
+                .filter_map(|dirent| fs::read_link(dirent.path()).ok())
+                .map(|target| target.to_string_lossy().to_string())
+                .collect::<Vec<String>>()),
+            Err(_) => Err(format!("Unable to read directory {}", dirname.display())),
         }
     }
 
     fn read_proc_pids(&self) -> Result<Vec<(usize, u32)>, String> {
         let mut pids = vec![];
-        if let Ok(dir) = fs::read_dir("/proc") {
+        if let Ok(dir) = fs::read_dir(&self.proc_root) {
             for dirent in dir.flatten() {
                 if let Ok(meta) = dirent.metadata() {
                     let uid = meta.st_uid();
@@ -67,13 +123,17 @@ impl ProcfsAPI for RealFS {
                 }
             }
         } else {
-            return Err("Could not open /proc".to_string());
+            return Err(format!("Could not open {}", self.proc_root.display()));
         };
         Ok(pids)
     }
 
-    fn user_by_uid(&self, uid: u32) -> Option<String> {
-        get_user_by_uid(uid).map(|u| u.to_string_lossy().to_string())
+    fn user_by_uid(&self, uid: u32) -> Result<Option<String>, String> {
+        let lookup = move || get_user_by_uid(uid).map(|u| u.to_string_lossy().to_string());
+        match self.user_timeout {
+            Some(timeout) => lookup_with_timeout(uid, lookup, timeout),
+            None => Ok(lookup()),
+        }
     }
 
     fn clock_ticks_per_sec(&self) -> usize {
@@ -87,6 +147,61 @@ impl ProcfsAPI for RealFS {
     fn now_in_secs_since_epoch(&self) -> u64 {
         unix_now()
     }
+
+    fn own_pid(&self) -> usize {
+        // In some other PID namespace, {proc_root}/self is our pid in that namespace, if we are in
+        // it at all.
+        if self.proc_root == path::Path::new("/proc") {
+            std::process::id() as usize
+        } else {
+            fs::read_link(self.proc_root.join("self"))
+                .ok()
+                .and_then(|target| target.to_string_lossy().parse::<usize>().ok())
+                .unwrap_or(0)
+        }
+    }
+
+    fn pid_from_host(&self, host_pid: usize) -> Option<usize> {
+        // The NSpid line of the host's /proc/{pid}/status lists the pid in each nested namespace,
+        // innermost last.  A process that has only the host pid is not in the other namespace.
+        if self.proc_root == path::Path::new("/proc") {
+            return Some(host_pid);
+        }
+        let status = fs::read_to_string(format!("/proc/{host_pid}/status")).ok()?;
+        let nspids = status
+            .lines()
+            .find_map(|l| l.strip_prefix("NSpid:"))?
+            .split_ascii_whitespace()
+            .collect::<Vec<&str>>();
+        if nspids.len() < 2 {
+            return None;
+        }
+        let pid = nspids[nspids.len() - 1].parse::<usize>().ok()?;
+        if self.proc_root.join(pid.to_string()).exists() {
+            Some(pid)
+        } else {
+            None
+        }
+    }
+
+    fn read_sys_to_string(&self, path: &str) -> Result<String, String> {
+        let filename = format!("/sys/{path}");
+        match fs::read_to_string(path::Path::new(&filename)) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(format!("Unable to read {filename}")),
+        }
+    }
+
+    fn read_sys_dir(&self, path: &str) -> Result<Vec<String>, String> {
+        let dirname = format!("/sys/{path}");
+        match fs::read_dir(path::Path::new(&dirname)) {
+            Ok(dir) => Ok(dir
+                .flatten()
+                .map(|dirent| dirent.file_name().to_string_lossy().to_string())
+                .collect::<Vec<String>>()),
+            Err(_) => Err(format!("Unable to read directory {dirname}")),
+        }
+    }
 }
 
 pub fn unix_now() -> u64 {
@@ -137,6 +252,12 @@ pub struct MockFS {
     ticks_per_sec: usize,
     pagesz: usize,
     now: u64,
+    sys_files: HashMap<String, String>,
+    links: HashMap<String, Vec<String>>,
+    own_pid: usize,
+    host_pids: Option<HashMap<usize, usize>>,
+    user_delay: Option<(Vec<u32>, Duration, Duration)>,
+    files_read: Mutex<Vec<String>>,
 }
 
 #[cfg(test)]
@@ -154,27 +275,108 @@ impl MockFS {
             ticks_per_sec: 100,
             pagesz: 4,
             now,
+            sys_files: HashMap::new(),
+            links: HashMap::new(),
+            own_pid: 0,
+            host_pids: None,
+            user_delay: None,
+            files_read: Mutex::new(vec![]),
         }
     }
+
+    // Files under /sys, keyed by their path relative to /sys.  Directories are implied.
+    pub fn with_sys_files(mut self, sys_files: HashMap<String, String>) -> MockFS {
+        self.sys_files = sys_files;
+        self
+    }
+
+    // The targets of the symbolic links in directories under /proc, keyed by the directory's path
+    // relative to /proc, eg "{PID}/fd".  Other directories can't be read.
+    pub fn with_links(mut self, links: HashMap<String, Vec<String>>) -> MockFS {
+        self.links = links;
+        self
+    }
+
+    // Replace the table of user names.
+    pub fn with_users(mut self, users: HashMap<u32, String>) -> MockFS {
+        self.users = users;
+        self
+    }
+
+    // Make the lookup of each user in `slow_uids` take `delay`, and give up on any lookup after
+    // `timeout`, as for a slow user database.  By default lookups are instantaneous.
+    pub fn with_user_delay(
+        mut self,
+        slow_uids: &[u32],
+        delay: Duration,
+        timeout: Duration,
+    ) -> MockFS {
+        self.user_delay = Some((slow_uids.to_vec(), delay, timeout));
+        self
+    }
+
+    // The pid that sonar itself should appear to have; there is no such process by default.
+    pub fn with_own_pid(mut self, own_pid: usize) -> MockFS {
+        self.own_pid = own_pid;
+        self
+    }
+
+    // Map host pids to pids in the namespace of /proc, other host pids are not in the namespace.
+    // By default /proc is the host's.
+    pub fn with_host_pids(mut self, host_pids: HashMap<usize, usize>) -> MockFS {
+        self.host_pids = Some(host_pids);
+        self
+    }
+
+    // The paths of the files under /proc that have been read, in order, whether they exist or not.
+    pub fn files_read(&self) -> Vec<String> {
+        self.files_read.lock().expect("Test: lock").clone()
+    }
 }
 
 #[cfg(test)]
 impl ProcfsAPI for MockFS {
     fn read_to_string(&self, path: &str) -> Result<String, String> {
+        self.files_read
+            .lock()
+            .expect("Test: lock")
+            .push(path.to_string());
         match self.files.get(path) {
             Some(s) => Ok(s.clone()),
             None => Err(format!("Unable to read /proc/{path}")),
         }
     }
 
+    fn read_proc_links(&self, path: &str) -> Result<Vec<String>, String> {
+        match self.links.get(path) {
+            Some(targets) => Ok(targets.clone()),
+            None => Err(format!("Unable to read directory /proc/{path}")),
+        }
+    }
+
     fn read_proc_pids(&self) -> Result<Vec<(usize, u32)>, String> {
         Ok(self.pids.clone())
     }
 
-    fn user_by_uid(&self, uid: u32) -> Option<String> {
-        match self.users.get(&uid) {
-            Some(s) => Some(s.clone()),
-            None => None,
+    fn user_by_uid(&self, uid: u32) -> Result<Option<String>, String> {
+        let name = self.users.get(&uid).cloned();
+        match self.user_delay {
+            Some((ref slow_uids, delay, timeout)) => {
+                let delay = if slow_uids.contains(&uid) {
+                    delay
+                } else {
+                    Duration::ZERO
+                };
+                lookup_with_timeout(
+                    uid,
+                    move || {
+                        std::thread::sleep(delay);
+                        name
+                    },
+                    timeout,
+                )
+            }
+            None => Ok(name),
         }
     }
 
@@ -189,4 +391,70 @@ impl ProcfsAPI for MockFS {
     fn now_in_secs_since_epoch(&self) -> u64 {
         self.now
     }
+
+    fn own_pid(&self) -> usize {
+        self.own_pid
+    }
+
+    fn pid_from_host(&self, host_pid: usize) -> Option<usize> {
+        match self.host_pids {
+            Some(ref host_pids) => host_pids.get(&host_pid).copied(),
+            None => Some(host_pid),
+        }
+    }
+
+    fn read_sys_to_string(&self, path: &str) -> Result<String, String> {
+        match self.sys_files.get(path) {
+            Some(s) => Ok(s.clone()),
+            None => Err(format!("Unable to read /sys/{path}")),
+        }
+    }
+
+    fn read_sys_dir(&self, path: &str) -> Result<Vec<String>, String> {
+        let prefix = path.to_string() + "/";
+        let mut names = vec![];
+        for k in self.sys_files.keys() {
+            if let Some(rest) = k.strip_prefix(&prefix) {
+                let name = rest.split('/').next().unwrap_or_default().to_string();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        if names.is_empty() {
+            Err(format!("Unable to read directory /sys/{path}"))
+        } else {
+            Ok(names)
+        }
+    }
+}
+
+#[test]
+pub fn realfs_proc_root_test() {
+    let root = std::env::temp_dir().join(format!("sonar-proc-root-{}", std::process::id()));
+    fs::create_dir_all(root.join("123")).expect("Test: create dir");
+    fs::write(root.join("stat"), "cpu 1 2 3\n").expect("Test: write stat");
+    fs::write(root.join("123/stat"), "123 (x) S\n").expect("Test: write pid stat");
+
+    let fs = RealFS::with_proc_root(root.to_str().expect("Test: utf8 path"));
+    assert!(fs.read_to_string("stat").expect("Test: stat") == "cpu 1 2 3\n");
+    assert!(fs.read_to_string("123/stat").expect("Test: pid stat") == "123 (x) S\n");
+    let err = fs.read_to_string("124/stat").unwrap_err();
+    assert!(err == format!("Unable to read {}/124/stat", root.display()));
+    let pids = fs.read_proc_pids().expect("Test: pids");
+    assert!(pids.len() == 1);
+    assert!(pids[0].0 == 123);
+
+    // Sonar is not in the namespace until it has a self link there.
+    assert!(fs.own_pid() == 0);
+    std::os::unix::fs::symlink("123", root.join("self")).expect("Test: symlink");
+    assert!(fs.own_pid() == 123);
+    assert!(fs.read_proc_pids().expect("Test: pids").len() == 1);
+
+    // Sonar's own process has no pid in a nested namespace.
+    assert!(fs.pid_from_host(std::process::id() as usize).is_none());
+    assert!(RealFS::new().pid_from_host(123) == Some(123));
+
+    fs::remove_dir_all(&root).expect("Test: remove dir");
+    assert!(fs.read_proc_pids().is_err());
 }
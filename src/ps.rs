@@ -1,6 +1,8 @@
 #![allow(clippy::type_complexity)]
 #![allow(clippy::too_many_arguments)]
 
+use crate::containers;
+use crate::errors;
 use crate::gpu;
 use crate::gpuset;
 use crate::hostname;
@@ -10,11 +12,15 @@ use crate::log;
 use crate::output;
 use crate::procfs;
 use crate::procfsapi;
-use crate::util::three_places;
+#[cfg(test)]
+use crate::slurm;
+use crate::util::{three_places, truncate_chars};
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{self, Write};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 type Pid = usize;
 type JobID = usize;
@@ -34,17 +40,36 @@ struct ProcInfo<'a> {
     rolledup: usize,
     is_system_job: bool,
     has_children: bool,
+    is_zombie: bool,
+    is_kernel_thread: bool,
     job_id: usize,
     cpu_percentage: f64,
     cputime_sec: usize,
     mem_percentage: f64,
     mem_size_kib: usize,
     rssanon_kib: usize,
+    major_faults: usize,
+    minor_faults: usize,
+    num_threads: usize,
+    voluntary_ctxt_switches: usize,
+    nonvoluntary_ctxt_switches: usize,
     gpu_cards: gpuset::GpuSet,
     gpu_percentage: f64,
     gpu_mem_percentage: f64,
     gpu_mem_size_kib: usize,
     gpu_status: GpuStatus,
+    container_runtime: Option<String>,
+    container_image: Option<String>,
+    job_info: Option<jobs::JobInfo>,
+    env: Vec<(String, String)>,
+    job_cpu_usec: Option<u64>,
+    oom_score: Option<i64>,
+    oom_score_adj: Option<i64>,
+    tcp_connections: Option<u64>,
+    gpus_allowed: Vec<usize>,
+    io: Option<procfs::ProcessIo>,
+    anon_huge_kib: usize,
+    cpu_times: procfs::CpuTimes,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -76,11 +101,18 @@ fn add_proc_info<'a, F>(
     pid: Pid,
     ppid: Pid,
     has_children: bool,
+    is_zombie: bool,
+    is_kernel_thread: bool,
     cpu_percentage: f64,
     cputime_sec: usize,
     mem_percentage: f64,
     mem_size_kib: usize,
     rssanon_kib: usize,
+    major_faults: usize,
+    minor_faults: usize,
+    num_threads: usize,
+    voluntary_ctxt_switches: usize,
+    nonvoluntary_ctxt_switches: usize,
     gpu_cards: &gpuset::GpuSet,
     gpu_percentage: f64,
     gpu_mem_percentage: f64,
@@ -97,6 +129,11 @@ fn add_proc_info<'a, F>(
             e.mem_percentage += mem_percentage;
             e.mem_size_kib += mem_size_kib;
             e.rssanon_kib += rssanon_kib;
+            e.major_faults += major_faults;
+            e.minor_faults += minor_faults;
+            e.num_threads += num_threads;
+            e.voluntary_ctxt_switches += voluntary_ctxt_switches;
+            e.nonvoluntary_ctxt_switches += nonvoluntary_ctxt_switches;
             gpuset::union_gpuset(&mut e.gpu_cards, gpu_cards);
             e.gpu_percentage += gpu_percentage;
             e.gpu_mem_percentage += gpu_mem_percentage;
@@ -113,17 +150,36 @@ fn add_proc_info<'a, F>(
             rolledup: 0,
             is_system_job: uid < 1000,
             has_children,
+            is_zombie,
+            is_kernel_thread,
             job_id: lookup_job_by_pid(pid),
             cpu_percentage,
             cputime_sec,
             mem_percentage,
             mem_size_kib,
             rssanon_kib,
+            major_faults,
+            minor_faults,
+            num_threads,
+            voluntary_ctxt_switches,
+            nonvoluntary_ctxt_switches,
             gpu_cards: gpu_cards.clone(),
             gpu_percentage,
             gpu_mem_percentage,
             gpu_mem_size_kib,
             gpu_status: GpuStatus::Ok,
+            container_runtime: None,
+            container_image: None,
+            job_info: None,
+            env: vec![],
+            job_cpu_usec: None,
+            oom_score: None,
+            oom_score_adj: None,
+            tcp_connections: None,
+            gpus_allowed: vec![],
+            io: None,
+            anon_huge_kib: 0,
+            cpu_times: Default::default(),
         });
 }
 
@@ -134,12 +190,48 @@ pub struct PsOptions<'a> {
     pub min_cpu_percent: Option<f64>,
     pub min_mem_percent: Option<f64>,
     pub min_cpu_time: Option<usize>,
+    pub min_threads: Option<usize>,
+    pub min_gpu_percent: Option<f64>,
+    pub max_command_length: Option<usize>,
+    pub cpu_time_breakdown: bool,
+    pub scan_threads: Option<usize>,
+    pub user_lookup_timeout: Option<u64>,
+    pub profile: procfs::Profile,
     pub exclude_system_jobs: bool,
     pub exclude_users: Vec<&'a str>,
     pub exclude_commands: Vec<&'a str>,
+    pub exclude_pids: Vec<Pid>,
+    pub exclude_pgrps: Vec<Pid>,
+    pub case_insensitive_filters: bool,
+    pub explain_filters: bool,
     pub lockdir: Option<String>,
+    pub proc_root: Option<String>,
     pub load: bool,
     pub json: bool,
+    pub msgpack: bool,
+    pub containers: bool,
+    pub no_defunct_suffix: bool,
+    pub job_info: bool,
+    pub env_allowlist: Vec<&'a str>,
+    pub include_self: bool,
+    pub job_cgroup_cpu: bool,
+    pub d_state_warn: Option<usize>,
+    pub expect_cluster: Option<String>,
+    pub oom_score: bool,
+    pub tcp_connections: bool,
+    pub gpu_affinity: bool,
+    pub io: bool,
+    pub hugepages: bool,
+    pub intern_strings: bool,
+    pub syslog_summary: bool,
+    pub tags: Vec<(String, String)>,
+    pub token: String,
+    pub exclude_kernel_threads: bool,
+    pub gpu_only: bool,
+    pub exclude_gpus: Vec<String>,
+    pub aggregate_by_command: bool,
+    pub mem_pct_of_limit: bool,
+    pub sort: bool,
 }
 
 pub fn create_snapshot(
@@ -256,20 +348,32 @@ fn do_create_snapshot(
         hostname: &hostname,
         timestamp,
         version: VERSION,
-        flat_data: !opts.json,
+        flat_data: !opts.json && !opts.msgpack && !opts.syslog_summary,
         opts,
     };
 
-    let fs = procfsapi::RealFS::new();
+    let fs = match &opts.proc_root {
+        Some(proc_root) => procfsapi::RealFS::with_proc_root(proc_root),
+        None => procfsapi::RealFS::new(),
+    }
+    .with_user_timeout(opts.user_lookup_timeout.map(Duration::from_secs));
     let gpus = gpu::RealGpuAPI::new();
-    match collect_data(&fs, &gpus, jobs, &print_params) {
+    let containers = containers::RealContainerAPI::new();
+    match collect_data(&fs, &gpus, &containers, jobs, &print_params) {
         output::Value::A(elts) => {
             for i in 0..elts.len() {
                 output::write_csv(writer, elts.at(i));
             }
         }
+        output::Value::O(datum) if opts.syslog_summary => {
+            let _ = writeln!(writer, "{}", syslog_summary(&fs, &datum));
+        }
         obj @ output::Value::O(_) => {
-            output::write_json(writer, &obj);
+            if opts.msgpack {
+                output::write_msgpack(writer, &obj);
+            } else {
+                output::write_json(writer, &obj);
+            }
         }
         output::Value::E() => {
             // interrupted, don't print anything
@@ -280,6 +384,102 @@ fn do_create_snapshot(
     }
 }
 
+// Summarize a sample, as collected for JSON, in a single line of key=value pairs for syslog: the
+// number of processes (including those rolled up, but not sonar's heartbeat), the three users using
+// the most CPU, and then the load of the node as a whole, so far as it is known: the one-minute
+// load average, the share of memory in use, and the cards' average utilization and share of memory
+// in use.  A sample that could not be collected is summarized by its error.
+
+fn syslog_summary(fs: &dyn procfsapi::ProcfsAPI, datum: &output::Object) -> String {
+    let get_f = |o: &output::Object, key: &str| match o.get(key) {
+        Some(output::Value::F(x)) => *x,
+        Some(output::Value::U(x)) => *x as f64,
+        _ => 0.0,
+    };
+    let get_u = |o: &output::Object, key: &str| match o.get(key) {
+        Some(output::Value::U(x)) => *x,
+        _ => 0,
+    };
+    let get_s = |o: &output::Object, key: &str| match o.get(key) {
+        Some(output::Value::S(s)) => s.clone(),
+        _ => "".to_string(),
+    };
+
+    let mut line = format!(
+        "sonar host={} time={}",
+        get_s(datum, "host"),
+        get_s(datum, "time")
+    );
+    if datum.get("error").is_some() {
+        line += &format!(" error=\"{}\"", syslog_escape(&get_s(datum, "error")));
+        return line;
+    }
+
+    let mut procs = 0;
+    let mut cpu_by_user = HashMap::<String, f64>::new();
+    if let Some(output::Value::A(samples)) = datum.get("samples") {
+        for i in 0..samples.len() {
+            if let output::Value::O(s) = samples.at(i) {
+                if get_s(s, "cmd") == "_heartbeat_" {
+                    continue;
+                }
+                procs += get_u(s, "rolledup") + 1;
+                *cpu_by_user.entry(get_s(s, "user")).or_default() += get_f(s, "cpu%");
+            }
+        }
+    }
+    let mut users = cpu_by_user.into_iter().collect::<Vec<(String, f64)>>();
+    users.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let top_users = users
+        .iter()
+        .take(3)
+        .map(|(user, cpu)| format!("{user}:{}", three_places(*cpu)))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    line += &format!(" procs={procs} top_users={}", syslog_escape(&top_users));
+    if datum.get("load1").is_some() {
+        line += &format!(" load1={}", three_places(get_f(datum, "load1")));
+    }
+    if let (Ok(total), Some(available)) = (
+        procfs::get_memtotal_kib(fs),
+        procfs::get_memavailable_kib(fs),
+    ) {
+        let used = total.saturating_sub(available) as f64;
+        line += &format!(" mem%={}", three_places(used * 100.0 / total as f64));
+    }
+    if let Some(output::Value::O(gpus)) = datum.get("gpusummary") {
+        line += &format!(
+            " gpus={} gpu%={}",
+            get_u(gpus, "cards"),
+            three_places(get_f(gpus, "cutil%"))
+        );
+        if gpus.get("mused%").is_some() {
+            line += &format!(" gpumem%={}", three_places(get_f(gpus, "mused%")));
+        }
+    }
+    line
+}
+
+// Backslash-escape a value for the syslog summary so that it stays on one line and within its
+// quotes: backslash and double quote are escaped, and control characters become \n, \t, \r or \xNN.
+
+fn syslog_escape(s: &str) -> String {
+    let mut t = String::new();
+    for c in s.chars() {
+        match c {
+            '\\' => t += "\\\\",
+            '"' => t += "\\\"",
+            '\n' => t += "\\n",
+            '\t' => t += "\\t",
+            '\r' => t += "\\r",
+            c if c.is_control() => t += &format!("\\x{:02x}", c as u32),
+            c => t.push(c),
+        }
+    }
+    t
+}
+
 // If this returns an output::Value::O then that is an object to write (eg JSON), otherwise it must
 // be an output::Value::A and each should be written individually (eg CSV), or it is
 // output::Value::E, in which case we were interrupted.  The first two cases are controlled by
@@ -288,10 +488,11 @@ fn do_create_snapshot(
 fn collect_data(
     fs: &dyn procfsapi::ProcfsAPI,
     gpus: &dyn gpu::GpuAPI,
+    containers: &dyn containers::ContainerAPI,
     jobs: &mut dyn jobs::JobManager,
     print_params: &PrintParameters,
 ) -> output::Value {
-    match do_collect_data(fs, gpus, jobs, print_params) {
+    match do_collect_data(fs, gpus, containers, jobs, print_params) {
         Ok(output::Value::A(mut elts)) => {
             if elts.len() == 0 && print_params.opts.always_print_something {
                 elts.push_o(make_heartbeat(&print_params))
@@ -305,7 +506,7 @@ fn collect_data(
         }
         Err(error) => {
             let mut hb = make_heartbeat(&print_params);
-            hb.push_s("error", error);
+            error.add_to(&mut hb);
             if print_params.flat_data {
                 output::Value::A(output::Array::from_vec(vec![output::Value::O(hb)]))
             } else {
@@ -318,8 +519,11 @@ fn collect_data(
 fn make_heartbeat(print_params: &PrintParameters) -> output::Object {
     let mut fields = output::Object::new();
     fields.push_s("v", print_params.version.to_string());
+    output::push_schema(&mut fields, output::PS_SCHEMA_VERSION);
     fields.push_s("time", print_params.timestamp.to_string());
     fields.push_s("host", print_params.hostname.to_string());
+    output::push_tags(&mut fields, &print_params.opts.tags);
+    output::push_token(&mut fields, &print_params.opts.token);
     fields.push_s("user", "_sonar_".to_string());
     fields.push_s("cmd", "_heartbeat_".to_string());
     fields
@@ -328,9 +532,14 @@ fn make_heartbeat(print_params: &PrintParameters) -> output::Object {
 fn do_collect_data(
     fs: &dyn procfsapi::ProcfsAPI,
     gpus: &dyn gpu::GpuAPI,
+    containers: &dyn containers::ContainerAPI,
     jobs: &mut dyn jobs::JobManager,
     print_params: &PrintParameters,
-) -> Result<output::Value, String> {
+) -> Result<output::Value, errors::Error> {
+    // The time taken to collect the data is reported so that it is possible to detect when sonar
+    // itself is becoming slow.
+    let start = Instant::now();
+
     let no_gpus = gpuset::empty_gpuset();
     let mut proc_by_pid = ProcTable::new();
 
@@ -339,11 +548,36 @@ fn do_collect_data(
     }
 
     // The total RAM installed is in the `MemTotal` field of /proc/meminfo.  We need this for
-    // various things.  Not getting it is a hard error.
+    // various things.  Not getting it is a hard error.  In a memory-constrained container or cgroup
+    // the percentages can optionally be relative to the cgroup's limit instead.
+
+    let mut memtotal_kib = procfs::get_memtotal_kib(fs)?;
+    let mem_limit_kib = if print_params.opts.mem_pct_of_limit {
+        procfs::get_memory_limit_kib(fs, memtotal_kib)
+    } else {
+        None
+    };
+    if let Some(limit_kib) = mem_limit_kib {
+        memtotal_kib = limit_kib;
+    }
+    let scan_threads = print_params.opts.scan_threads.unwrap_or(1);
+    let (mut procinfo_output, system_stat, user_lookup_error) =
+        procfs::get_process_information(fs, memtotal_kib, scan_threads, print_params.opts.profile)?;
+
+    // Processes that are stuck in uninterruptible sleep usually indicate IO or storage problems.
+    let procs_dstate = procinfo_output
+        .values()
+        .filter(|p| p.is_uninterruptible)
+        .count();
 
-    let memtotal_kib = procfs::get_memtotal_kib(fs)?;
-    let (procinfo_output, _cpu_total_secs, per_cpu_secs) =
-        procfs::get_process_information(fs, memtotal_kib)?;
+    // Sonar sees itself and the children it runs (eg sacct, scontrol), which would otherwise
+    // pollute the data for the node.
+
+    let own_pid = fs.own_pid();
+    if !print_params.opts.include_self && own_pid != 0 {
+        procinfo_output.retain(|_, p| p.pid != own_pid && p.ppid != own_pid);
+    }
+    let per_cpu_secs = &system_stat.per_cpu_secs;
 
     let pprocinfo_output = &procinfo_output;
 
@@ -355,7 +589,18 @@ fn do_collect_data(
 
     let mut lookup_job_by_pid = |pid: Pid| jobs.job_id_from_pid(pid, pprocinfo_output);
 
+    // Processes excluded by pid or process group are left out of the table before they can be
+    // rolled up or aggregated with other processes.  They are still part of the node-level data.
+    //
+    // The explanations are counts of records per filter, there can be very many records.
+    let mut excluded = BTreeMap::<&'static str, u64>::new();
     for proc in pprocinfo_output.values() {
+        if let Some(reason) = filter_pid(proc.pid, Some(proc.pgrp), print_params.opts) {
+            if print_params.opts.explain_filters {
+                *excluded.entry(reason).or_default() += 1;
+            }
+            continue;
+        }
         add_proc_info(
             &mut proc_by_pid,
             &mut lookup_job_by_pid,
@@ -365,16 +610,28 @@ fn do_collect_data(
             proc.pid,
             proc.ppid,
             proc.has_children,
+            proc.is_zombie,
+            proc.is_kernel_thread,
             proc.cpu_pct,
             proc.cputime_sec,
             proc.mem_pct,
             proc.mem_size_kib,
             proc.rssanon_kib,
+            proc.major_faults,
+            proc.minor_faults,
+            proc.num_threads,
+            proc.voluntary_ctxt_switches,
+            proc.nonvoluntary_ctxt_switches,
             &no_gpus, // gpu_cards
             0.0,      // gpu_percentage
             0.0,      // gpu_mem_percentage
             0,
         ); // gpu_mem_size_kib
+        if print_params.opts.cpu_time_breakdown {
+            if let Some(proc_info) = proc_by_pid.get_mut(&proc.pid) {
+                proc_info.cpu_times = proc.cpu_times;
+            }
+        }
     }
 
     if interrupt::is_interrupted() {
@@ -387,81 +644,138 @@ fn do_collect_data(
     // This is a soft failure, surfaced through dashboards; we do not want mail about it under
     // normal circumstances.
     let mut gpu_status = GpuStatus::Ok;
+    let mut gpu_error: Option<String> = None;
 
     let gpu_utilization: Vec<gpu::Process>;
     let mut gpu_info: Option<output::Object> = None;
+    let mut gpu_summary: Option<output::Object> = None;
+    let mut has_gpus = false;
+    let mut cards: Vec<gpu::Card> = vec![];
+    let mut card_indices: Vec<i32> = vec![];
+    let gpus: &dyn gpu::GpuAPI = &gpu::ExcludingGpuAPI::new(gpus, &print_params.opts.exclude_gpus);
     match gpus.probe() {
         None => {}
         Some(mut gpu) => {
+            has_gpus = true;
+            // The card configuration maps UUIDs to card indices and has the cards' memory sizes.
+            if print_params.opts.gpu_affinity || print_params.opts.load {
+                cards = gpu.get_card_configuration().unwrap_or_default();
+            }
             match gpu.get_card_utilization() {
-                Err(_) => {
+                Err(e) => {
                     gpu_status = GpuStatus::UnknownFailure;
+                    gpu_error.get_or_insert(e);
                 }
-                Ok(ref cards) => {
+                Ok(ref states) => {
+                    let mem_size_kib = |index: i32| {
+                        cards
+                            .iter()
+                            .find(|c| c.index == index)
+                            .map(|c| c.mem_size_kib)
+                            .filter(|kib| *kib > 0)
+                    };
                     let mut s = output::Object::new();
-                    s = add_key(s, "fan%", cards, |c: &gpu::CardState| {
+                    s = add_key(s, "fan%", states, |c: &gpu::CardState| {
                         nonzero(c.fan_speed_pct as i64)
                     });
-                    s = add_key(s, "mode", cards, |c: &gpu::CardState| {
-                        if c.compute_mode == "Default" {
+                    s = add_key(s, "mode", states, |c: &gpu::CardState| {
+                        if c.compute_mode_raw == "Default" {
+                            output::Value::E()
+                        } else {
+                            output::Value::S(c.compute_mode_raw.clone())
+                        }
+                    });
+                    s = add_key(s, "cmode", states, |c: &gpu::CardState| {
+                        if c.compute_mode == gpu::COMPUTE_MODE_DEFAULT {
                             output::Value::E()
                         } else {
                             output::Value::S(c.compute_mode.clone())
                         }
                     });
-                    s = add_key(s, "perf", cards, |c: &gpu::CardState| {
+                    s = add_key(s, "perf", states, |c: &gpu::CardState| {
                         output::Value::S(c.perf_state.clone())
                     });
                     // Reserved memory is really not interesting, it's possible it would have been
                     // interesting as part of the card configuration.
-                    //s = add_key(s, "mreskib", cards, |c: &gpu::CardState| nonzero(c.mem_reserved_kib));
-                    s = add_key(s, "musekib", cards, |c: &gpu::CardState| {
+                    //s = add_key(s, "mreskib", states, |c: &gpu::CardState| nonzero(c.mem_reserved_kib));
+                    s = add_key(s, "musekib", states, |c: &gpu::CardState| {
                         nonzero(c.mem_used_kib)
                     });
-                    s = add_key(s, "cutil%", cards, |c: &gpu::CardState| {
+                    s = add_key(s, "cutil%", states, |c: &gpu::CardState| {
                         nonzero(c.gpu_utilization_pct as i64)
                     });
-                    s = add_key(s, "mutil%", cards, |c: &gpu::CardState| {
-                        nonzero(c.mem_utilization_pct as i64)
+                    // mutil% is memory bandwidth utilization, mused% is memory capacity in use.
+                    s = add_key(s, "mutil%", states, |c: &gpu::CardState| {
+                        nonzero(c.mem_bandwidth_pct as i64)
                     });
-                    s = add_key(s, "tempc", cards, |c: &gpu::CardState| {
+                    s = add_key(
+                        s,
+                        "mused%",
+                        states,
+                        |c: &gpu::CardState| match mem_size_kib(c.index) {
+                            Some(size) => nonzero(c.mem_used_kib * 100 / size),
+                            None => output::Value::E(),
+                        },
+                    );
+                    s = add_key(s, "tempc", states, |c: &gpu::CardState| {
                         nonzero(c.temp_c.into())
                     });
-                    s = add_key(s, "poww", cards, |c: &gpu::CardState| {
+                    s = add_key(s, "poww", states, |c: &gpu::CardState| {
                         nonzero(c.power_watt.into())
                     });
-                    s = add_key(s, "powlimw", cards, |c: &gpu::CardState| {
+                    s = add_key(s, "powlimw", states, |c: &gpu::CardState| {
                         nonzero(c.power_limit_watt.into())
                     });
-                    s = add_key(s, "cez", cards, |c: &gpu::CardState| {
+                    s = add_key(s, "cez", states, |c: &gpu::CardState| {
                         nonzero(c.ce_clock_mhz.into())
                     });
-                    s = add_key(s, "memz", cards, |c: &gpu::CardState| {
+                    s = add_key(s, "memz", states, |c: &gpu::CardState| {
                         nonzero(c.mem_clock_mhz.into())
                     });
                     if !s.is_empty() {
                         gpu_info = Some(s);
                     }
+                    gpu_summary = summarize_gpus(states, &cards);
+                    card_indices = states.iter().map(|c| c.index).collect();
                 }
             }
             match gpu.get_process_utilization(&user_by_pid) {
-                Err(_e) => {
+                Err(e) => {
                     gpu_status = GpuStatus::UnknownFailure;
+                    gpu_error.get_or_insert(e);
                 }
                 Ok(conf) => {
-                    gpu_utilization = conf;
+                    // The cards report host pids, which are translated to the namespace of the
+                    // process table when that is not the host's.  The user lookup in the GPU
+                    // layer was by host pid, so the table is the authority on the user then.
+                    gpu_utilization = conf
+                        .into_iter()
+                        .filter_map(|mut proc| {
+                            let pid = fs.pid_from_host(proc.pid)?;
+                            if pid != proc.pid {
+                                proc.pid = pid;
+                                (proc.user, proc.uid) = match pprocinfo_output.get(&pid) {
+                                    Some(p) => (p.user.clone(), p.uid),
+                                    None => ("_unknown_".to_string(), 1),
+                                };
+                            }
+                            Some(proc)
+                        })
+                        .collect();
                     for proc in &gpu_utilization {
-                        let (ppid, has_children) =
+                        let pgrp = pprocinfo_output.get(&proc.pid).map(|p| p.pgrp);
+                        if filter_pid(proc.pid, pgrp, print_params.opts).is_some() {
+                            continue;
+                        }
+                        // The host's process table is the authority on the command, the card
+                        // often can't know it.  The card's command, if any, is for processes that
+                        // are not in the table.
+                        let (ppid, has_children, command) =
                             if let Some(process) = pprocinfo_output.get(&proc.pid) {
-                                (process.ppid, process.has_children)
+                                (process.ppid, process.has_children, process.command.as_str())
                             } else {
-                                (1, true)
+                                (1, true, proc.command.as_deref().unwrap_or("_unknown_"))
                             };
-                        // FIXME: This is not what we want, we can do better.
-                        let command = match &proc.command {
-                            Some(cmd) => cmd,
-                            _ => "_unknown_",
-                        };
                         add_proc_info(
                             &mut proc_by_pid,
                             &mut lookup_job_by_pid,
@@ -471,17 +785,38 @@ fn do_collect_data(
                             proc.pid,
                             ppid,
                             has_children,
-                            0.0, // cpu_percentage
-                            0,   // cputime_sec
-                            0.0, // mem_percentage
-                            0,   // mem_size_kib
-                            0,   // rssanon_kib
+                            false, // is_zombie
+                            false, // is_kernel_thread
+                            0.0,   // cpu_percentage
+                            0,     // cputime_sec
+                            0.0,   // mem_percentage
+                            0,     // mem_size_kib
+                            0,     // rssanon_kib
+                            0,     // major_faults
+                            0,     // minor_faults
+                            0,     // num_threads
+                            0,     // voluntary_ctxt_switches
+                            0,     // nonvoluntary_ctxt_switches
                             &proc.devices,
                             proc.gpu_pct,
                             proc.mem_pct,
                             proc.mem_size_kib,
                         );
                     }
+                    if let Some(ref mut summary) = gpu_summary {
+                        let jobs_on_cards = gpu_utilization
+                            .iter()
+                            .filter_map(|proc| {
+                                proc_by_pid
+                                    .get(&proc.pid)
+                                    .map(|p| (p.job_id, &proc.devices))
+                            })
+                            .collect::<Vec<(usize, &gpuset::GpuSet)>>();
+                        summary.push_a(
+                            "shared_job_count",
+                            shared_job_counts(&card_indices, &jobs_on_cards),
+                        );
+                    }
                 }
             }
         }
@@ -501,6 +836,144 @@ fn do_collect_data(
         }
     }
 
+    // Container information is best-effort and costly, so only if requested.  Processes that are
+    // not in a container, or whose cgroup information has vanished, are left alone.
+
+    if print_params.opts.containers {
+        let mut container_table = containers::ContainerTable::new(containers);
+        for proc_info in proc_by_pid.values_mut() {
+            if let Ok(cgroup) = fs.read_to_string(&format!("{}/cgroup", proc_info.pid)) {
+                if let Some(c) = containers::container_from_cgroup(&cgroup) {
+                    proc_info.container_image = container_table.image(&c);
+                    proc_info.container_runtime = Some(c.runtime);
+                }
+            }
+        }
+    }
+
+    // The environment of other users' processes is usually not readable, in which case the process
+    // simply gets no variables.
+
+    if !print_params.opts.env_allowlist.is_empty() {
+        for proc_info in proc_by_pid.values_mut() {
+            if let Ok(environ) = fs.read_to_string(&format!("{}/environ", proc_info.pid)) {
+                proc_info.env = filter_environ(&environ, &print_params.opts.env_allowlist);
+            }
+        }
+    }
+
+    // The GPUs a process may use are in its environment, which is usually not readable for other
+    // users' processes, and in any case it is only known for processes that run on a GPU node.
+
+    if print_params.opts.gpu_affinity && has_gpus {
+        for proc_info in proc_by_pid.values_mut() {
+            if let Ok(environ) = fs.read_to_string(&format!("{}/environ", proc_info.pid)) {
+                proc_info.gpus_allowed = gpus_allowed_from_environ(&environ, &cards);
+            }
+        }
+    }
+
+    // The IO counters can be read only for sonar's own user's processes unless sonar is privileged.
+
+    if print_params.opts.io {
+        for proc_info in proc_by_pid.values_mut() {
+            proc_info.io = procfs::get_process_io(fs, proc_info.pid);
+        }
+    }
+
+    // Like the IO counters, smaps_rollup can be read only for sonar's own user's processes unless
+    // sonar is privileged.  Unreadable processes get zero.
+
+    if print_params.opts.hugepages {
+        for proc_info in proc_by_pid.values_mut() {
+            proc_info.anon_huge_kib =
+                procfs::get_anon_hugepages_kib(fs, proc_info.pid).unwrap_or_default();
+        }
+    }
+
+    // The kernel's OOM-killer badness scores, which are cheap to read.  Processes that have
+    // vanished get no scores.
+
+    if print_params.opts.oom_score {
+        for proc_info in proc_by_pid.values_mut() {
+            proc_info.oom_score = procfs::get_proc_int(fs, proc_info.pid, "oom_score");
+            proc_info.oom_score_adj = procfs::get_proc_int(fs, proc_info.pid, "oom_score_adj");
+        }
+    }
+
+    // Established TCP connections are attributed to processes through the socket inodes of their
+    // fds.  The connections are per network namespace, and processes in containers need not share
+    // sonar's, so the table of each namespace is read once, through a process in it.  The fds and
+    // namespaces of other users' processes are usually not readable, those processes get no count.
+
+    if print_params.opts.tcp_connections {
+        let mut inodes_by_ns = HashMap::<String, HashSet<u64>>::new();
+        for proc_info in proc_by_pid.values_mut() {
+            let pid = proc_info.pid;
+            let Some(ns) = procfs::get_net_namespace(fs, pid) else {
+                continue;
+            };
+            if !inodes_by_ns.contains_key(&ns) {
+                match procfs::get_established_tcp_inodes(fs, pid) {
+                    Some(inodes) => inodes_by_ns.insert(ns.clone(), inodes),
+                    None => continue,
+                };
+            }
+            proc_info.tcp_connections = procfs::count_process_sockets(fs, pid, &inodes_by_ns[&ns]);
+        }
+    }
+
+    // Job queue information is costly and is looked up once per job, if requested.  Jobs with ID 0
+    // are not in the queue.
+    //
+    // The window of a job's reservation is looked up once per reservation, as several jobs may run
+    // in the same reservation.
+
+    if print_params.opts.job_info {
+        let mut info_by_job = HashMap::<JobID, Option<jobs::JobInfo>>::new();
+        let mut reservations = HashMap::<String, Option<jobs::Reservation>>::new();
+        for proc_info in proc_by_pid.values_mut() {
+            if proc_info.job_id != 0 {
+                proc_info.job_info = info_by_job
+                    .entry(proc_info.job_id)
+                    .or_insert_with(|| {
+                        let mut info = jobs.job_info(proc_info.job_id)?;
+                        if !info.reservation.is_empty() {
+                            let reservation = reservations
+                                .entry(info.reservation.clone())
+                                .or_insert_with(|| jobs.reservation(&info.reservation));
+                            if let Some(r) = reservation {
+                                info.reservation_start = r.start_time.clone();
+                                info.reservation_end = r.end_time.clone();
+                            }
+                        }
+                        Some(info)
+                    })
+                    .clone();
+            }
+        }
+    }
+
+    // The job's cgroup has the authoritative CPU time for the job, which is read once per job from
+    // the cgroup of any of the job's processes.  A process whose cgroup can't be read, eg because
+    // it has exited, does not stop the next process of the job from being tried.
+
+    if print_params.opts.job_cgroup_cpu {
+        let mut usec_by_job = HashMap::<JobID, u64>::new();
+        for proc_info in proc_by_pid.values() {
+            if proc_info.job_id != 0 && !usec_by_job.contains_key(&proc_info.job_id) {
+                if let Some(usec) =
+                    procfs::get_job_cgroup_cpu_usec(fs, proc_info.pid, proc_info.job_id)
+                {
+                    usec_by_job.insert(proc_info.job_id, usec);
+                }
+            }
+        }
+        for proc_info in proc_by_pid.values_mut() {
+            proc_info.job_cpu_usec = usec_by_job.get(&proc_info.job_id).copied();
+        }
+    }
+
     if interrupt::is_interrupted() {
         return Ok(output::Value::E());
     }
@@ -522,10 +995,10 @@ fn do_collect_data(
         //   entry in `rolledup` is a copy of that job
         //
         // - Otherwise, the entry in `rolledup` represent rolled-up information for a
-        //   (jobid,ppid,command) triple
+        //   (jobid,ppid,command,is_zombie) tuple; zombies are never merged with live processes
         //
-        // - There is a hash table `index` that maps the (jobid,ppid,command) triple to the entry in
-        //   `rolledup`, if any
+        // - There is a hash table `index` that maps the (jobid,ppid,command,is_zombie) tuple to the
+        //   entry in `rolledup`, if any
         //
         // - When we're done rolling up, we print the `rolledup` table.
         //
@@ -534,23 +1007,33 @@ fn do_collect_data(
         // is probably the right thing.
 
         let mut rolledup = vec![];
-        let mut index = HashMap::<(JobID, Pid, &str), usize>::new();
-        for proc_info in proc_by_pid.values() {
+        let mut index = HashMap::<(JobID, Pid, &str, bool), usize>::new();
+        let mut saturated = false;
+        // The first process of a group provides the pid etc of the rolled-up record, with --sort
+        // it should not depend on the hash table's order.
+        let mut procs = proc_by_pid.values().collect::<Vec<&ProcInfo>>();
+        if print_params.opts.sort {
+            procs.sort_by_key(|p| p.pid);
+        }
+        for proc_info in procs {
             if proc_info.job_id == 0 || proc_info.has_children {
                 rolledup.push(proc_info.clone());
             } else {
-                let key = (proc_info.job_id, proc_info.ppid, proc_info.command);
+                let key = (
+                    proc_info.job_id,
+                    proc_info.ppid,
+                    proc_info.command,
+                    proc_info.is_zombie,
+                );
                 if let Some(x) = index.get(&key) {
                     let p = &mut rolledup[*x];
-                    p.cpu_percentage += proc_info.cpu_percentage;
-                    p.cputime_sec += proc_info.cputime_sec;
-                    p.mem_percentage += proc_info.mem_percentage;
-                    p.mem_size_kib += proc_info.mem_size_kib;
-                    p.rssanon_kib += proc_info.rssanon_kib;
+                    if add_counters(p, proc_info) {
+                        saturated = true;
+                    }
                     gpuset::union_gpuset(&mut p.gpu_cards, &proc_info.gpu_cards);
-                    p.gpu_percentage += proc_info.gpu_percentage;
-                    p.gpu_mem_percentage += proc_info.gpu_mem_percentage;
-                    p.gpu_mem_size_kib += proc_info.gpu_mem_size_kib;
+                    p.oom_score = p.oom_score.max(proc_info.oom_score);
+                    p.oom_score_adj = p.oom_score_adj.max(proc_info.oom_score_adj);
+                    union_gpus_allowed(&mut p.gpus_allowed, &proc_info.gpus_allowed);
                     p.rolledup += 1;
                 } else {
                     let x = rolledup.len();
@@ -562,7 +1045,12 @@ fn do_collect_data(
                 }
             }
         }
+        if saturated {
+            log::warning("Counters overflowed during rollup, values were clamped");
+        }
         rolledup
+    } else if print_params.opts.aggregate_by_command {
+        aggregate_by_command(proc_by_pid)
     } else {
         proc_by_pid
             .drain()
@@ -570,18 +1058,125 @@ fn do_collect_data(
             .collect::<Vec<ProcInfo>>()
     };
 
-    let candidates = candidates
+    let mut candidates = candidates
         .drain(0..)
-        .filter(|proc_info| filter_proc(proc_info, print_params))
+        .filter(|proc_info| match filter_proc(proc_info, print_params) {
+            None => true,
+            Some(reason) => {
+                if print_params.opts.explain_filters {
+                    *excluded.entry(reason).or_default() += 1;
+                }
+                false
+            }
+        })
         .collect::<Vec<ProcInfo>>();
 
+    // The table order is arbitrary and differs between runs.  Aggregated records all have pid 0 and
+    // are distinguished by the rest of the key.
+    if print_params.opts.sort {
+        candidates.sort_by(|a, b| {
+            (a.pid, a.command, a.is_zombie, a.user).cmp(&(b.pid, b.command, b.is_zombie, b.user))
+        });
+    }
+
+    // The job's cgroup CPU time is for the whole job, so it is on the job's first record only, lest
+    // the consumer add it up across the records.
     let mut records: Vec<output::Object> = vec![];
-    for c in candidates {
+    let mut jobs_with_cpu = HashSet::<JobID>::new();
+    for mut c in candidates {
+        if c.job_cpu_usec.is_some() && !jobs_with_cpu.insert(c.job_id) {
+            c.job_cpu_usec = None;
+        }
         records.push(generate_candidate(&c, print_params));
     }
 
+    // A GPU failure is flagged on the process records with `gpufail` and is also reported with its
+    // cause on a record of its own, which is present even if all the processes are filtered out.
+
+    if let Some(ref msg) = gpu_error {
+        let mut hb = make_heartbeat(print_params);
+        errors::Error::new(
+            errors::ErrorCode::GpuProbeFailure,
+            format!("Could not obtain GPU data: {msg}"),
+        )
+        .add_to(&mut hb);
+        records.push(hb);
+    }
+
+    // Above the threshold the D-state count is reported as a health error on a record of its own,
+    // the data are still good.
+
+    if let Some(threshold) = print_params.opts.d_state_warn {
+        if procs_dstate > threshold {
+            let mut hb = make_heartbeat(print_params);
+            errors::Error::new(
+                errors::ErrorCode::Health,
+                format!(
+                    "{procs_dstate} processes in uninterruptible sleep, threshold is {threshold}"
+                ),
+            )
+            .add_to(&mut hb);
+            records.push(hb);
+        }
+    }
+
+    // A user lookup that timed out is reported on a record of its own, the data are still good but
+    // some user names are missing.
+
+    if let Some(ref msg) = user_lookup_error {
+        let mut hb = make_heartbeat(print_params);
+        errors::Error::new(errors::ErrorCode::System, msg.clone()).add_to(&mut hb);
+        records.push(hb);
+    }
+
+    // Likewise a cluster name that disagrees with the job queue's is reported on a record of its
+    // own, as the data may be attributed to the wrong cluster downstream.
+
+    if let Some(ref expected) = print_params.opts.expect_cluster {
+        if let Some(actual) = jobs.cluster_name() {
+            if actual != *expected {
+                let mut hb = make_heartbeat(print_params);
+                errors::Error::new(
+                    errors::ErrorCode::Config,
+                    format!("Cluster name is {expected} but the job queue reports {actual}"),
+                )
+                .add_to(&mut hb);
+                records.push(hb);
+            }
+        }
+    }
+
+    let collect_ms = start.elapsed().as_millis() as u64;
+    let uptime_secs = fs
+        .now_in_secs_since_epoch()
+        .saturating_sub(system_stat.boot_time);
+    // The load averages are cheap and always useful, so unlike the per-cpu load they do not depend
+    // on --load.
+    let loadavg = procfs::get_loadavg(fs);
+    // Ditto the fault and swap counters, from which the consumer computes rates to detect
+    // thrashing.
+    let vmstat = procfs::get_vmstat(fs);
+
     if print_params.flat_data {
-        if print_params.opts.load && records.len() > 0{
+        if !records.is_empty() {
+            records[0].push_u("collect_ms", collect_ms);
+            records[0].push_u("uptime_secs", uptime_secs);
+            if let Some(ref loadavg) = loadavg {
+                push_loadavg(&mut records[0], loadavg);
+            }
+            if let Some(ref vmstat) = vmstat {
+                push_vmstat(&mut records[0], vmstat);
+            }
+            if let Some(limit_kib) = mem_limit_kib {
+                records[0].push_u("mem_limit_kib", limit_kib as u64);
+            }
+            // The cpu profile does not collect the memory fields and context switch counts of the
+            // processes, so that their absence does not mean zero.
+            if print_params.opts.profile == procfs::Profile::Cpu {
+                records[0].push_s("profile", "cpu".to_string());
+            }
+        }
+        if print_params.opts.load && records.len() > 0 {
             if !per_cpu_secs.is_empty() {
                 let mut a = output::Array::from_vec(
                     per_cpu_secs
@@ -592,9 +1187,26 @@ fn do_collect_data(
                 a.set_encode_nonempty_base45();
                 records[0].push_a("load", a);
             }
+            if let Some(offline) = offline_cpus(&system_stat.offline_cpus) {
+                records[0].push_s("offline_cpus", offline);
+            }
+            records[0].push_u("procs_running", system_stat.procs_running);
+            records[0].push_u("procs_blocked", system_stat.procs_blocked);
+            records[0].push_u("procs_dstate", procs_dstate as u64);
+            if let Some(energy) = energy_object(fs) {
+                records[0].push_o("energy_uj", energy);
+            }
             if let Some(info) = gpu_info {
                 records[0].push_o("gpuinfo", info);
             }
+            if let Some(summary) = gpu_summary {
+                records[0].push_o("gpusummary", summary);
+            }
+        }
+
+        // There is no envelope to hold the explanations, so they go to stderr.
+        for (reason, count) in excluded {
+            log::info(&format!("Excluded {count} record(s) by {reason}"));
         }
 
         let mut result = output::Array::new();
@@ -605,8 +1217,25 @@ fn do_collect_data(
     } else {
         let mut datum = output::Object::new();
         datum.push_s("v", print_params.version.to_string());
+        output::push_schema(&mut datum, output::PS_SCHEMA_VERSION);
         datum.push_s("time", print_params.timestamp.to_string());
         datum.push_s("host", print_params.hostname.to_string());
+        output::push_tags(&mut datum, &print_params.opts.tags);
+        output::push_token(&mut datum, &print_params.opts.token);
+        datum.push_u("collect_ms", collect_ms);
+        datum.push_u("uptime_secs", uptime_secs);
+        if let Some(ref loadavg) = loadavg {
+            push_loadavg(&mut datum, loadavg);
+        }
+        if let Some(ref vmstat) = vmstat {
+            push_vmstat(&mut datum, vmstat);
+        }
+        if let Some(limit_kib) = mem_limit_kib {
+            datum.push_u("mem_limit_kib", limit_kib as u64);
+        }
+        if print_params.opts.profile == procfs::Profile::Cpu {
+            datum.push_s("profile", "cpu".to_string());
+        }
         if print_params.opts.load {
             if !per_cpu_secs.is_empty() {
                 let a = output::Array::from_vec(
@@ -617,36 +1246,367 @@ fn do_collect_data(
                 );
                 datum.push_a("load", a);
             }
+            if let Some(offline) = offline_cpus(&system_stat.offline_cpus) {
+                datum.push_s("offline_cpus", offline);
+            }
+            datum.push_u("procs_running", system_stat.procs_running);
+            datum.push_u("procs_blocked", system_stat.procs_blocked);
+            datum.push_u("procs_dstate", procs_dstate as u64);
+            if let Some(energy) = energy_object(fs) {
+                datum.push_o("energy_uj", energy);
+            }
             if let Some(info) = gpu_info {
                 datum.push_o("gpuinfo", info);
             }
+            if let Some(summary) = gpu_summary {
+                datum.push_o("gpusummary", summary);
+            }
+        }
+        if print_params.opts.intern_strings {
+            let mut table = output::StringTable::new();
+            for o in records.iter_mut() {
+                o.intern_field("user", "user_ix", &mut table);
+                o.intern_field("cmd", "cmd_ix", &mut table);
+            }
+            datum.push_a("strings", table.into_array());
         }
         let mut samples = output::Array::new();
         for o in records {
             samples.push_o(o);
         }
         datum.push_a("samples", samples);
+        if !excluded.is_empty() {
+            let mut a = output::Array::new();
+            for (reason, count) in excluded {
+                let mut e = output::Object::new();
+                e.push_s("reason", reason.to_string());
+                e.push_u("count", count);
+                a.push_o(e);
+            }
+            datum.push_a("excluded", a);
+        }
         Ok(output::Value::O(datum))
     }
 }
 
+// Aggregation by command is for node-level profiling and is unlike rollup: all processes with the
+// same command name are merged regardless of job, user, and parent.  The aggregate has no pid, ppid
+// or job, its `rolledup` field is the number of processes merged into it, as for rollup, and its
+// user is "_mixed_" if the processes did not all have the same user.  Zombies are aggregated
+// separately from live processes.  There is no meaningful process tree after this.
+
+fn aggregate_by_command(mut proc_by_pid: ProcTable) -> Vec<ProcInfo> {
+    let mut aggregated: Vec<ProcInfo> = vec![];
+    let mut index = HashMap::<(&str, bool), usize>::new();
+    let mut saturated = false;
+    for (_, proc_info) in proc_by_pid.drain() {
+        let key = (proc_info.command, proc_info.is_zombie);
+        if let Some(x) = index.get(&key) {
+            let p = &mut aggregated[*x];
+            if p.user != proc_info.user {
+                p.user = "_mixed_";
+            }
+            p.is_system_job = p.is_system_job && proc_info.is_system_job;
+            p.is_kernel_thread = p.is_kernel_thread && proc_info.is_kernel_thread;
+            if add_counters(p, &proc_info) {
+                saturated = true;
+            }
+            gpuset::union_gpuset(&mut p.gpu_cards, &proc_info.gpu_cards);
+            p.oom_score = p.oom_score.max(proc_info.oom_score);
+            p.oom_score_adj = p.oom_score_adj.max(proc_info.oom_score_adj);
+            union_gpus_allowed(&mut p.gpus_allowed, &proc_info.gpus_allowed);
+            if proc_info.gpu_status != GpuStatus::Ok {
+                p.gpu_status = proc_info.gpu_status;
+            }
+            p.rolledup += 1;
+        } else {
+            index.insert(key, aggregated.len());
+            aggregated.push(ProcInfo {
+                pid: 0,
+                ppid: 0,
+                job_id: 0,
+                has_children: false,
+                container_runtime: None,
+                container_image: None,
+                job_info: None,
+                env: vec![],
+                job_cpu_usec: None,
+                ..proc_info
+            });
+        }
+    }
+    if saturated {
+        log::warning("Counters overflowed during aggregation, values were clamped");
+    }
+    aggregated
+}
+
+// Add the counters of `q` into `p` when merging records.  The integer counters can in principle
+// overflow when many long-running processes are merged, they saturate rather than wrap around (or
+// panic, in debug builds).  Return true if any counter saturated.
+
+fn add_counters(p: &mut ProcInfo, q: &ProcInfo) -> bool {
+    let mut saturated = false;
+    let mut add = |x: &mut usize, y: usize| match x.checked_add(y) {
+        Some(v) => *x = v,
+        None => {
+            *x = usize::MAX;
+            saturated = true;
+        }
+    };
+    add(&mut p.cputime_sec, q.cputime_sec);
+    add(&mut p.mem_size_kib, q.mem_size_kib);
+    add(&mut p.rssanon_kib, q.rssanon_kib);
+    add(&mut p.major_faults, q.major_faults);
+    add(&mut p.minor_faults, q.minor_faults);
+    add(&mut p.num_threads, q.num_threads);
+    add(&mut p.voluntary_ctxt_switches, q.voluntary_ctxt_switches);
+    add(
+        &mut p.nonvoluntary_ctxt_switches,
+        q.nonvoluntary_ctxt_switches,
+    );
+    add(&mut p.gpu_mem_size_kib, q.gpu_mem_size_kib);
+    add(&mut p.anon_huge_kib, q.anon_huge_kib);
+    let mut add_u64 = |x: &mut u64, y: u64| match x.checked_add(y) {
+        Some(v) => *x = v,
+        None => {
+            *x = u64::MAX;
+            saturated = true;
+        }
+    };
+    if let Some(qio) = q.io {
+        let pio = p.io.get_or_insert_with(Default::default);
+        add_u64(&mut pio.rchar, qio.rchar);
+        add_u64(&mut pio.wchar, qio.wchar);
+        add_u64(&mut pio.read_bytes, qio.read_bytes);
+        add_u64(&mut pio.write_bytes, qio.write_bytes);
+    }
+    if let Some(n) = q.tcp_connections {
+        let sum = p.tcp_connections.get_or_insert(0);
+        add_u64(sum, n);
+    }
+    p.cpu_times.user_sec += q.cpu_times.user_sec;
+    p.cpu_times.system_sec += q.cpu_times.system_sec;
+    p.cpu_times.child_user_sec += q.cpu_times.child_user_sec;
+    p.cpu_times.child_system_sec += q.cpu_times.child_system_sec;
+    p.cpu_percentage += q.cpu_percentage;
+    p.mem_percentage += q.mem_percentage;
+    p.gpu_percentage += q.gpu_percentage;
+    p.gpu_mem_percentage += q.gpu_mem_percentage;
+    saturated
+}
+
+// /proc/{pid}/environ is a sequence of NUL-terminated NAME=value strings.  Return the ones whose
+// names are in the allowlist, in the order they appear.
+
+fn filter_environ(environ: &str, allowlist: &[&str]) -> Vec<(String, String)> {
+    environ
+        .split('\0')
+        .filter_map(|var| var.split_once('='))
+        .filter(|(name, _)| allowlist.contains(name))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+// The variables that name the GPUs a process may use, in order of precedence.  Slurm's variables
+// hold the node's card indices.  The CUDA and ROCm variables hold indices or UUIDs, but the indices
+// are relative to the cards the process can see, which are the node's cards unless the job's cgroup
+// constrains the devices, as Slurm can do, in which case Slurm's variables are also set.
+
+const GPU_AFFINITY_VARIABLES: [&str; 4] = [
+    "SLURM_STEP_GPUS",
+    "SLURM_JOB_GPUS",
+    "CUDA_VISIBLE_DEVICES",
+    "ROCR_VISIBLE_DEVICES",
+];
+
+// Return the sorted indices of the GPUs the process may use according to its environment, or an
+// empty vector if that's not known.  UUIDs (and UUID prefixes, as CUDA allows) are mapped to card
+// indices with `cards`; entries that can't be mapped, such as MIG instances, are ignored.
+
+fn gpus_allowed_from_environ(environ: &str, cards: &[gpu::Card]) -> Vec<usize> {
+    let vars = filter_environ(environ, &GPU_AFFINITY_VARIABLES);
+    let value = match GPU_AFFINITY_VARIABLES
+        .iter()
+        .find_map(|name| vars.iter().find(|(n, _)| n == name))
+    {
+        Some((_, value)) => value,
+        None => return vec![],
+    };
+    let mut indices = vec![];
+    for dev in value.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+        if let Ok(index) = dev.parse::<usize>() {
+            indices.push(index);
+        } else if let Some(card) = cards
+            .iter()
+            .find(|c| !c.uuid.is_empty() && c.uuid.starts_with(dev))
+        {
+            if card.index >= 0 {
+                indices.push(card.index as usize);
+            }
+        }
+    }
+    indices.sort();
+    indices.dedup();
+    indices
+}
+
+fn union_gpus_allowed(lhs: &mut Vec<usize>, rhs: &[usize]) {
+    lhs.extend_from_slice(rhs);
+    lhs.sort();
+    lhs.dedup();
+}
+
+// The offline cpus as a comma-separated list of indices, if there are any.  Their slots in `load`,
+// if any, are zero.
+
+fn offline_cpus(cpus: &[usize]) -> Option<String> {
+    if cpus.is_empty() {
+        None
+    } else {
+        Some(
+            cpus.iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+        )
+    }
+}
+
+fn push_loadavg(o: &mut output::Object, loadavg: &procfs::LoadAvg) {
+    o.push_f("load1", loadavg.load1);
+    o.push_f("load5", loadavg.load5);
+    o.push_f("load15", loadavg.load15);
+    o.push_u("runnable", loadavg.runnable);
+    o.push_u("existing", loadavg.existing);
+}
+
+fn push_vmstat(o: &mut output::Object, vmstat: &procfs::VmStat) {
+    o.push_u("pgmajfault", vmstat.pgmajfault);
+    o.push_u("pswpin", vmstat.pswpin);
+    o.push_u("pswpout", vmstat.pswpout);
+}
+
+// The node's cumulative energy counters by domain, if there are any.
+
+fn energy_object(fs: &dyn procfsapi::ProcfsAPI) -> Option<output::Object> {
+    let counters = procfs::get_energy_counters(fs);
+    if counters.is_empty() {
+        return None;
+    }
+    let mut energy = output::Object::new();
+    for (domain, uj) in counters {
+        energy.push_u(&domain, uj);
+    }
+    Some(energy)
+}
+
+// Node-level GPU pressure: the number of cards, the total memory in use across them, their average
+// compute and memory bandwidth utilization, and the share of their memory capacity in use if the
+// card configuration has the memory size of all of them.  None if there are no cards.
+
+fn summarize_gpus(cards: &[gpu::CardState], config: &[gpu::Card]) -> Option<output::Object> {
+    if cards.is_empty() {
+        return None;
+    }
+    let n = cards.len() as f64;
+    let mut summary = output::Object::new();
+    summary.push_u("cards", cards.len() as u64);
+    summary.push_i("musekib", cards.iter().map(|c| c.mem_used_kib).sum());
+    summary.push_f(
+        "cutil%",
+        three_places(
+            cards
+                .iter()
+                .map(|c| c.gpu_utilization_pct as f64)
+                .sum::<f64>()
+                / n,
+        ),
+    );
+    summary.push_f(
+        "mutil%",
+        three_places(
+            cards
+                .iter()
+                .map(|c| c.mem_bandwidth_pct as f64)
+                .sum::<f64>()
+                / n,
+        ),
+    );
+    let sizes = cards
+        .iter()
+        .map(|c| {
+            config
+                .iter()
+                .find(|k| k.index == c.index)
+                .map(|k| k.mem_size_kib)
+                .filter(|kib| *kib > 0)
+        })
+        .collect::<Option<Vec<i64>>>();
+    if let Some(sizes) = sizes {
+        let used = cards.iter().map(|c| c.mem_used_kib).sum::<i64>() as f64;
+        let total = sizes.iter().sum::<i64>() as f64;
+        summary.push_f("mused%", three_places(used * 100.0 / total));
+    }
+    Some(summary)
+}
+
+// The number of distinct jobs with processes on each card, positioned by card index like the values
+// of add_key.  More than one means the card is shared between jobs, whether through MPS or plain
+// concurrent use.  Processes not in a job, and processes on unknown cards, are not counted.
+
+fn shared_job_counts(
+    card_indices: &[i32],
+    jobs_on_cards: &[(usize, &gpuset::GpuSet)],
+) -> output::Array {
+    let mut jobs_by_card: HashMap<usize, HashSet<usize>> = HashMap::new();
+    for (job_id, devices) in jobs_on_cards {
+        if *job_id == 0 {
+            continue;
+        }
+        if let Some(devices) = devices {
+            for dev in devices {
+                jobs_by_card.entry(*dev).or_default().insert(*job_id);
+            }
+        }
+    }
+    let mut values = vec![];
+    for index in card_indices {
+        let ix = (*index).max(0) as usize;
+        if values.len() <= ix {
+            values.resize_with(ix + 1, output::Value::E);
+        }
+        values[ix] = output::Value::U(jobs_by_card.get(&ix).map(|j| j.len()).unwrap_or(0) as u64);
+    }
+    let mut vs = output::Array::from_vec(values);
+    vs.set_csv_separator("|".to_string());
+    vs
+}
+
 fn add_key<'a>(
     mut s: output::Object,
     key: &str,
     cards: &[gpu::CardState],
-    extract: fn(&gpu::CardState) -> output::Value,
+    extract: impl Fn(&gpu::CardState) -> output::Value,
 ) -> output::Object {
-    let mut vs = output::Array::new();
+    // The values are positioned by card index.  The indices are tightly packed unless some cards
+    // have been excluded, and the excluded cards get empty values.
+    let mut values = vec![];
     let mut any_nonempty = false;
-    vs.set_csv_separator("|".to_string());
     for c in cards {
         let v = extract(c);
         if let output::Value::E() = v {
         } else {
             any_nonempty = true;
         }
-        vs.push(v);
+        let ix = c.index.max(0) as usize;
+        if values.len() <= ix {
+            values.resize_with(ix + 1, output::Value::E);
+        }
+        values[ix] = v;
     }
+    let mut vs = output::Array::from_vec(values);
+    vs.set_csv_separator("|".to_string());
     if any_nonempty {
         s.push(key, output::Value::A(vs));
     }
@@ -661,18 +1621,36 @@ fn nonzero(x: i64) -> output::Value {
     }
 }
 
-fn filter_proc(proc_info: &ProcInfo, params: &PrintParameters) -> bool {
-    let mut included = false;
+// Return None if the process is not excluded by --exclude-pids or --exclude-pgrps, otherwise the
+// name of the filter that excluded it.  The process group is None for processes that are not in the
+// process table, ie are known only to the GPU.
+
+fn filter_pid(pid: Pid, pgrp: Option<Pid>, opts: &PsOptions) -> Option<&'static str> {
+    if opts.exclude_pids.contains(&pid) {
+        return Some("exclude-pids");
+    }
+    if matches!(pgrp, Some(pgrp) if opts.exclude_pgrps.contains(&pgrp)) {
+        return Some("exclude-pgrps");
+    }
+    None
+}
+
+// Return None if the record passes the filters, otherwise the name of the filter that excluded it.
+
+fn filter_proc<'a>(proc_info: &ProcInfo<'a>, params: &PrintParameters) -> Option<&'static str> {
+    let mut excluded_by = None;
 
     // The logic here is that if any of the inclusion filters are provided, then the set of those
     // that are provided constitute the entire inclusion filter, and the record must pass at least
     // one of those to be included.  Otherwise, when none of the filters are provided then the
     // record is included by default.
 
-    if params.opts.min_cpu_percent.is_some()
+    let other_inclusion_filters = params.opts.min_cpu_percent.is_some()
         || params.opts.min_mem_percent.is_some()
         || params.opts.min_cpu_time.is_some()
-    {
+        || params.opts.min_threads.is_some();
+    if other_inclusion_filters {
+        let mut included = false;
         if let Some(cpu_cutoff_percent) = params.opts.min_cpu_percent {
             if proc_info.cpu_percentage >= cpu_cutoff_percent {
                 included = true;
@@ -688,36 +1666,74 @@ fn filter_proc(proc_info: &ProcInfo, params: &PrintParameters) -> bool {
                 included = true;
             }
         }
-    } else {
-        included = true;
+        if let Some(threads_cutoff) = params.opts.min_threads {
+            if proc_info.num_threads >= threads_cutoff {
+                included = true;
+            }
+        }
+        if !included {
+            excluded_by = Some("min-cutoffs");
+        }
+    }
+
+    // The GPU filter is an inclusion filter too, but it only has an opinion about processes that
+    // use a GPU: CPU-only processes are judged by the other filters alone.  A process that uses a
+    // GPU but is below the cutoff is excluded unless one of the other filters admitted it.
+
+    if let Some(gpu_cutoff_percent) = params.opts.min_gpu_percent {
+        let uses_gpu = !matches!(&proc_info.gpu_cards, Some(cards) if cards.is_empty());
+        if uses_gpu {
+            if proc_info.gpu_percentage >= gpu_cutoff_percent {
+                excluded_by = None;
+            } else if !other_inclusion_filters {
+                excluded_by = Some("min-gpu-percent");
+            }
+        }
+    }
+    if excluded_by.is_some() {
+        return excluded_by;
     }
 
     // The exclusion filters apply after the inclusion filters and the record must pass all of the
     // ones that are provided.
 
     if params.opts.exclude_system_jobs && proc_info.is_system_job {
-        included = false;
+        return Some("exclude-system-jobs");
     }
-    if !params.opts.exclude_users.is_empty()
-        && params
-            .opts
-            .exclude_users
-            .iter()
-            .any(|x| *x == proc_info.user)
-    {
-        included = false;
+    if params.opts.exclude_kernel_threads && proc_info.is_kernel_thread {
+        return Some("exclude-kernel-threads");
+    }
+    // The GPU data have been merged into the records by now.
+    if params.opts.gpu_only && proc_info.gpu_percentage == 0.0 && proc_info.gpu_mem_size_kib == 0 {
+        return Some("gpu-only");
+    }
+    // With case-insensitive filters the filter terms have already been lowercased.
+    let filter_key = |s: &'a str| -> Cow<'a, str> {
+        if params.opts.case_insensitive_filters {
+            Cow::Owned(s.to_lowercase())
+        } else {
+            Cow::Borrowed(s)
+        }
+    };
+    if !params.opts.exclude_users.is_empty() {
+        let user = filter_key(proc_info.user);
+        if params.opts.exclude_users.iter().any(|x| *x == user) {
+            return Some("exclude-users");
+        }
     }
-    if !params.opts.exclude_commands.is_empty()
-        && params
+    if !params.opts.exclude_commands.is_empty() {
+        let command = filter_key(proc_info.command);
+        if params
             .opts
             .exclude_commands
             .iter()
-            .any(|x| proc_info.command.starts_with(x))
-    {
-        included = false;
+            .any(|x| command.starts_with(x))
+        {
+            return Some("exclude-commands");
+        }
     }
 
-    included
+    None
 }
 
 struct PrintParameters<'a> {
@@ -733,13 +1749,26 @@ fn generate_candidate(proc_info: &ProcInfo, print_params: &PrintParameters) -> o
 
     if print_params.flat_data {
         fields.push_s("v", print_params.version.to_string());
+        output::push_schema(&mut fields, output::PS_SCHEMA_VERSION);
         fields.push_s("time", print_params.timestamp.to_string());
         fields.push_s("host", print_params.hostname.to_string());
+        output::push_tags(&mut fields, &print_params.opts.tags);
+        output::push_token(&mut fields, &print_params.opts.token);
     }
 
     fields.push_s("user", proc_info.user.to_string());
-    fields.push_s("cmd", proc_info.command.to_string());
-
+    // Truncation is for the output only, rollup and the filters have used the full command name.
+    let command = match print_params.opts.max_command_length {
+        Some(max) => truncate_chars(proc_info.command, max),
+        None => proc_info.command.to_string(),
+    };
+    if proc_info.is_zombie && !print_params.opts.no_defunct_suffix {
+        // This tag is used by older consumers but it's an artifact of `ps`, not the kernel.
+        fields.push_s("cmd", command + " <defunct>");
+    } else {
+        fields.push_s("cmd", command);
+    }
+
     // Only print optional fields whose values are not their defaults.  The defaults are defined in
     // README.md.  The values there must agree with those used by Jobanalyzer's parser.
 
@@ -792,23 +1821,121 @@ fn generate_candidate(proc_info: &ProcInfo, print_params: &PrintParameters) -> o
     if proc_info.cputime_sec != 0 {
         fields.push_u("cputime_sec", proc_info.cputime_sec as u64);
     }
+    for (name, secs) in [
+        ("utime_sec", proc_info.cpu_times.user_sec),
+        ("stime_sec", proc_info.cpu_times.system_sec),
+        ("cutime_sec", proc_info.cpu_times.child_user_sec),
+        ("cstime_sec", proc_info.cpu_times.child_system_sec),
+    ] {
+        if secs != 0.0 {
+            fields.push_f(name, three_places(secs));
+        }
+    }
+    if let Some(usec) = proc_info.job_cpu_usec {
+        fields.push_u("job_cputime_sec", usec / 1_000_000);
+    }
+    if proc_info.major_faults != 0 {
+        fields.push_u("majflt", proc_info.major_faults as u64);
+    }
+    if proc_info.minor_faults != 0 {
+        fields.push_u("minflt", proc_info.minor_faults as u64);
+    }
+    if proc_info.voluntary_ctxt_switches != 0 {
+        fields.push_u("nvcsw", proc_info.voluntary_ctxt_switches as u64);
+    }
+    if proc_info.nonvoluntary_ctxt_switches != 0 {
+        fields.push_u("nivcsw", proc_info.nonvoluntary_ctxt_switches as u64);
+    }
     if proc_info.gpu_status != GpuStatus::Ok {
         fields.push_u("gpufail", proc_info.gpu_status as u64);
     }
     if proc_info.rolledup > 0 {
         fields.push_u("rolledup", proc_info.rolledup as u64);
     }
+    if proc_info.is_zombie {
+        fields.push_u("zombie", 1);
+    }
+    if let Some(score) = proc_info.oom_score {
+        fields.push_i("oom_score", score);
+    }
+    if let Some(adj) = proc_info.oom_score_adj {
+        fields.push_i("oom_score_adj", adj);
+    }
+    if let Some(io) = proc_info.io {
+        for (name, value) in [
+            ("rchar", io.rchar),
+            ("wchar", io.wchar),
+            ("read_bytes", io.read_bytes),
+            ("write_bytes", io.write_bytes),
+        ] {
+            if value != 0 {
+                fields.push_u(name, value);
+            }
+        }
+    }
+    if proc_info.anon_huge_kib != 0 {
+        fields.push_u("anonhugekib", proc_info.anon_huge_kib as u64);
+    }
+    if !proc_info.gpus_allowed.is_empty() {
+        fields.push_s(
+            "gpus_allowed",
+            proc_info
+                .gpus_allowed
+                .iter()
+                .map(|num| num.to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+        );
+    }
+    if let Some(n) = proc_info.tcp_connections {
+        if n > 0 {
+            fields.push_u("tcp_connections", n);
+        }
+    }
+    if let Some(ref runtime) = proc_info.container_runtime {
+        fields.push_s("container_runtime", runtime.clone());
+    }
+    if let Some(ref image) = proc_info.container_image {
+        fields.push_s("container_image", image.clone());
+    }
+    if !proc_info.env.is_empty() {
+        let mut env = output::Object::new();
+        for (name, value) in &proc_info.env {
+            env.push_s(name, value.clone());
+        }
+        fields.push_o("env", env);
+    }
+    if let Some(ref info) = proc_info.job_info {
+        if !info.account.is_empty() {
+            fields.push_s("account", info.account.clone());
+        }
+        if !info.partition.is_empty() {
+            fields.push_s("partition", info.partition.clone());
+        }
+        if !info.reservation.is_empty() {
+            fields.push_s("reservation", info.reservation.clone());
+        }
+        if !info.reservation_start.is_empty() {
+            fields.push_s("reservation_start", info.reservation_start.clone());
+        }
+        if !info.reservation_end.is_empty() {
+            fields.push_s("reservation_end", info.reservation_end.clone());
+        }
+    }
 
     fields
 }
 
 #[cfg(test)]
-pub struct MockJobManager { }
+pub struct MockJobManager {}
 
 #[cfg(test)]
 impl jobs::JobManager for MockJobManager {
-    fn job_id_from_pid(&mut self, pid: usize, _processes: &HashMap<usize, procfs::Process>)
-        -> usize {
+    fn job_id_from_pid(
+        &mut self,
+        pid: usize,
+        _processes: &HashMap<usize, procfs::Process>,
+    ) -> usize {
         pid
     }
 }
@@ -829,8 +1956,9 @@ pub fn collect_data_test() {
     let now = procfsapi::unix_now();
     let fs = procfsapi::MockFS::new(files, pids, users, now);
     let gpus = gpu::MockGpuAPI::new();
+    let containers = containers::MockContainerAPI::new(HashMap::new());
     let mut jobs = MockJobManager {};
-    match collect_data(&fs, &gpus, &mut jobs, &print_params) {
+    match collect_data(&fs, &gpus, &containers, &mut jobs, &print_params) {
         // flat_data, so should be array
         output::Value::A(a) => {
             // No data, so this should be length 1
@@ -838,13 +1966,2515 @@ pub fn collect_data_test() {
             // Mock APIs, so we should have a heartbeat and an error
             match a.at(0) {
                 output::Value::O(obj) => {
-                    assert!(obj.get("error").is_some())
-                },
-                _ => { assert!(false) }
+                    assert!(obj.get("error").is_some());
+                    assert!(
+                        matches!(obj.get("error_code"), Some(output::Value::S(s)) if s == "system")
+                    );
+                }
+                _ => {
+                    assert!(false)
+                }
             }
-        },
+        }
         _ => {
             assert!(false);
         }
     }
 }
+
+#[test]
+pub fn schema_version_test() {
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new());
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    let has_schema = |o: &output::Object| matches!(o.get("schema"), Some(output::Value::U(n)) if *n == output::PS_SCHEMA_VERSION);
+    assert!(has_schema(&datum));
+
+    // Also every flat record, including the heartbeat that carries an error when there is no data
+    let print_params = PrintParameters {
+        hostname: "hello",
+        timestamp: "2025-01-24T10:39:00+01:00",
+        version: "0.99",
+        flat_data: true,
+        opts: &Default::default(),
+    };
+    let empty = procfsapi::MockFS::new(
+        HashMap::new(),
+        vec![],
+        HashMap::new(),
+        procfsapi::unix_now(),
+    );
+    for fs in [fs, empty] {
+        let gpus = gpu::MockGpuAPI::new();
+        match collect_data(
+            &fs,
+            &gpus,
+            &containers,
+            &mut MockJobManager {},
+            &print_params,
+        ) {
+            output::Value::A(records) => {
+                assert!(records.len() > 0);
+                for i in 0..records.len() {
+                    match records.at(i) {
+                        output::Value::O(o) => assert!(has_schema(o)),
+                        _ => panic!("Test: Expected object"),
+                    }
+                }
+            }
+            _ => panic!("Test: Expected records"),
+        }
+    }
+}
+
+#[test]
+pub fn token_test() {
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new());
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(datum.get("token").is_none());
+
+    let opts = PsOptions {
+        token: "s3cret".to_string(),
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(get_string(&datum, "token") == Some("s3cret"));
+    // Only in the envelope, not in the samples
+    assert!(all_samples(&datum).iter().all(|o| o.get("token").is_none()));
+}
+
+// Construct a MockFS with a process table.  Each process is (pid, ppid, uid, command, state); the
+// users are "root" (uid 0) and "zappa" (uid 1000).  Per-process data that are not varied here are
+// fixed but plausible.  Additional files (eg per-process cgroup) can be added to `extra_files`, and
+// a per-process stat or status file there replaces the fixed one.
+
+#[cfg(test)]
+fn mock_fs_with_processes(
+    procs: &[(usize, usize, u32, &str, &str)],
+    mut extra_files: HashMap<String, String>,
+) -> procfsapi::MockFS {
+    let boot_time = 1698303295u64;
+    extra_files
+        .entry("stat".to_string())
+        .or_insert_with(|| format!("btime {boot_time}\nprocs_running 3\nprocs_blocked 2"));
+    extra_files
+        .entry("meminfo".to_string())
+        .or_insert_with(|| "MemTotal:       16093776 kB".to_string());
+    let mut pids = vec![];
+    for (pid, ppid, uid, command, state) in procs {
+        pids.push((*pid, *uid));
+        extra_files.entry(format!("{pid}/stat")).or_insert_with(|| {
+            format!("{pid} ({command}) {state} {ppid} {pid} {pid} 0 -1 4194560 19293188 3117638 1823 557 51361 15728 5390 2925 20 0 1 0 16400 5144358912 184775")
+        });
+        extra_files.insert(
+            format!("{pid}/statm"),
+            "1255967 185959 54972 200 0 316078 0".to_string(),
+        );
+        extra_files
+            .entry(format!("{pid}/status"))
+            .or_insert_with(|| "RssAnon: 12345 kB".to_string());
+    }
+    let mut users = HashMap::new();
+    users.insert(0, "root".to_string());
+    users.insert(1000, "zappa".to_string());
+    procfsapi::MockFS::new(extra_files, pids, users, boot_time + 1000)
+}
+
+// Run collect_data with JSON output and return the envelope.
+
+#[cfg(test)]
+fn collect_mock_data(
+    fs: &procfsapi::MockFS,
+    containers: &dyn containers::ContainerAPI,
+    jobs: &mut dyn jobs::JobManager,
+    opts: &PsOptions,
+) -> output::Object {
+    collect_mock_gpu_data(fs, &gpu::MockGpuAPI::new(), containers, jobs, opts)
+}
+
+#[cfg(test)]
+fn collect_mock_gpu_data(
+    fs: &procfsapi::MockFS,
+    gpus: &gpu::MockGpuAPI,
+    containers: &dyn containers::ContainerAPI,
+    jobs: &mut dyn jobs::JobManager,
+    opts: &PsOptions,
+) -> output::Object {
+    let print_params = PrintParameters {
+        hostname: "hello",
+        timestamp: "2025-01-24T10:39:00+01:00",
+        version: "0.99",
+        flat_data: false,
+        opts,
+    };
+    match collect_data(fs, gpus, containers, jobs, &print_params) {
+        output::Value::O(datum) => datum,
+        _ => panic!("Test: Expected object"),
+    }
+}
+
+#[cfg(test)]
+fn all_samples(datum: &output::Object) -> Vec<&output::Object> {
+    match datum.get("samples") {
+        Some(output::Value::A(samples)) => (0..samples.len())
+            .map(|i| match samples.at(i) {
+                output::Value::O(o) => o,
+                _ => panic!("Test: Expected object"),
+            })
+            .collect::<Vec<&output::Object>>(),
+        _ => panic!("Test: Expected samples"),
+    }
+}
+
+// Find the sample record for the pid in the envelope.
+
+#[cfg(test)]
+fn find_sample(datum: &output::Object, pid: u64) -> Option<&output::Object> {
+    all_samples(datum)
+        .into_iter()
+        .find(|o| matches!(o.get("pid"), Some(output::Value::U(p)) if *p == pid))
+}
+
+#[cfg(test)]
+fn get_string<'a>(o: &'a output::Object, key: &str) -> Option<&'a str> {
+    match o.get(key) {
+        Some(output::Value::S(s)) => Some(s.as_str()),
+        _ => None,
+    }
+}
+
+#[test]
+pub fn container_info_test() {
+    let id = "4b8d0a7cfa1c2bb4c8f4ab12e0f2d57d29fc0fd6a4b0a1e3c5d7e9f1a3b5c7d9";
+    let mut files = HashMap::new();
+    files.insert(
+        "100/cgroup".to_string(),
+        format!("0::/system.slice/docker-{id}.scope\n"),
+    );
+    files.insert(
+        "101/cgroup".to_string(),
+        "0::/user.slice/user-1000.slice/session-3.scope\n".to_string(),
+    );
+    let fs = mock_fs_with_processes(
+        &[(100, 1, 1000, "python", "S"), (101, 1, 1000, "bash", "S")],
+        files,
+    );
+    let mut images = HashMap::new();
+    images.insert(id.to_string(), "pytorch/pytorch:2.1.0".to_string());
+    let containers = containers::MockContainerAPI::new(images);
+
+    // Off by default
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    let python = find_sample(&datum, 100).expect("Test: python");
+    assert!(python.get("container_runtime").is_none());
+
+    let opts = PsOptions {
+        containers: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    let python = find_sample(&datum, 100).expect("Test: python");
+    assert!(get_string(python, "container_runtime") == Some("docker"));
+    assert!(get_string(python, "container_image") == Some("pytorch/pytorch:2.1.0"));
+    let bash = find_sample(&datum, 101).expect("Test: bash");
+    assert!(bash.get("container_runtime").is_none());
+    assert!(bash.get("container_image").is_none());
+}
+
+#[cfg(test)]
+pub struct FixedJobManager {
+    job_id: usize,
+}
+
+#[cfg(test)]
+impl jobs::JobManager for FixedJobManager {
+    fn job_id_from_pid(
+        &mut self,
+        _pid: usize,
+        _processes: &HashMap<usize, procfs::Process>,
+    ) -> usize {
+        self.job_id
+    }
+
+    fn job_info(&mut self, job_id: usize) -> Option<jobs::JobInfo> {
+        // The fixture has information about job 4139711
+        if job_id == 4139711 {
+            slurm::parse_scontrol_job(std::include_str!("testdata/scontrol-output.txt"))
+        } else {
+            None
+        }
+    }
+
+    fn cluster_name(&mut self) -> Option<String> {
+        // The fixture is for cluster "fox"
+        slurm::parse_slurm_conf(std::include_str!("testdata/slurm.conf"))
+    }
+}
+
+// Job 17 runs in reservation ml-course, whose window is in the fixture, and job 18 in reservation
+// gone, which can't be found.  Other jobs are not known.
+
+#[cfg(test)]
+pub struct ReservedJobManager {
+    reservation_lookups: usize,
+}
+
+#[cfg(test)]
+impl jobs::JobManager for ReservedJobManager {
+    fn job_id_from_pid(
+        &mut self,
+        pid: usize,
+        _processes: &HashMap<usize, procfs::Process>,
+    ) -> usize {
+        pid - 83
+    }
+
+    fn job_info(&mut self, job_id: usize) -> Option<jobs::JobInfo> {
+        let reservation = match job_id {
+            17 => "ml-course",
+            18 => "gone",
+            _ => return None,
+        };
+        slurm::parse_scontrol_job(&format!(
+            "JobId={job_id} Account=ec201 Partition=accel Reservation={reservation}"
+        ))
+    }
+
+    fn reservation(&mut self, name: &str) -> Option<jobs::Reservation> {
+        self.reservation_lookups += 1;
+        if name == "ml-course" {
+            slurm::parse_scontrol_reservation(std::include_str!(
+                "testdata/scontrol-reservation-output.txt"
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+pub fn zombie_rollup_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 50, 1000, "firefox", "S"),
+            (101, 50, 1000, "firefox", "S"),
+            (102, 50, 1000, "firefox", "Z"),
+        ],
+        HashMap::new(),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let mut jobs = FixedJobManager { job_id: 17 };
+
+    // The live processes are rolled up, the zombie is kept apart and keeps the old suffix.
+    let opts = PsOptions {
+        rollup: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    let samples = all_samples(&datum);
+    assert!(samples.len() == 2);
+    let live = samples
+        .iter()
+        .find(|o| o.get("zombie").is_none())
+        .expect("Test: live");
+    assert!(get_string(live, "cmd") == Some("firefox"));
+    assert!(matches!(live.get("rolledup"), Some(output::Value::U(1))));
+    // Fault counts are summed
+    assert!(matches!(
+        live.get("minflt"),
+        Some(output::Value::U(38586376))
+    ));
+    let zombie = samples
+        .iter()
+        .find(|o| o.get("zombie").is_some())
+        .expect("Test: zombie");
+    assert!(get_string(zombie, "cmd") == Some("firefox <defunct>"));
+    assert!(zombie.get("rolledup").is_none());
+
+    // Without the suffix the commands are the same, but the zombie is still not rolled up.
+    let opts = PsOptions {
+        rollup: true,
+        no_defunct_suffix: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    let samples = all_samples(&datum);
+    assert!(samples.len() == 2);
+    assert!(samples
+        .iter()
+        .all(|o| get_string(o, "cmd") == Some("firefox")));
+    let zombie = samples
+        .iter()
+        .find(|o| o.get("zombie").is_some())
+        .expect("Test: zombie");
+    assert!(matches!(zombie.get("zombie"), Some(output::Value::U(1))));
+    assert!(zombie.get("rolledup").is_none());
+}
+
+#[test]
+pub fn rollup_saturation_test() {
+    // Minor fault counts near the maximum, which would wrap around when summed.
+    let minflt = u64::MAX - 10;
+    let mut files = HashMap::new();
+    for pid in [100, 101, 102] {
+        files.insert(
+            format!("{pid}/stat"),
+            format!("{pid} (firefox) S 50 {pid} {pid} 0 -1 4194560 {minflt} 3117638 1823 557 51361 15728 5390 2925 20 0 1 0 16400 5144358912 184775"),
+        );
+        files.insert(
+            format!("{pid}/io"),
+            format!("rchar: {minflt}\nwchar: 10\nread_bytes: 0\nwrite_bytes: 0\n"),
+        );
+    }
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 50, 1000, "firefox", "S"),
+            (101, 50, 1000, "firefox", "S"),
+            (102, 50, 1000, "firefox", "S"),
+        ],
+        files,
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let mut jobs = FixedJobManager { job_id: 17 };
+
+    let opts = PsOptions {
+        rollup: true,
+        io: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    let samples = all_samples(&datum);
+    assert!(samples.len() == 1);
+    assert!(matches!(
+        samples[0].get("rolledup"),
+        Some(output::Value::U(2))
+    ));
+    assert!(matches!(
+        samples[0].get("minflt"),
+        Some(output::Value::U(u64::MAX))
+    ));
+    assert!(matches!(
+        samples[0].get("rchar"),
+        Some(output::Value::U(u64::MAX))
+    ));
+    assert!(matches!(
+        samples[0].get("wchar"),
+        Some(output::Value::U(30))
+    ));
+    // Counters that did not overflow are summed as usual.
+    assert!(matches!(
+        samples[0].get("majflt"),
+        Some(output::Value::U(5469))
+    ));
+
+    let opts = PsOptions {
+        aggregate_by_command: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    let samples = all_samples(&datum);
+    assert!(samples.len() == 1);
+    assert!(matches!(
+        samples[0].get("minflt"),
+        Some(output::Value::U(u64::MAX))
+    ));
+}
+
+#[test]
+pub fn ctxt_switches_rollup_test() {
+    let mut files = HashMap::new();
+    for (pid, voluntary, nonvoluntary) in [(100, 1000, 10), (101, 2000, 20), (102, 3000, 30)] {
+        files.insert(
+            format!("{pid}/status"),
+            format!("RssAnon: 12345 kB\nvoluntary_ctxt_switches: {voluntary}\nnonvoluntary_ctxt_switches: {nonvoluntary}\n"),
+        );
+    }
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 50, 1000, "firefox", "S"),
+            (101, 50, 1000, "firefox", "S"),
+            (102, 50, 1000, "firefox", "S"),
+            (103, 50, 1000, "python", "S"),
+        ],
+        files,
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let mut jobs = FixedJobManager { job_id: 17 };
+
+    let opts = PsOptions {
+        rollup: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    let samples = all_samples(&datum);
+    let firefox = samples
+        .iter()
+        .find(|o| get_string(o, "cmd") == Some("firefox"))
+        .expect("Test: firefox");
+    assert!(matches!(firefox.get("nvcsw"), Some(output::Value::U(6000))));
+    assert!(matches!(firefox.get("nivcsw"), Some(output::Value::U(60))));
+
+    // Absent counts are zero and are not reported.
+    let python = samples
+        .iter()
+        .find(|o| get_string(o, "cmd") == Some("python"))
+        .expect("Test: python");
+    assert!(python.get("nvcsw").is_none());
+    assert!(python.get("nivcsw").is_none());
+}
+
+#[test]
+pub fn mem_pct_of_limit_test() {
+    // A 4GiB cgroup limit on a 16GB node, the process's RSS is about 4.6% of MemTotal but 17.7%
+    // of the limit.
+    let mut files = HashMap::new();
+    files.insert("self/cgroup".to_string(), "0::/container\n".to_string());
+    let mut sys_files = HashMap::new();
+    sys_files.insert(
+        "fs/cgroup/container/memory.max".to_string(),
+        "4294967296\n".to_string(),
+    );
+    let fs =
+        mock_fs_with_processes(&[(100, 1, 1000, "python", "S")], files).with_sys_files(sys_files);
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+
+    let opts = PsOptions {
+        min_mem_percent: Some(10.0),
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(find_sample(&datum, 100).is_none());
+    assert!(datum.get("mem_limit_kib").is_none());
+
+    let opts = PsOptions {
+        min_mem_percent: Some(10.0),
+        mem_pct_of_limit: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(find_sample(&datum, 100).is_some());
+    assert!(matches!(
+        datum.get("mem_limit_kib"),
+        Some(output::Value::U(4194304))
+    ));
+}
+
+#[test]
+pub fn sort_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (300, 1, 1000, "bash", "S"),
+            (100, 1, 1000, "python", "R"),
+            (102, 1, 1000, "python", "R"),
+            (101, 1, 1000, "python", "R"),
+            (200, 1, 0, "sshd", "S"),
+            (103, 1, 1000, "python", "Z"),
+        ],
+        HashMap::new(),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let samples_of = |opts: &PsOptions| -> Vec<(Option<u64>, String)> {
+        let datum = collect_mock_data(&fs, &containers, &mut FixedJobManager { job_id: 17 }, opts);
+        all_samples(&datum)
+            .iter()
+            .map(|o| {
+                let pid = match o.get("pid") {
+                    Some(output::Value::U(p)) => Some(*p),
+                    _ => None,
+                };
+                (pid, get_string(o, "cmd").unwrap_or_default().to_string())
+            })
+            .collect()
+    };
+
+    let opts = PsOptions {
+        sort: true,
+        ..Default::default()
+    };
+    let first = samples_of(&opts);
+    let pids = first.iter().map(|(p, _)| p.unwrap()).collect::<Vec<u64>>();
+    assert!(pids == [100, 101, 102, 103, 200, 300]);
+    assert!(samples_of(&opts) == first);
+
+    // The rolled-up python record has no pid but is sorted by its smallest pid.
+    let opts = PsOptions {
+        sort: true,
+        rollup: true,
+        ..Default::default()
+    };
+    let first = samples_of(&opts);
+    assert!(
+        first
+            == [
+                (None, "python".to_string()),
+                (Some(103), "python <defunct>".to_string()),
+                (Some(200), "sshd".to_string()),
+                (Some(300), "bash".to_string()),
+            ]
+    );
+    for _ in 0..5 {
+        assert!(samples_of(&opts) == first);
+    }
+
+    let opts = PsOptions {
+        sort: true,
+        aggregate_by_command: true,
+        ..Default::default()
+    };
+    let first = samples_of(&opts);
+    let commands = first.iter().map(|(_, c)| c.as_str()).collect::<Vec<&str>>();
+    assert!(commands == ["bash", "python", "python <defunct>", "sshd"]);
+    assert!(samples_of(&opts) == first);
+}
+
+#[test]
+pub fn tcp_connections_test() {
+    // 100, 101 and 102 are in the host's network namespace, 103 is in a container's
+    let mut files = HashMap::new();
+    files.insert(
+        "100/net/tcp".to_string(),
+        "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 20011 1 0000000000000000 100 0 0 10 0
+   1: 0A000005:0016 0A000001:D2F4 01 00000000:00000000 02:0009C4F0 00000000     0        0 20012 4 0000000000000000 20 4 1 10 -1
+   2: 0A000005:9C40 0A000009:1F90 01 00000000:00000000 00:00000000 00000000  1000        0 20013 1 0000000000000000 20 4 30 10 -1
+   3: 0A000005:9C41 0A000009:1F90 06 00000000:00000000 03:00000DB5 00000000     0        0 0 3 0000000000000000
+".to_string(),
+    );
+    files.insert(
+        "103/net/tcp".to_string(),
+        "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0A000005:9C40 0A000009:1F90 01 00000000:00000000 00:00000000 00000000  1000        0 20015 1 0000000000000000 20 4 30 10 -1
+".to_string(),
+    );
+    files.insert(
+        "100/net/tcp6".to_string(),
+        "  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0000000000000000FFFF00000500000A:9C42 0000000000000000FFFF00000900000A:1F90 01 00000000:00000000 00:00000000 00000000  1000        0 20014 1 0000000000000000 20 4 30 10 -1
+".to_string(),
+    );
+    for file in ["net/tcp", "net/tcp6"] {
+        let table = files[&format!("100/{file}")].clone();
+        files.insert(format!("101/{file}"), table);
+    }
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "S"),
+            (101, 1, 1000, "bash", "S"),
+            (102, 1, 0, "sshd", "S"),
+            (103, 1, 1000, "server", "S"),
+        ],
+        files,
+    )
+    .with_links(HashMap::from([
+        ("100/ns".to_string(), vec!["net:[4026531840]".to_string()]),
+        ("101/ns".to_string(), vec!["net:[4026531840]".to_string()]),
+        ("103/ns".to_string(), vec!["net:[4026532999]".to_string()]),
+        // Two established connections, the listening socket, a socket that is not TCP, and files
+        (
+            "100/fd".to_string(),
+            vec![
+                "/dev/null".to_string(),
+                "socket:[20013]".to_string(),
+                "socket:[20014]".to_string(),
+                "socket:[20011]".to_string(),
+                "socket:[31337]".to_string(),
+                "pipe:[20020]".to_string(),
+            ],
+        ),
+        ("101/fd".to_string(), vec!["/dev/pts/0".to_string()]),
+        // The fds and namespace of 102 can't be read
+        // Only the connection in its own namespace counts
+        (
+            "103/fd".to_string(),
+            vec!["socket:[20015]".to_string(), "socket:[20013]".to_string()],
+        ),
+    ]));
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(find_sample(&datum, 100)
+        .unwrap()
+        .get("tcp_connections")
+        .is_none());
+
+    let opts = PsOptions {
+        tcp_connections: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    let python = find_sample(&datum, 100).expect("Test: python");
+    assert!(matches!(
+        python.get("tcp_connections"),
+        Some(output::Value::U(2))
+    ));
+    assert!(find_sample(&datum, 101)
+        .unwrap()
+        .get("tcp_connections")
+        .is_none());
+    assert!(find_sample(&datum, 102)
+        .unwrap()
+        .get("tcp_connections")
+        .is_none());
+    let server = find_sample(&datum, 103).expect("Test: server");
+    assert!(matches!(
+        server.get("tcp_connections"),
+        Some(output::Value::U(1))
+    ));
+    // Each namespace's table is read once
+    let files_read = fs.files_read();
+    let host_tables = ["100/net/tcp", "101/net/tcp"]
+        .iter()
+        .filter(|f| files_read.contains(&f.to_string()))
+        .count();
+    assert!(host_tables == 1);
+}
+
+#[test]
+pub fn process_io_test() {
+    // Two processes in a job read mostly from the page cache; the io of 102 can't be read.
+    let mut files = HashMap::new();
+    files.insert(
+        "100/io".to_string(),
+        "rchar: 1000000\nwchar: 2000\nsyscr: 20\nsyscw: 2\nread_bytes: 4096\nwrite_bytes: 0\ncancelled_write_bytes: 0\n".to_string(),
+    );
+    files.insert(
+        "101/io".to_string(),
+        "rchar: 3000000\nwchar: 0\nsyscr: 60\nsyscw: 0\nread_bytes: 8192\nwrite_bytes: 0\ncancelled_write_bytes: 0\n".to_string(),
+    );
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 50, 1000, "reader", "S"),
+            (101, 50, 1000, "reader", "S"),
+            (102, 1, 0, "sshd", "S"),
+        ],
+        files,
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let mut jobs = FixedJobManager { job_id: 17 };
+
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &Default::default());
+    assert!(find_sample(&datum, 100).unwrap().get("rchar").is_none());
+
+    let opts = PsOptions {
+        io: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    let reader = find_sample(&datum, 100).expect("Test: reader");
+    assert!(matches!(
+        reader.get("rchar"),
+        Some(output::Value::U(1000000))
+    ));
+    assert!(matches!(reader.get("wchar"), Some(output::Value::U(2000))));
+    assert!(matches!(
+        reader.get("read_bytes"),
+        Some(output::Value::U(4096))
+    ));
+    assert!(reader.get("write_bytes").is_none());
+    assert!(find_sample(&datum, 102).unwrap().get("rchar").is_none());
+
+    let opts = PsOptions {
+        io: true,
+        rollup: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    let samples = all_samples(&datum);
+    let rolled = samples
+        .iter()
+        .find(|s| matches!(s.get("rolledup"), Some(output::Value::U(1))))
+        .expect("Test: rolled up");
+    assert!(matches!(
+        rolled.get("rchar"),
+        Some(output::Value::U(4000000))
+    ));
+    assert!(matches!(rolled.get("wchar"), Some(output::Value::U(2000))));
+    assert!(matches!(
+        rolled.get("read_bytes"),
+        Some(output::Value::U(12288))
+    ));
+}
+
+#[test]
+pub fn hugepages_test() {
+    let mut files = HashMap::new();
+    files.insert(
+        "100/smaps_rollup".to_string(),
+        std::include_str!("testdata/smaps-rollup.txt").to_string(),
+    );
+    files.insert(
+        "101/smaps_rollup".to_string(),
+        "Rss: 1000 kB\nAnonHugePages: 2048 kB\n".to_string(),
+    );
+    // 102 is not readable
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 50, 1000, "trainer", "S"),
+            (101, 50, 1000, "trainer", "S"),
+            (102, 1, 0, "sshd", "S"),
+        ],
+        files,
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let mut jobs = FixedJobManager { job_id: 17 };
+
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &Default::default());
+    assert!(find_sample(&datum, 100)
+        .unwrap()
+        .get("anonhugekib")
+        .is_none());
+
+    let opts = PsOptions {
+        hugepages: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    let trainer = find_sample(&datum, 100).expect("Test: trainer");
+    assert!(matches!(
+        trainer.get("anonhugekib"),
+        Some(output::Value::U(1050624))
+    ));
+    let trainer = find_sample(&datum, 101).expect("Test: trainer");
+    assert!(matches!(
+        trainer.get("anonhugekib"),
+        Some(output::Value::U(2048))
+    ));
+    assert!(find_sample(&datum, 102)
+        .unwrap()
+        .get("anonhugekib")
+        .is_none());
+
+    let opts = PsOptions {
+        hugepages: true,
+        rollup: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    let samples = all_samples(&datum);
+    let rolled = samples
+        .iter()
+        .find(|s| matches!(s.get("rolledup"), Some(output::Value::U(1))))
+        .expect("Test: rolled up");
+    assert!(matches!(
+        rolled.get("anonhugekib"),
+        Some(output::Value::U(1052672))
+    ));
+}
+
+#[test]
+pub fn max_command_length_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "trénings-skript", "R"),
+            (101, 1, 1000, "bash", "S"),
+            (102, 1, 1000, "långkjøring", "Z"),
+        ],
+        HashMap::new(),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let opts = PsOptions {
+        max_command_length: Some(6),
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    // The cut falls right after a two-byte char, and the ellipsis counts toward the limit
+    assert!(get_string(find_sample(&datum, 100).unwrap(), "cmd") == Some("tré..."));
+    assert!(get_string(find_sample(&datum, 101).unwrap(), "cmd") == Some("bash"));
+    assert!(get_string(find_sample(&datum, 102).unwrap(), "cmd") == Some("lån... <defunct>"));
+
+    // Too short for any of the command
+    let opts = PsOptions {
+        max_command_length: Some(3),
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(get_string(find_sample(&datum, 101).unwrap(), "cmd") == Some("..."));
+}
+
+#[test]
+pub fn cpu_time_breakdown_test() {
+    // 101 is busy in the kernel and has no children that have exited.
+    let mut files = HashMap::new();
+    files.insert(
+        "101/stat".to_string(),
+        "101 (python) R 50 101 101 0 -1 4194560 19293188 3117638 1823 557 1000 3050 0 0 20 0 1 0 16400 5144358912 184775".to_string(),
+    );
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 50, 1000, "python", "R"),
+            (101, 50, 1000, "python", "R"),
+        ],
+        files,
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let mut jobs = FixedJobManager { job_id: 17 };
+
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &Default::default());
+    assert!(find_sample(&datum, 100).unwrap().get("utime_sec").is_none());
+
+    let opts = PsOptions {
+        cpu_time_breakdown: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    // The fixed stat file has utime 51361, stime 15728, cutime 5390, cstime 2925 at 100 ticks/s
+    let p = find_sample(&datum, 100).expect("Test: pid 100");
+    assert!(matches!(p.get("utime_sec"), Some(output::Value::F(f)) if *f == 513.61));
+    assert!(matches!(p.get("stime_sec"), Some(output::Value::F(f)) if *f == 157.28));
+    assert!(matches!(p.get("cutime_sec"), Some(output::Value::F(f)) if *f == 53.9));
+    assert!(matches!(p.get("cstime_sec"), Some(output::Value::F(f)) if *f == 29.25));
+    assert!(matches!(p.get("cputime_sec"), Some(output::Value::U(754))));
+    let q = find_sample(&datum, 101).expect("Test: pid 101");
+    assert!(matches!(q.get("utime_sec"), Some(output::Value::F(f)) if *f == 10.0));
+    assert!(matches!(q.get("stime_sec"), Some(output::Value::F(f)) if *f == 30.5));
+    assert!(q.get("cutime_sec").is_none());
+    assert!(q.get("cstime_sec").is_none());
+
+    let opts = PsOptions {
+        cpu_time_breakdown: true,
+        rollup: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    let samples = all_samples(&datum);
+    assert!(samples.len() == 1);
+    assert!(matches!(samples[0].get("utime_sec"), Some(output::Value::F(f)) if *f == 523.61));
+    assert!(matches!(samples[0].get("stime_sec"), Some(output::Value::F(f)) if *f == 187.78));
+    assert!(matches!(samples[0].get("cutime_sec"), Some(output::Value::F(f)) if *f == 53.9));
+}
+
+#[test]
+pub fn case_insensitive_filters_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "Python", "S"),
+            (101, 1, 1000, "bash", "S"),
+            (102, 1, 1001, "vim", "S"),
+        ],
+        HashMap::new(),
+    )
+    .with_users(HashMap::from([
+        (1000, "zappa".to_string()),
+        (1001, "Beefheart".to_string()),
+    ]));
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let pids = |opts: &PsOptions| -> Vec<u64> {
+        let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, opts);
+        let mut pids = all_samples(&datum)
+            .iter()
+            .filter_map(|o| match o.get("pid") {
+                Some(output::Value::U(p)) => Some(*p),
+                _ => None,
+            })
+            .collect::<Vec<u64>>();
+        pids.sort();
+        pids
+    };
+
+    // By default case matters
+    let opts = PsOptions {
+        exclude_users: vec!["beefheart"],
+        exclude_commands: vec!["py"],
+        ..Default::default()
+    };
+    assert!(pids(&opts) == [100, 101, 102]);
+    let opts = PsOptions {
+        exclude_users: vec!["Beefheart"],
+        exclude_commands: vec!["Py"],
+        ..Default::default()
+    };
+    assert!(pids(&opts) == [101]);
+
+    // The terms are lowercased by the command line parser
+    let opts = PsOptions {
+        exclude_users: vec!["beefheart"],
+        case_insensitive_filters: true,
+        ..Default::default()
+    };
+    assert!(pids(&opts) == [100, 101]);
+    let opts = PsOptions {
+        exclude_commands: vec!["py", "bas"],
+        case_insensitive_filters: true,
+        ..Default::default()
+    };
+    assert!(pids(&opts) == [102]);
+}
+
+#[test]
+pub fn exclude_pids_test() {
+    // 100 is a monitoring agent and 101 its helper in the same process group.
+    let mut files = HashMap::new();
+    files.insert(
+        "101/stat".to_string(),
+        "101 (agent-helper) S 100 100 100 0 -1 4194560 19293188 3117638 1823 557 51361 15728 5390 2925 20 0 1 0 16400 5144358912 184775".to_string(),
+    );
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "agent", "S"),
+            (101, 100, 1000, "agent-helper", "S"),
+            (102, 1, 1000, "python", "R"),
+            (103, 1, 1000, "bash", "S"),
+        ],
+        files,
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let gpus = gpu::MockGpuAPI::new().with_processes(vec![gpu::Process {
+        devices: gpuset::singleton_gpuset(Some(0)),
+        pid: 101,
+        user: "zappa".to_string(),
+        uid: 1000,
+        gpu_pct: 5.0,
+        ..Default::default()
+    }]);
+    let pids = |opts: &PsOptions| -> Vec<u64> {
+        let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, opts);
+        let mut pids = all_samples(&datum)
+            .iter()
+            .filter_map(|o| match o.get("pid") {
+                Some(output::Value::U(p)) => Some(*p),
+                _ => None,
+            })
+            .collect::<Vec<u64>>();
+        pids.sort();
+        pids
+    };
+
+    assert!(pids(&Default::default()) == [100, 101, 102, 103]);
+    let opts = PsOptions {
+        exclude_pids: vec![103],
+        ..Default::default()
+    };
+    assert!(pids(&opts) == [100, 101, 102]);
+    // The GPU process in the excluded group does not come back from the GPU data
+    let opts = PsOptions {
+        exclude_pgrps: vec![100],
+        ..Default::default()
+    };
+    assert!(pids(&opts) == [102, 103]);
+
+    // They compose with the other exclusion filters
+    let opts = PsOptions {
+        exclude_pids: vec![103],
+        exclude_pgrps: vec![100],
+        exclude_commands: vec!["py"],
+        ..Default::default()
+    };
+    assert!(pids(&opts).is_empty());
+
+    let opts = PsOptions {
+        exclude_pids: vec![103],
+        exclude_pgrps: vec![100],
+        explain_filters: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, &opts);
+    let excluded = match datum.get("excluded") {
+        Some(output::Value::A(a)) => a,
+        _ => panic!("Test: no excluded"),
+    };
+    let reasons = (0..excluded.len())
+        .map(|i| match excluded.at(i) {
+            output::Value::O(o) => (
+                get_string(o, "reason").unwrap().to_string(),
+                match o.get("count") {
+                    Some(output::Value::U(n)) => *n,
+                    _ => panic!("Test: no count"),
+                },
+            ),
+            _ => panic!("Test: Expected object"),
+        })
+        .collect::<Vec<(String, u64)>>();
+    assert!(
+        reasons
+            == [
+                ("exclude-pgrps".to_string(), 2),
+                ("exclude-pids".to_string(), 1)
+            ]
+    );
+}
+
+#[test]
+pub fn gpu_affinity_test() {
+    let mut files = HashMap::new();
+    files.insert(
+        "100/environ".to_string(),
+        "HOME=/home/zappa\0CUDA_VISIBLE_DEVICES=GPU-bbbb2222,0\0".to_string(),
+    );
+    // Slurm's physical indices take precedence over the cgroup-relative CUDA indices
+    files.insert(
+        "101/environ".to_string(),
+        "CUDA_VISIBLE_DEVICES=0,1\0SLURM_JOB_GPUS=3,2\0".to_string(),
+    );
+    files.insert(
+        "102/environ".to_string(),
+        "CUDA_VISIBLE_DEVICES=MIG-cccc3333\0".to_string(),
+    );
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "S"),
+            (101, 1, 1000, "python", "S"),
+            (102, 1, 1000, "python", "S"),
+            (103, 1, 1000, "bash", "S"),
+        ],
+        files,
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let cards = [
+        "GPU-aaaa1111",
+        "GPU-bbbb2222",
+        "GPU-cccc3333",
+        "GPU-dddd4444",
+    ]
+    .iter()
+    .enumerate()
+    .map(|(i, uuid)| gpu::Card {
+        index: i as i32,
+        uuid: uuid.to_string(),
+        ..Default::default()
+    })
+    .collect::<Vec<gpu::Card>>();
+    let gpus = gpu::MockGpuAPI::with_cards(cards);
+    let opts = PsOptions {
+        gpu_affinity: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, &opts);
+    let allowed = |pid: u64| -> Option<String> {
+        let sample = find_sample(&datum, pid).expect("Test: pid");
+        get_string(sample, "gpus_allowed").map(|s| s.to_string())
+    };
+    assert!(allowed(100).as_deref() == Some("0,1"));
+    assert!(allowed(101).as_deref() == Some("2,3"));
+    assert!(allowed(102).is_none());
+    assert!(allowed(103).is_none());
+
+    // Off by default, and meaningless without GPUs
+    let datum = collect_mock_gpu_data(
+        &fs,
+        &gpus,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(find_sample(&datum, 100)
+        .unwrap()
+        .get("gpus_allowed")
+        .is_none());
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(find_sample(&datum, 100)
+        .unwrap()
+        .get("gpus_allowed")
+        .is_none());
+}
+
+#[test]
+pub fn node_procs_test() {
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new());
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(datum.get("procs_running").is_none());
+    // The value is not predictable, only its presence
+    assert!(matches!(datum.get("collect_ms"), Some(output::Value::U(_))));
+    // The mock clock is 1000s after the mock boot time
+    assert!(matches!(
+        datum.get("uptime_secs"),
+        Some(output::Value::U(1000))
+    ));
+
+    let opts = PsOptions {
+        load: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(matches!(
+        datum.get("procs_running"),
+        Some(output::Value::U(3))
+    ));
+    assert!(matches!(
+        datum.get("procs_blocked"),
+        Some(output::Value::U(2))
+    ));
+    // The mock has no sysfs
+    assert!(datum.get("offline_cpus").is_none());
+
+    let fs = fs.with_sys_files(HashMap::from([
+        (
+            "devices/system/cpu/present".to_string(),
+            "0-7\n".to_string(),
+        ),
+        (
+            "devices/system/cpu/online".to_string(),
+            "0-2,4-5\n".to_string(),
+        ),
+    ]));
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(get_string(&datum, "offline_cpus") == Some("3,6,7"));
+}
+
+#[test]
+pub fn node_loadavg_test() {
+    let mut files = HashMap::new();
+    files.insert(
+        "stat".to_string(),
+        "cpu  2000 0 800 60000 0 0 0 0 0 0\ncpu0 1000 0 500 30000 0 0 0 0 0 0\ncpu1 1000 0 300 30000 0 0 0 0 0 0\nbtime 1698303295\n".to_string(),
+    );
+    files.insert(
+        "loadavg".to_string(),
+        "0.52 0.58 1.59 3/1187 23456\n".to_string(),
+    );
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], files);
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+
+    // The load averages do not depend on --load, the per-cpu load does
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(matches!(datum.get("load1"), Some(output::Value::F(x)) if *x == 0.52));
+    assert!(matches!(datum.get("load5"), Some(output::Value::F(x)) if *x == 0.58));
+    assert!(matches!(datum.get("load15"), Some(output::Value::F(x)) if *x == 1.59));
+    assert!(matches!(datum.get("runnable"), Some(output::Value::U(3))));
+    assert!(matches!(
+        datum.get("existing"),
+        Some(output::Value::U(1187))
+    ));
+    assert!(datum.get("load").is_none());
+
+    let opts = PsOptions {
+        load: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(matches!(datum.get("load1"), Some(output::Value::F(x)) if *x == 0.52));
+    match datum.get("load") {
+        Some(output::Value::A(a)) => assert!(a.len() == 2),
+        _ => panic!("Test: no load"),
+    }
+
+    // Also in the CSV output, on the first record
+    let print_params = PrintParameters {
+        hostname: "hello",
+        timestamp: "2025-01-24T10:39:00+01:00",
+        version: "0.99",
+        flat_data: true,
+        opts: &Default::default(),
+    };
+    let records = match collect_data(
+        &fs,
+        &gpu::MockGpuAPI::new(),
+        &containers,
+        &mut MockJobManager {},
+        &print_params,
+    ) {
+        output::Value::A(records) => records,
+        _ => panic!("Test: Expected records"),
+    };
+    match records.at(0) {
+        output::Value::O(o) => {
+            assert!(matches!(o.get("runnable"), Some(output::Value::U(3))));
+            assert!(o.get("load").is_none());
+        }
+        _ => panic!("Test: Expected object"),
+    }
+
+    // No /proc/loadavg, no fields
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new());
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(datum.get("load1").is_none());
+    assert!(datum.get("existing").is_none());
+}
+
+#[test]
+pub fn node_vmstat_test() {
+    let mut files = HashMap::new();
+    files.insert(
+        "vmstat".to_string(),
+        "pswpin 182734\npswpout 406620\npgfault 16661377\npgmajfault 2914485\n".to_string(),
+    );
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], files);
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+
+    // The counters are cumulative and do not depend on --load
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(matches!(
+        datum.get("pgmajfault"),
+        Some(output::Value::U(2914485))
+    ));
+    assert!(matches!(
+        datum.get("pswpin"),
+        Some(output::Value::U(182734))
+    ));
+    assert!(matches!(
+        datum.get("pswpout"),
+        Some(output::Value::U(406620))
+    ));
+    assert!(datum.get("pgfault").is_none());
+
+    // No /proc/vmstat, no fields
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new());
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(datum.get("pgmajfault").is_none());
+    assert!(datum.get("pswpout").is_none());
+}
+
+#[test]
+pub fn job_info_test() {
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new());
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let opts = PsOptions {
+        job_info: true,
+        ..Default::default()
+    };
+
+    let mut jobs = FixedJobManager { job_id: 4139711 };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    let python = find_sample(&datum, 100).expect("Test: python");
+    assert!(get_string(python, "account") == Some("ec201"));
+    assert!(get_string(python, "partition") == Some("accel"));
+
+    // Unknown job, as when scontrol is not available
+    let datum = collect_mock_data(&fs, &containers, &mut FixedJobManager { job_id: 17 }, &opts);
+    let python = find_sample(&datum, 100).expect("Test: python");
+    assert!(python.get("account").is_none());
+    assert!(python.get("partition").is_none());
+}
+
+#[test]
+pub fn job_reservation_test() {
+    // pid 100 is in job 17, 101 in job 18, 102 in job 19.
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (101, 1, 1000, "python", "R"),
+            (102, 1, 1000, "python", "R"),
+        ],
+        HashMap::new(),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let opts = PsOptions {
+        job_info: true,
+        ..Default::default()
+    };
+    let mut jobs = ReservedJobManager {
+        reservation_lookups: 0,
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts);
+    assert!(jobs.reservation_lookups == 2);
+
+    let reserved = find_sample(&datum, 100).expect("Test: reserved");
+    assert!(get_string(reserved, "reservation") == Some("ml-course"));
+    assert!(get_string(reserved, "reservation_start") == Some("2024-10-21T08:00:00"));
+    assert!(get_string(reserved, "reservation_end") == Some("2024-10-25T16:00:00"));
+
+    // The reservation is known but its window is not
+    let unknown = find_sample(&datum, 101).expect("Test: unknown window");
+    assert!(get_string(unknown, "reservation") == Some("gone"));
+    assert!(unknown.get("reservation_start").is_none());
+
+    // Not in the queue
+    let unreserved = find_sample(&datum, 102).expect("Test: unreserved");
+    assert!(unreserved.get("reservation").is_none());
+    assert!(unreserved.get("reservation_end").is_none());
+
+    // Not looked up without --job-info
+    let mut jobs = ReservedJobManager {
+        reservation_lookups: 0,
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &Default::default());
+    assert!(jobs.reservation_lookups == 0);
+    assert!(find_sample(&datum, 100)
+        .expect("Test: python")
+        .get("reservation")
+        .is_none());
+}
+
+#[test]
+pub fn kernel_threads_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (2, 0, 0, "kthreadd", "S"),
+            (50, 2, 0, "kworker/0:1-events", "I"),
+            (100, 1, 1000, "python", "R"),
+        ],
+        HashMap::new(),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+
+    // Included by default
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(all_samples(&datum).len() == 3);
+
+    let opts = PsOptions {
+        exclude_kernel_threads: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(all_samples(&datum).len() == 1);
+    assert!(find_sample(&datum, 100).is_some());
+}
+
+#[test]
+pub fn aggregate_by_command_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (101, 100, 1000, "python", "R"),
+            (200, 1, 0, "python", "S"),
+            (300, 1, 1000, "bash", "S"),
+        ],
+        HashMap::new(),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let opts = PsOptions {
+        aggregate_by_command: true,
+        ..Default::default()
+    };
+    // Every process is its own job, but aggregation ignores that.
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    let samples = all_samples(&datum);
+    assert!(samples.len() == 2);
+    let python = samples
+        .iter()
+        .find(|o| get_string(o, "cmd") == Some("python"))
+        .expect("Test: python");
+    assert!(get_string(python, "user") == Some("_mixed_"));
+    assert!(matches!(python.get("rolledup"), Some(output::Value::U(2))));
+    assert!(matches!(
+        python.get("minflt"),
+        Some(output::Value::U(57879564))
+    ));
+    assert!(python.get("pid").is_none());
+    assert!(python.get("ppid").is_none());
+    assert!(python.get("job").is_none());
+    let bash = samples
+        .iter()
+        .find(|o| get_string(o, "cmd") == Some("bash"))
+        .expect("Test: bash");
+    assert!(get_string(bash, "user") == Some("zappa"));
+    assert!(bash.get("rolledup").is_none());
+    assert!(bash.get("pid").is_none());
+
+    // Rollup is unaffected: the processes are in different jobs and are not merged.
+    let opts = PsOptions {
+        rollup: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    let samples = all_samples(&datum);
+    assert!(samples.len() == 4);
+    assert!(samples.iter().all(|o| o.get("rolledup").is_none()));
+}
+
+#[test]
+pub fn env_allowlist_test() {
+    let mut files = HashMap::new();
+    files.insert(
+        "100/environ".to_string(),
+        "HOME=/home/zappa\0SLURM_JOB_ID=4139711\0SECRET_TOKEN=abc=def\0WANDB_RUN_ID=run-17\0"
+            .to_string(),
+    );
+    // No environ for 200, as for another user's process.
+    let fs = mock_fs_with_processes(
+        &[(100, 1, 1000, "python", "R"), (200, 1, 1000, "bash", "S")],
+        files,
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let opts = PsOptions {
+        env_allowlist: vec!["SLURM_JOB_ID", "WANDB_RUN_ID", "MISSING"],
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    let python = find_sample(&datum, 100).expect("Test: python");
+    let env = match python.get("env") {
+        Some(output::Value::O(env)) => env,
+        _ => panic!("Test: no env"),
+    };
+    assert!(get_string(env, "SLURM_JOB_ID") == Some("4139711"));
+    assert!(get_string(env, "WANDB_RUN_ID") == Some("run-17"));
+    assert!(env.get("HOME").is_none());
+    assert!(env.get("SECRET_TOKEN").is_none());
+    assert!(env.get("MISSING").is_none());
+    let bash = find_sample(&datum, 200).expect("Test: bash");
+    assert!(bash.get("env").is_none());
+
+    // Not requested
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(find_sample(&datum, 100)
+        .expect("Test: python")
+        .get("env")
+        .is_none());
+}
+
+#[test]
+pub fn gpu_summary_test() {
+    let cards = vec![
+        gpu::CardState {
+            index: 0,
+            mem_used_kib: 1048576,
+            gpu_utilization_pct: 100.0,
+            mem_bandwidth_pct: 40.0,
+            ..Default::default()
+        },
+        gpu::CardState {
+            index: 1,
+            mem_used_kib: 524288,
+            gpu_utilization_pct: 25.0,
+            mem_bandwidth_pct: 0.0,
+            ..Default::default()
+        },
+    ];
+    let config = (0..2)
+        .map(|index| gpu::Card {
+            index,
+            mem_size_kib: 4194304,
+            ..Default::default()
+        })
+        .collect::<Vec<gpu::Card>>();
+    let summary = summarize_gpus(&cards, &config).expect("Test: summary");
+    assert!(matches!(summary.get("cards"), Some(output::Value::U(2))));
+    assert!(matches!(
+        summary.get("musekib"),
+        Some(output::Value::I(1572864))
+    ));
+    assert!(matches!(summary.get("cutil%"), Some(output::Value::F(x)) if *x == 62.5));
+    assert!(matches!(summary.get("mutil%"), Some(output::Value::F(x)) if *x == 20.0));
+    assert!(matches!(summary.get("mused%"), Some(output::Value::F(x)) if *x == 18.75));
+    assert!(summarize_gpus(&[], &config).is_none());
+
+    // The capacity share is absent unless the capacity of every card is known
+    let summary = summarize_gpus(&cards[..1], &config).expect("Test: summary");
+    assert!(matches!(summary.get("mused%"), Some(output::Value::F(x)) if *x == 25.0));
+    let summary = summarize_gpus(&cards, &config[..1]).expect("Test: summary");
+    assert!(summary.get("mused%").is_none());
+}
+
+#[test]
+pub fn exclude_self_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (200, 1, 1000, "sonar", "R"),
+            (201, 200, 1000, "scontrol", "S"),
+        ],
+        HashMap::new(),
+    )
+    .with_own_pid(200);
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(find_sample(&datum, 100).is_some());
+    assert!(find_sample(&datum, 200).is_none());
+    assert!(find_sample(&datum, 201).is_none());
+
+    let opts = PsOptions {
+        include_self: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(find_sample(&datum, 100).is_some());
+    assert!(find_sample(&datum, 200).is_some());
+    assert!(find_sample(&datum, 201).is_some());
+}
+
+#[test]
+pub fn job_cgroup_cpu_test() {
+    // MockJobManager makes every process its own job.
+    let mut files = HashMap::new();
+    files.insert(
+        "100/cgroup".to_string(),
+        "0::/system.slice/slurmstepd.scope/job_100/step_0/user/task_0\n".to_string(),
+    );
+    let mut sys_files = HashMap::new();
+    sys_files.insert(
+        "fs/cgroup/system.slice/slurmstepd.scope/job_100/cpu.stat".to_string(),
+        "usage_usec 7265123456\nuser_usec 7000000000\nsystem_usec 265123456\n".to_string(),
+    );
+    let fs = mock_fs_with_processes(
+        &[(100, 1, 1000, "python", "R"), (200, 1, 1000, "bash", "S")],
+        files,
+    )
+    .with_sys_files(sys_files);
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let opts = PsOptions {
+        job_cgroup_cpu: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    let python = find_sample(&datum, 100).expect("Test: python");
+    assert!(matches!(
+        python.get("job_cputime_sec"),
+        Some(output::Value::U(7265))
+    ));
+    let bash = find_sample(&datum, 200).expect("Test: bash");
+    assert!(bash.get("job_cputime_sec").is_none());
+
+    // When both are in job 17, the job's time is on its first record only, and it is found from
+    // the cgroup of 100 even if 200 is tried first and its cgroup can't be read.
+    let mut files = HashMap::new();
+    files.insert(
+        "100/cgroup".to_string(),
+        "0::/system.slice/slurmstepd.scope/job_17/step_0/user/task_0\n".to_string(),
+    );
+    let mut sys_files = HashMap::new();
+    sys_files.insert(
+        "fs/cgroup/system.slice/slurmstepd.scope/job_17/cpu.stat".to_string(),
+        "usage_usec 7265123456\n".to_string(),
+    );
+    let fs = mock_fs_with_processes(
+        &[(100, 1, 1000, "python", "R"), (200, 1, 1000, "bash", "S")],
+        files,
+    )
+    .with_sys_files(sys_files);
+    let opts = PsOptions {
+        job_cgroup_cpu: true,
+        sort: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut FixedJobManager { job_id: 17 }, &opts);
+    let python = find_sample(&datum, 100).expect("Test: python");
+    assert!(matches!(
+        python.get("job_cputime_sec"),
+        Some(output::Value::U(7265))
+    ));
+    let bash = find_sample(&datum, 200).expect("Test: bash");
+    assert!(bash.get("job_cputime_sec").is_none());
+}
+
+#[test]
+pub fn d_state_warn_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "D"),
+            (101, 1, 1000, "python", "D"),
+            (102, 1, 1000, "rsync", "D"),
+            (200, 1, 1000, "bash", "S"),
+        ],
+        HashMap::new(),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let health_errors = |datum: &output::Object| {
+        all_samples(datum)
+            .into_iter()
+            .filter(|o| get_string(o, "error_code") == Some("health"))
+            .count()
+    };
+
+    let opts = PsOptions {
+        load: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(matches!(
+        datum.get("procs_dstate"),
+        Some(output::Value::U(3))
+    ));
+    assert!(health_errors(&datum) == 0);
+
+    // At the threshold: silent
+    let opts = PsOptions {
+        d_state_warn: Some(3),
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(health_errors(&datum) == 0);
+
+    // Above the threshold: one error record, and the process data are still there
+    let opts = PsOptions {
+        d_state_warn: Some(2),
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(health_errors(&datum) == 1);
+    assert!(find_sample(&datum, 200).is_some());
+}
+
+#[test]
+pub fn user_lookup_timeout_test() {
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new())
+        .with_user_delay(
+            &[1000],
+            std::time::Duration::from_millis(500),
+            std::time::Duration::from_millis(20),
+        );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    let python = find_sample(&datum, 100).expect("Test: python");
+    assert!(get_string(python, "user") == Some("_user_1000"));
+    let errors = all_samples(&datum)
+        .into_iter()
+        .filter(|o| get_string(o, "error_code") == Some("system"))
+        .collect::<Vec<&output::Object>>();
+    assert!(errors.len() == 1);
+    assert!(get_string(errors[0], "error")
+        .unwrap()
+        .contains("timed out"));
+}
+
+#[test]
+pub fn oom_score_test() {
+    let mut files = HashMap::new();
+    files.insert("100/oom_score".to_string(), "667\n".to_string());
+    files.insert("100/oom_score_adj".to_string(), "500\n".to_string());
+    files.insert("101/oom_score".to_string(), "12\n".to_string());
+    files.insert("101/oom_score_adj".to_string(), "-1000\n".to_string());
+    // No scores for 102, as for a process that has vanished.
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (101, 1, 1000, "python", "R"),
+            (102, 1, 1000, "bash", "S"),
+        ],
+        files,
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let opts = PsOptions {
+        oom_score: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    let p = find_sample(&datum, 100).expect("Test: 100");
+    assert!(matches!(p.get("oom_score"), Some(output::Value::I(667))));
+    assert!(matches!(
+        p.get("oom_score_adj"),
+        Some(output::Value::I(500))
+    ));
+    let p = find_sample(&datum, 101).expect("Test: 101");
+    assert!(matches!(p.get("oom_score"), Some(output::Value::I(12))));
+    assert!(matches!(
+        p.get("oom_score_adj"),
+        Some(output::Value::I(-1000))
+    ));
+    let p = find_sample(&datum, 102).expect("Test: 102");
+    assert!(p.get("oom_score").is_none());
+    assert!(p.get("oom_score_adj").is_none());
+
+    // Rolled-up records carry the maximum scores.
+    let opts = PsOptions {
+        oom_score: true,
+        rollup: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut FixedJobManager { job_id: 17 }, &opts);
+    let python = all_samples(&datum)
+        .into_iter()
+        .find(|o| get_string(o, "cmd") == Some("python"))
+        .expect("Test: python");
+    assert!(matches!(python.get("rolledup"), Some(output::Value::U(1))));
+    assert!(matches!(
+        python.get("oom_score"),
+        Some(output::Value::I(667))
+    ));
+    assert!(matches!(
+        python.get("oom_score_adj"),
+        Some(output::Value::I(500))
+    ));
+
+    // Not requested
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(find_sample(&datum, 100)
+        .expect("Test: 100")
+        .get("oom_score")
+        .is_none());
+}
+
+#[test]
+pub fn msgpack_test() {
+    let mut files = HashMap::new();
+    files.insert("100/oom_score_adj".to_string(), "-1000\n".to_string());
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (101, 100, 1000, "python", "R"),
+            (102, 1, 0, "bash", "Z"),
+        ],
+        files,
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let opts = PsOptions {
+        load: true,
+        oom_score: true,
+        msgpack: true,
+        tags: vec![("cluster".to_string(), "saga".to_string())],
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    output::assert_msgpack_roundtrip(&output::Value::O(datum));
+}
+
+#[test]
+pub fn expect_cluster_test() {
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new());
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let config_errors = |datum: &output::Object| {
+        all_samples(datum)
+            .into_iter()
+            .filter(|o| get_string(o, "error_code") == Some("config"))
+            .map(|o| get_string(o, "error").expect("Test: error").to_string())
+            .collect::<Vec<String>>()
+    };
+    let opts = |cluster: &str| PsOptions {
+        expect_cluster: Some(cluster.to_string()),
+        ..Default::default()
+    };
+
+    // Agreement: silent
+    let mut jobs = FixedJobManager { job_id: 17 };
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts("fox"));
+    assert!(config_errors(&datum).is_empty());
+
+    // Disagreement: one error record, and the process data are still there
+    let datum = collect_mock_data(&fs, &containers, &mut jobs, &opts("saga"));
+    let errors = config_errors(&datum);
+    assert!(errors.len() == 1);
+    assert!(errors[0].contains("saga") && errors[0].contains("fox"));
+    assert!(find_sample(&datum, 100).is_some());
+
+    // No job queue information: nothing to compare with
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts("saga"));
+    assert!(config_errors(&datum).is_empty());
+}
+
+#[test]
+pub fn min_threads_test() {
+    // The stat files are the fixed ones from mock_fs_with_processes but with num_threads varied.
+    let mut files = HashMap::new();
+    for (pid, threads) in [(100, 1), (101, 15), (102, 16), (103, 64)] {
+        files.insert(
+            format!("{pid}/stat"),
+            format!("{pid} (python) R 1 {pid} {pid} 0 -1 4194560 19293188 3117638 1823 557 51361 15728 5390 2925 20 0 {threads} 0 16400 5144358912 184775"),
+        );
+    }
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (101, 1, 1000, "python", "R"),
+            (102, 1, 1000, "python", "R"),
+            (103, 1, 1000, "python", "R"),
+        ],
+        files,
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let opts = PsOptions {
+        min_threads: Some(16),
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(find_sample(&datum, 100).is_none());
+    // Just below the cutoff
+    assert!(find_sample(&datum, 101).is_none());
+    // At the cutoff
+    assert!(find_sample(&datum, 102).is_some());
+    assert!(find_sample(&datum, 103).is_some());
+
+    // Any of the inclusion filters admits the record
+    let opts = PsOptions {
+        min_threads: Some(16),
+        min_cpu_time: Some(0),
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(find_sample(&datum, 100).is_some());
+
+    // Rolled-up records have the sum of the threads
+    let opts = PsOptions {
+        min_threads: Some(32),
+        rollup: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut FixedJobManager { job_id: 17 }, &opts);
+    let python = all_samples(&datum)
+        .into_iter()
+        .find(|o| get_string(o, "cmd") == Some("python"))
+        .expect("Test: python");
+    assert!(matches!(python.get("rolledup"), Some(output::Value::U(3))));
+}
+
+#[test]
+pub fn gpu_process_command_test() {
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new());
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let gpu_process = |pid: usize, command: Option<&str>| gpu::Process {
+        devices: gpuset::singleton_gpuset(Some(0)),
+        pid,
+        user: "zappa".to_string(),
+        uid: 1000,
+        gpu_pct: 50.0,
+        command: command.map(|c| c.to_string()),
+        ..Default::default()
+    };
+    let gpus = gpu::MockGpuAPI::new().with_processes(vec![
+        gpu_process(100, Some("/opt/bin/python3.12")),
+        gpu_process(200, Some("trainer")),
+        gpu_process(300, None),
+    ]);
+    let datum = collect_mock_gpu_data(
+        &fs,
+        &gpus,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    let host = find_sample(&datum, 100).expect("Test: pid 100");
+    assert!(get_string(host, "cmd") == Some("python"));
+    assert!(matches!(host.get("gpu%"), Some(output::Value::F(f)) if *f == 50.0));
+    let card_only = find_sample(&datum, 200).expect("Test: pid 200");
+    assert!(get_string(card_only, "cmd") == Some("trainer"));
+    let unknown = find_sample(&datum, 300).expect("Test: pid 300");
+    assert!(get_string(unknown, "cmd") == Some("_unknown_"));
+}
+
+#[test]
+pub fn min_gpu_percent_test() {
+    // 100 is busy on the GPU, 101 holds a GPU but is idle, 102 does not use a GPU.
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (101, 1, 1000, "python", "S"),
+            (102, 1, 1000, "bash", "S"),
+        ],
+        HashMap::new(),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let gpu_process = |pid: usize, gpu_pct: f64| gpu::Process {
+        devices: gpuset::singleton_gpuset(Some(0)),
+        pid,
+        user: "zappa".to_string(),
+        uid: 1000,
+        gpu_pct,
+        ..Default::default()
+    };
+    let gpus =
+        gpu::MockGpuAPI::new().with_processes(vec![gpu_process(100, 50.0), gpu_process(101, 0.0)]);
+
+    let opts = PsOptions {
+        min_gpu_percent: Some(5.0),
+        ..Default::default()
+    };
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, &opts);
+    assert!(find_sample(&datum, 100).is_some());
+    assert!(find_sample(&datum, 101).is_none());
+    // CPU-only processes are not excluded by the GPU filter alone
+    assert!(find_sample(&datum, 102).is_some());
+
+    // With another inclusion filter, the idle GPU process can still be admitted by it, and the
+    // CPU-only process must pass it
+    let opts = PsOptions {
+        min_gpu_percent: Some(5.0),
+        min_cpu_time: Some(0),
+        ..Default::default()
+    };
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, &opts);
+    assert!(find_sample(&datum, 101).is_some());
+    assert!(find_sample(&datum, 102).is_some());
+
+    let opts = PsOptions {
+        min_gpu_percent: Some(5.0),
+        min_threads: Some(1000),
+        ..Default::default()
+    };
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, &opts);
+    assert!(find_sample(&datum, 100).is_some());
+    assert!(find_sample(&datum, 101).is_none());
+    assert!(find_sample(&datum, 102).is_none());
+}
+
+#[test]
+pub fn gpu_only_test() {
+    // 100 computes on the GPU, 101 only holds GPU memory, 102 and 103 are CPU-only.
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (101, 1, 1000, "python", "S"),
+            (102, 1, 1000, "bash", "S"),
+            (103, 1, 0, "sshd", "S"),
+        ],
+        HashMap::new(),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let gpus = gpu::MockGpuAPI::new().with_processes(vec![
+        gpu::Process {
+            devices: gpuset::singleton_gpuset(Some(0)),
+            pid: 100,
+            user: "zappa".to_string(),
+            uid: 1000,
+            gpu_pct: 50.0,
+            ..Default::default()
+        },
+        gpu::Process {
+            devices: gpuset::singleton_gpuset(Some(0)),
+            pid: 101,
+            user: "zappa".to_string(),
+            uid: 1000,
+            mem_size_kib: 1024,
+            ..Default::default()
+        },
+    ]);
+
+    let datum = collect_mock_gpu_data(
+        &fs,
+        &gpus,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(all_samples(&datum).len() == 4);
+
+    let opts = PsOptions {
+        gpu_only: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, &opts);
+    assert!(all_samples(&datum).len() == 2);
+    assert!(find_sample(&datum, 100).is_some());
+    assert!(find_sample(&datum, 101).is_some());
+
+    // It is an additional exclusion
+    let opts = PsOptions {
+        gpu_only: true,
+        min_gpu_percent: Some(5.0),
+        ..Default::default()
+    };
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, &opts);
+    assert!(all_samples(&datum).len() == 1);
+    assert!(find_sample(&datum, 100).is_some());
+}
+
+#[test]
+pub fn explain_filters_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (101, 1, 1000, "bash", "S"),
+            (102, 1, 1001, "vim", "S"),
+            (103, 1, 0, "sshd", "S"),
+        ],
+        HashMap::new(),
+    )
+    .with_users(HashMap::from([
+        (0, "root".to_string()),
+        (1000, "zappa".to_string()),
+        (1001, "beefheart".to_string()),
+    ]));
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let gpus = gpu::MockGpuAPI::new().with_processes(vec![gpu::Process {
+        devices: gpuset::singleton_gpuset(Some(0)),
+        pid: 100,
+        user: "zappa".to_string(),
+        uid: 1000,
+        gpu_pct: 50.0,
+        ..Default::default()
+    }]);
+    let explanations = |opts: &PsOptions| -> Vec<(String, u64)> {
+        let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, opts);
+        let excluded = match datum.get("excluded") {
+            Some(output::Value::A(a)) => a,
+            None => return vec![],
+            _ => panic!("Test: bad excluded"),
+        };
+        (0..excluded.len())
+            .map(|i| match excluded.at(i) {
+                output::Value::O(o) => (
+                    get_string(o, "reason").expect("Test: reason").to_string(),
+                    match o.get("count") {
+                        Some(output::Value::U(n)) => *n,
+                        _ => panic!("Test: no count"),
+                    },
+                ),
+                _ => panic!("Test: Expected object"),
+            })
+            .collect::<Vec<(String, u64)>>()
+    };
+    let explained = |reason: &str, count: u64| (reason.to_string(), count);
+
+    let opts = PsOptions {
+        min_gpu_percent: Some(60.0),
+        exclude_users: vec!["beefheart"],
+        exclude_commands: vec!["bash"],
+        explain_filters: true,
+        ..Default::default()
+    };
+    assert!(
+        explanations(&opts)
+            == [
+                explained("exclude-commands", 1),
+                explained("exclude-users", 1),
+                explained("min-gpu-percent", 1),
+            ]
+    );
+
+    // The inclusion filters are judged first, and the records are counted per filter
+    let opts = PsOptions {
+        min_threads: Some(2),
+        min_gpu_percent: Some(60.0),
+        exclude_users: vec!["beefheart"],
+        gpu_only: true,
+        explain_filters: true,
+        ..Default::default()
+    };
+    assert!(explanations(&opts) == [explained("min-cutoffs", 4)]);
+    let opts = PsOptions {
+        gpu_only: true,
+        exclude_users: vec!["beefheart"],
+        explain_filters: true,
+        ..Default::default()
+    };
+    assert!(explanations(&opts) == [explained("gpu-only", 3)]);
+
+    // Off by default
+    let opts = PsOptions {
+        gpu_only: true,
+        ..Default::default()
+    };
+    assert!(explanations(&opts).is_empty());
+}
+
+#[test]
+pub fn exclude_gpus_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (101, 1, 1000, "python", "R"),
+            (102, 1, 1000, "python", "R"),
+            (103, 1, 1000, "python", "R"),
+        ],
+        HashMap::new(),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let cards = ["GPU-aaaa1111", "GPU-bbbb2222", "GPU-cccc3333"]
+        .iter()
+        .enumerate()
+        .map(|(i, uuid)| gpu::Card {
+            index: i as i32,
+            uuid: uuid.to_string(),
+            ..Default::default()
+        })
+        .collect::<Vec<gpu::Card>>();
+    let card_states = (0..3)
+        .map(|i| gpu::CardState {
+            index: i,
+            temp_c: 50 + i,
+            ..Default::default()
+        })
+        .collect::<Vec<gpu::CardState>>();
+    // 100, 101 and 102 run on one card each, 103 on cards 1 and 2, and 200 is only known to the
+    // GPU.
+    let process = |pid: usize, devices: &[usize]| gpu::Process {
+        devices: Some(devices.iter().copied().collect()),
+        pid,
+        user: "zappa".to_string(),
+        uid: 1000,
+        gpu_pct: 10.0,
+        ..Default::default()
+    };
+    let gpus = gpu::MockGpuAPI::with_cards(cards)
+        .with_card_states(card_states)
+        .with_processes(vec![
+            process(100, &[0]),
+            process(101, &[1]),
+            process(102, &[2]),
+            process(103, &[1, 2]),
+            process(200, &[2]),
+        ]);
+    let opts = PsOptions {
+        load: true,
+        exclude_gpus: vec!["0".to_string(), "GPU-cccc3333".to_string()],
+        ..Default::default()
+    };
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, &opts);
+
+    // The remaining card keeps its position
+    let info = match datum.get("gpuinfo") {
+        Some(output::Value::O(info)) => info,
+        _ => panic!("Test: no gpuinfo"),
+    };
+    match info.get("tempc") {
+        Some(output::Value::A(a)) => {
+            assert!(a.len() == 2);
+            assert!(matches!(a.at(0), output::Value::E()));
+            assert!(matches!(a.at(1), output::Value::I(51)));
+        }
+        _ => panic!("Test: no tempc"),
+    }
+    match datum.get("gpusummary") {
+        Some(output::Value::O(summary)) => {
+            assert!(matches!(summary.get("cards"), Some(output::Value::U(1))))
+        }
+        _ => panic!("Test: no gpusummary"),
+    }
+
+    // The processes' use of the excluded cards is gone
+    let gpus_of = |pid: u64| find_sample(&datum, pid).and_then(|o| get_string(o, "gpus"));
+    assert!(gpus_of(100).is_none());
+    assert!(find_sample(&datum, 100).unwrap().get("gpu%").is_none());
+    assert!(gpus_of(101) == Some("1"));
+    assert!(gpus_of(102).is_none());
+    assert!(gpus_of(103) == Some("1"));
+    assert!(find_sample(&datum, 200).is_none());
+
+    // Nothing is excluded by default
+    let opts = PsOptions {
+        load: true,
+        ..Default::default()
+    };
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, &opts);
+    assert!(find_sample(&datum, 200).is_some());
+    match datum.get("gpuinfo") {
+        Some(output::Value::O(info)) => match info.get("tempc") {
+            Some(output::Value::A(a)) => assert!(a.len() == 3),
+            _ => panic!("Test: no tempc"),
+        },
+        _ => panic!("Test: no gpuinfo"),
+    }
+}
+
+#[test]
+pub fn gpu_mem_bandwidth_test() {
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new());
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let opts = PsOptions {
+        load: true,
+        ..Default::default()
+    };
+    // Card 0 has most of its memory allocated but is idle, card 1 streams through a small buffer,
+    // and the capacity of card 2 is unknown.  The capacity is from the card configuration.
+    let card = |index: i32, mem_size_kib: i64| gpu::Card {
+        index,
+        mem_size_kib,
+        ..Default::default()
+    };
+    let config = vec![
+        card(0, 40 * 1024 * 1024),
+        card(1, 40 * 1024 * 1024),
+        card(2, 0),
+    ];
+    let gpus = gpu::MockGpuAPI::with_cards(config).with_card_states(vec![
+        gpu::CardState {
+            index: 0,
+            mem_used_kib: 30 * 1024 * 1024,
+            mem_bandwidth_pct: 0.0,
+            ..Default::default()
+        },
+        gpu::CardState {
+            index: 1,
+            mem_used_kib: 4 * 1024 * 1024,
+            mem_bandwidth_pct: 90.0,
+            ..Default::default()
+        },
+        gpu::CardState {
+            index: 2,
+            mem_used_kib: 1024 * 1024,
+            mem_bandwidth_pct: 20.0,
+            ..Default::default()
+        },
+    ]);
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, &opts);
+    let info = match datum.get("gpuinfo") {
+        Some(output::Value::O(info)) => info,
+        _ => panic!("Test: no gpuinfo"),
+    };
+    match info.get("mutil%") {
+        Some(output::Value::A(a)) => {
+            assert!(a.len() == 3);
+            assert!(matches!(a.at(0), output::Value::E()));
+            assert!(matches!(a.at(1), output::Value::I(90)));
+            assert!(matches!(a.at(2), output::Value::I(20)));
+        }
+        _ => panic!("Test: no mutil%"),
+    }
+    match info.get("mused%") {
+        Some(output::Value::A(a)) => {
+            assert!(a.len() == 3);
+            assert!(matches!(a.at(0), output::Value::I(75)));
+            assert!(matches!(a.at(1), output::Value::I(10)));
+            assert!(matches!(a.at(2), output::Value::E()));
+        }
+        _ => panic!("Test: no mused%"),
+    }
+    match datum.get("gpusummary") {
+        Some(output::Value::O(summary)) => {
+            assert!(matches!(summary.get("mutil%"), Some(output::Value::F(x)) if *x == 36.667));
+            assert!(summary.get("mused%").is_none());
+        }
+        _ => panic!("Test: no gpusummary"),
+    }
+}
+
+#[test]
+pub fn gpu_shared_jobs_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (101, 1, 1000, "python", "R"),
+            (102, 1, 1000, "python", "R"),
+        ],
+        HashMap::new(),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let card_states = (0..3)
+        .map(|i| gpu::CardState {
+            index: i,
+            ..Default::default()
+        })
+        .collect::<Vec<gpu::CardState>>();
+    let process = |pid: usize, devices: &[usize]| gpu::Process {
+        devices: Some(devices.iter().copied().collect()),
+        pid,
+        user: "zappa".to_string(),
+        uid: 1000,
+        gpu_pct: 10.0,
+        ..Default::default()
+    };
+    let gpus = gpu::MockGpuAPI::new()
+        .with_card_states(card_states)
+        .with_processes(vec![
+            process(100, &[0]),
+            process(101, &[0]),
+            process(102, &[1]),
+        ]);
+    let opts = PsOptions {
+        load: true,
+        ..Default::default()
+    };
+    let shared = |datum: &output::Object| -> Vec<u64> {
+        match datum.get("gpusummary") {
+            Some(output::Value::O(summary)) => match summary.get("shared_job_count") {
+                Some(output::Value::A(a)) => (0..a.len())
+                    .map(|i| match a.at(i) {
+                        output::Value::U(n) => *n,
+                        _ => panic!("Test: Expected count"),
+                    })
+                    .collect::<Vec<u64>>(),
+                _ => panic!("Test: no shared_job_count"),
+            },
+            _ => panic!("Test: no gpusummary"),
+        }
+    };
+
+    // The job ID is the pid, so two jobs share card 0 and card 2 is idle
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut MockJobManager {}, &opts);
+    assert!(shared(&datum) == [2, 1, 0]);
+
+    // All processes are in the same job
+    let mut jobs = FixedJobManager { job_id: 17 };
+    let datum = collect_mock_gpu_data(&fs, &gpus, &containers, &mut jobs, &opts);
+    assert!(shared(&datum) == [1, 1, 0]);
+}
+
+#[test]
+pub fn syslog_summary_test() {
+    let fs = mock_fs_with_processes(
+        &[
+            (100, 1, 1000, "python", "R"),
+            (101, 1, 1000, "python", "R"),
+            (102, 1, 0, "sshd", "D"),
+        ],
+        HashMap::from([
+            (
+                "loadavg".to_string(),
+                "1.50 0.75 0.25 2/300 4711\n".to_string(),
+            ),
+            (
+                "meminfo".to_string(),
+                "MemTotal:       16000000 kB\nMemAvailable:    4000000 kB\n".to_string(),
+            ),
+        ]),
+    );
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let opts = PsOptions {
+        syslog_summary: true,
+        load: true,
+        // A health error on a heartbeat record of its own
+        d_state_warn: Some(0),
+        ..Default::default()
+    };
+    let mut datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    let cpu = match find_sample(&datum, 100).expect("Test: python").get("cpu%") {
+        Some(output::Value::F(x)) => *x,
+        _ => panic!("Test: no cpu%"),
+    };
+    let mut gpus = output::Object::new();
+    gpus.push_u("cards", 2);
+    gpus.push_f("cutil%", 37.5);
+    gpus.push_f("mused%", 50.0);
+    datum.push_o("gpusummary", gpus);
+    let line = syslog_summary(&fs, &datum);
+    assert!(!line.contains('\n'));
+    // The heartbeat is not a process and sonar is not a user
+    assert!(all_samples(&datum)
+        .iter()
+        .any(|s| get_string(s, "user") == Some("_sonar_")));
+    assert!(line.starts_with("sonar host=hello time=2025-01-24T10:39:00+01:00 procs=3 "));
+    assert!(line.contains(&format!(
+        " top_users=zappa:{},root:{} ",
+        three_places(2.0 * cpu),
+        three_places(cpu)
+    )));
+    assert!(!line.contains("_sonar_"));
+    // The load is that of the node
+    assert!(line.ends_with(" load1=1.5 mem%=75 gpus=2 gpu%=37.5 gpumem%=50"));
+
+    // A failed sample is summarized by its error, which stays on one line
+    let mut hb = output::Object::new();
+    hb.push_s("host", "hello".to_string());
+    hb.push_s("time", "2025-01-24T10:39:00+01:00".to_string());
+    errors::Error::new(
+        errors::ErrorCode::System,
+        "No \"stat\"\n\tat\\x".to_string(),
+    )
+    .add_to(&mut hb);
+    assert!(
+        syslog_summary(&fs, &hb)
+            == "sonar host=hello time=2025-01-24T10:39:00+01:00 error=\"No \\\"stat\\\"\\n\\tat\\\\x\""
+    );
+}
+
+#[test]
+pub fn cpu_profile_test() {
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new());
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let datum = collect_mock_data(
+        &fs,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    assert!(datum.get("profile").is_none());
+    assert!(find_sample(&datum, 100)
+        .expect("Test: python")
+        .get("rssanonkib")
+        .is_some());
+
+    // The memory fields are not collected, and the envelope says so
+    let opts = PsOptions {
+        profile: procfs::Profile::Cpu,
+        ..Default::default()
+    };
+    let datum = collect_mock_data(&fs, &containers, &mut MockJobManager {}, &opts);
+    assert!(get_string(&datum, "profile") == Some("cpu"));
+    let python = find_sample(&datum, 100).expect("Test: python");
+    assert!(python.get("rssanonkib").is_none() && python.get("cpukib").is_none());
+    assert!(python.get("cpu%").is_some());
+}
+
+// A GPU failure is on the process records and, with its cause, on a record of its own.
+
+#[test]
+pub fn gpu_failure_test() {
+    let fs = mock_fs_with_processes(&[(100, 1, 1000, "python", "R")], HashMap::new());
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let datum = collect_mock_gpu_data(
+        &fs,
+        &gpu::MockGpuAPI::failing(),
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    let python = find_sample(&datum, 100).expect("Test: python");
+    assert!(matches!(python.get("gpufail"), Some(output::Value::U(1))));
+    let errors = all_samples(&datum)
+        .into_iter()
+        .filter(|o| get_string(o, "error_code") == Some("gpu-probe"))
+        .collect::<Vec<&output::Object>>();
+    assert!(errors.len() == 1);
+    assert!(get_string(errors[0], "error") == Some("Could not obtain GPU data: Mock card failure"));
+}
+
+// With another PID namespace the cards' host pids are translated, and the user is the one in the
+// namespace's process table.  Host pid 5101 is not in the namespace.
+
+#[test]
+pub fn gpu_host_pids_test() {
+    let fs = mock_fs_with_processes(&[(100, 1, 0, "python", "R")], HashMap::new())
+        .with_host_pids(HashMap::from([(5100, 100)]));
+    let containers = containers::MockContainerAPI::new(HashMap::new());
+    let gpu_process = |pid: usize, gpu_pct: f64| gpu::Process {
+        devices: gpuset::singleton_gpuset(Some(0)),
+        pid,
+        user: "zappa".to_string(),
+        uid: 1000,
+        gpu_pct,
+        ..Default::default()
+    };
+    let gpus = gpu::MockGpuAPI::new()
+        .with_processes(vec![gpu_process(5100, 50.0), gpu_process(5101, 25.0)]);
+    let datum = collect_mock_gpu_data(
+        &fs,
+        &gpus,
+        &containers,
+        &mut MockJobManager {},
+        &Default::default(),
+    );
+    let python = find_sample(&datum, 100).expect("Test: python");
+    assert!(matches!(python.get("gpu%"), Some(output::Value::F(x)) if *x == 50.0));
+    assert!(get_string(python, "user") == Some("root"));
+    assert!(find_sample(&datum, 5100).is_none());
+    assert!(find_sample(&datum, 5101).is_none());
+}
@@ -1,5 +1,6 @@
 // Abstraction of jobs::JobManager for SLURM.
 
+use crate::command;
 use crate::jobs;
 use crate::procfs;
 
@@ -18,6 +19,127 @@ impl jobs::JobManager for SlurmJobManager {
         let slurm_job_id = get_slurm_job_id(pid).unwrap_or_default();
         slurm_job_id.trim().parse::<usize>().unwrap_or_default()
     }
+
+    fn job_info(&mut self, job_id: usize) -> Option<jobs::JobInfo> {
+        match command::safe_command(
+            "scontrol",
+            &["show", "job", "--oneliner", &job_id.to_string()],
+            SCONTROL_TIMEOUT_S,
+        ) {
+            Ok(output) => parse_scontrol_job(&output),
+            Err(_) => None,
+        }
+    }
+
+    fn reservation(&mut self, name: &str) -> Option<jobs::Reservation> {
+        match command::safe_command(
+            "scontrol",
+            &["show", "reservation", "--oneliner", name],
+            SCONTROL_TIMEOUT_S,
+        ) {
+            Ok(output) => parse_scontrol_reservation(&output),
+            Err(_) => None,
+        }
+    }
+
+    fn cluster_name(&mut self) -> Option<String> {
+        let mut filenames = SLURM_CONF_FILES.map(|s| s.to_string()).to_vec();
+        if let Ok(filename) = std::env::var("SLURM_CONF") {
+            filenames.insert(0, filename);
+        }
+        filenames
+            .iter()
+            .find_map(|filename| std::fs::read_to_string(filename).ok())
+            .and_then(|text| parse_slurm_conf(&text))
+    }
+}
+
+// The cluster name is read from the node's slurm.conf rather than asked of the controller, as it is
+// needed for every sample.  SLURM_CONF overrides the default location, and in a configless setup
+// slurmd keeps a copy of the controller's file in its cache.
+const SLURM_CONF_FILES: [&str; 2] = ["/etc/slurm/slurm.conf", "/run/slurm/conf/slurm.conf"];
+
+// scontrol is normally fast, but the controller may be overloaded.  We don't want to hold up the
+// sample for long, and the information is optional.
+const SCONTROL_TIMEOUT_S: u64 = 5;
+
+// The output of `scontrol show job --oneliner <id>` is a single line of space-separated Key=Value
+// pairs.  Reservation is "(null)" or absent for jobs that are not in a reservation.  The
+// reservation's window is not in the job's output and is left empty here.
+
+pub fn parse_scontrol_job(output: &str) -> Option<jobs::JobInfo> {
+    let fields = parse_scontrol_fields(output);
+    let account = fields.get("Account");
+    let partition = fields.get("Partition");
+    if account.is_none() && partition.is_none() {
+        return None;
+    }
+    let get = |key: &str| fields.get(key).cloned().unwrap_or_default();
+    Some(jobs::JobInfo {
+        account: get("Account"),
+        partition: get("Partition"),
+        reservation: fields
+            .get("Reservation")
+            .filter(|r| r.as_str() != "(null)")
+            .cloned()
+            .unwrap_or_default(),
+        reservation_start: "".to_string(),
+        reservation_end: "".to_string(),
+    })
+}
+
+// The output of `scontrol show reservation --oneliner <name>` is a line of Key=Value pairs like
+// that for a job, with the window in StartTime and EndTime.
+
+pub fn parse_scontrol_reservation(output: &str) -> Option<jobs::Reservation> {
+    let fields = parse_scontrol_fields(output);
+    match (fields.get("StartTime"), fields.get("EndTime")) {
+        (Some(start_time), Some(end_time)) => Some(jobs::Reservation {
+            start_time: start_time.clone(),
+            end_time: end_time.clone(),
+        }),
+        _ => None,
+    }
+}
+
+// Values that contain spaces, eg Command, WorkDir and SubmitLine, are cut at the first space.  A
+// word in such a value may look like a pair, eg an argument `Account=x` in the submit line, so only
+// the first occurrence of a key counts; the keys that are used come before those values.
+
+fn parse_scontrol_fields(output: &str) -> HashMap<&str, String> {
+    let mut fields = HashMap::<&str, String>::new();
+    for word in output.split_ascii_whitespace() {
+        if let Some((key, value)) = word.split_once('=') {
+            if is_scontrol_key(key) {
+                fields.entry(key).or_insert_with(|| value.to_string());
+            }
+        }
+    }
+    fields
+}
+
+// Keys are eg "JobId", "CPUs/Task", "AllocNode:Sid".
+
+fn is_scontrol_key(key: &str) -> bool {
+    key.starts_with(|c: char| c.is_ascii_uppercase())
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == ':' || c == '/' || c == '_')
+}
+
+// slurm.conf has one `Key=Value` setting per line, with case-insensitive keys and comments from `#`
+// to the end of the line.  Return the value of ClusterName.
+
+pub fn parse_slurm_conf(text: &str) -> Option<String> {
+    for l in text.lines() {
+        let l = l.split('#').next().unwrap_or_default();
+        if let Some((key, value)) = l.split_once('=') {
+            if key.trim().eq_ignore_ascii_case("ClusterName") && !value.trim().is_empty() {
+                return Some(value.trim().to_string());
+            }
+        }
+    }
+    None
 }
 
 fn get_slurm_job_id(pid: usize) -> Option<String> {
@@ -46,3 +168,48 @@ fn get_slurm_job_id(pid: usize) -> Option<String> {
         Err(_) => None,
     }
 }
+
+#[test]
+pub fn parse_scontrol_job_test() {
+    let info = parse_scontrol_job(std::include_str!("testdata/scontrol-output.txt"))
+        .expect("Test: Must have data");
+    assert!(info.account == "ec201");
+    assert!(info.partition == "accel");
+    assert!(info.reservation.is_empty());
+    assert!(parse_scontrol_job("slurm_load_jobs error: Invalid job id specified").is_none());
+
+    // Pairs in the submit line do not override the job's own fields
+    let info = parse_scontrol_job(
+        "JobId=17 Account=ec201 QOS=normal Partition=normal Command=/home/zappa/job.sh \
+         SubmitLine=sbatch Account=ec999 Partition=accel job.sh WorkDir=/home/zappa",
+    )
+    .expect("Test: Must have data");
+    assert!(info.account == "ec201");
+    assert!(info.partition == "normal");
+
+    let info = parse_scontrol_job("JobId=17 Account=ec201 Partition=normal Reservation=(null)")
+        .expect("Test: Must have data");
+    assert!(info.reservation.is_empty());
+    let info = parse_scontrol_job("JobId=17 Account=ec201 Partition=normal Reservation=ml-course")
+        .expect("Test: Must have data");
+    assert!(info.reservation == "ml-course");
+}
+
+#[test]
+pub fn parse_scontrol_reservation_test() {
+    let r = parse_scontrol_reservation(std::include_str!(
+        "testdata/scontrol-reservation-output.txt"
+    ))
+    .expect("Test: Must have data");
+    assert!(r.start_time == "2024-10-21T08:00:00");
+    assert!(r.end_time == "2024-10-25T16:00:00");
+    assert!(parse_scontrol_reservation("Reservation nonesuch not found").is_none());
+}
+
+#[test]
+pub fn parse_slurm_conf_test() {
+    let cluster = parse_slurm_conf(std::include_str!("testdata/slurm.conf"));
+    assert!(cluster.as_deref() == Some("fox"));
+    assert!(parse_slurm_conf("clustername = saga\n").as_deref() == Some("saga"));
+    assert!(parse_slurm_conf("#ClusterName=saga\nSlurmctldHost=ctl\n").is_none());
+}
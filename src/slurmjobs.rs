@@ -1,12 +1,14 @@
 // Run sacct, extract output and reformat as CSV or JSON on stdout.
 
 use crate::command;
+use crate::errors;
+use crate::log;
 use crate::output;
 use crate::time;
 
 #[cfg(test)]
 use std::cmp::min;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io;
 
 // Default sacct reporting window.  Note this value is baked into the help message in main.rs too.
@@ -15,33 +17,169 @@ const DEFAULT_WINDOW: u32 = 90;
 // 3 minutes ought to be enough for anyone.
 const TIMEOUT_S: u64 = 180;
 
+// A --span covering years on a busy cluster can produce gigabytes of sacct output, which we don't
+// want to buffer.  Note this value is baked into the help message in main.rs too.
+const DEFAULT_MAX_OUTPUT_MIB: usize = 512;
+
 // Same output format as sacctd, which uses this version number.
 const VERSION: &str = "0.1.0";
 
+#[derive(Default)]
+pub struct SlurmjobsOptions {
+    pub window: Option<u32>,
+    pub span: Option<String>,
+    pub strict: bool,
+    pub nest_steps: bool,
+    pub batch_size: Option<usize>,
+    pub max_output_mib: Option<usize>,
+    pub job_submit_info: bool,
+    pub token: String,
+}
+
 pub fn show_slurm_jobs(
     writer: &mut dyn io::Write,
-    window: &Option<u32>,
-    span: &Option<String>,
     timestamp: &str,
     json: bool,
+    msgpack: bool,
+    opts: &SlurmjobsOptions,
 ) {
-    match collect_jobs(window, span, json) {
-        Ok(jobs) => print_jobs(writer, jobs, json),
-        Err(error) => print_error(writer, error, timestamp, json)
+    // MessagePack output has the same structure as the JSON output.
+    let json = json || msgpack;
+    let max_output_bytes = opts
+        .max_output_mib
+        .unwrap_or(DEFAULT_MAX_OUTPUT_MIB)
+        .saturating_mul(1024 * 1024);
+    let mut printer = JobPrinter::new(writer, json, msgpack, opts.batch_size, &opts.token);
+    // Steps can only be nested once all the jobs are known, otherwise the jobs are printed as they
+    // are parsed.
+    let collect = |emit: &mut dyn FnMut(output::Object)| {
+        collect_jobs(
+            &opts.window,
+            &opts.span,
+            opts.strict,
+            json,
+            opts.job_submit_info,
+            max_output_bytes,
+            emit,
+        )
+    };
+    let result = if opts.nest_steps {
+        let mut jobs = output::Array::new();
+        let result = collect(&mut |job| jobs.push_o(job));
+        if result.is_ok() {
+            for job in nest_steps(jobs).into_vec() {
+                if let output::Value::O(o) = job {
+                    printer.push(o);
+                }
+            }
+        }
+        result
+    } else {
+        collect(&mut |job| printer.push(job))
+    };
+    match result {
+        Ok(()) => printer.finish(),
+        Err(error) => printer.fail(error, timestamp),
     }
 }
 
-fn print_jobs(writer: &mut dyn io::Write, jobs: output::Array, json: bool) {
-    if json {
+// With a batch size, JSON and MessagePack output is a sequence of envelopes with at most that many
+// jobs each, so that a large window does not produce one huge message, and each envelope is written
+// as soon as it is full so that the jobs need not all be held in memory.  There is always at least
+// one envelope, even if there are no jobs.  CSV output is one record per line anyway and is written
+// as the jobs arrive.
+
+struct JobPrinter<'a> {
+    writer: &'a mut dyn io::Write,
+    json: bool,
+    msgpack: bool,
+    batch_size: Option<usize>,
+    token: &'a str,
+    jobs: output::Array,
+    envelopes: usize,
+}
+
+impl<'a> JobPrinter<'a> {
+    fn new(
+        writer: &'a mut dyn io::Write,
+        json: bool,
+        msgpack: bool,
+        batch_size: Option<usize>,
+        token: &'a str,
+    ) -> JobPrinter<'a> {
+        JobPrinter {
+            writer,
+            json,
+            msgpack,
+            batch_size,
+            token,
+            jobs: output::Array::new(),
+            envelopes: 0,
+        }
+    }
+
+    fn push(&mut self, job: output::Object) {
+        if self.json {
+            self.jobs.push_o(job);
+            if let Some(n) = self.batch_size {
+                if self.jobs.len() >= n.max(1) {
+                    self.flush();
+                }
+            }
+        } else {
+            output::write_csv(self.writer, &output::Value::O(job));
+        }
+    }
+
+    fn finish(mut self) {
+        if self.json && (self.jobs.len() > 0 || self.envelopes == 0) {
+            self.flush();
+        }
+    }
+
+    // Errors are detected before any job is pushed.
+
+    fn fail(self, error: errors::Error, timestamp: &str) {
+        print_error(
+            self.writer,
+            error,
+            timestamp,
+            self.json,
+            self.msgpack,
+            self.token,
+        )
+    }
+
+    fn flush(&mut self) {
         let mut envelope = output::Object::new();
         envelope.push_s("v", VERSION.to_string());
-        envelope.push_a("jobs", jobs);
-        output::write_json(writer, &output::Value::O(envelope));
-    } else {
-        for i in 0..jobs.len() {
-            output::write_csv(writer, jobs.at(i));
+        output::push_schema(&mut envelope, output::SLURM_SCHEMA_VERSION);
+        output::push_token(&mut envelope, self.token);
+        envelope.push_a(
+            "jobs",
+            std::mem::replace(&mut self.jobs, output::Array::new()),
+        );
+        write_envelope(self.writer, envelope, self.msgpack);
+        self.envelopes += 1;
+    }
+}
+
+#[cfg(test)]
+fn print_jobs(
+    writer: &mut dyn io::Write,
+    jobs: output::Array,
+    json: bool,
+    msgpack: bool,
+    batch_size: Option<usize>,
+    token: &str,
+) {
+    let mut printer = JobPrinter::new(writer, json, msgpack, batch_size, token);
+    for job in jobs.into_vec() {
+        if let output::Value::O(o) = job {
+            printer.push(o);
         }
     }
+    printer.finish();
 }
 
 // For JSON, if there's an error, it gets placed in the envelope.  But for CSV, it needs to be
@@ -50,30 +188,59 @@ fn print_jobs(writer: &mut dyn io::Write, jobs: output::Array, json: bool) {
 // the back end, the ingestor needs to deal with a possibly synthesized record that has only that
 // field, and not assume that any particular field is present.
 
-fn print_error(writer: &mut dyn io::Write, error: String, timestamp: &str, json: bool) {
+fn print_error(
+    writer: &mut dyn io::Write,
+    error: errors::Error,
+    timestamp: &str,
+    json: bool,
+    msgpack: bool,
+    token: &str,
+) {
     let mut envelope = output::Object::new();
     envelope.push_s("v", VERSION.to_string());
-    envelope.push_s("error", error);
+    output::push_schema(&mut envelope, output::SLURM_SCHEMA_VERSION);
+    output::push_token(&mut envelope, token);
+    error.add_to(&mut envelope);
     envelope.push_s("timestamp", timestamp.to_string());
     if json {
-        output::write_json(writer, &output::Value::O(envelope));
+        write_envelope(writer, envelope, msgpack);
     } else {
         output::write_csv(writer, &output::Value::O(envelope));
     }
 }
 
+fn write_envelope(writer: &mut dyn io::Write, envelope: output::Object, msgpack: bool) {
+    if msgpack {
+        output::write_msgpack(writer, &output::Value::O(envelope));
+    } else {
+        output::write_json(writer, &output::Value::O(envelope));
+    }
+}
+
+// The parsed jobs are passed to `emit` one at a time.  An error is returned before any job is.
+
 fn collect_jobs(
     window: &Option<u32>,
     span: &Option<String>,
+    strict: bool,
     json: bool,
-) -> Result<output::Array, String> {
-    let (job_states, field_names) = parameters();
+    job_submit_info: bool,
+    max_output_bytes: usize,
+    emit: &mut dyn FnMut(output::Object),
+) -> Result<(), errors::Error> {
+    let (job_states, mut field_names) = parameters();
+    if job_submit_info {
+        add_submit_info(&mut field_names);
+    }
 
     // Parse the options to compute the time range to pass to sacct.
     let (from, to) = if let Some(s) = span {
         let components = s.split(',').collect::<Vec<&str>>();
         if components.len() != 2 || !check_ymd(components[0]) || !check_ymd(components[1]) {
-            return Err(format!("Bad --span: {}", s));
+            return Err(errors::Error::new(
+                errors::ErrorCode::BadArgument,
+                format!("Bad --span: {}", s),
+            ));
         }
         (components[0].to_string(), components[1].to_string())
     } else {
@@ -85,7 +252,7 @@ fn collect_jobs(
     };
 
     // Run sacct and parse the output.
-    match command::safe_command(
+    match command::safe_command_with_limit(
         "sacct",
         &[
             "-aP",
@@ -100,17 +267,52 @@ fn collect_jobs(
             &to,
         ],
         TIMEOUT_S,
+        max_output_bytes,
     ) {
-        Err(e) => {
-            Err(format!("sacct failed: {:?}", e))
-        }
+        Err(e) => Err(errors::Error::new(
+            errors::ErrorCode::from_cmd_error(&e),
+            format!("sacct failed: {:?}", e),
+        )),
         Ok(sacct_output) => {
             let local = time::now_local();
-            Ok(parse_jobs(&sacct_output, &field_names, &local, !json))
+            checked_parse_jobs(&sacct_output, &field_names, &local, !json, strict, emit)
         }
     }
 }
 
+// In strict mode, any malformed line is an error.  Otherwise malformed lines are logged and
+// skipped, unless every line is malformed: that is format drift, not an absence of jobs, and is an
+// error too.  Empty sacct output is not an error.  The lines are checked before any job is passed
+// to `emit`.
+
+fn checked_parse_jobs(
+    sacct_output: &str,
+    field_names: &[&str],
+    local: &libc::tm,
+    version_per_line: bool,
+    strict: bool,
+    emit: &mut dyn FnMut(output::Object),
+) -> Result<(), errors::Error> {
+    let malformed = sacct_output
+        .lines()
+        .enumerate()
+        .filter_map(|(lineno, line)| check_line(lineno, line, field_names))
+        .collect::<Vec<String>>();
+    if !malformed.is_empty() {
+        if strict || malformed.len() == sacct_output.lines().count() {
+            return Err(errors::Error::new(
+                errors::ErrorCode::Parse,
+                format!("Malformed sacct output: {}", malformed.join("; ")),
+            ));
+        }
+        for m in malformed {
+            log::error(&format!("Skipping malformed sacct output: {m}"));
+        }
+    }
+    parse_jobs_into(sacct_output, field_names, local, version_per_line, emit);
+    Ok(())
+}
+
 // This is a dumb hack.  These arrays are global and shared between production and testing code, but
 // we don't want to depend on lazy_static.
 
@@ -161,6 +363,13 @@ fn parameters() -> (Vec<&'static str>, Vec<&'static str>) {
         "Partition",
         "AllocTRES",
         "Priority",
+        // Cluster disambiguates jobs in a federation.
+        "Cluster",
+        // Usage per TRES, for steps; with JSON the GPU components are also broken out.
+        "TRESUsageInTot",
+        "TRESUsageInAve",
+        // QOS matters for fair-share and priority.
+        "QOS",
         // JobName must be last in case it contains `|`, code below will clean that up.
         "JobName",
     ];
@@ -168,6 +377,16 @@ fn parameters() -> (Vec<&'static str>, Vec<&'static str>) {
     (job_states, field_names)
 }
 
+// The working directory and submit line of a job help reproduce it, but they can reveal private
+// paths and program arguments and are only extracted on request.  They go before JobName, which
+// must stay last.  A `|` in the submit line can't be told from one in the job name, the excess
+// fields end up in the job name.
+
+fn add_submit_info(field_names: &mut Vec<&'static str>) {
+    let last = field_names.len() - 1;
+    field_names.splice(last..last, ["WorkDir", "SubmitLine"]);
+}
+
 fn check_ymd(s: &str) -> bool {
     let mut k = 0;
     for f in s.split('-') {
@@ -179,12 +398,53 @@ fn check_ymd(s: &str) -> bool {
     k == 3
 }
 
+// A line is malformed if it has fewer fields than there are field names, which indicates that
+// sacct's configuration or version does not match our expectations.  Returns a description of the
+// problem if the line is malformed.
+
+fn check_line(lineno: usize, line: &str, field_names: &[&str]) -> Option<String> {
+    let n = line.split('|').count();
+    if n < field_names.len() {
+        Some(format!(
+            "line {}: {n} fields, expected {}",
+            lineno + 1,
+            field_names.len()
+        ))
+    } else {
+        None
+    }
+}
+
+// Returns the parsed jobs and a description of each line that was malformed and was skipped.
+
+#[cfg(test)]
 fn parse_jobs(
     sacct_output: &str,
     field_names: &[&str],
     local: &libc::tm,
     version_per_line: bool,
-) -> output::Array {
+) -> (output::Array, Vec<String>) {
+    let mut jobs = output::Array::new();
+    let malformed = parse_jobs_into(
+        sacct_output,
+        field_names,
+        local,
+        version_per_line,
+        &mut |job| jobs.push_o(job),
+    );
+    (jobs, malformed)
+}
+
+// Passes the parsed jobs to `emit` one at a time and returns a description of each line that was
+// malformed and was skipped.
+
+fn parse_jobs_into(
+    sacct_output: &str,
+    field_names: &[&str],
+    local: &libc::tm,
+    version_per_line: bool,
+    emit: &mut dyn FnMut(output::Object),
+) -> Vec<String> {
     // Fields that are dates that may be reinterpreted before transmission.
     let date_fields = HashSet::from(["Start", "End", "Submit"]);
 
@@ -194,14 +454,25 @@ fn parse_jobs(
     // Zero values in "controlled" fields.
     let zero_values = HashSet::from(["Unknown", "0", "00:00:00", "0:0", "0.00M"]);
 
+    // TRES usage fields and the fields holding their GPU components, which are structured and
+    // therefore only added for json.
+    let gpu_usage_fields = HashMap::from([
+        ("TRESUsageInTot", "GpuUsageInTot"),
+        ("TRESUsageInAve", "GpuUsageInAve"),
+    ]);
+
     // For csv, push out records individually; if we add "common" fields (such as error information)
     // they will piggyback on the first record, as does `load` for `ps`.
     //
     // For json, collect records in an array and then push out an envelope containing that array, as
     // this envelope can later be adapted to hold more fields.
 
-    let mut jobs = output::Array::new();
-    for line in sacct_output.lines() {
+    let mut malformed = vec![];
+    for (lineno, line) in sacct_output.lines().enumerate() {
+        if let Some(m) = check_line(lineno, line, field_names) {
+            malformed.push(m);
+            continue;
+        }
         let mut field_store = line.split('|').collect::<Vec<&str>>();
 
         // If there are more fields than field names then that's because the job name
@@ -215,6 +486,7 @@ fn parse_jobs(
         let mut output_line = output::Object::new();
         if version_per_line {
             output_line.push_s("v", VERSION.to_string());
+            output::push_schema(&mut output_line, output::SLURM_SCHEMA_VERSION);
         }
         for (i, name) in field_names.iter().enumerate() {
             let mut val = fields[i].to_string();
@@ -234,12 +506,118 @@ fn parse_jobs(
                         val = time::format_iso8601(&t).to_string()
                     }
                 }
+                if !version_per_line {
+                    // Every record, job or step, has its own allocation.
+                    if *name == "AllocTRES" {
+                        let alloc = parse_tres(&val);
+                        if !alloc.is_empty() {
+                            output_line.push_o("AllocResources", alloc);
+                        }
+                    }
+                    if let Some(gpu_name) = gpu_usage_fields.get(name) {
+                        let gpu_usage = parse_gpu_tres(&val);
+                        if !gpu_usage.is_empty() {
+                            output_line.push_o(gpu_name, gpu_usage);
+                        }
+                    }
+                }
                 output_line.push_s(name, val);
             }
         }
-        jobs.push_o(output_line);
+        emit(output_line);
+    }
+    malformed
+}
+
+// Return all the components of a TRES list (see below) as an object with the names as in the list
+// (billing, cpu, gres/gpu, gres/gpu:a100, mem, node, ...) and the values as strings.  Malformed
+// elements are ignored.
+
+fn parse_tres(tres: &str) -> output::Object {
+    let mut o = output::Object::new();
+    for elt in tres.split(',') {
+        if let Some((name, value)) = elt.split_once('=') {
+            let (name, value) = (name.trim(), value.trim());
+            if !name.is_empty() && !value.is_empty() {
+                o.push_s(name, value.to_string());
+            }
+        }
     }
-    jobs
+    o
+}
+
+// A TRES list is name=value,name=value,... where the names may contain '/' and ':', eg
+// "cpu=00:10:00,gres/gpumem=30772M,gres/gpuutil=87,mem=10121984K".  Return the gres/gpu components
+// as an object with the "gres/" prefix removed (gpumem, gpuutil, gpu:a100, ...), the values are
+// kept as strings as in the rest of the record.  Malformed elements are ignored.
+
+fn parse_gpu_tres(tres: &str) -> output::Object {
+    let mut gpu = output::Object::new();
+    for elt in tres.split(',') {
+        if let Some((name, value)) = elt.split_once('=') {
+            if let Some(name) = name.trim().strip_prefix("gres/") {
+                if name.starts_with("gpu") && !value.is_empty() {
+                    gpu.push_s(name, value.trim().to_string());
+                }
+            }
+        }
+    }
+    gpu
+}
+
+// With --nest-steps, each step record (batch, extern, 0, ...) is moved into a "steps" array in the
+// record of its job, in the order the steps appear, and the jobs keep their order.  A step belongs
+// to the job whose JobIDRaw is the part of the step's JobIDRaw before the '.'.  Every array task
+// and every het job component has a JobIDRaw of its own and so becomes a job with its own steps;
+// the array or het job as a whole is not grouped further, as its parts have separate resources and
+// accounting.  The Cluster is part of the key, for federations.  A step whose job is not in the
+// output remains a top-level record where it was.
+
+fn nest_steps(jobs: output::Array) -> output::Array {
+    let field = |o: &output::Object, name: &str| -> String {
+        match o.get(name) {
+            Some(output::Value::S(s)) => s.clone(),
+            _ => "".to_string(),
+        }
+    };
+    let records = jobs
+        .into_vec()
+        .into_iter()
+        .map(|v| match v {
+            output::Value::O(o) => o,
+            _ => panic!("Should not happen"),
+        })
+        .collect::<Vec<output::Object>>();
+    let parent_key = |o: &output::Object| -> Option<(String, String)> {
+        let raw = field(o, "JobIDRaw");
+        raw.split_once('.')
+            .map(|(job_id, _)| (field(o, "Cluster"), job_id.to_string()))
+    };
+    let job_keys = records
+        .iter()
+        .filter(|o| parent_key(o).is_none())
+        .map(|o| (field(o, "Cluster"), field(o, "JobIDRaw")))
+        .collect::<HashSet<(String, String)>>();
+
+    let mut toplevel = vec![];
+    let mut steps = HashMap::<(String, String), output::Array>::new();
+    for o in records {
+        match parent_key(&o) {
+            Some(key) if job_keys.contains(&key) => steps
+                .entry(key)
+                .or_insert_with(output::Array::new)
+                .push_o(o),
+            _ => toplevel.push(o),
+        }
+    }
+    let mut nested = output::Array::new();
+    for mut o in toplevel {
+        if let Some(job_steps) = steps.remove(&(field(&o, "Cluster"), field(&o, "JobIDRaw"))) {
+            o.push_a("steps", job_steps);
+        }
+        nested.push_o(o);
+    }
+    nested
 }
 
 // There is a test case that the "error" field is generated correctly in ../tests/slurm-no-sacct.sh.
@@ -249,11 +627,12 @@ fn parse_jobs(
 pub fn test_format_jobs() {
     let (_, field_names) = parameters();
 
-    // Actual sacct output from Fox, anonymized and with one command name replaced and Priority
-    // added.
+    // Actual sacct output from Fox, anonymized and with one command name replaced and Priority,
+    // Cluster, TRES usage, and QOS added.
     let sacct_output = std::include_str!("testdata/sacct-output.txt");
 
-    // The golang `sacctd` output for the above input, with Priority added.
+    // The golang `sacctd` output for the above input, with schema, Priority, Cluster, TRES usage,
+    // and QOS added.
     let expected = std::include_str!("testdata/sacctd-output.txt");
 
     let mut output = Vec::new();
@@ -261,8 +640,9 @@ pub fn test_format_jobs() {
     // The output below depends on us being in UTC+01:00 and not in dst so mock that.
     local.tm_gmtoff = 3600;
     local.tm_isdst = 0;
-    let jobs = parse_jobs(sacct_output, &field_names, &local, true);
-    print_jobs(&mut output, jobs, false);
+    let (jobs, malformed) = parse_jobs(sacct_output, &field_names, &local, true);
+    assert!(malformed.is_empty());
+    print_jobs(&mut output, jobs, false, false, None, "");
     if output != expected.as_bytes() {
         let xs = &output;
         let ys = expected.as_bytes();
@@ -291,3 +671,466 @@ pub fn test_format_jobs() {
         assert!(false);
     }
 }
+
+#[test]
+pub fn test_bad_span() {
+    let span = Some("2024-10-01".to_string());
+    match collect_jobs(&None, &span, false, false, false, usize::MAX, &mut |_| {}) {
+        Err(e) => assert!(e.code == errors::ErrorCode::BadArgument),
+        Ok(_) => panic!("Test: Expected error"),
+    }
+}
+
+// Test that the error envelope carries the schema version and the token.
+#[test]
+pub fn test_error_envelope() {
+    use crate::json;
+
+    let e = errors::Error::new(errors::ErrorCode::BadArgument, "Bad --span".to_string());
+    let mut output = Vec::new();
+    print_error(
+        &mut output,
+        e,
+        "2024-10-01T00:00:00+02:00",
+        true,
+        false,
+        "s3cret",
+    );
+    match json::parse(&String::from_utf8_lossy(&output)) {
+        Ok(output::Value::O(o)) => {
+            assert!(matches!(o.get("schema"),
+                Some(output::Value::U(n)) if *n == output::SLURM_SCHEMA_VERSION));
+            assert!(matches!(o.get("token"), Some(output::Value::S(s)) if s == "s3cret"));
+        }
+        _ => panic!("Test: Expected object"),
+    }
+
+    // A program that can't be run for lack of permission gives a permission-denied code, as sacct
+    // would.
+    let e = command::safe_command("/etc/passwd", &[], 2).expect_err("Test: Expected failure");
+    let e = errors::Error::new(
+        errors::ErrorCode::from_cmd_error(&e),
+        format!("sacct failed: {:?}", e),
+    );
+    let mut output = Vec::new();
+    print_error(&mut output, e, "2024-10-01T00:00:00+02:00", true, false, "");
+    match json::parse(&String::from_utf8_lossy(&output)) {
+        Ok(output::Value::O(o)) => {
+            assert!(matches!(o.get("error_code"),
+                Some(output::Value::S(s)) if s == "permission-denied"));
+            assert!(
+                matches!(o.get("error"), Some(output::Value::S(s)) if s.contains("/etc/passwd"))
+            );
+        }
+        _ => panic!("Test: Expected object"),
+    }
+}
+
+// Test that the cluster is picked up from a federation, where jobs on the same system can come from
+// different clusters.
+#[test]
+pub fn test_federated_jobs() {
+    let (_, field_names) = parameters();
+    let sacct_output = "\
+67108865|67108865|ec-aaaaa|ec85|COMPLETED|2024-11-13T11:08:00|2024-11-13T13:07:24||||||7164|0:0|||||6|10000M|1||2024-11-13T08:30:40|00:00:00|22:53.824|400|11:06:33|c1-28|normal|billing=6,cpu=6,mem=10000M,node=1|100|fox|||normal|train
+134217729|134217729|ec-bbbbb|ec85|FAILED|2024-11-13T11:08:00|2024-11-13T13:07:24||||||7164|1:0|||||6|10000M|1||2024-11-13T08:30:40|00:00:00|22:53.824|400|11:06:33|gpu-1|accel|billing=6,cpu=6,mem=10000M,node=1|100|fox-gpu|||devel|a|b
+";
+    let local = time::now_local();
+    let (jobs, _) = parse_jobs(sacct_output, &field_names, &local, false);
+    assert!(jobs.len() == 2);
+    let get = |i: usize, name: &str| -> String {
+        match jobs.at(i) {
+            output::Value::O(o) => match o.get(name) {
+                Some(output::Value::S(s)) => s.clone(),
+                _ => panic!("Test: Expected string field"),
+            },
+            _ => panic!("Test: Expected object"),
+        }
+    };
+    assert!(get(0, "Cluster") == "fox");
+    assert!(get(0, "QOS") == "normal");
+    assert!(get(0, "JobName") == "train");
+    assert!(get(1, "Cluster") == "fox-gpu");
+    assert!(get(1, "QOS") == "devel");
+    // The job name with `|` is still reassembled correctly.
+    assert!(get(1, "JobName") == "ab");
+}
+
+// Test that the working directory and submit line are extracted when requested, for jobs and not
+// for steps, which have neither.
+#[test]
+pub fn test_submit_info() {
+    let (_, mut field_names) = parameters();
+    add_submit_info(&mut field_names);
+    assert!(field_names.last() == Some(&"JobName"));
+    let sacct_output = "\
+67108865|67108865|ec-aaaaa|ec85|COMPLETED|2024-11-13T11:08:00|2024-11-13T13:07:24||||||7164|0:0|||||6|10000M|1||2024-11-13T08:30:40|00:00:00|22:53.824|400|11:06:33|c1-28|normal|billing=6,cpu=6,mem=10000M,node=1|100|fox|||normal|/fp/homes01/u01/ec-aaaaa/run 2|sbatch --gres=gpu:1 --export=X=1 train.sh|train
+67108865.batch|67108865.batch||ec85|COMPLETED|2024-11-13T11:08:00|2024-11-13T13:07:24||||||7164|0:0|||||6||1||2024-11-13T11:08:00|00:00:00|22:53.824||11:06:33|c1-28||cpu=6,mem=10000M,node=1||fox||||||batch
+";
+    let local = time::now_local();
+    let (jobs, malformed) = parse_jobs(sacct_output, &field_names, &local, false);
+    assert!(malformed.is_empty());
+    assert!(jobs.len() == 2);
+    let get = |i: usize, name: &str| -> Option<String> {
+        match jobs.at(i) {
+            output::Value::O(o) => match o.get(name) {
+                Some(output::Value::S(s)) => Some(s.clone()),
+                _ => None,
+            },
+            _ => panic!("Test: Expected object"),
+        }
+    };
+    assert!(get(0, "WorkDir").as_deref() == Some("/fp/homes01/u01/ec-aaaaa/run 2"));
+    assert!(get(0, "SubmitLine").as_deref() == Some("sbatch --gres=gpu:1 --export=X=1 train.sh"));
+    assert!(get(0, "JobName").as_deref() == Some("train"));
+    assert!(get(1, "WorkDir").is_none());
+    assert!(get(1, "SubmitLine").is_none());
+    assert!(get(1, "JobName").as_deref() == Some("batch"));
+}
+
+// Test that a truncated line is skipped and reported and that the other lines are kept, or, in
+// strict mode, that it causes an error.
+#[test]
+pub fn test_malformed_jobs() {
+    let (_, field_names) = parameters();
+    let sacct_output = "\
+973821|973821|ec-aaaaa|ec85|COMPLETED|2024-11-13T11:08:00|2024-11-13T13:07:24||||||7164|0:0|||||6|10000M|1||2024-11-13T08:30:40|00:00:00|22:53.824|400|11:06:33|c1-28|normal|billing=6,cpu=6,mem=10000M,node=1|100|fox||||command
+973822|973822|ec-aaaaa|ec85|COMPLETED|2024-11-13T11:08:00
+973823|973823|ec-aaaaa|ec85|COMPLETED|2024-11-13T11:08:00|2024-11-13T13:07:24||||||7164|0:0|||||6|10000M|1||2024-11-13T08:30:40|00:00:00|22:53.824|400|11:06:33|c1-28|normal|billing=6,cpu=6,mem=10000M,node=1|100|fox||||command
+";
+    let local = time::now_local();
+    let (jobs, malformed) = parse_jobs(sacct_output, &field_names, &local, false);
+    assert!(jobs.len() == 2);
+    assert!(malformed.len() == 1);
+    assert!(malformed[0].starts_with("line 2: 6 fields"));
+
+    let mut n = 0;
+    match checked_parse_jobs(
+        sacct_output,
+        &field_names,
+        &local,
+        false,
+        false,
+        &mut |_| n += 1,
+    ) {
+        Ok(()) => assert!(n == 2),
+        Err(_) => panic!("Test: Expected jobs"),
+    }
+    // Nothing is emitted before the error
+    let mut n = 0;
+    match checked_parse_jobs(sacct_output, &field_names, &local, false, true, &mut |_| {
+        n += 1
+    }) {
+        Ok(_) => panic!("Test: Expected error"),
+        Err(e) => assert!(e.code == errors::ErrorCode::Parse && n == 0),
+    }
+}
+
+// Test that the steps of a multi-step job are grouped under the job, and that array tasks and het
+// job components are grouped separately.
+#[test]
+pub fn test_nest_steps() {
+    let (_, field_names) = parameters();
+    let local = time::now_local();
+    let job_ids = |jobs: &output::Array| -> Vec<String> {
+        (0..jobs.len())
+            .map(|i| match jobs.at(i) {
+                output::Value::O(o) => match o.get("JobID") {
+                    Some(output::Value::S(s)) => s.clone(),
+                    _ => panic!("Test: Expected JobID"),
+                },
+                _ => panic!("Test: Expected object"),
+            })
+            .collect::<Vec<String>>()
+    };
+    let steps_of = |jobs: &output::Array, i: usize| -> Vec<String> {
+        match jobs.at(i) {
+            output::Value::O(o) => match o.get("steps") {
+                Some(output::Value::A(a)) => job_ids(a),
+                None => vec![],
+                _ => panic!("Test: Expected steps array"),
+            },
+            _ => panic!("Test: Expected object"),
+        }
+    };
+
+    let (jobs, _) = parse_jobs(
+        std::include_str!("testdata/sacct-output.txt"),
+        &field_names,
+        &local,
+        false,
+    );
+    let jobs = nest_steps(jobs);
+    assert!(job_ids(&jobs).iter().all(|id| !id.contains('.')));
+    assert!(job_ids(&jobs)[0] == "973821");
+    assert!(steps_of(&jobs, 0) == ["973821.batch", "973821.extern", "973821.0"]);
+    assert!(steps_of(&jobs, 1) == ["973947.batch", "973947.extern", "973947.0"]);
+
+    // JobID, JobIDRaw, and the rest of the line.  1000_1 and 1000_2 are tasks of array job 1000;
+    // 2000+0 and 2000+1 are components of het job 2000.  The parent of 3001.batch is not present.
+    let rest = "ec-aaaaa|ec85|COMPLETED|2024-11-13T11:08:00|2024-11-13T13:07:24||||||7164|0:0|||||6|10000M|1||2024-11-13T08:30:40|00:00:00|22:53.824|400|11:06:33|c1-28|normal|billing=6,cpu=6,mem=10000M,node=1|100|fox||||command";
+    let sacct_output = [
+        ("1000_1", "1001"),
+        ("1000_1.batch", "1001.batch"),
+        ("1000_2", "1002"),
+        ("1000_2.batch", "1002.batch"),
+        ("1000_2.0", "1002.0"),
+        ("2000+0", "2000"),
+        ("2000+0.batch", "2000.batch"),
+        ("3001.batch", "3001.batch"),
+        ("2000+1", "2001"),
+        ("2000+1.0", "2001.0"),
+    ]
+    .iter()
+    .map(|(id, raw)| format!("{id}|{raw}|{rest}\n"))
+    .collect::<String>();
+    let (jobs, _) = parse_jobs(&sacct_output, &field_names, &local, false);
+    let jobs = nest_steps(jobs);
+    assert!(job_ids(&jobs) == ["1000_1", "1000_2", "2000+0", "3001.batch", "2000+1"]);
+    assert!(steps_of(&jobs, 0) == ["1000_1.batch"]);
+    assert!(steps_of(&jobs, 1) == ["1000_2.batch", "1000_2.0"]);
+    assert!(steps_of(&jobs, 2) == ["2000+0.batch"]);
+    assert!(steps_of(&jobs, 3).is_empty());
+    assert!(steps_of(&jobs, 4) == ["2000+1.0"]);
+}
+
+// Test that output where no line can be parsed is an error even when not strict, and that empty
+// output is not.
+#[test]
+pub fn test_unparseable_jobs() {
+    let (_, field_names) = parameters();
+    let local = time::now_local();
+    let sacct_output = "\
+JobID|JobName|Partition|Account|AllocCPUS|State|ExitCode
+973821|command|normal|ec85|6|COMPLETED|0:0
+";
+    match checked_parse_jobs(
+        sacct_output,
+        &field_names,
+        &local,
+        false,
+        false,
+        &mut |_| {},
+    ) {
+        Ok(_) => panic!("Test: Expected error"),
+        Err(e) => assert!(e.code == errors::ErrorCode::Parse),
+    }
+    let mut n = 0;
+    match checked_parse_jobs("", &field_names, &local, false, false, &mut |_| n += 1) {
+        Ok(()) => assert!(n == 0),
+        Err(_) => panic!("Test: Expected no jobs"),
+    }
+}
+
+// Test that the GPU components of the TRES usage are broken out for json, and only for json.
+#[test]
+pub fn test_gpu_tres_usage() {
+    let (_, field_names) = parameters();
+    let local = time::now_local();
+    let sacct_output = std::include_str!("testdata/sacct-output.txt");
+    fn find<'a>(jobs: &'a output::Array, job_id: &str) -> &'a output::Object {
+        for i in 0..jobs.len() {
+            if let output::Value::O(o) = jobs.at(i) {
+                if matches!(o.get("JobID"), Some(output::Value::S(s)) if s == job_id) {
+                    return o;
+                }
+            }
+        }
+        panic!("Test: no job {job_id}")
+    }
+
+    let (jobs, _) = parse_jobs(sacct_output, &field_names, &local, false);
+    let step = find(&jobs, "973821.0");
+    assert!(
+        matches!(step.get("TRESUsageInTot"), Some(output::Value::S(s)) if s.contains("gres/gpuutil=87"))
+    );
+    let gpu = match step.get("GpuUsageInTot") {
+        Some(output::Value::O(o)) => o,
+        _ => panic!("Test: no GpuUsageInTot"),
+    };
+    assert!(matches!(gpu.get("gpuutil"), Some(output::Value::S(s)) if s == "87"));
+    assert!(matches!(gpu.get("gpumem"), Some(output::Value::S(s)) if s == "30772M"));
+    assert!(gpu.get("cpu").is_none());
+    assert!(step.get("GpuUsageInAve").is_some());
+    // No TRES usage, no GPU usage
+    assert!(find(&jobs, "973821.batch").get("GpuUsageInTot").is_none());
+
+    // Not for CSV
+    let (jobs, _) = parse_jobs(sacct_output, &field_names, &local, true);
+    assert!(find(&jobs, "973821.0").get("GpuUsageInTot").is_none());
+
+    // Typed GPUs and junk
+    let gpu = parse_gpu_tres("cpu=00:01:00,gres/gpu:a100=2,,gres/gpuutil=,bogus,gres/shard=1");
+    assert!(matches!(gpu.get("gpu:a100"), Some(output::Value::S(s)) if s == "2"));
+    assert!(gpu.get("gpuutil").is_none());
+    assert!(gpu.get("shard").is_none());
+    assert!(parse_gpu_tres("").is_empty());
+}
+
+// Test that each step of a multi-step job carries its own allocation, broken out for json, so that
+// resources can be attributed to the batch, extern, and user steps.
+#[test]
+pub fn test_step_alloc_tres() {
+    let (_, field_names) = parameters();
+    let local = time::now_local();
+    let sacct_output = std::include_str!("testdata/sacct-steps-output.txt");
+    let alloc = |jobs: &output::Array, job_id: &str| -> Vec<(String, String)> {
+        for i in 0..jobs.len() {
+            if let output::Value::O(o) = jobs.at(i) {
+                if matches!(o.get("JobID"), Some(output::Value::S(s)) if s == job_id) {
+                    return match o.get("AllocResources") {
+                        Some(output::Value::O(a)) => ["billing", "cpu", "gres/gpu", "mem"]
+                            .iter()
+                            .filter_map(|name| match a.get(name) {
+                                Some(output::Value::S(s)) => Some((name.to_string(), s.clone())),
+                                _ => None,
+                            })
+                            .collect(),
+                        None => vec![],
+                        _ => panic!("Test: Expected object"),
+                    };
+                }
+            }
+        }
+        panic!("Test: no job {job_id}")
+    };
+    let pairs = |xs: &[(&str, &str)]| -> Vec<(String, String)> {
+        xs.iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    };
+
+    let (jobs, malformed) = parse_jobs(sacct_output, &field_names, &local, false);
+    assert!(malformed.is_empty() && jobs.len() == 5);
+    assert!(
+        alloc(&jobs, "5000")
+            == pairs(&[
+                ("billing", "16"),
+                ("cpu", "8"),
+                ("gres/gpu", "2"),
+                ("mem", "64G")
+            ])
+    );
+    assert!(
+        alloc(&jobs, "5000.batch") == pairs(&[("cpu", "8"), ("gres/gpu", "2"), ("mem", "64G")])
+    );
+    assert!(
+        alloc(&jobs, "5000.extern")
+            == pairs(&[
+                ("billing", "16"),
+                ("cpu", "8"),
+                ("gres/gpu", "2"),
+                ("mem", "64G")
+            ])
+    );
+    assert!(alloc(&jobs, "5000.0") == pairs(&[("cpu", "4"), ("mem", "32G")]));
+    assert!(alloc(&jobs, "5000.1") == pairs(&[("cpu", "2"), ("mem", "8G")]));
+
+    // Typed GPUs keep their type, and the steps keep their allocations when nested
+    let jobs = nest_steps(jobs);
+    let steps = match jobs.at(0) {
+        output::Value::O(o) => match o.get("steps") {
+            Some(output::Value::A(a)) => a,
+            _ => panic!("Test: Expected steps"),
+        },
+        _ => panic!("Test: Expected object"),
+    };
+    match steps.at(2) {
+        output::Value::O(o) => match o.get("AllocResources") {
+            Some(output::Value::O(a)) => {
+                assert!(matches!(a.get("gres/gpu:a100"), Some(output::Value::S(s)) if s == "1"))
+            }
+            _ => panic!("Test: Expected AllocResources"),
+        },
+        _ => panic!("Test: Expected object"),
+    }
+
+    // Not for CSV
+    let (jobs, _) = parse_jobs(sacct_output, &field_names, &local, true);
+    assert!(alloc(&jobs, "5000.0").is_empty());
+
+    let tres = parse_tres("cpu=4,,bogus,gres/gpu=,mem= 32G");
+    assert!(matches!(tres.get("cpu"), Some(output::Value::S(s)) if s == "4"));
+    assert!(matches!(tres.get("mem"), Some(output::Value::S(s)) if s == "32G"));
+    assert!(tres.get("gres/gpu").is_none());
+    assert!(parse_tres("").is_empty());
+}
+
+// Test that with a batch size the jobs are emitted as a sequence of well-formed envelopes that
+// together hold all the jobs in order, and that without one there is a single envelope.
+#[test]
+pub fn test_batched_jobs() {
+    use crate::json;
+
+    let (_, field_names) = parameters();
+    let local = time::now_local();
+    let rest = "ec-aaaaa|ec85|COMPLETED|2024-11-13T11:08:00|2024-11-13T13:07:24||||||7164|0:0|||||6|10000M|1||2024-11-13T08:30:40|00:00:00|22:53.824|400|11:06:33|c1-28|normal|billing=6,cpu=6,mem=10000M,node=1|100|fox||||command";
+    let sacct_output = (0..25)
+        .map(|i| format!("{}|{}|{rest}\n", 1000 + i, 1000 + i))
+        .collect::<String>();
+    let envelopes = |batch_size: Option<usize>| -> Vec<Vec<String>> {
+        let (jobs, malformed) = parse_jobs(&sacct_output, &field_names, &local, false);
+        assert!(malformed.is_empty() && jobs.len() == 25);
+        let mut output = Vec::new();
+        print_jobs(&mut output, jobs, true, false, batch_size, "");
+        String::from_utf8_lossy(&output)
+            .lines()
+            .map(|line| match json::parse(line) {
+                Ok(output::Value::O(o)) => {
+                    assert!(matches!(o.get("v"), Some(output::Value::S(s)) if s == VERSION));
+                    assert!(o.get("token").is_none());
+                    assert!(matches!(o.get("schema"),
+                                     Some(output::Value::U(n)) if *n == output::SLURM_SCHEMA_VERSION));
+                    match o.get("jobs") {
+                        Some(output::Value::A(a)) => (0..a.len())
+                            .map(|i| match a.at(i) {
+                                output::Value::O(job) => match job.get("JobID") {
+                                    Some(output::Value::S(s)) => s.clone(),
+                                    _ => panic!("Test: Expected JobID"),
+                                },
+                                _ => panic!("Test: Expected object"),
+                            })
+                            .collect::<Vec<String>>(),
+                        _ => panic!("Test: Expected jobs array"),
+                    }
+                }
+                _ => panic!("Test: Expected envelope"),
+            })
+            .collect::<Vec<Vec<String>>>()
+    };
+    let all = (0..25)
+        .map(|i| format!("{}", 1000 + i))
+        .collect::<Vec<String>>();
+
+    let batches = envelopes(Some(10));
+    assert!(batches.iter().map(|b| b.len()).collect::<Vec<usize>>() == [10, 10, 5]);
+    assert!(batches.concat() == all);
+
+    let batches = envelopes(Some(25));
+    assert!(batches.len() == 1 && batches[0] == all);
+
+    let batches = envelopes(None);
+    assert!(batches.len() == 1 && batches[0] == all);
+
+    // No jobs is still one envelope
+    let mut output = Vec::new();
+    print_jobs(
+        &mut output,
+        output::Array::new(),
+        true,
+        false,
+        Some(10),
+        "s3cret",
+    );
+    let lines = String::from_utf8_lossy(&output)
+        .lines()
+        .map(|l| l.to_string())
+        .collect::<Vec<_>>();
+    assert!(lines.len() == 1);
+    match json::parse(&lines[0]) {
+        Ok(output::Value::O(o)) => {
+            assert!(matches!(o.get("token"), Some(output::Value::S(s)) if s == "s3cret"))
+        }
+        _ => panic!("Test: Expected envelope"),
+    }
+}
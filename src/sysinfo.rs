@@ -1,28 +1,177 @@
+use crate::diff;
+use crate::errors;
 use crate::gpu;
 use crate::hostname;
+use crate::json;
+use crate::log;
 use crate::output;
 use crate::procfs;
 use crate::procfsapi;
 
-use std::io;
-#[cfg(test)]
 use std::collections::HashMap;
+use std::io;
+
+#[derive(Default)]
+pub struct SysinfoOptions {
+    pub infiniband: bool,
+    pub cpu_flags: bool,
+    pub tags: Vec<(String, String)>,
+    pub token: String,
+    pub metadata: Option<String>,
+    // The sections to collect, all of them if None.
+    pub sections: Option<Vec<String>>,
+    pub exclude_gpus: Vec<String>,
+    pub physical_index: bool,
+    pub diff: Option<String>,
+}
+
+// The names of the sections that can be selected with --sections.
 
-pub fn show_system(writer: &mut dyn io::Write, timestamp: &str, csv: bool) {
-    let sysinfo = compute_sysinfo(&procfsapi::RealFS::new(), &gpu::RealGpuAPI::new(), timestamp);
+pub const SECTIONS: &[&str] = &["cpu", "memory", "gpu"];
+
+impl SysinfoOptions {
+    fn collects(&self, section: &str) -> bool {
+        match &self.sections {
+            Some(sections) => sections.iter().any(|s| s == section),
+            None => true,
+        }
+    }
+}
+
+pub fn show_system(
+    writer: &mut dyn io::Write,
+    timestamp: &str,
+    csv: bool,
+    flat: bool,
+    msgpack: bool,
+    opts: &SysinfoOptions,
+) {
+    let metadata = opts.metadata.as_deref().and_then(read_metadata);
+    let sysinfo = compute_sysinfo(
+        &procfsapi::RealFS::new(),
+        &gpu::RealGpuAPI::new(),
+        timestamp,
+        opts,
+        metadata,
+    );
+    let sysinfo = match opts.diff {
+        Some(ref filename) => {
+            let mut packet = diff_packet(timestamp, &sysinfo, filename);
+            output::push_tags(&mut packet, &opts.tags);
+            output::push_token(&mut packet, &opts.token);
+            packet
+        }
+        None => sysinfo,
+    };
     if csv {
         output::write_csv(writer, &output::Value::O(sysinfo));
+    } else if flat {
+        output::write_flat(writer, &output::Value::O(sysinfo));
+    } else if msgpack {
+        output::write_msgpack(writer, &output::Value::O(sysinfo));
     } else {
         output::write_json(writer, &output::Value::O(sysinfo));
     }
 }
 
+// The operator's node metadata file must hold a JSON object.  The metadata are optional, so a
+// missing or malformed file is reported and otherwise ignored.
+
+fn read_metadata(filename: &str) -> Option<output::Object> {
+    let text = match std::fs::read_to_string(filename) {
+        Ok(text) => text,
+        Err(e) => {
+            log::error(&format!("Could not read metadata file {filename}: {e}"));
+            return None;
+        }
+    };
+    match json::parse(&text) {
+        Ok(output::Value::O(o)) => Some(o),
+        Ok(_) => {
+            log::error(&format!("Metadata file {filename} does not hold an object"));
+            None
+        }
+        Err(e) => {
+            log::error(&format!("Malformed metadata file {filename}: {e}"));
+            None
+        }
+    }
+}
+
+// Fields that change all the time and that are not compared by --diff.
+
+const DIFF_IGNORED: &[&str] = &["timestamp", "token", "uptime_secs", "xmit_data", "rcv_data"];
+
+// The diff packet has the same "version", "timestamp", and "hostname" as the sysinfo packet, the
+// name of the baseline file, and the array of changes from the baseline to the current data (see
+// diff.rs), which is empty if nothing changed.  A baseline that can't be read is an error.
+
+fn diff_packet(timestamp: &str, sysinfo: &output::Object, filename: &str) -> output::Object {
+    let baseline = match std::fs::read_to_string(filename) {
+        Ok(text) => match json::parse(&text) {
+            Ok(output::Value::O(o)) => o,
+            Ok(_) => {
+                return error_packet(
+                    timestamp,
+                    errors::Error::new(
+                        errors::ErrorCode::BadArgument,
+                        format!("Baseline file {filename} does not hold an object"),
+                    ),
+                )
+            }
+            Err(e) => {
+                return error_packet(
+                    timestamp,
+                    errors::Error::new(
+                        errors::ErrorCode::BadArgument,
+                        format!("Malformed baseline file {filename}: {e}"),
+                    ),
+                )
+            }
+        },
+        Err(e) => {
+            return error_packet(
+                timestamp,
+                errors::Error::new(
+                    errors::ErrorCode::BadArgument,
+                    format!("Could not read baseline file {filename}: {e}"),
+                ),
+            )
+        }
+    };
+    let mut packet = new_sysinfo(timestamp);
+    packet.push_s("baseline", filename.to_string());
+    packet.push_a(
+        "changes",
+        diff::diff(
+            &output::Value::O(baseline),
+            &output::Value::O(sysinfo.clone()),
+            DIFF_IGNORED,
+        ),
+    );
+    packet
+}
+
 // The packet always has "version", "timestamp", and "hostname", and then it has either an "error"
 // field or the sysinfo fields ("cpu_cores", etc) for the node.  Fields that have default values (0,
-// "", []) may be omitted.
+// "", []) may be omitted.  The operator's metadata, if any, are under "metadata" so that they can't
+// clobber the collected fields.
 
-fn compute_sysinfo(fs: &dyn procfsapi::ProcfsAPI, gpus: &dyn gpu::GpuAPI, timestamp: &str) -> output::Object {
-    try_compute_sysinfo(fs, gpus, timestamp).unwrap_or_else(|e: String| error_packet(timestamp, e))
+fn compute_sysinfo(
+    fs: &dyn procfsapi::ProcfsAPI,
+    gpus: &dyn gpu::GpuAPI,
+    timestamp: &str,
+    opts: &SysinfoOptions,
+    metadata: Option<output::Object>,
+) -> output::Object {
+    let mut sysinfo = try_compute_sysinfo(fs, gpus, timestamp, opts)
+        .unwrap_or_else(|e| error_packet(timestamp, e));
+    output::push_tags(&mut sysinfo, &opts.tags);
+    output::push_token(&mut sysinfo, &opts.token);
+    if let Some(metadata) = metadata {
+        sysinfo.push_o("metadata", metadata);
+    }
+    sysinfo
 }
 
 const GIB: usize = 1024 * 1024 * 1024;
@@ -31,22 +180,26 @@ fn try_compute_sysinfo(
     fs: &dyn procfsapi::ProcfsAPI,
     gpus: &dyn gpu::GpuAPI,
     timestamp: &str,
-) -> Result<output::Object, String> {
-    let (model, sockets, cores_per_socket, threads_per_core) = procfs::get_cpu_info(fs)?;
-    let mem_by = procfs::get_memtotal_kib(fs)? * 1024;
-    let mem_gib = (mem_by as f64 / GIB as f64).round() as i64;
-    let (mut cards, manufacturer) = match gpus.probe() {
+    opts: &SysinfoOptions,
+) -> Result<output::Object, errors::Error> {
+    let cpu_info = if opts.collects("cpu") {
+        Some(procfs::get_cpu_info(fs)?)
+    } else {
+        None
+    };
+    let memtotal_kib = if opts.collects("memory") {
+        Some(procfs::get_memtotal_kib(fs)?)
+    } else {
+        None
+    };
+    let gpus: &dyn gpu::GpuAPI = &gpu::ExcludingGpuAPI::new(gpus, &opts.exclude_gpus);
+    let (mut cards, manufacturer) = match opts.collects("gpu").then(|| gpus.probe()).flatten() {
         Some(mut device) => (
             device.get_card_configuration().unwrap_or_default(),
             device.get_manufacturer(),
         ),
         None => (vec![], "UNKNOWN".to_string()),
     };
-    let ht = if threads_per_core > 1 {
-        " (hyperthreaded)"
-    } else {
-        ""
-    };
 
     let mut gpu_info = output::Array::new();
     let (gpu_desc, gpu_cards, gpumem_gb) = if !cards.is_empty() {
@@ -86,6 +239,12 @@ fn try_compute_sysinfo(
             total_mem_by += c.mem_size_kib * 1024;
         }
 
+        let physical_index = if opts.physical_index {
+            physical_indices(&cards)
+        } else {
+            HashMap::new()
+        };
+
         // Compute the info blobs
         for c in &cards {
             let gpu::Card {
@@ -102,10 +261,15 @@ fn try_compute_sysinfo(
                 min_power_limit_watt,
                 max_ce_clock_mhz,
                 max_mem_clock_mhz,
+                compute_mode,
+                compute_mode_raw,
             } = c;
             let mut gpu = output::Object::new();
             gpu.push_s("bus_addr", bus_addr.to_string());
             gpu.push_i("index", *index as i64);
+            if let Some(physical) = physical_index.get(index) {
+                gpu.push_u("physical_index", *physical as u64);
+            }
             gpu.push_s("uuid", uuid.to_string());
             gpu.push_s("manufacturer", manufacturer.clone());
             gpu.push_s("model", model.to_string());
@@ -118,6 +282,12 @@ fn try_compute_sysinfo(
             gpu.push_i("min_power_limit_watt", *min_power_limit_watt as i64);
             gpu.push_i("max_ce_clock_mhz", *max_ce_clock_mhz as i64);
             gpu.push_i("max_mem_clock_mhz", *max_mem_clock_mhz as i64);
+            if !compute_mode.is_empty() {
+                gpu.push_s("compute_mode", compute_mode.to_string());
+            }
+            if !compute_mode_raw.is_empty() {
+                gpu.push_s("compute_mode_raw", compute_mode_raw.to_string());
+            }
             gpu_info.push_o(gpu);
         }
 
@@ -125,15 +295,77 @@ fn try_compute_sysinfo(
     } else {
         ("".to_string(), 0, 0)
     };
-    let cpu_cores = sockets * cores_per_socket * threads_per_core;
+
+    // The description has a part for each collected section, in the order cpu, memory, gpu.
+    let mut description = vec![];
+    if let Some((model, sockets, cores_per_socket, threads_per_core)) = &cpu_info {
+        let ht = if *threads_per_core > 1 {
+            " (hyperthreaded)"
+        } else {
+            ""
+        };
+        description.push(format!("{sockets}x{cores_per_socket}{ht} {model}"));
+    }
+    let mem_gib = memtotal_kib.map(|kib| (kib as f64 * 1024.0 / GIB as f64).round() as i64);
+    if let Some(mem_gib) = mem_gib {
+        description.push(format!("{mem_gib} GiB"));
+    }
+    if let Some(d) = gpu_desc.strip_prefix(", ") {
+        description.push(d.to_string());
+    }
 
     let mut sysinfo = new_sysinfo(timestamp);
-    sysinfo.push_s(
-        "description",
-        format!("{sockets}x{cores_per_socket}{ht} {model}, {mem_gib} GiB{gpu_desc}"),
-    );
-    sysinfo.push_i("cpu_cores", cpu_cores as i64);
-    sysinfo.push_i("mem_gb", mem_gib);
+    sysinfo.push_s("description", description.join(", "));
+    if let Some((_, sockets, cores_per_socket, threads_per_core)) = cpu_info {
+        sysinfo.push_i(
+            "cpu_cores",
+            (sockets * cores_per_socket * threads_per_core) as i64,
+        );
+    }
+    if let (Some(memtotal_kib), Some(mem_gib)) = (memtotal_kib, mem_gib) {
+        sysinfo.push_i("mem_gb", mem_gib);
+        if let Some(limit_kib) = procfs::get_memory_limit_kib(fs, memtotal_kib) {
+            sysinfo.push_u("mem_limit_kib", limit_kib as u64);
+        }
+    }
+    if let Ok(boot_time) = procfs::get_boot_time(fs) {
+        sysinfo.push_u(
+            "uptime_secs",
+            fs.now_in_secs_since_epoch().saturating_sub(boot_time),
+        );
+    }
+    if cpu_info.is_some() {
+        let cpu_state = procfs::get_cpu_state(fs);
+        if let Some(n) = cpu_state.present_cpus {
+            sysinfo.push_u("cpus_present", n);
+        }
+        if let Some(n) = cpu_state.online_cpus {
+            sysinfo.push_u("cpus_online", n);
+        }
+        if let Some(control) = cpu_state.smt_control {
+            sysinfo.push_s("smt_control", control);
+        }
+        if let Some(active) = cpu_state.smt_active {
+            sysinfo.push_u("smt_active", active as u64);
+        }
+        let cpu_freq = procfs::get_cpu_freq(fs);
+        if let Some(mhz) = cpu_freq.max_mhz {
+            sysinfo.push_u("cpu_max_mhz", mhz);
+        }
+        if let Some(mhz) = cpu_freq.base_mhz {
+            sysinfo.push_u("cpu_base_mhz", mhz);
+        }
+        if opts.cpu_flags {
+            let flags = procfs::get_cpu_flags(fs);
+            if !flags.is_empty() {
+                let mut a = output::Array::new();
+                for f in flags {
+                    a.push_s(f);
+                }
+                sysinfo.push_a("cpu_flags", a);
+            }
+        }
+    }
     if gpu_cards != 0 {
         sysinfo.push_i("gpu_cards", gpu_cards as i64);
         if gpumem_gb != 0 {
@@ -143,28 +375,89 @@ fn try_compute_sysinfo(
             sysinfo.push_a("gpu_info", gpu_info);
         }
     }
+    if opts.infiniband {
+        let mut ib_info = output::Array::new();
+        for p in procfs::get_infiniband_ports(fs) {
+            let mut port = output::Object::new();
+            port.push_s("device", p.device);
+            port.push_s("port", p.port);
+            port.push_s("state", p.state);
+            port.push_u("xmit_data", p.xmit_data);
+            port.push_u("rcv_data", p.rcv_data);
+            ib_info.push_o(port);
+        }
+        if ib_info.len() > 0 {
+            sysinfo.push_a("ib_info", ib_info);
+        }
+    }
 
     Ok(sysinfo)
 }
 
-fn error_packet(timestamp: &str, error: String) -> output::Object {
+fn error_packet(timestamp: &str, error: errors::Error) -> output::Object {
     let mut sysinfo = new_sysinfo(timestamp);
-    sysinfo.push_s("error", error);
+    error.add_to(&mut sysinfo);
     sysinfo
 }
 
 fn new_sysinfo(timestamp: &str) -> output::Object {
     let mut sysinfo = output::Object::new();
     sysinfo.push_s("version", env!("CARGO_PKG_VERSION").to_string());
+    output::push_schema(&mut sysinfo, output::SYSINFO_SCHEMA_VERSION);
     sysinfo.push_s("timestamp", timestamp.to_string());
     sysinfo.push_s("hostname", hostname::get());
     return sysinfo;
 }
 
+// The card indices can change at boot, while the PCI bus addresses identify the physical slots.
+// Map each card's index to its position in bus address order, so that consumers can refer to the
+// same card across reboots.  Cards without a parseable bus address get no position.  Excluded cards
+// are not counted.
+
+fn physical_indices(cards: &[gpu::Card]) -> HashMap<i32, usize> {
+    let mut addrs = cards
+        .iter()
+        .filter_map(|c| parse_bus_addr(&c.bus_addr).map(|addr| (addr, c.index)))
+        .collect::<Vec<((u32, u32, u32, u32), i32)>>();
+    addrs.sort();
+    addrs
+        .into_iter()
+        .enumerate()
+        .map(|(physical, (_, index))| (index, physical))
+        .collect()
+}
+
+// A PCI bus address is domain:bus:device.function in hex, eg NVIDIA's "00000000:3B:00.0" or AMD's
+// "0000:3b:00.0".  The fields are compared numerically since the vendors differ in width and case.
+
+fn parse_bus_addr(addr: &str) -> Option<(u32, u32, u32, u32)> {
+    let (domain, rest) = addr.trim().split_once(':')?;
+    let (bus, rest) = rest.split_once(':')?;
+    let (device, function) = rest.split_once('.')?;
+    let hex = |s: &str| u32::from_str_radix(s, 16).ok();
+    Some((hex(domain)?, hex(bus)?, hex(device)?, hex(function)?))
+}
+
 // The end-to-end test for show_system() is black-box, see ../tests.  The reason for this is partly
 // that not all the system interfaces used by that function are virtualized at this time, and partly
 // that we only care that the output syntax looks right.
 
+// A one-socket node with hyperthreading and 16GB of memory.
+
+#[cfg(test)]
+fn mock_node_files() -> HashMap<String, String> {
+    HashMap::from([
+        (
+            "cpuinfo".to_string(),
+            std::include_str!("testdata/cpuinfo-short.txt").to_string(),
+        ),
+        (
+            "meminfo".to_string(),
+            "MemTotal:       16093776 kB\n".to_string(),
+        ),
+    ])
+}
+
 // Test that an error field is added correctly if we fail to obtain information we must have.
 
 #[test]
@@ -178,6 +471,444 @@ pub fn sysinfo_error_test() {
         &procfsapi::MockFS::new(files, pids, users, now),
         &gpu::MockGpuAPI::new(),
         "2025-01-24 09:19:00+01:00",
+        &Default::default(),
+        None,
     );
     assert!(sysinfo.get("error").is_some());
+    assert!(matches!(sysinfo.get("error_code"), Some(output::Value::S(s)) if s == "system"));
+    assert!(sysinfo.get("token").is_none());
+
+    // The token is attached even to the error packet.
+    let sysinfo = compute_sysinfo(
+        &procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), now),
+        &gpu::MockGpuAPI::new(),
+        "2025-01-24 09:19:00+01:00",
+        &SysinfoOptions {
+            token: "s3cret".to_string(),
+            ..Default::default()
+        },
+        None,
+    );
+    assert!(matches!(sysinfo.get("token"), Some(output::Value::S(s)) if s == "s3cret"));
+}
+
+// Test that the card-level modes make it into gpu_info, and are omitted when unknown.
+
+#[test]
+pub fn sysinfo_gpu_modes_test() {
+    let files = mock_node_files();
+    let now = procfsapi::unix_now();
+    let cards = vec![
+        gpu::Card {
+            bus_addr: "00000000:18:00.0".to_string(),
+            index: 0,
+            model: "NVIDIA A100".to_string(),
+            compute_mode: gpu::COMPUTE_MODE_EXCLUSIVE_PROCESS.to_string(),
+            compute_mode_raw: "ExclusiveProcess".to_string(),
+            ..Default::default()
+        },
+        gpu::Card {
+            bus_addr: "00000000:3B:00.0".to_string(),
+            index: 1,
+            model: "NVIDIA A100".to_string(),
+            ..Default::default()
+        },
+    ];
+    let sysinfo = compute_sysinfo(
+        &procfsapi::MockFS::new(files, vec![], HashMap::new(), now),
+        &gpu::MockGpuAPI::with_cards(cards),
+        "2025-01-24 09:19:00+01:00",
+        &Default::default(),
+        None,
+    );
+    assert!(sysinfo.get("error").is_none());
+    let gpu_info = match sysinfo.get("gpu_info") {
+        Some(output::Value::A(a)) => a,
+        _ => panic!("Test: no gpu_info"),
+    };
+    assert!(gpu_info.len() == 2);
+    let (first, second) = match (gpu_info.at(0), gpu_info.at(1)) {
+        (output::Value::O(a), output::Value::O(b)) => (a, b),
+        _ => panic!("Test: bad gpu_info"),
+    };
+    assert!(
+        matches!(first.get("compute_mode"), Some(output::Value::S(s)) if s == "exclusive-process")
+    );
+    assert!(
+        matches!(first.get("compute_mode_raw"), Some(output::Value::S(s)) if s == "ExclusiveProcess")
+    );
+    assert!(second.get("compute_mode").is_none());
+    assert!(second.get("compute_mode_raw").is_none());
+}
+
+// Test that the uptime is the time since the boot time in /proc/stat, and is omitted without it.
+
+#[test]
+pub fn sysinfo_uptime_test() {
+    let mut files = mock_node_files();
+    let now = 1698303295 + 86400 + 17;
+    let sysinfo = compute_sysinfo(
+        &procfsapi::MockFS::new(files.clone(), vec![], HashMap::new(), now),
+        &gpu::MockGpuAPI::new(),
+        "2025-01-24 09:19:00+01:00",
+        &Default::default(),
+        None,
+    );
+    assert!(sysinfo.get("error").is_none());
+    assert!(sysinfo.get("uptime_secs").is_none());
+
+    files.insert(
+        "stat".to_string(),
+        "cpu  1 2 3\nbtime 1698303295\n".to_string(),
+    );
+    let sysinfo = compute_sysinfo(
+        &procfsapi::MockFS::new(files, vec![], HashMap::new(), now),
+        &gpu::MockGpuAPI::new(),
+        "2025-01-24 09:19:00+01:00",
+        &Default::default(),
+        None,
+    );
+    assert!(matches!(
+        sysinfo.get("uptime_secs"),
+        Some(output::Value::U(86417))
+    ));
+}
+
+// Test that only the selected sections are collected and reported.
+
+#[test]
+pub fn sysinfo_sections_test() {
+    let files = mock_node_files();
+    let now = procfsapi::unix_now();
+    let cards = vec![gpu::Card {
+        bus_addr: "00000000:18:00.0".to_string(),
+        model: "NVIDIA A100".to_string(),
+        mem_size_kib: 40 * 1024 * 1024,
+        ..Default::default()
+    }];
+
+    let sysinfo = compute_sysinfo(
+        &procfsapi::MockFS::new(files.clone(), vec![], HashMap::new(), now),
+        &gpu::MockGpuAPI::with_cards(cards.clone()),
+        "2025-01-24 09:19:00+01:00",
+        &SysinfoOptions {
+            sections: Some(vec!["cpu".to_string(), "memory".to_string()]),
+            ..Default::default()
+        },
+        None,
+    );
+    assert!(sysinfo.get("error").is_none());
+    assert!(matches!(
+        sysinfo.get("cpu_cores"),
+        Some(output::Value::I(8))
+    ));
+    assert!(matches!(sysinfo.get("mem_gb"), Some(output::Value::I(15))));
+    assert!(sysinfo.get("gpu_cards").is_none());
+    assert!(sysinfo.get("gpu_info").is_none());
+    assert!(
+        matches!(sysinfo.get("description"), Some(output::Value::S(s))
+        if s == "1x4 (hyperthreaded) Intel(R) Xeon(R) CPU E5-2637 v4 @ 3.50GHz, 15 GiB")
+    );
+
+    // Without the cpu and memory sections their files are not read, so their absence is no error.
+    let sysinfo = compute_sysinfo(
+        &procfsapi::MockFS::new(HashMap::new(), vec![], HashMap::new(), now),
+        &gpu::MockGpuAPI::with_cards(cards),
+        "2025-01-24 09:19:00+01:00",
+        &SysinfoOptions {
+            sections: Some(vec!["gpu".to_string()]),
+            ..Default::default()
+        },
+        None,
+    );
+    assert!(sysinfo.get("error").is_none());
+    assert!(sysinfo.get("cpu_cores").is_none());
+    assert!(sysinfo.get("mem_gb").is_none());
+    assert!(matches!(
+        sysinfo.get("gpu_cards"),
+        Some(output::Value::I(1))
+    ));
+    assert!(
+        matches!(sysinfo.get("description"), Some(output::Value::S(s)) if s == "1x NVIDIA A100 @ 40GiB")
+    );
+}
+
+// Test that the vector extensions are reported only with --cpu-flags.
+
+#[test]
+pub fn sysinfo_cpu_flags_test() {
+    let mut files = mock_node_files();
+    files
+        .get_mut("cpuinfo")
+        .expect("Test: cpuinfo")
+        .push_str("flags\t\t: fpu sse4_2 avx fma avx2\n");
+    let now = procfsapi::unix_now();
+    let sysinfo_with = |opts: &SysinfoOptions| {
+        compute_sysinfo(
+            &procfsapi::MockFS::new(files.clone(), vec![], HashMap::new(), now),
+            &gpu::MockGpuAPI::new(),
+            "2025-01-24 09:19:00+01:00",
+            opts,
+            None,
+        )
+    };
+
+    let sysinfo = sysinfo_with(&Default::default());
+    assert!(sysinfo.get("cpu_flags").is_none());
+
+    let sysinfo = sysinfo_with(&SysinfoOptions {
+        cpu_flags: true,
+        ..Default::default()
+    });
+    match sysinfo.get("cpu_flags") {
+        Some(output::Value::A(a)) => {
+            assert!(a.len() == 2);
+            assert!(matches!(a.at(0), output::Value::S(s) if s == "avx"));
+            assert!(matches!(a.at(1), output::Value::S(s) if s == "avx2"));
+        }
+        _ => panic!("Test: Expected cpu_flags"),
+    }
+}
+
+// Test that excluded cards are left out of the card information and the others keep their indices.
+
+#[test]
+pub fn sysinfo_exclude_gpus_test() {
+    let files = mock_node_files();
+    let cards = ["GPU-aaaa1111", "GPU-bbbb2222", "GPU-cccc3333"]
+        .iter()
+        .enumerate()
+        .map(|(i, uuid)| gpu::Card {
+            index: i as i32,
+            uuid: uuid.to_string(),
+            model: if i == 0 { "NVIDIA T400" } else { "NVIDIA A100" }.to_string(),
+            ..Default::default()
+        })
+        .collect::<Vec<gpu::Card>>();
+    let sysinfo = compute_sysinfo(
+        &procfsapi::MockFS::new(files, vec![], HashMap::new(), procfsapi::unix_now()),
+        &gpu::MockGpuAPI::with_cards(cards),
+        "2025-01-24 09:19:00+01:00",
+        &SysinfoOptions {
+            exclude_gpus: vec!["0".to_string(), "GPU-cccc3333".to_string()],
+            ..Default::default()
+        },
+        None,
+    );
+    assert!(matches!(
+        sysinfo.get("gpu_cards"),
+        Some(output::Value::I(1))
+    ));
+    let gpu_info = match sysinfo.get("gpu_info") {
+        Some(output::Value::A(a)) => a,
+        _ => panic!("Test: no gpu_info"),
+    };
+    assert!(gpu_info.len() == 1);
+    match gpu_info.at(0) {
+        output::Value::O(o) => {
+            assert!(matches!(o.get("index"), Some(output::Value::I(1))));
+            assert!(matches!(o.get("uuid"), Some(output::Value::S(s)) if s == "GPU-bbbb2222"));
+        }
+        _ => panic!("Test: bad gpu_info"),
+    }
+}
+
+// Test that the operator's metadata end up under "metadata" and don't clobber collected fields.
+
+#[test]
+pub fn sysinfo_metadata_test() {
+    let files = mock_node_files();
+    let metadata = match json::parse(std::include_str!("testdata/node-metadata.json")) {
+        Ok(output::Value::O(o)) => o,
+        _ => panic!("Test: bad metadata fixture"),
+    };
+    let sysinfo = compute_sysinfo(
+        &procfsapi::MockFS::new(files, vec![], HashMap::new(), procfsapi::unix_now()),
+        &gpu::MockGpuAPI::new(),
+        "2025-01-24 09:19:00+01:00",
+        &Default::default(),
+        Some(metadata),
+    );
+    assert!(sysinfo.get("error").is_none());
+    assert!(matches!(sysinfo.get("hostname"), Some(output::Value::S(s)) if *s == hostname::get()));
+    assert!(matches!(sysinfo.get("schema"),
+                     Some(output::Value::U(n)) if *n == output::SYSINFO_SCHEMA_VERSION));
+    assert!(matches!(
+        sysinfo.get("cpu_cores"),
+        Some(output::Value::I(8))
+    ));
+    let metadata = match sysinfo.get("metadata") {
+        Some(output::Value::O(o)) => o,
+        _ => panic!("Test: no metadata"),
+    };
+    assert!(matches!(metadata.get("asset_tag"), Some(output::Value::S(s)) if s == "UIO-004711"));
+    assert!(
+        matches!(metadata.get("hostname"), Some(output::Value::S(s)) if s == "not-the-real-hostname")
+    );
+    match metadata.get("location") {
+        Some(output::Value::O(l)) => {
+            assert!(matches!(l.get("rack"), Some(output::Value::S(s)) if s == "r12"));
+            assert!(matches!(l.get("slot"), Some(output::Value::U(7))));
+        }
+        _ => panic!("Test: no location"),
+    }
+}
+
+// Test that with --physical-index each card gets its position in bus address order, which here
+// differs from the index order.
+
+#[test]
+pub fn sysinfo_physical_index_test() {
+    let files = mock_node_files();
+    let cards = [
+        (0, "00000000:AF:00.0"),
+        (1, "00000000:18:00.0"),
+        (2, "0000:3b:00.0"),
+        (3, "00000001:04:00.0"),
+        (4, ""),
+    ]
+    .iter()
+    .map(|(index, bus_addr)| gpu::Card {
+        index: *index,
+        bus_addr: bus_addr.to_string(),
+        model: "NVIDIA A100".to_string(),
+        ..Default::default()
+    })
+    .collect::<Vec<gpu::Card>>();
+    let physical = |opts: &SysinfoOptions| -> HashMap<i64, Option<u64>> {
+        let sysinfo = compute_sysinfo(
+            &procfsapi::MockFS::new(files.clone(), vec![], HashMap::new(), procfsapi::unix_now()),
+            &gpu::MockGpuAPI::with_cards(cards.clone()),
+            "2025-01-24 09:19:00+01:00",
+            opts,
+            None,
+        );
+        let gpu_info = match sysinfo.get("gpu_info") {
+            Some(output::Value::A(a)) => a,
+            _ => panic!("Test: no gpu_info"),
+        };
+        (0..gpu_info.len())
+            .map(|i| match gpu_info.at(i) {
+                output::Value::O(o) => match (o.get("index"), o.get("physical_index")) {
+                    (Some(output::Value::I(index)), Some(output::Value::U(p))) => {
+                        (*index, Some(*p))
+                    }
+                    (Some(output::Value::I(index)), None) => (*index, None),
+                    _ => panic!("Test: bad index"),
+                },
+                _ => panic!("Test: bad gpu_info"),
+            })
+            .collect()
+    };
+
+    let mapping = physical(&SysinfoOptions {
+        physical_index: true,
+        ..Default::default()
+    });
+    assert!(mapping.len() == 5);
+    assert!(mapping[&1] == Some(0));
+    assert!(mapping[&2] == Some(1));
+    assert!(mapping[&0] == Some(2));
+    assert!(mapping[&3] == Some(3));
+    assert!(mapping[&4].is_none());
+
+    // Off by default
+    let mapping = physical(&Default::default());
+    assert!(mapping.values().all(|p| p.is_none()));
+}
+
+// Test that --diff isolates a change to one GPU field, even though the cards are listed in a
+// different order and the baseline has been through JSON.
+
+#[test]
+pub fn sysinfo_diff_test() {
+    let files = mock_node_files();
+    let card = |index: i32, uuid: &str, driver: &str| gpu::Card {
+        index,
+        uuid: uuid.to_string(),
+        model: "NVIDIA A100".to_string(),
+        driver: driver.to_string(),
+        ..Default::default()
+    };
+    let sysinfo = |cards: Vec<gpu::Card>, timestamp: &str| {
+        compute_sysinfo(
+            &procfsapi::MockFS::new(files.clone(), vec![], HashMap::new(), procfsapi::unix_now()),
+            &gpu::MockGpuAPI::with_cards(cards),
+            timestamp,
+            &Default::default(),
+            None,
+        )
+    };
+    let old = sysinfo(
+        vec![
+            card(0, "GPU-aaaa", "535.104.05"),
+            card(1, "GPU-bbbb", "535.104.05"),
+        ],
+        "2025-01-24 09:19:00+01:00",
+    );
+    let new = sysinfo(
+        vec![
+            card(0, "GPU-bbbb", "535.104.05"),
+            card(1, "GPU-aaaa", "550.54.15"),
+        ],
+        "2025-01-25 09:19:00+01:00",
+    );
+
+    let dir = std::env::temp_dir().join(format!("sonar-diff-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let filename = dir.join("baseline.json");
+    let mut buf = vec![];
+    output::write_json(&mut buf, &output::Value::O(old));
+    std::fs::write(&filename, buf).unwrap();
+    let filename = filename.to_str().unwrap();
+
+    let packet = diff_packet("2025-01-25 09:19:00+01:00", &new, filename);
+    assert!(packet.get("error").is_none());
+    assert!(matches!(packet.get("baseline"), Some(output::Value::S(s)) if s == filename));
+    let changes = match packet.get("changes") {
+        Some(output::Value::A(a)) => a,
+        _ => panic!("Test: no changes"),
+    };
+    let summary = (0..changes.len())
+        .map(|i| match changes.at(i) {
+            output::Value::O(o) => {
+                match (o.get("path"), o.get("change"), o.get("old"), o.get("new")) {
+                    (
+                        Some(output::Value::S(p)),
+                        Some(output::Value::S(c)),
+                        Some(output::Value::S(old)),
+                        Some(output::Value::S(new)),
+                    ) => format!("{c} {p} {old} {new}"),
+                    (Some(output::Value::S(p)), Some(output::Value::S(c)), _, _) => {
+                        format!("{c} {p}")
+                    }
+                    _ => panic!("Test: bad change"),
+                }
+            }
+            _ => panic!("Test: Expected object"),
+        })
+        .collect::<Vec<String>>();
+    // The index is a change too, as the cards were renumbered.
+    assert!(
+        summary
+            == [
+                "changed gpu_info.GPU-aaaa.index",
+                "changed gpu_info.GPU-aaaa.driver 535.104.05 550.54.15",
+                "changed gpu_info.GPU-bbbb.index",
+            ]
+    );
+
+    // Nothing changed
+    let mut buf = vec![];
+    output::write_json(&mut buf, &output::Value::O(new.clone()));
+    std::fs::write(filename, buf).unwrap();
+    let packet = diff_packet("2025-01-25 09:19:00+01:00", &new, filename);
+    assert!(matches!(packet.get("changes"), Some(output::Value::A(a)) if a.len() == 0));
+
+    // Bad baseline
+    std::fs::write(filename, "[1, 2]").unwrap();
+    let packet = diff_packet("2025-01-25 09:19:00+01:00", &new, filename);
+    assert!(matches!(packet.get("error_code"), Some(output::Value::S(s)) if s == "bad-argument"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
 }
@@ -0,0 +1,63 @@
+// The output records of `ps`, `sysinfo` and `slurm` can carry an authentication or identification
+// token, for the receiving end to validate.  The token is taken from the first of these that is
+// present and not empty:
+//
+// - the `--token TOKEN` option (also as `token = TOKEN` in a config file, as that becomes an
+//   option)
+// - the SONAR_TOKEN environment variable
+// - the first line of the file named by `--token-file FILE`
+//
+// and is otherwise empty, in which case no token is output.  The token is a secret: it must not
+// appear in log or error messages, only in the output records.
+
+use std::fs;
+
+pub const TOKEN_ENV_VAR: &str = "SONAR_TOKEN";
+
+pub fn resolve_token(
+    flag: Option<&str>,
+    env: Option<&str>,
+    filename: Option<&str>,
+) -> Result<String, String> {
+    if let Some(token) = flag.filter(|t| !t.is_empty()) {
+        return Ok(token.to_string());
+    }
+    if let Some(token) = env.filter(|t| !t.is_empty()) {
+        return Ok(token.to_string());
+    }
+    if let Some(filename) = filename {
+        // The error from reading is about the file, it does not include its contents.
+        return match fs::read_to_string(filename) {
+            Ok(text) => Ok(text.lines().next().unwrap_or("").trim().to_string()),
+            Err(e) => Err(format!("Could not read token file {filename}: {e}")),
+        };
+    }
+    Ok("".to_string())
+}
+
+#[test]
+pub fn resolve_token_test() {
+    let dir = std::env::temp_dir().join(format!("sonar-token-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let filename = dir.join("token");
+    fs::write(&filename, "from-file\n# not part of the token\n").unwrap();
+    let filename = filename.to_str().unwrap();
+
+    // Precedence is flag > env > file > empty
+    let resolve = |flag, env, file| resolve_token(flag, env, file).unwrap();
+    assert!(resolve(Some("from-flag"), Some("from-env"), Some(filename)) == "from-flag");
+    assert!(resolve(None, Some("from-env"), Some(filename)) == "from-env");
+    assert!(resolve(None, None, Some(filename)) == "from-file");
+    assert!(resolve(None, None, None).is_empty());
+
+    // Empty values do not count
+    assert!(resolve(Some(""), Some("from-env"), None) == "from-env");
+    assert!(resolve(None, Some(""), Some(filename)) == "from-file");
+
+    // The file is not read if it is not needed, and an unreadable file is an error
+    let missing = "/nonexistent/sonar-token";
+    assert!(resolve(None, Some("from-env"), Some(missing)) == "from-env");
+    assert!(resolve_token(None, None, Some(missing)).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
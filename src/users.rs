@@ -32,6 +32,9 @@ use std::ffi::{CStr, OsStr, OsString};
 use std::mem;
 use std::os::unix::ffi::OsStrExt;
 use std::ptr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 use libc::passwd as c_passwd;
 use libc::uid_t;
@@ -76,3 +79,29 @@ pub fn get_user_by_uid(uid: uid_t) -> Option<OsString> {
         ))
     })
 }
+
+/// Run a user lookup on a helper thread and wait at most `timeout` for it to complete.  Lookups can
+/// block for a very long time when the user database is remote (LDAP via NSS, say) and slow.  On
+/// timeout an error message is returned and the helper thread is abandoned; it exits when the
+/// lookup eventually returns, or with the process.
+pub fn lookup_with_timeout<F>(
+    uid: uid_t,
+    lookup: F,
+    timeout: Duration,
+) -> Result<Option<String>, String>
+where
+    F: FnOnce() -> Option<String> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // The receiver is gone if we timed out.
+        let _ = sender.send(lookup());
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(name) => Ok(name),
+        Err(_) => Err(format!(
+            "Lookup of user {uid} timed out after {}ms",
+            timeout.as_millis()
+        )),
+    }
+}
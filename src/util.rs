@@ -84,6 +84,35 @@ pub fn json_quote_test() {
     assert!(&json_quote("abc\u{0008}de") == r#"abc de"#);
 }
 
+// If `s` is longer than `max` chars then truncate it to `max` chars, the last three of which are
+// "..." to mark the truncation (if `max` is less than three then the result is only dots).
+// Truncation is on char boundaries so the result is valid UTF-8.
+pub fn truncate_chars(s: &str, max: usize) -> String {
+    if s.chars().nth(max).is_none() {
+        return s.to_string();
+    }
+    let marker = &"..."[..max.min(3)];
+    let keep = max - marker.len();
+    match s.char_indices().nth(keep) {
+        Some((ix, _)) => s[..ix].to_string() + marker,
+        None => s.to_string(),
+    }
+}
+
+#[test]
+pub fn truncate_chars_test() {
+    assert!(&truncate_chars("python", 10) == "python");
+    assert!(&truncate_chars("python", 6) == "python");
+    assert!(&truncate_chars("python3.12", 9) == "python...");
+    // Multi-byte chars are kept whole
+    assert!(&truncate_chars("blåbærsyltetøy", 7) == "blåb...");
+    assert!(&truncate_chars("日本語のコマンド", 6) == "日本語...");
+    // The result is never longer than the limit
+    assert!(&truncate_chars("python", 3) == "...");
+    assert!(&truncate_chars("python", 2) == "..");
+    assert!(truncate_chars("x", 0).is_empty());
+}
+
 // If the value contains a , or " then quote the string, and double every "
 pub fn csv_quote(s: &str) -> String {
     let mut t = "".to_string();
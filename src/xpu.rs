@@ -22,9 +22,9 @@ impl gpu::GPU for XpuGPU {
     fn get_card_configuration(&mut self) -> Result<Vec<gpu::Card>, String> {
         let mut num_devices: cty::uint32_t = 0;
         if unsafe { xpu_device_get_count(&mut num_devices) } != 0 {
-            return Ok(vec![])
+            return Ok(vec![]);
         }
-        return Ok(vec![])
+        return Ok(vec![]);
     }
 
     fn get_process_utilization(
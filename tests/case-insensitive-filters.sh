@@ -0,0 +1,22 @@
+#!/usr/bin/env bash
+#
+# Test that --case-insensitive-filters lowercases the filter terms given on the command line, and
+# that the terms are compared as given without it.
+
+set -e
+( cd .. ; cargo build )
+numbad=$(../target/debug/sonar ps --case-insensitive-filters --exclude-users ROOT --exclude-commands KWORKER | \
+    awk "
+/,user=root,/ { print }
+/,cmd=kworker/ { print }
+" | \
+    wc -l)
+if [[ $numbad -ne 0 ]]; then
+    echo "Case-insensitive filtering did not work"
+    exit 1
+fi
+numroot=$(../target/debug/sonar ps --exclude-users ROOT | grep -c ',user=root,' || true)
+if [[ $numroot -eq 0 ]]; then
+    echo "Filtering without --case-insensitive-filters was not case-sensitive"
+    exit 1
+fi
@@ -0,0 +1,86 @@
+#!/usr/bin/env bash
+#
+# Test that settings are read from a --config file and that the command line overrides them.
+
+set -e
+( cd .. ; cargo build )
+config=$(mktemp)
+trap "rm -f $config" EXIT
+cat > $config <<CONFIG
+# Test configuration
+[ps]
+exclude-system-jobs
+max-command-length = 1
+CONFIG
+
+# Print the records whose command, without the truncation marker, is longer than $1.
+too_long() {
+    awk -v max=$1 '
+{
+    s = substr($0, index($0, ",cmd=") + 5)
+    ix = index(s, ",")
+    if (ix > 0)
+        s = substr(s, 1, ix - 1)
+    sub(/\.\.\.$/, "", s)
+    if (length(s) > max)
+        print
+}'
+}
+
+# The file takes effect
+output=$(../target/debug/sonar ps --config $config)
+numbad=$( (echo "$output" | too_long 1 ; echo "$output" | grep ',user=root,' || true) | wc -l)
+if [[ $numbad -ne 0 ]]; then
+    echo "Settings from the config file did not take effect"
+    exit 1
+fi
+
+# The command line overrides the file
+# (and some command is longer than the file allows).
+output=$(../target/debug/sonar ps --config $config --max-command-length 3)
+numbad=$(echo "$output" | too_long 3 | wc -l)
+if [[ $(echo "$output" | too_long 1 | wc -l) -eq 0 ]]; then
+    numbad=1
+fi
+if [[ $numbad -ne 0 ]]; then
+    echo "The command line did not override the config file"
+    exit 1
+fi
+
+# List values may have spaces after the commas, and `=false` turns off an option without a value
+# also without a config file
+cat > $config <<CONFIG
+[ps]
+exclude-users = root, $LOGNAME
+CONFIG
+numbad=$(../target/debug/sonar ps --config $config --load=false | \
+    grep -E ",user=(root|$LOGNAME)," | wc -l)
+if [[ $numbad -ne 0 ]]; then
+    echo "The spaces in a list value in the config file were not ignored"
+    exit 1
+fi
+if ! ../target/debug/sonar ps --load=false > /dev/null; then
+    echo "--load=false without a config file was not accepted"
+    exit 1
+fi
+
+# --include-kernel-threads on the command line overrides --exclude-kernel-threads in the file
+cat > $config <<CONFIG
+[ps]
+exclude-kernel-threads
+CONFIG
+if [[ $(../target/debug/sonar ps --config $config | grep -c ',ppid=2,') -ne 0 ]]; then
+    echo "exclude-kernel-threads in the config file did not take effect"
+    exit 1
+fi
+output=$(../target/debug/sonar ps --config $config --include-kernel-threads)
+if [[ $(echo "$output" | grep -c ',ppid=2,') -eq 0 ]]; then
+    echo "--include-kernel-threads did not override the config file"
+    exit 1
+fi
+
+# A missing file is an error
+if ../target/debug/sonar ps --config /nonexistent/sonar.ini > /dev/null 2>&1; then
+    echo "A missing config file was not an error"
+    exit 1
+fi
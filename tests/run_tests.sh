@@ -15,7 +15,9 @@ fi
 # later we could just iterate over all scripts that end with .sh
 # and are not this script
 for test in amd-gpu \
+                case-insensitive-filters \
                 command-line \
+                config-file \
                 exclude-commands \
                 exclude-system-jobs \
                 exclude-users \
@@ -33,7 +35,9 @@ for test in amd-gpu \
                 rollup2 \
                 slurm-no-sacct \
                 slurm-syntax \
+                sysinfo-diff \
                 sysinfo-syntax \
+                token \
                 user \
             ; do
     echo $test
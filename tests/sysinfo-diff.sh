@@ -0,0 +1,33 @@
+#!/usr/bin/env bash
+#
+# Check that `sonar sysinfo --diff` carries the tags, both on the changes and on the error record for
+# a baseline that can't be read.
+# Requirement: the `jq` utility.
+
+set -e
+( cd .. ; cargo build )
+if [[ $(command -v jq) == "" ]]; then
+    echo "Install jq first"
+    exit 1
+fi
+baseline=$(mktemp)
+trap "rm -f $baseline" EXIT
+../target/debug/sonar sysinfo > $baseline
+
+output=$(../target/debug/sonar sysinfo --diff $baseline --tag site=osl --tag rack=r12)
+if [[ $(jq -r '.tags.site + "," + .tags.rack' <<< $output) != "osl,r12" ]]; then
+    echo "Tags missing from the diff: $output"
+    exit 1
+fi
+if [[ $(jq -r '.changes | type' <<< $output) != "array" ]]; then
+    echo "Changes missing from the diff: $output"
+    exit 1
+fi
+
+output=$(../target/debug/sonar sysinfo --diff /nonexistent/sonar-baseline.json --tag site=osl)
+if [[ $(jq -r '.tags.site' <<< $output) != "osl" ]]; then
+    echo "Tags missing from the diff error: $output"
+    exit 1
+fi
+
+echo " Ok"
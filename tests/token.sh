@@ -0,0 +1,41 @@
+#!/usr/bin/env bash
+#
+# Check that the token is taken from --token, SONAR_TOKEN, or --token-file in that order, and that it
+# appears in the output.
+# Requirement: the `jq` utility.
+
+set -e
+( cd .. ; cargo build )
+if [[ $(command -v jq) == "" ]]; then
+    echo "Install jq first"
+    exit 1
+fi
+tokenfile=$(mktemp)
+trap "rm -f $tokenfile" EXIT
+echo "from-file" > $tokenfile
+
+check() {
+    local expected=$1
+    shift
+    local token=$(../target/debug/sonar "$@" | head -n 1 | jq -r '.token // ""')
+    if [[ $token != $expected ]]; then
+        echo "Expected token '$expected' with $*, got '$token'"
+        exit 1
+    fi
+}
+
+unset SONAR_TOKEN
+check "" sysinfo
+check from-flag sysinfo --token from-flag --token-file $tokenfile
+check from-file sysinfo --token-file $tokenfile
+check from-flag ps --json --token from-flag
+SONAR_TOKEN=from-env check from-env sysinfo --token-file $tokenfile
+SONAR_TOKEN=from-env check from-flag sysinfo --token from-flag
+
+# An unreadable token file is an error
+if ../target/debug/sonar sysinfo --token-file /nonexistent/sonar-token > /dev/null 2>&1; then
+    echo "A missing token file was not an error"
+    exit 1
+fi
+
+echo " Ok"